@@ -48,7 +48,8 @@ CREATE TABLE `tx_out` (
   `indexOut`        int(10) unsigned            		NOT NULL,
   `value`           bigint(8) unsigned            		NOT NULL,
   `scriptPubKey`    blob                                NOT NULL,
-  `address`     	varchar(36) 					DEFAULT NULL,
+  `address`     	varchar(90) 					DEFAULT NULL, # empty for outputs with several destinations
+  `destinations`   	varchar(750) 					DEFAULT NULL, # comma separated for multisig
   `unspent`        	bit DEFAULT TRUE                    NOT NULL,
 
   PRIMARY KEY (`id`)
@@ -122,7 +123,7 @@ LOAD DATA INFILE '/media/tmp/dump/tx_out-0-393489.csv'
 INTO TABLE tx_out
 FIELDS TERMINATED BY ';'
 LINES TERMINATED BY '\n'
-(@txid, indexOut, value, @scriptPubKey, address)
+(@txid, indexOut, value, @scriptPubKey, address, destinations)
 SET txid = unhex(@txid),
 	scriptPubKey = unhex(@scriptPubKey);
 COMMIT;
@@ -33,7 +33,7 @@ CREATE OR REPLACE VIEW view_balances AS
     FROM
         tx_out
     WHERE
-        unspent = TRUE
+        unspent = TRUE AND address <> ''
     GROUP BY address
     ORDER BY balance DESC;
 
@@ -1,9 +1,12 @@
+use std::cmp;
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::fs::File;
-use std::hash::BuildHasherDefault;
+use std::io::{BufWriter, Read, Write};
+use std::fs::{self, File};
+use std::hash::{BuildHasherDefault, Hasher};
 use std::path::Path;
 
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use memmap::Mmap;
 use rustc_serialize::json;
 use twox_hash::XxHash;
 
@@ -14,15 +17,47 @@ use blockchain::utils;
 use blockchain::parser::types::CoinType;
 
 
+/// File layout (little endian):
+/// <magic> <version u32> <latest_blk_idx u32> <index u64> <len u64> <t_created i64> <checksum u64> <records...>
+/// Each record holds the block hash and its location, see BlockLocation.
+/// The checksum is a XxHash64 over all records.
+const MAGIC: &'static [u8; 8] = b"RBPCHAIN";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 8 + 4 + 4 + 8 + 8 + 8 + 8;
+const RECORD_SIZE: usize = 32 + 4 + 4 + 8;
+
+/// Position of a block within the blk files
+#[derive(Debug, Clone, PartialEq)]
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct BlockLocation {
+    pub blk_index: u32,  // Index of the blk file
+    pub blk_offset: u64, // Offset of the block header within the blk file
+    pub blocksize: u32   // Block size in bytes (0 if unknown)
+}
+
+/// JSON format used by previous versions (chain.json), only used for migration.
+/// Storages created before block locations were tracked don't contain `locations`.
+#[derive(RustcDecodable, RustcEncodable)]
+struct LegacyChainStorage {
+    hashes: Vec<[u8; 32]>,
+    locations: Option<Vec<BlockLocation>>,
+    index: usize,
+    latest_blk_idx: u32,
+    t_created: i64
+}
+
 /// Represents the Blockchain without stales or orphan blocks.
 /// Buffer does not hold the whole blockchain, just the block hashes with the appropriate order.
 /// It is also possible to serialize and load the hashes from file for faster processing.
-#[derive(RustcDecodable, RustcEncodable)]
+/// Loaded records stay memory mapped, new blocks are kept in memory until the next `serialize`.
 pub struct ChainStorage {
-    hashes: Vec<[u8; 32]>,
+    mmap: Option<Mmap>,                         // Records of the loaded file
+    n_mapped: usize,                            // Number of records in mmap
+    appended: Vec<([u8; 32], BlockLocation)>,   // Blocks added since load
     hashes_len: usize,
 
     index: usize,            // Index of the latest processed block_hash
+    held_back: usize,        // Number of latest blocks which are excluded from processing, see hold_back()
     pub latest_blk_idx: u32, // Index of blk.dat file for the latest processed block
     pub t_created: i64       // CreatedAt timestamp
 }
@@ -30,11 +65,13 @@ pub struct ChainStorage {
 impl ChainStorage {
 
     /// Extends an existing ChainStorage with new hashes.
+    /// `locations` has to contain the BlockLocation for each header.
     pub fn extend(&mut self, headers: Vec<Hashed<BlockHeader>>,
+        locations: &HashMap<[u8; 32], BlockLocation, BuildHasherDefault<XxHash>>,
         coin_type: &CoinType, latest_blk_idx: u32) -> OpResult<()> {
 
         let len = headers.len();
-        let mut hashes: Vec<[u8; 32]> = Vec::with_capacity(len);
+        let mut entries: Vec<([u8; 32], BlockLocation)> = Vec::with_capacity(len);
         for i in 0..len {
             if i < len - 1 {
                 if headers[i].hash != headers[i + 1].value.prev_hash {
@@ -42,13 +79,13 @@ impl ChainStorage {
                         .join_msg("Longest-chain consistency check failed!"));
                 }
             }
-            hashes.push(headers[i].hash);
+            entries.push((headers[i].hash, transform!(locations.get(&headers[i].hash)).clone()));
         }
 
-        if !hashes.is_empty() {
-            if self.hashes.is_empty() {
+        if !entries.is_empty() {
+            if self.hashes_len == 0 {
                 // Genesis block consistency check
-                let first_hash = transform!(hashes.first().cloned());
+                let first_hash = transform!(entries.first()).0;
                 if &coin_type.genesis_hash != &first_hash {
                     let errbuf = format!("Genesis hash for `{}` does not match:\n  Got: {}\n  Exp: {}",
                         coin_type.name,
@@ -58,54 +95,196 @@ impl ChainStorage {
                 } else {
                     debug!(target: "chain", "Genesis hash is valid.");
                 }
-                self.hashes.append(&mut hashes);
+                self.appended.append(&mut entries);
             } else {
                 // Create a slice to insert only new blocks
-                let latest_hash = transform!(self.hashes.last()).clone();
-                let latest_known_idx = transform!(headers.iter().position(|h| h.hash == latest_hash));
+                let latest_hash = transform!(self.get_hash(self.hashes_len - 1));
+                let latest_known_idx = transform!(entries.iter().position(|e| e.0 == latest_hash));
 
-                let mut new_hashes = hashes.split_off(latest_known_idx + 1);
-                if new_hashes.len() > 0 {
+                let mut new_entries = entries.split_off(latest_known_idx + 1);
+                if new_entries.len() > 0 {
                     debug!(target: "chain", "\n  -> latest known block:  {}\n  -> first new block:     {}",
-                           utils::arr_to_hex_swapped(transform!(self.hashes.last())),
-                           utils::arr_to_hex_swapped(transform!(new_hashes.first())));
-                    self.hashes.append(&mut new_hashes);
+                           utils::arr_to_hex_swapped(&latest_hash),
+                           utils::arr_to_hex_swapped(&transform!(new_entries.first()).0));
+                    self.appended.append(&mut new_entries);
                 }
             }
-            debug!(target: "chain", "Inserted {} new blocks ...", self.hashes.len() - self.hashes_len);
+            debug!(target: "chain", "Inserted {} new blocks ...", self.n_mapped + self.appended.len() - self.hashes_len);
         }
-        self.hashes_len = self.hashes.len();
+        self.hashes_len = self.n_mapped + self.appended.len();
         self.latest_blk_idx = latest_blk_idx;
         Ok(())
     }
 
-    /// Loads serialized object and creates a new instance
+    /// Appends a single block to the end of the chain
+    pub fn push(&mut self, hash: [u8; 32], location: BlockLocation) {
+        self.appended.push((hash, location));
+        self.hashes_len += 1;
+    }
+
+    /// Removes all blocks from height `len` upwards, e.g. after a chain reorganization.
+    /// Returns the removed blocks. Blocks which are already consumed can't be removed.
+    pub fn truncate(&mut self, len: usize) -> OpResult<Vec<([u8; 32], BlockLocation)>> {
+        if len < self.index {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                "Unable to remove blocks below height {}, they are already processed", self.index)));
+        }
+        let mut removed = Vec::with_capacity(self.hashes_len.saturating_sub(len));
+        for height in len..self.hashes_len {
+            let location = match self.get_location(height) {
+                Some(location) => location,
+                None => BlockLocation { blk_index: 0, blk_offset: 0, blocksize: 0 }
+            };
+            removed.push((transform!(self.get_hash(height)), location));
+        }
+        if len < self.n_mapped {
+            self.n_mapped = len;
+            self.appended.clear();
+        } else {
+            self.appended.truncate(len - self.n_mapped);
+        }
+        self.hashes_len = cmp::min(self.hashes_len, len);
+        Ok(removed)
+    }
+
+    /// Loads serialized object and creates a new instance.
+    /// Files in the legacy JSON format are migrated transparently.
     pub fn load(path: &Path) -> OpResult<ChainStorage> {
-        let mut encoded = String::new();
+        let mut file = try!(File::open(&path));
+        let mut magic = [0u8; 8];
+        let is_binary = match file.read_exact(&mut magic) {
+            Ok(_) => &magic == MAGIC,
+            Err(_) => false
+        };
+        let storage = match is_binary {
+            true => try!(ChainStorage::load_binary(file, path)),
+            false => {
+                info!(target: "chain", "Migrating {} from JSON ...", path.display());
+                try!(ChainStorage::load_json(path))
+            }
+        };
+        debug!(target: "chain", "Imported {} hashes from {}. Current block height: {} ... (latest blk.dat index: {})",
+                       storage.len(), path.display(), storage.get_cur_height(), storage.latest_blk_idx);
+        Ok(storage)
+    }
 
+    fn load_binary(mut file: File, path: &Path) -> OpResult<ChainStorage> {
+        let version = try!(file.read_u32::<LittleEndian>());
+        if version != VERSION {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                "Unsupported chain storage version {} in {}", version, path.display())));
+        }
+        let latest_blk_idx = try!(file.read_u32::<LittleEndian>());
+        let index = try!(file.read_u64::<LittleEndian>()) as usize;
+        let len = try!(file.read_u64::<LittleEndian>()) as usize;
+        let t_created = try!(file.read_i64::<LittleEndian>());
+        let checksum = try!(file.read_u64::<LittleEndian>());
+
+        let mmap = try!(unsafe { Mmap::map(&file) });
+        if mmap.len() != HEADER_SIZE + len * RECORD_SIZE || index > len {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                "Chain storage {} is truncated or corrupted", path.display())));
+        }
+        if ChainStorage::checksum(&mmap[HEADER_SIZE..]) != checksum {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                "Checksum mismatch in chain storage {}", path.display())));
+        }
+        Ok(ChainStorage {
+            mmap: Some(mmap),
+            n_mapped: len,
+            appended: Vec::new(),
+            hashes_len: len,
+            index: index,
+            held_back: 0,
+            latest_blk_idx: latest_blk_idx,
+            t_created: t_created
+        })
+    }
+
+    fn load_json(path: &Path) -> OpResult<ChainStorage> {
+        let mut encoded = String::new();
         let mut file = try!(File::open(&path));
         try!(file.read_to_string(&mut encoded));
+        let legacy = try!(json::decode::<LegacyChainStorage>(&encoded));
 
-        let storage = try!(json::decode::<ChainStorage>(&encoded));
-        debug!(target: "chain", "Imported {} hashes from {}. Current block height: {} ... (latest blk.dat index: {})",
-                       storage.hashes.len(), path.display(), storage.get_cur_height(), storage.latest_blk_idx);
-        Ok(storage)
+        let mut locations = legacy.locations.unwrap_or(Vec::new());
+        if locations.len() != legacy.hashes.len() {
+            warn!(target: "chain", "{} doesn't contain block locations. Rerun with --reindex to enable `getblock`.",
+                  path.display());
+            locations = vec![BlockLocation { blk_index: 0, blk_offset: 0, blocksize: 0 }; legacy.hashes.len()];
+        }
+        let appended: Vec<([u8; 32], BlockLocation)> = legacy.hashes.into_iter().zip(locations).collect();
+        Ok(ChainStorage {
+            mmap: None,
+            n_mapped: 0,
+            hashes_len: appended.len(),
+            appended: appended,
+            index: legacy.index,
+            held_back: 0,
+            latest_blk_idx: legacy.latest_blk_idx,
+            t_created: legacy.t_created
+        })
     }
 
-    /// Serializes the current instance to a file
+    /// Serializes the current instance to a file.
+    /// The file is written to a temporary file first and renamed afterwards.
     pub fn serialize(&self, path: &Path) -> OpResult<usize> {
-        let encoded = try!(json::encode(&self));
-        let mut file = try!(File::create(&path));
-        try!(file.write_all(encoded.as_bytes()));
+        let mut records = Vec::with_capacity(self.hashes_len * RECORD_SIZE);
+        if let Some(ref mmap) = self.mmap {
+            records.extend_from_slice(&mmap[HEADER_SIZE..HEADER_SIZE + self.n_mapped * RECORD_SIZE]);
+        }
+        for &(ref hash, ref location) in &self.appended {
+            records.extend_from_slice(hash);
+            try!(records.write_u32::<LittleEndian>(location.blk_index));
+            try!(records.write_u32::<LittleEndian>(location.blocksize));
+            try!(records.write_u64::<LittleEndian>(location.blk_offset));
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(try!(File::create(&tmp_path)));
+            try!(writer.write_all(MAGIC));
+            try!(writer.write_u32::<LittleEndian>(VERSION));
+            try!(writer.write_u32::<LittleEndian>(self.latest_blk_idx));
+            try!(writer.write_u64::<LittleEndian>(self.index as u64));
+            try!(writer.write_u64::<LittleEndian>(self.hashes_len as u64));
+            try!(writer.write_i64::<LittleEndian>(self.t_created));
+            try!(writer.write_u64::<LittleEndian>(ChainStorage::checksum(&records)));
+            try!(writer.write_all(&records));
+            try!(try!(writer.into_inner().map_err(|e| e.into_error())).sync_all());
+        }
+        try!(fs::rename(&tmp_path, path));
         debug!(target: "chain", "Serialized {} hashes to {}. Latest processed block height: {} ... (latest blk.dat index: {})",
-                       self.hashes.len(), path.display(), self.get_cur_height(), self.latest_blk_idx);
-        Ok(encoded.len())
+                       self.hashes_len, path.display(), self.get_cur_height(), self.latest_blk_idx);
+        Ok(HEADER_SIZE + records.len())
+    }
+
+    #[inline]
+    fn checksum(records: &[u8]) -> u64 {
+        let mut hasher = XxHash::with_seed(0);
+        hasher.write(records);
+        hasher.finish()
+    }
+
+    /// Returns the raw record at the given height
+    #[inline]
+    fn get_record(&self, height: usize) -> Option<&[u8]> {
+        match self.mmap {
+            Some(ref mmap) if height < self.n_mapped => {
+                let offset = HEADER_SIZE + height * RECORD_SIZE;
+                Some(&mmap[offset..offset + RECORD_SIZE])
+            }
+            _ => None
+        }
     }
 
     /// Returns the next hash without removing it
     #[inline]
     pub fn get_next(&self) -> Option<[u8; 32]> {
-        self.hashes.get(self.index).cloned()
+        match self.remaining() {
+            0 => None,
+            _ => self.get_hash(self.index)
+        }
     }
 
     /// Marks current hash as consumed.
@@ -119,10 +298,21 @@ impl ChainStorage {
         }
     }
 
+    /// Marks all blocks below the given height as processed
+    pub fn skip_to(&mut self, height: usize) {
+        self.index = cmp::min(height, self.hashes_len);
+    }
+
+    /// Excludes the latest `count` blocks from processing, they are still stored.
+    /// Used with --follow, so recent blocks are only processed once they are confirmed.
+    pub fn hold_back(&mut self, count: usize) {
+        self.held_back = count;
+    }
+
     /// Returns number of remaining blocks
     #[inline]
     pub fn remaining(&self) -> usize {
-        self.hashes_len.saturating_sub(self.index)
+        self.hashes_len.saturating_sub(self.held_back).saturating_sub(self.index)
     }
 
     /// Returns current block height
@@ -135,14 +325,77 @@ impl ChainStorage {
     pub fn len(&self) -> usize {
         self.hashes_len
     }
+
+    /// Returns the block hash at the given height
+    #[inline]
+    pub fn get_hash(&self, height: usize) -> Option<[u8; 32]> {
+        match self.get_record(height) {
+            Some(record) => {
+                let mut hash = [0u8; 32];
+                hash.copy_from_slice(&record[..32]);
+                Some(hash)
+            }
+            None => self.appended.get(height.wrapping_sub(self.n_mapped)).map(|e| e.0)
+        }
+    }
+
+    /// Returns the height of the given block hash
+    pub fn get_height(&self, hash: &[u8; 32]) -> Option<usize> {
+        (0..self.hashes_len).position(|height| self.get_hash(height).as_ref() == Some(hash))
+    }
+
+    /// Returns the height of the given block hash, but only searches the latest `depth` blocks
+    pub fn get_recent_height(&self, hash: &[u8; 32], depth: usize) -> Option<usize> {
+        (self.hashes_len.saturating_sub(depth)..self.hashes_len).rev()
+            .find(|&height| self.get_hash(height).as_ref() == Some(hash))
+    }
+
+    /// Returns the location of the block at the given height.
+    /// None if the height is unknown or the storage was migrated without locations.
+    pub fn get_location(&self, height: usize) -> Option<BlockLocation> {
+        let location = match self.get_record(height) {
+            Some(record) => BlockLocation {
+                blk_index: LittleEndian::read_u32(&record[32..36]),
+                blocksize: LittleEndian::read_u32(&record[36..40]),
+                blk_offset: LittleEndian::read_u64(&record[40..48])
+            },
+            None => match self.appended.get(height.wrapping_sub(self.n_mapped)) {
+                Some(entry) => entry.1.clone(),
+                None => return None
+            }
+        };
+        match location.blocksize {
+            0 => None,
+            _ => Some(location)
+        }
+    }
+
+    /// Returns the lowest blk file index of all remaining blocks.
+    /// None if there are no remaining blocks or a location is unknown.
+    pub fn min_remaining_blk_idx(&self) -> Option<u32> {
+        let mut min_idx = None;
+        for height in self.index..self.hashes_len {
+            let blk_index = match self.get_location(height) {
+                Some(location) => location.blk_index,
+                None => return None
+            };
+            if min_idx.map_or(true, |idx| blk_index < idx) {
+                min_idx = Some(blk_index);
+            }
+        }
+        min_idx
+    }
 }
 
 impl Default for ChainStorage {
     fn default() -> ChainStorage {
         ChainStorage {
-            hashes: Vec::new(),
+            mmap: None,
+            n_mapped: 0,
+            appended: Vec::new(),
             hashes_len: 0,
             index: 0,
+            held_back: 0,
             latest_blk_idx: 0,
             t_created: 0
         }
@@ -259,7 +512,6 @@ impl<'a> Iterator for RevBlockIterator<'a> {
 
 #[cfg(test)]
 mod tests {
-    use rustc_serialize::json;
     use std::env;
     use std::fs;
     use super::*;
@@ -268,6 +520,28 @@ mod tests {
     use blockchain::proto::header::BlockHeader;
     use blockchain::parser::types::{CoinType, Bitcoin};
 
+    /// Extends the storage with a single header located at the start of blk00000.dat
+    fn extend(chain_storage: &mut ChainStorage, header: BlockHeader,
+              coin_type: &CoinType, latest_blk_idx: u32) -> OpResult<()> {
+        let header = Hashed::double_sha256(header);
+        let mut locations = HashMap::default();
+        locations.insert(header.hash, BlockLocation { blk_index: 0, blk_offset: 8, blocksize: 285 });
+        chain_storage.extend(vec![header], &locations, coin_type, latest_blk_idx)
+    }
+
+    fn genesis_header() -> BlockHeader {
+        BlockHeader::new(
+            0x00000001,
+            [0u8; 32],
+            [0x3b, 0xa3, 0xed, 0xfd, 0x7a, 0x7b, 0x12, 0xb2,
+             0x7a, 0xc7, 0x2c, 0x3e, 0x67, 0x76, 0x8f, 0x61,
+             0x7f, 0xc8, 0x1b, 0xc3, 0x88, 0x8a, 0x51, 0x32,
+             0x3a, 0x9f, 0xb8, 0xaa, 0x4b, 0x1e, 0x5e, 0x4a],
+            1231006505,
+            0x1d00ffff,
+            2083236893)
+    }
+
     #[test]
     fn chain_storage() {
         let mut chain_storage = ChainStorage::default();
@@ -287,13 +561,15 @@ mod tests {
 
         // Extend storage and match genesis block
         let coin_type = CoinType::from(Bitcoin);
-        chain_storage.extend(vec![Hashed::double_sha256(new_header)], &coin_type, 1).unwrap();
+        extend(&mut chain_storage, new_header, &coin_type, 1).unwrap();
         assert_eq!(coin_type.genesis_hash, chain_storage.get_next().unwrap());
 
         assert_eq!(1, chain_storage.latest_blk_idx);
+        assert_eq!(Some(0), chain_storage.get_height(&coin_type.genesis_hash));
+        assert_eq!(8, chain_storage.get_location(0).unwrap().blk_offset);
 
         // Serialize storage
-        let pathbuf = env::temp_dir().as_path().join("chain.test.json");
+        let pathbuf = env::temp_dir().as_path().join("chain.test.dat");
         chain_storage.serialize(pathbuf.as_path()).unwrap();
 
         // Load storage
@@ -304,10 +580,67 @@ mod tests {
 
         assert_eq!(0, chain_storage.get_cur_height());
         assert_eq!(1, chain_storage.latest_blk_idx);
+        assert_eq!(Some(BlockLocation { blk_index: 0, blk_offset: 8, blocksize: 285 }), chain_storage.get_location(0));
+        assert!(chain_storage.get_location(1).is_none());
+        assert_eq!(Some(0), chain_storage.min_remaining_blk_idx());
         fs::remove_file(pathbuf.as_path()).unwrap();
 
         chain_storage.consume_next();
         assert_eq!(1, chain_storage.get_cur_height());
+        assert_eq!(None, chain_storage.min_remaining_blk_idx());
+    }
+
+    #[test]
+    fn chain_storage_truncate() {
+        let mut chain_storage = ChainStorage::default();
+        let coin_type = CoinType::from(Bitcoin);
+        extend(&mut chain_storage, genesis_header(), &coin_type, 0).unwrap();
+        let location = BlockLocation { blk_index: 1, blk_offset: 8, blocksize: 285 };
+        chain_storage.push([1u8; 32], location.clone());
+        chain_storage.push([2u8; 32], location.clone());
+        assert_eq!(3, chain_storage.len());
+        assert_eq!(Some(2), chain_storage.get_recent_height(&[2u8; 32], 2));
+        assert_eq!(None, chain_storage.get_recent_height(&coin_type.genesis_hash, 2));
+
+        // Remove both appended blocks of a stored chain
+        let pathbuf = env::temp_dir().as_path().join("chain.truncate.dat");
+        chain_storage.serialize(pathbuf.as_path()).unwrap();
+        let mut chain_storage = ChainStorage::load(pathbuf.as_path()).unwrap();
+        fs::remove_file(pathbuf.as_path()).unwrap();
+        chain_storage.consume_next();
+        let removed = chain_storage.truncate(1).unwrap();
+        assert_eq!(vec![([1u8; 32], location.clone()), ([2u8; 32], location.clone())], removed);
+        assert_eq!(1, chain_storage.len());
+        assert_eq!(None, chain_storage.get_hash(1));
+
+        chain_storage.push([3u8; 32], location.clone());
+        assert_eq!(Some([3u8; 32]), chain_storage.get_next());
+        assert!(chain_storage.truncate(0).is_err());
+    }
+
+    #[test]
+    fn chain_storage_hold_back() {
+        let mut chain_storage = ChainStorage::default();
+        let coin_type = CoinType::from(Bitcoin);
+        extend(&mut chain_storage, genesis_header(), &coin_type, 0).unwrap();
+        let location = BlockLocation { blk_index: 1, blk_offset: 8, blocksize: 285 };
+        chain_storage.push([1u8; 32], location.clone());
+        chain_storage.push([2u8; 32], location.clone());
+
+        chain_storage.hold_back(2);
+        assert_eq!(1, chain_storage.remaining());
+        chain_storage.consume_next();
+        assert_eq!(0, chain_storage.remaining());
+        assert_eq!(None, chain_storage.get_next());
+        assert_eq!(3, chain_storage.len());
+
+        // The held back blocks are stored
+        let pathbuf = env::temp_dir().as_path().join("chain.hold_back.dat");
+        chain_storage.serialize(pathbuf.as_path()).unwrap();
+        let chain_storage = ChainStorage::load(pathbuf.as_path()).unwrap();
+        fs::remove_file(pathbuf.as_path()).unwrap();
+        assert_eq!(2, chain_storage.remaining());
+        assert_eq!(Some([1u8; 32]), chain_storage.get_next());
     }
 
     #[test]
@@ -330,7 +663,7 @@ mod tests {
 
         // Extend storage and match genesis block
         let coin_type = CoinType::from(Bitcoin);
-        chain_storage.extend(vec![Hashed::double_sha256(new_header)], &coin_type, 1).unwrap();
+        extend(&mut chain_storage, new_header, &coin_type, 1).unwrap();
         assert_eq!(coin_type.genesis_hash, chain_storage.get_next().unwrap());
         assert_eq!(1, chain_storage.latest_blk_idx);
 
@@ -345,7 +678,7 @@ mod tests {
             1231006505,
             0x1d00ffff,
             2083236893);
-        chain_storage.extend(vec![Hashed::double_sha256(same_header)], &coin_type, 1).unwrap();
+        extend(&mut chain_storage, same_header, &coin_type, 1).unwrap();
         assert_eq!(coin_type.genesis_hash, chain_storage.get_next().unwrap());
         assert_eq!(1, chain_storage.latest_blk_idx);
 
@@ -360,27 +693,34 @@ mod tests {
             1231006505,
             0x1d00ffff,
             2083236893);
-        chain_storage.extend(vec![Hashed::double_sha256(bogus_header)], &coin_type, 1).unwrap();
+        extend(&mut chain_storage, bogus_header, &coin_type, 1).unwrap();
     }
 
     #[test]
     #[should_panic]
     fn load_bogus_chain_storage() {
         // Must fail
-        let encoded = String::from("AABAAAFKAAANANFANAAMMDDMDAMDADNNDANANDNAVCACANAFMAFAMMAMDAMDM");
-        match json::decode::<ChainStorage>(&encoded) {
-            Ok(_) => return,
-            Err(_) => panic!()
-        };
+        let pathbuf = env::temp_dir().as_path().join("chain.bogus.dat");
+        fs::write(&pathbuf, "AABAAAFKAAANANFANAAMMDDMDAMDADNNDANANDNAVCACANAFMAFAMMAMDAMDM").unwrap();
+        let result = ChainStorage::load(pathbuf.as_path());
+        fs::remove_file(pathbuf.as_path()).unwrap();
+        result.unwrap();
     }
 
     #[test]
     #[should_panic]
-    fn serialize_bogus_chain_storage() {
-        let encoded = String::from("AABAAAFKAAANANFANAAMMDDMDAMDADNNDANANDNAVCACANAFMAFAMMAMDAMDM");
-        match json::decode::<ChainStorage>(&encoded) {
-            Ok(_) => return,
-            Err(_) => panic!()
-        };
+    fn load_corrupted_chain_storage() {
+        let mut chain_storage = ChainStorage::default();
+        extend(&mut chain_storage, genesis_header(), &CoinType::from(Bitcoin), 1).unwrap();
+        let pathbuf = env::temp_dir().as_path().join("chain.corrupted.dat");
+        chain_storage.serialize(pathbuf.as_path()).unwrap();
+
+        // Flip a bit in the genesis hash
+        let mut content = fs::read(&pathbuf).unwrap();
+        content[HEADER_SIZE] ^= 0x01;
+        fs::write(&pathbuf, content).unwrap();
+        let result = ChainStorage::load(pathbuf.as_path());
+        fs::remove_file(pathbuf.as_path()).unwrap();
+        result.unwrap();
     }
 }
@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::BuildHasherDefault;
+use std::io::{Read, Seek, SeekFrom};
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+use twox_hash::XxHash;
+
+use errors::{OpError, OpErrorKind, OpResult};
+use blockchain::parser::chain::{BlockLocation, ChainStorage};
+use blockchain::proto::raw::RawBlock;
+use blockchain::utils;
+use blockchain::utils::blkfile::BlkFile;
+use blockchain::utils::revfile::RevReader;
+use blockchain::utils::reader::BlockchainRead;
+
+use ParserOptions;
+
+/// Seconds to wait between two scans for new blocks
+const POLL_INTERVAL: u64 = 5;
+/// Forks are only detected within the latest blocks of the chain
+const MAX_REORG_DEPTH: usize = 100;
+/// Polls in which a block which is complete on disk may fail to parse before following stops
+const MAX_PARSE_ATTEMPTS: usize = 12;
+
+/// Follows the blk files of a running node after the initial scan.
+/// New blocks are appended to the ChainStorage and passed to the callback once they are confirmed.
+/// The node preallocates and truncates the blk file it is writing to, so this file is read
+/// with plain file reads. Only the completed blk files are memory mapped.
+pub struct Follower<'a> {
+    options: &'a mut ParserOptions,
+    chain_storage: ChainStorage,
+    blk_index: u32,     // Index of the blk file which is written by the node
+    blk_offset: u64,    // Position after the latest complete block in this file
+    undo: Option<RevReader>, // Attaches spent outputs to each block, see --undo
+    n_parse_failures: usize, // Failed attempts to parse the block at blk_offset
+    pending: HashMap<[u8; 32], ([u8; 32], BlockLocation), BuildHasherDefault<XxHash>> /* Blocks outside of the longest chain with their prev_hash */
+}
+
+impl<'a> Follower<'a> {
+
+    /// Continues after the latest block of the chain storage
+    pub fn new(options: &'a mut ParserOptions, chain_storage: ChainStorage) -> OpResult<Follower<'a>> {
+        // Blocks are downloaded out of order, so start after the block with the highest position
+        let mut tail = None;
+        for height in chain_storage.len().saturating_sub(MAX_REORG_DEPTH)..chain_storage.len() {
+            let location = match chain_storage.get_location(height) {
+                Some(location) => location,
+                None => return Err(OpError::new(OpErrorKind::ValidateError).join_msg(
+                    "Chain storage doesn't contain block locations, rerun with --reindex to follow new blocks."))
+            };
+            let end = (location.blk_index, location.blk_offset + location.blocksize as u64);
+            if tail.map_or(true, |t| end > t) {
+                tail = Some(end);
+            }
+        }
+        let (blk_index, blk_offset) = transform!(tail);
+        let undo = match options.undo {
+            true => Some(RevReader::new(options.blockchain_dir.clone(), options.coin_type.clone())),
+            false => None
+        };
+        Ok(Follower {
+            options: options,
+            chain_storage: chain_storage,
+            blk_index: blk_index,
+            blk_offset: blk_offset,
+            undo: undo,
+            n_parse_failures: 0,
+            pending: Default::default()
+        })
+    }
+
+    /// Polls for new blocks until the process is terminated
+    pub fn run(&mut self) -> OpResult<()> {
+        info!(target: "follow", "Following blk{:05}.dat at height {} for new blocks (confirmations: {}) ...",
+              self.blk_index, self.chain_storage.len() - 1, self.options.confirmations);
+        loop {
+            if try!(self.poll()) > 0 {
+                try!(self.connect_pending());
+                try!(self.process_confirmed());
+                self.prune_pending();
+            }
+            thread::sleep(Duration::from_secs(POLL_INTERVAL));
+        }
+    }
+
+    /// Reads all new complete blocks and returns their count.
+    /// Continues with the next blk file once the node started writing it.
+    fn poll(&mut self) -> OpResult<usize> {
+        let mut n_blocks = 0;
+        loop {
+            let n = try!(self.read_new_blocks());
+            n_blocks += n;
+            if n > 0 {
+                continue;
+            }
+            match BlkFile::open(&self.options.blockchain_dir, self.blk_index + 1) {
+                Ok(ref file) if file.size > 0 => {
+                    self.blk_index = file.index;
+                    self.blk_offset = 0;
+                    self.n_parse_failures = 0;
+                    debug!(target: "follow", "Continuing with blk{:05}.dat", self.blk_index);
+                }
+                _ => return Ok(n_blocks)
+            }
+        }
+    }
+
+    /// Reads new blocks from the current blk file until the written data ends
+    fn read_new_blocks(&mut self) -> OpResult<usize> {
+        let path = self.options.blockchain_dir.join(format!("blk{:05}.dat", self.blk_index));
+        let mut file = try!(File::open(&path));
+        let mut n_blocks = 0;
+        loop {
+            let len = try!(file.metadata()).len();
+            if self.blk_offset + 8 > len {
+                break;
+            }
+            let mut meta = [0u8; 8];
+            try!(file.seek(SeekFrom::Start(self.blk_offset)));
+            try!(file.read_exact(&mut meta));
+            let magic = LittleEndian::read_u32(&meta[..4]);
+            let blocksize = LittleEndian::read_u32(&meta[4..]);
+            // Preallocated space, the next block isn't written yet
+            if magic == 0 {
+                break;
+            }
+            if magic != self.options.coin_type.magic {
+                return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                    "Got invalid magic value in blk{:05}.dat at offset {}: 0x{:x}, expected: 0x{:x}",
+                    self.blk_index, self.blk_offset, magic, self.options.coin_type.magic)));
+            }
+            // Checked before allocating, the size may not be written completely yet
+            if self.blk_offset + 8 + blocksize as u64 > len {
+                break;
+            }
+            let mut bytes = vec![0u8; blocksize as usize];
+            if file.read_exact(&mut bytes).is_err() {
+                break;
+            }
+
+            // The node may still be writing the block, try again with the next poll
+            let block_offset = self.blk_offset + 8;
+            let block = match (&bytes[..]).read_block(self.blk_index, block_offset as usize, blocksize,
+                                                      self.options.coin_type.version_id) {
+                Ok(ref block) if block.compute_merkle_root() != block.header.value.merkle_root => None,
+                Ok(block) => Some(block),
+                Err(_) => None
+            };
+            let block = match block {
+                Some(block) => block,
+                None => {
+                    try!(self.parse_failed(block_offset));
+                    break;
+                }
+            };
+            self.blk_offset = block_offset + blocksize as u64;
+            self.n_parse_failures = 0;
+
+            let hash = block.header.hash;
+            if self.chain_storage.get_recent_height(&hash, MAX_REORG_DEPTH).is_none() {
+                let location = BlockLocation {
+                    blk_index: self.blk_index,
+                    blk_offset: block_offset,
+                    blocksize: blocksize
+                };
+                self.pending.insert(hash, (block.header.value.prev_hash, location));
+                n_blocks += 1;
+            }
+        }
+        Ok(n_blocks)
+    }
+
+    /// Retries the incomplete block at `block_offset` with the next poll.
+    /// Fails if the block still can't be parsed after MAX_PARSE_ATTEMPTS polls.
+    fn parse_failed(&mut self, block_offset: u64) -> OpResult<()> {
+        self.n_parse_failures += 1;
+        if self.n_parse_failures < MAX_PARSE_ATTEMPTS {
+            debug!(target: "follow", "Incomplete block in blk{:05}.dat at offset {}, retrying later",
+                   self.blk_index, block_offset);
+            return Ok(());
+        }
+        Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+            "Unable to parse the block in blk{:05}.dat at offset {} after {} attempts, the blk file may be corrupted.",
+            self.blk_index, block_offset, self.n_parse_failures)))
+    }
+
+    /// Appends pending blocks to the longest chain. If a fork outgrows the current chain,
+    /// the blocks above the fork point are replaced, as long as they weren't passed to the callback.
+    fn connect_pending(&mut self) -> OpResult<()> {
+        while let Some((fork_height, branch)) = self.best_branch() {
+            let tip_height = self.chain_storage.len() - 1;
+            if fork_height + branch.len() <= tip_height {
+                break;
+            }
+            if fork_height < tip_height {
+                let removed = match self.chain_storage.truncate(fork_height + 1) {
+                    Ok(removed) => removed,
+                    Err(_) => return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                        "Chain reorganization at height {} affects blocks which were already processed. \
+                         Rerun with --reindex and increase --confirmations.", fork_height + 1)))
+                };
+                info!(target: "follow", "Chain reorganization at height {}, replacing {} blocks ...",
+                      fork_height + 1, removed.len());
+                // Keep the stale blocks, the old chain could become the longest one again
+                let mut prev_hash = transform!(self.chain_storage.get_hash(fork_height));
+                for (hash, location) in removed {
+                    self.pending.insert(hash, (prev_hash, location));
+                    prev_hash = hash;
+                }
+            }
+            for hash in branch {
+                let (_, location) = transform!(self.pending.remove(&hash));
+                self.chain_storage.push(hash, location);
+                info!(target: "follow", "New block at height {}: {}",
+                      self.chain_storage.len() - 1, utils::arr_to_hex_swapped(&hash));
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the longest branch of pending blocks which is connected to the chain.
+    /// Returns the height of the fork point and the branch, starting with the oldest block.
+    fn best_branch(&self) -> Option<(usize, Vec<[u8; 32]>)> {
+        let mut best: Option<(usize, Vec<[u8; 32]>)> = None;
+        for (hash, &(prev_hash, _)) in &self.pending {
+            let mut branch = vec![*hash];
+            let mut prev_hash = prev_hash;
+            while let Some(&(prev, _)) = self.pending.get(&prev_hash) {
+                branch.push(prev_hash);
+                prev_hash = prev;
+            }
+            let fork_height = match self.chain_storage.get_recent_height(&prev_hash, MAX_REORG_DEPTH) {
+                Some(height) => height,
+                None => continue
+            };
+            if best.as_ref().map_or(true, |&(height, ref b)| fork_height + branch.len() > height + b.len()) {
+                branch.reverse();
+                best = Some((fork_height, branch));
+            }
+        }
+        best
+    }
+
+    /// Drops pending blocks which fork off below the processed blocks, they can't replace them anymore
+    fn prune_pending(&mut self) {
+        let processed = self.chain_storage.get_cur_height();
+        let mut stale = Vec::new();
+        for (hash, &(prev_hash, _)) in &self.pending {
+            let mut prev_hash = prev_hash;
+            while let Some(&(prev, _)) = self.pending.get(&prev_hash) {
+                prev_hash = prev;
+            }
+            match self.chain_storage.get_recent_height(&prev_hash, MAX_REORG_DEPTH) {
+                Some(fork_height) if fork_height + 1 < processed => stale.push(*hash),
+                _ => ()
+            }
+        }
+        if !stale.is_empty() {
+            debug!(target: "follow", "Dropping {} stale blocks below height {}", stale.len(), processed);
+        }
+        for hash in stale {
+            self.pending.remove(&hash);
+        }
+    }
+
+    /// Passes all blocks with enough confirmations to the callback and saves a checkpoint
+    fn process_confirmed(&mut self) -> OpResult<()> {
+        if self.chain_storage.remaining() <= self.options.confirmations {
+            return Ok(());
+        }
+        while self.chain_storage.remaining() > self.options.confirmations {
+            let height = self.chain_storage.get_cur_height();
+            let location = transform!(self.chain_storage.get_location(height));
+            let blk_file = try!(BlkFile::open(&self.options.blockchain_dir, location.blk_index));
+            let block = match location.blk_index == self.blk_index {
+                true => try!(RawBlock::read(&blk_file, location.blk_offset as usize, location.blocksize,
+                                            self.options.coin_type.version_id)),
+                false => try!(RawBlock::new(try!(blk_file.mmap()), location.blk_index, location.blk_offset as usize,
+                                            location.blocksize, self.options.coin_type.version_id))
+            };
+            if self.options.verify_merkle_root {
+                try!(block.verify_merkle_root());
+            }
+
+            let callback = &mut self.options.callback;
+            if callback.raw_blocks() && self.undo.is_none() {
+                callback.on_raw_block(block, height);
+            } else {
+                let mut block = try!(block.parse());
+                if let Some(ref mut undo) = self.undo {
+                    block.undo = Some(try!(undo.read_undo(&block)));
+                }
+                match callback.mapper() {
+                    Some(mapper) => {
+                        let mapped = mapper.map(&block);
+                        callback.reduce(block, mapped, height);
+                    }
+                    None => callback.on_block(block, height)
+                }
+            }
+            self.chain_storage.consume_next();
+        }
+
+        let height = self.chain_storage.get_cur_height();
+        if !try!((*self.options.callback).on_checkpoint(height)) {
+            return Err(checkpoints_required());
+        }
+        self.chain_storage.latest_blk_idx = self.chain_storage.min_remaining_blk_idx().unwrap_or(self.blk_index);
+        try!(self.chain_storage.serialize(self.options.chain_storage_path.as_path()));
+        info!(target: "follow", "Processed all blocks up to height {}", height - 1);
+        Ok(())
+    }
+}
+
+/// The callback output is only flushed at checkpoints while following new blocks
+pub fn checkpoints_required() -> OpError {
+    OpError::new(OpErrorKind::RuntimeError)
+        .join_msg("Callback doesn't support checkpoints, they are required to follow new blocks.")
+}
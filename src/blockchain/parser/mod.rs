@@ -8,15 +8,19 @@ use twox_hash::XxHash;
 
 use blockchain::proto::Hashed;
 use blockchain::utils::blkfile::BlkFile;
-use blockchain::parser::worker::Worker;
+use blockchain::utils::revfile::RevReader;
+use blockchain::parser::worker::{BlockQueue, ChainWorker, Worker};
 use blockchain::proto::block::Block;
 use blockchain::proto::header::BlockHeader;
+use blockchain::proto::raw::RawBlock;
+use callbacks::{BlockMapper, MapResult};
 use errors::{OpError, OpErrorKind, OpResult};
 
 use ParserOptions;
 
 pub mod worker;
 pub mod chain;
+pub mod follow;
 pub mod types;
 
 /// Specifies ParseMode. The first time the blockchain needs to be indexed,
@@ -29,8 +33,9 @@ pub enum ParseMode {
 
 /// Wrapper to pass different data between threads. Specified by ParseMode
 pub enum ParseResult {
-    FullData(Block),
-    Indexing(BlockHeader),
+    FullData(Block, Option<MapResult>), // optional result of the callback's map step
+    Raw(RawBlock),              // unparsed block, see Callback::raw_blocks
+    Indexing(BlockHeader, chain::BlockLocation),
     Complete(String),           // contains the name of the finished thread
     Error(OpError)              // Indicates critical error
 }
@@ -47,14 +52,20 @@ struct WorkerStats {
 pub struct BlockchainParser<'a> {
     //TODO: make the collections for headers and blocks more generic
     unsorted_headers: HashMap<[u8; 32], BlockHeader, BuildHasherDefault<XxHash>>,   /* holds all headers in parse mode HeadersOnly  */
-    unsorted_blocks:  HashMap<[u8; 32], Block, BuildHasherDefault<XxHash>>,         /* holds all blocks in parse mode FullData      */
+    unsorted_blocks:  HashMap<[u8; 32], ParseResult, BuildHasherDefault<XxHash>>,   /* holds all blocks in parse mode FullData      */
+    locations:        HashMap<[u8; 32], chain::BlockLocation, BuildHasherDefault<XxHash>>, /* blk file locations of all headers */
     remaining_files:  Arc<Mutex<VecDeque<BlkFile>>>,    /* Remaining files (shared between all threads) */
+    queue:            Option<Arc<BlockQueue>>,          /* Remaining blocks in chain order (FullData)   */
+    mapper:           Option<Arc<BlockMapper>>,         /* Map step of the callback (FullData)          */
+    raw:              bool,                             /* Callback receives RawBlocks (FullData)       */
     h_workers:        Vec<JoinHandle<()>>,              /* Worker job handles                           */
     mode:             ParseMode,                        /* ParseMode (FullData or Indexing)           */
     options:          &'a mut ParserOptions,            /* struct to hold cli arguments                 */
     chain_storage:    chain::ChainStorage,              /* Hash storage with the longest chain          */
     stats:            WorkerStats,                      /* struct for thread management & statistics    */
-    t_started:        f64                               /* Start timestamp                              */
+    t_started:        f64,                              /* Start timestamp                              */
+    checkpoint_height: usize,                           /* Block height of the latest checkpoint        */
+    t_checkpoint:     f64                               /* Timestamp of the latest checkpoint           */
 }
 
 impl<'a> BlockchainParser<'a> {
@@ -77,13 +88,19 @@ impl<'a> BlockchainParser<'a> {
         BlockchainParser {
             unsorted_headers:   Default::default(),
             unsorted_blocks:    Default::default(),
+            locations:          Default::default(),
             remaining_files:    Arc::new(Mutex::new(blk_files)),
+            queue:              None,
+            mapper:             None,
+            raw:                false,
             h_workers:          Vec::with_capacity(options.thread_count as usize),
             mode:               parse_mode,
             options:            options,
             chain_storage:      chain_storage,
             stats:              Default::default(),
-            t_started:          0.0
+            t_started:          0.0,
+            checkpoint_height:  0,
+            t_checkpoint:       0.0
         }
     }
 
@@ -91,8 +108,24 @@ impl<'a> BlockchainParser<'a> {
     pub fn start_worker(&mut self, tx_channel: mpsc::SyncSender<ParseResult>) -> OpResult<()> {
 
         self.t_started = time::precise_time_s();
+        self.t_checkpoint = self.t_started;
+        self.checkpoint_height = self.chain_storage.get_cur_height();
         if self.mode == ParseMode::FullData {
+            let checkpoints = self.options.checkpoint_blocks.is_some() || self.options.checkpoint_interval.is_some();
+            if checkpoints && !(*self.options.callback).checkpoints() {
+                warn!(target: "parser", "Callback doesn't support checkpoints, they are disabled for this scan.");
+                self.disable_checkpoints();
+            }
             (*self.options.callback).on_start(self.options.coin_type.clone(), self.chain_storage.get_cur_height());
+            // Raw blocks can't carry undo data
+            self.raw = (*self.options.callback).raw_blocks() && !self.options.undo;
+            self.mapper = match self.raw {
+                true => None,
+                false => (*self.options.callback).mapper()
+            };
+            if self.mapper.is_some() {
+                debug!(target: "parser", "Executing map step of the callback on worker threads.");
+            }
         }
 
         // save latest blk file index for resume mode.
@@ -104,12 +137,26 @@ impl<'a> BlockchainParser<'a> {
         debug!(target: "parser", "Starting {} threads. {:?}",
                self.options.thread_count, self.mode);
 
+        // Blocks are read in chain order at their known locations, this keeps the reorder buffer small.
+        // Reading whole blk files instead would buffer most of the blockchain in memory.
+        if self.mode == ParseMode::FullData {
+            if self.chain_storage.remaining() > 0 && self.chain_storage.min_remaining_blk_idx().is_none() {
+                return Err(OpError::new(OpErrorKind::ValidateError)
+                    .join_msg("Chain storage doesn't contain block locations, please rerun with --reindex."));
+            }
+            return self.start_chain_worker(tx_channel);
+        }
+
         // Start all workers
         for i in 0..self.options.thread_count {
             let tx = tx_channel.clone();
             let coin_type = self.options.coin_type.clone();
             let remaining_files = self.remaining_files.clone(); // Increment arc
             let mode = self.mode.clone();
+            let mapper = self.mapper.clone();
+            let raw = self.raw;
+            let recover = self.options.recover;
+            let undo = self.rev_reader();
 
             let rem = try!(remaining_files.lock()).len();
             if rem == 0 {
@@ -118,7 +165,7 @@ impl<'a> BlockchainParser<'a> {
 
             // Spawn worker
             let child = thread::Builder::new().name(format!("worker-{}", i)).spawn(move || {
-                match Worker::new(tx, remaining_files, coin_type, mode) {
+                match Worker::new(tx, remaining_files, coin_type, mode, mapper, raw, recover, undo) {
                     Ok(mut w) => w.process(),
                     Err(OpError { kind: OpErrorKind::None, ..}) => {
                         return;
@@ -134,6 +181,47 @@ impl<'a> BlockchainParser<'a> {
         Ok(())
     }
 
+    /// Starts all workers for ParseMode::FullData, which read the remaining blocks at their known locations
+    fn start_chain_worker(&mut self, tx_channel: mpsc::SyncSender<ParseResult>) -> OpResult<()> {
+        let start = self.chain_storage.get_cur_height();
+        let mut blocks = Vec::with_capacity(self.chain_storage.remaining());
+        for height in start..start + self.chain_storage.remaining() {
+            blocks.push((transform!(self.chain_storage.get_hash(height)),
+                         transform!(self.chain_storage.get_location(height))));
+        }
+        let queue = Arc::new(BlockQueue::new(blocks, self.options.worker_backlog));
+        self.queue = Some(queue.clone());
+        // The node keeps writing to the latest blk file while it is followed
+        let active_blk_idx = match self.options.follow {
+            true => Some(self.stats.latest_blk_idx),
+            false => None
+        };
+
+        for i in 0..self.options.thread_count {
+            let tx = tx_channel.clone();
+            let queue = queue.clone();
+            let coin_type = self.options.coin_type.clone();
+            let blockchain_dir = self.options.blockchain_dir.clone();
+            let mapper = self.mapper.clone();
+            let raw = self.raw;
+            let undo = self.rev_reader();
+
+            let child = thread::Builder::new().name(format!("worker-{}", i)).spawn(move || {
+                ChainWorker::new(tx, queue, coin_type, blockchain_dir, mapper, raw, undo, active_blk_idx).process();
+            });
+            self.h_workers.push(try!(child));
+        }
+        Ok(())
+    }
+
+    /// Creates a reader for the undo data of each block if requested, see Block::undo
+    fn rev_reader(&self) -> Option<RevReader> {
+        match self.mode == ParseMode::FullData && self.options.undo {
+            true => Some(RevReader::new(self.options.blockchain_dir.clone(), self.options.coin_type.clone())),
+            false => None
+        }
+    }
+
     /// Dispatches all received data from workers.
     /// Blocks are passed to the user defined callback
     pub fn dispatch(&mut self, rx_channel: mpsc::Receiver<ParseResult>) -> OpResult<()> {
@@ -171,10 +259,14 @@ impl<'a> BlockchainParser<'a> {
 
             // Check if the next block is in unsorted HashMap
             if let Some(next_hash) = self.chain_storage.get_next() {
-                if let Some(block) = self.unsorted_blocks.remove(&next_hash) {
-                    self.on_block(block);
+                if let Some(result) = self.unsorted_blocks.remove(&next_hash) {
+                    self.on_block(result);
                 }
             }
+
+            if self.mode == ParseMode::FullData && self.checkpoint_due() {
+                try!(self.checkpoint());
+            }
                 // Check if all threads are finished
             if self.stats.n_complete_msgs == self.h_workers.len() && self.chain_storage.remaining() == 0 {
                 info!(target: "dispatch", "All threads finished.");
@@ -190,22 +282,24 @@ impl<'a> BlockchainParser<'a> {
         match result {
             // If a block arrives in the desired order, pass it to the callback
             // if not, add it to the unsorted HashMap for later dispatching
-            ParseResult::FullData(block) => {
+            ParseResult::FullData(block, mapped) => {
                 if self.options.verify_merkle_root {
                     block.verify_merkle_root();
                 }
-
-                if let Some(next_hash) = self.chain_storage.get_next() {
-                    if block.header.hash == next_hash {
-                        self.on_block(block);
-                    } else {
-                        self.unsorted_blocks.insert(block.header.hash, block);
-                    }
+                let hash = block.header.hash;
+                self.dispatch_block(hash, ParseResult::FullData(block, mapped));
+            }
+            ParseResult::Raw(block) => {
+                if self.options.verify_merkle_root {
+                    try!(block.verify_merkle_root());
                 }
+                let hash = block.hash;
+                self.dispatch_block(hash, ParseResult::Raw(block));
             }
             // Collect headers to built a valid blockchain
-            ParseResult::Indexing(header) => {
+            ParseResult::Indexing(header, location) => {
                 let header = Hashed::double_sha256(header);
+                self.locations.insert(header.hash, location);
                 self.unsorted_headers.insert(header.hash, header.value);
                 self.stats.n_valid_blocks += 1;
             }
@@ -222,11 +316,76 @@ impl<'a> BlockchainParser<'a> {
         Ok(())
     }
 
-    /// Triggers the callback and consumes the current block
-    fn on_block(&mut self, block: Block) {
-        (*self.options.callback).on_block(block, self.chain_storage.get_cur_height());
+    /// Passes the block to the callback if it arrived in the desired order,
+    /// if not, it's added to the unsorted HashMap for later dispatching
+    fn dispatch_block(&mut self, hash: [u8; 32], result: ParseResult) {
+        if let Some(next_hash) = self.chain_storage.get_next() {
+            if hash == next_hash {
+                self.on_block(result);
+            } else {
+                self.unsorted_blocks.insert(hash, result);
+            }
+        }
+    }
+
+    /// Triggers the callback and consumes the current block.
+    /// Blocks with a map result are passed to the reduce step of the callback.
+    fn on_block(&mut self, result: ParseResult) {
+        let height = self.chain_storage.get_cur_height();
+        match result {
+            ParseResult::FullData(block, Some(mapped)) => (*self.options.callback).reduce(block, mapped, height),
+            ParseResult::FullData(block, None) => (*self.options.callback).on_block(block, height),
+            ParseResult::Raw(block) => (*self.options.callback).on_raw_block(block, height),
+            _ => unreachable!()
+        }
         self.stats.n_valid_blocks += 1;
         self.chain_storage.consume_next();
+        if let Some(ref queue) = self.queue {
+            queue.consume();
+        }
+    }
+
+    /// Checks if a checkpoint is configured and the block count or interval is reached
+    fn checkpoint_due(&self) -> bool {
+        let n_blocks = self.chain_storage.get_cur_height() - self.checkpoint_height;
+        if n_blocks == 0 || self.chain_storage.remaining() == 0 {
+            return false;
+        }
+        match (self.options.checkpoint_blocks, self.options.checkpoint_interval) {
+            (Some(blocks), _) if n_blocks >= blocks => true,
+            (_, Some(secs)) => time::precise_time_s() - self.t_checkpoint >= secs as f64,
+            _ => false
+        }
+    }
+
+    /// Flushes the callback and persists the chain storage, so an aborted scan
+    /// can continue from the current height instead of starting over.
+    fn checkpoint(&mut self) -> OpResult<()> {
+        let height = self.chain_storage.get_cur_height();
+        self.checkpoint_height = height;
+        self.t_checkpoint = time::precise_time_s();
+
+        // The remaining blocks may be spread over multiple blk files, resume at the first one.
+        // Their locations are known (see start_worker), there is nothing to resume once all blocks are processed.
+        let blk_idx = match self.chain_storage.min_remaining_blk_idx() {
+            Some(idx) => idx,
+            None => return Ok(())
+        };
+        if !try!((*self.options.callback).on_checkpoint(height)) {
+            info!(target: "dispatch", "Callback skipped the checkpoint at height {}, the chain storage isn't saved.", height);
+            return Ok(());
+        }
+
+        info!(target: "dispatch", "Checkpoint at height {} ...", height);
+        self.chain_storage.latest_blk_idx = blk_idx;
+        try!(self.chain_storage.serialize(self.options.chain_storage_path.as_path()));
+        Ok(())
+    }
+
+    #[inline]
+    fn disable_checkpoints(&mut self) {
+        self.options.checkpoint_blocks = None;
+        self.options.checkpoint_interval = None;
     }
 
     /// Internal method whichs gets called if all workers are finished
@@ -240,6 +399,12 @@ impl<'a> BlockchainParser<'a> {
                 .unwrap_or(self.stats.n_valid_blocks));
 
         match self.mode {
+            // The callback keeps running to follow new blocks, so its output is flushed with a checkpoint
+            ParseMode::FullData if self.options.follow => {
+                if !try!((*self.options.callback).on_checkpoint(self.chain_storage.get_cur_height())) {
+                    return Err(follow::checkpoints_required());
+                }
+            }
             ParseMode::FullData => {
                 (*self.options.callback).on_complete(self.chain_storage.get_cur_height());
             }
@@ -257,7 +422,7 @@ impl<'a> BlockchainParser<'a> {
             ParseMode::Indexing => try!(chain::ChainBuilder::extract_blockchain(&self.unsorted_headers)),
             ParseMode::FullData => Vec::new()
         };
-        try!(self.chain_storage.extend(headers, &self.options.coin_type, self.stats.latest_blk_idx));
+        try!(self.chain_storage.extend(headers, &self.locations, &self.options.coin_type, self.stats.latest_blk_idx));
         self.chain_storage.serialize(self.options.chain_storage_path.as_path())
     }
 }
@@ -1,17 +1,23 @@
-use std::fs::File;
+use std::cmp;
 use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 use std::collections::VecDeque;
 use std::time::Duration;
-use std::io::{Seek, SeekFrom};
+use std::io::{Cursor, Seek, SeekFrom};
+use std::path::PathBuf;
 
-use byteorder::{LittleEndian, ReadBytesExt};
-use seek_bufread::BufReader;
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
 
+use callbacks::BlockMapper;
 use errors::{OpError, OpErrorKind, OpResult};
 use blockchain::parser::{ParseMode, ParseResult};
-use blockchain::utils::blkfile::BlkFile;
+use blockchain::utils::blkfile::{BlkFile, BlkMmap};
+use blockchain::utils::revfile::RevReader;
 use blockchain::parser::types::CoinType;
+use blockchain::parser::chain::BlockLocation;
+use blockchain::proto::raw::RawBlock;
+use blockchain::utils;
 use blockchain::utils::reader::{BlockchainRead};
 
 /// Represents a single Worker. All workers share Vector with remaining files.
@@ -22,22 +28,27 @@ pub struct Worker {
     pub remaining_files: Arc<Mutex<VecDeque<BlkFile>>>, // remaining BlkFiles to parse (shared with other threads)
     pub coin_type: CoinType,                            // Coin type
     pub blk_file: BlkFile,                              // Current blk file
-    pub reader: BufReader<File>,                        // Reader for the entire blk file content
+    pub reader: Cursor<BlkMmap>,                        // Reader for the memory mapped blk file
     pub mode: ParseMode,                                // Specifies if we should read the whole block data or just the header
+    pub mapper: Option<Arc<BlockMapper>>,               // Map step of the callback (FullData only)
+    pub raw: bool,                                      // Sends unparsed RawBlocks (FullData only)
+    pub recover: bool,                                  // Skips corrupted regions instead of aborting
+    pub undo: Option<RevReader>,                        // Attaches spent outputs to parsed blocks (FullData only)
     pub name: String                                    // Thread name
 }
 
 impl Worker {
     pub fn new(tx_channel: mpsc::SyncSender<ParseResult>,
         remaining_files: Arc<Mutex<VecDeque<BlkFile>>>,
-        coin_type: CoinType, mode: ParseMode) -> OpResult<Self> {
+        coin_type: CoinType, mode: ParseMode, mapper: Option<Arc<BlockMapper>>, raw: bool,
+        recover: bool, undo: Option<RevReader>) -> OpResult<Self> {
 
         let worker_name = String::from(transform!(thread::current().name()));
         // Grab initial blk file
         match Worker::get_next_file(&remaining_files) {
             Ok(file) => {
                 // prepare instance variables
-                let reader = try!(file.get_reader());
+                let reader = Cursor::new(try!(file.mmap()));
                 debug!(target: &worker_name, "Parsing blk{:05}.dat ({:.2} Mb)",
                     file.index,
                     file.size as f64 / 1000000.0);
@@ -49,6 +60,10 @@ impl Worker {
                     blk_file: file,
                     reader: reader,
                     mode: mode,
+                    mapper: mapper,
+                    raw: raw,
+                    recover: recover,
+                    undo: undo,
                     name: worker_name.clone(),
                 };
                 Ok(w)
@@ -96,30 +111,94 @@ impl Worker {
     /// Extracts data from blk files and sends them to main thread
     /// Returns false is if this thread can be disposed
     fn process_next_block(&mut self) -> OpResult<Option<()>> {
-        match try!(self.maybe_next()) {
-            false => Ok(None),
-            true => {
-                // Get metadata for next block
-                let magic = try!(self.reader.read_u32::<LittleEndian>());
-                if magic == 0 {
-                    //TODO: find a better way to detect incomplete blk file
-                    debug!(target: &self.name, "Got 0x00000000 as magic number. Finished.");
-                    return Ok(None);
-                }
-                // Verify magic value based on current coin type
-                if magic != self.coin_type.magic {
-                    let err = OpError::new(OpErrorKind::ValidateError)
-                        .join_msg(&format!("Got invalid magic value for {}: 0x{:x}, expected: 0x{:x}",
-                        self.coin_type.name, magic,
-                        self.coin_type.magic));
-                    return Err(err);
+        if !try!(self.maybe_next()) {
+            return Ok(None);
+        }
+        let offset = self.reader.position() as usize;
+        let data = self.reader.get_ref().clone();
+        if self.recover {
+            if !is_valid_block(&data, offset, &self.coin_type) {
+                self.resync(&data, offset);
+                return Ok(Some(()));
+            }
+        } else if !try!(self.check_block(&data, offset)) {
+            self.finish_file();
+            return Ok(Some(()));
+        }
+        // Skip magic value
+        try!(self.reader.seek(SeekFrom::Current(4)));
+        let result = try!(self.extract_data());
+        // Send parsed result to main thread
+        try!(self.tx_channel.send(result));
+        Ok(Some(()))
+    }
+
+    /// Verifies magic value and blocksize of the block at `offset`.
+    /// Returns false if there are no more blocks in this file.
+    fn check_block(&self, data: &BlkMmap, offset: usize) -> OpResult<bool> {
+        let bytes = data.as_ref();
+        match read_u32_at(bytes, offset) {
+            Some(magic) if magic == self.coin_type.magic => (),
+            None | Some(0) => {
+                // Bitcoin Core preallocates blk files, the unused space is zeroed
+                if bytes[offset..].iter().all(|&b| b == 0) {
+                    debug!(target: &self.name, "blk{:05}.dat: Reached zero padding at offset {}",
+                           self.blk_file.index, offset);
+                } else {
+                    warn!(target: &self.name, "blk{:05}.dat: Found zeros at offset {}, but the file continues. \
+                                              Skipping the rest of the file, use --recover to scan for remaining blocks.",
+                          self.blk_file.index, offset);
                 }
-                let result = try!(self.extract_data());
-                // Send parsed result to main thread
-                try!(self.tx_channel.send(result));
-                Ok(Some(()))
+                return Ok(false);
             }
+            Some(magic) => {
+                let err = OpError::new(OpErrorKind::ValidateError)
+                    .join_msg(&format!("Got invalid magic value for {} in blk{:05}.dat at offset {}: 0x{:x}, expected: 0x{:x}. \
+                                        Use --recover to skip corrupted regions.",
+                    self.coin_type.name, self.blk_file.index, offset, magic,
+                    self.coin_type.magic));
+                return Err(err);
+            }
+        }
+        match read_u32_at(bytes, offset + 4) {
+            Some(blocksize) if offset + 8 + blocksize as usize <= bytes.len() => Ok(true),
+            _ => {
+                warn!(target: &self.name, "blk{:05}.dat: Truncated block at offset {}, dropping the last {} bytes",
+                      self.blk_file.index, offset, bytes.len() - offset);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Scans forward from an invalid block at `offset` to the next valid one and logs the skipped range.
+    /// Finishes the current file if there is none.
+    fn resync(&mut self, data: &BlkMmap, offset: usize) {
+        let bytes = data.as_ref();
+        if let Some(next) = find_next_block(data, offset + 1, &self.coin_type) {
+            warn!(target: &self.name, "blk{:05}.dat: Skipped {} bytes of invalid data at offset {}",
+                  self.blk_file.index, next - offset, offset);
+            self.reader.set_position(next as u64);
+            return;
+        }
+        let truncated = read_u32_at(bytes, offset) == Some(self.coin_type.magic) &&
+            read_u32_at(bytes, offset + 4).map_or(true, |size| offset + 8 + size as usize > bytes.len());
+        if truncated {
+            warn!(target: &self.name, "blk{:05}.dat: Truncated block at offset {}, dropping the last {} bytes",
+                  self.blk_file.index, offset, bytes.len() - offset);
+        } else if bytes[offset..].iter().all(|&b| b == 0) {
+            debug!(target: &self.name, "blk{:05}.dat: Reached zero padding at offset {}",
+                   self.blk_file.index, offset);
+        } else {
+            warn!(target: &self.name, "blk{:05}.dat: Skipped {} bytes of invalid data at offset {} until end of file",
+                  self.blk_file.index, bytes.len() - offset, offset);
         }
+        self.finish_file();
+    }
+
+    /// Moves the reader to the end of the current file
+    fn finish_file(&mut self) {
+        let size = self.blk_file.size;
+        self.reader.set_position(size);
     }
 
     /// Extracts Block or BlockHeader. See ParseMode
@@ -130,16 +209,30 @@ impl Worker {
 
         // Extract next block
         let result = match self.mode {
+            ParseMode::FullData if self.raw => {
+                let block = try!(RawBlock::new(self.reader.get_ref().clone(), self.blk_file.index,
+                                               block_offset as usize, blocksize, self.coin_type.version_id));
+                Ok(ParseResult::Raw(block))
+            }
             ParseMode::FullData => {
-                let block = try!(self.reader.read_block(self.blk_file.index,
-                                                        block_offset as usize,
-                                                        blocksize,
-                                                        self.coin_type.version_id));
-                Ok(ParseResult::FullData(block))
+                let mut block = try!(self.reader.read_block(self.blk_file.index,
+                                                            block_offset as usize,
+                                                            blocksize,
+                                                            self.coin_type.version_id));
+                if let Some(ref mut undo) = self.undo {
+                    block.undo = Some(try!(undo.read_undo(&block)));
+                }
+                let mapped = self.mapper.as_ref().map(|mapper| mapper.map(&block));
+                Ok(ParseResult::FullData(block, mapped))
             }
             ParseMode::Indexing => {
                 let header = try!(self.reader.read_block_header());
-                Ok(ParseResult::Indexing(header))
+                let location = BlockLocation {
+                    blk_index: self.blk_file.index,
+                    blk_offset: block_offset,
+                    blocksize: blocksize
+                };
+                Ok(ParseResult::Indexing(header, location))
             }
         };
         // Seek to next block position
@@ -160,7 +253,7 @@ impl Worker {
                 Err(err) => return Err(tag_err!(err, "Unable to fetch data from reader: `{}`",
                     self.blk_file.path.as_path().display()))
             };
-            self.reader = try!(self.blk_file.get_reader());
+            self.reader = Cursor::new(try!(self.blk_file.mmap()));
             debug!(target: self.name.as_ref(), "Parsing blk{:05}.dat ({:.2} Mb)",
                       self.blk_file.index,
                       self.blk_file.size as f64 / 1000000.0);
@@ -168,9 +261,245 @@ impl Worker {
         return Ok(true);
     }
 
-    /// Returns next file from shared buffer or None
+    /// Returns next file from shared buffer or None.
+    /// Empty files are skipped, because they can't be memory mapped.
     fn get_next_file(files: &Arc<Mutex<VecDeque<BlkFile>>>) -> OpResult<BlkFile> {
         let mut locked = try!(files.lock());
-        Ok(transform!(locked.pop_front()))
+        loop {
+            let file = transform!(locked.pop_front());
+            if file.size > 0 {
+                return Ok(file);
+            }
+        }
+    }
+}
+
+#[inline]
+fn read_u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    match offset + 4 <= bytes.len() {
+        true => Some(LittleEndian::read_u32(&bytes[offset..])),
+        false => None
+    }
+}
+
+/// Returns true if a complete block starts at `offset`. The magic value has to match,
+/// and all transactions must be readable and fill exactly blocksize bytes.
+fn is_valid_block(data: &BlkMmap, offset: usize, coin_type: &CoinType) -> bool {
+    if read_u32_at(data.as_ref(), offset) != Some(coin_type.magic) {
+        return false;
+    }
+    let blocksize = match read_u32_at(data.as_ref(), offset + 4) {
+        Some(size) => size,
+        None => return false
+    };
+    match RawBlock::new(data.clone(), 0, offset + 8, blocksize, coin_type.version_id) {
+        Ok(block) => block.verify_size().is_ok(),
+        Err(_) => false
+    }
+}
+
+/// Scans byte by byte for the next valid block, starting at `offset`
+fn find_next_block(data: &BlkMmap, offset: usize, coin_type: &CoinType) -> Option<usize> {
+    let magic = utils::le::u32_to_array(coin_type.magic);
+    let bytes = data.as_ref();
+    (offset..bytes.len().saturating_sub(3))
+        .find(|&i| bytes[i..i + 4] == magic && is_valid_block(data, i, coin_type))
+}
+
+/// Remaining blocks of the main chain in chain order (shared between all threads).
+/// Workers only claim blocks within `window` blocks of the dispatcher,
+/// this bounds the number of parsed blocks waiting to be passed to the callback.
+pub struct BlockQueue {
+    blocks: Vec<([u8; 32], BlockLocation)>,
+    next: AtomicUsize,      // Index of the next unclaimed block
+    consumed: AtomicUsize,  // Number of blocks passed to the callback
+    window: usize
+}
+
+impl BlockQueue {
+    pub fn new(blocks: Vec<([u8; 32], BlockLocation)>, window: usize) -> BlockQueue {
+        BlockQueue {
+            blocks: blocks,
+            next: AtomicUsize::new(0),
+            consumed: AtomicUsize::new(0),
+            window: cmp::max(window, 1)
+        }
+    }
+
+    /// Claims the next block. Waits while the caller is too far ahead of the dispatcher.
+    /// Returns None if all blocks are claimed.
+    pub fn next(&self) -> Option<([u8; 32], BlockLocation)> {
+        let i = self.next.fetch_add(1, Ordering::SeqCst);
+        if i >= self.blocks.len() {
+            return None;
+        }
+        // The block at `consumed` is always within the window, so this can't deadlock
+        while i >= self.consumed.load(Ordering::SeqCst) + self.window {
+            thread::sleep(Duration::from_millis(1));
+        }
+        Some(self.blocks[i].clone())
+    }
+
+    /// Marks the next block as dispatched
+    #[inline]
+    pub fn consume(&self) {
+        self.consumed.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Reads the blocks of the main chain in chain order, using the locations from the ChainStorage.
+/// Used for ParseMode::FullData, so blocks arrive nearly in order and the dispatcher doesn't need
+/// to buffer the out-of-order blocks of entire blk files.
+pub struct ChainWorker {
+    tx_channel: mpsc::SyncSender<ParseResult>,  // SyncSender channel to communicate main thread
+    queue: Arc<BlockQueue>,                     // Remaining blocks (shared with other threads)
+    coin_type: CoinType,                        // Coin type
+    blockchain_dir: PathBuf,                    // Directory containing the blk files
+    mapper: Option<Arc<BlockMapper>>,           // Map step of the callback
+    raw: bool,                                  // Sends unparsed RawBlocks
+    undo: Option<RevReader>,                    // Attaches spent outputs to parsed blocks
+    active_blk_idx: Option<u32>,                // Blk file which is written by a running node, it isn't mapped
+    file: Option<(u32, BlkMmap)>,               // Currently mapped blk file and its index
+    name: String                                // Thread name
+}
+
+impl ChainWorker {
+    pub fn new(tx_channel: mpsc::SyncSender<ParseResult>, queue: Arc<BlockQueue>,
+        coin_type: CoinType, blockchain_dir: PathBuf, mapper: Option<Arc<BlockMapper>>, raw: bool,
+        undo: Option<RevReader>, active_blk_idx: Option<u32>) -> ChainWorker {
+        ChainWorker {
+            tx_channel: tx_channel,
+            queue: queue,
+            coin_type: coin_type,
+            blockchain_dir: blockchain_dir,
+            mapper: mapper,
+            raw: raw,
+            undo: undo,
+            active_blk_idx: active_blk_idx,
+            file: None,
+            name: String::from(thread::current().name().unwrap_or("worker"))
+        }
+    }
+
+    // Highest worker loop. Handles all thread errors
+    pub fn process(&mut self) {
+        loop {
+            match self.process_next_block() {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(err) => {
+                    error!(target: &self.name, "{}", &err);
+                    self.tx_channel.send(ParseResult::Error(err))
+                        .expect("Unable to contact main thread!");
+                    break;
+                }
+            }
+        }
+        self.tx_channel.send(ParseResult::Complete(self.name.clone()))
+            .expect("Couldn't send Complete msg");
+        loop {
+            // See Worker::process
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    /// Reads the next claimed block and sends it to main thread.
+    /// Returns false if there are no blocks left
+    fn process_next_block(&mut self) -> OpResult<bool> {
+        let (hash, location) = match self.queue.next() {
+            Some(entry) => entry,
+            None => return Ok(false)
+        };
+        let block = try!(self.read_block(&location));
+        if block.hash != hash {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                "Expected block {} in blk{:05}.dat @ {}, but got {}. Please rerun with --reindex.",
+                utils::arr_to_hex_swapped(&hash), location.blk_index, location.blk_offset,
+                utils::arr_to_hex_swapped(&block.hash))));
+        }
+        if self.raw {
+            try!(self.tx_channel.send(ParseResult::Raw(block)));
+        } else {
+            let mut block = try!(block.parse());
+            if let Some(ref mut undo) = self.undo {
+                block.undo = Some(try!(undo.read_undo(&block)));
+            }
+            let mapped = self.mapper.as_ref().map(|mapper| mapper.map(&block));
+            try!(self.tx_channel.send(ParseResult::FullData(block, mapped)));
+        }
+        Ok(true)
+    }
+
+    /// Maps a single block. Consecutive blocks are mostly in the same blk file, so it stays mapped.
+    fn read_block(&mut self, location: &BlockLocation) -> OpResult<RawBlock> {
+        if self.active_blk_idx == Some(location.blk_index) {
+            let blk_file = try!(BlkFile::open(&self.blockchain_dir, location.blk_index));
+            return RawBlock::read(&blk_file, location.blk_offset as usize, location.blocksize, self.coin_type.version_id);
+        }
+        if self.file.as_ref().map_or(true, |&(index, _)| index != location.blk_index) {
+            let blk_file = try!(BlkFile::open(&self.blockchain_dir, location.blk_index));
+            debug!(target: &self.name, "Reading from blk{:05}.dat", blk_file.index);
+            self.file = Some((blk_file.index, try!(blk_file.mmap())));
+        }
+        let mmap = transform!(self.file.as_ref()).1.clone();
+        RawBlock::new(mmap, location.blk_index, location.blk_offset as usize,
+                      location.blocksize, self.coin_type.version_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use memmap::MmapMut;
+    use blockchain::parser::chain::BlockLocation;
+
+    fn entry(i: u8) -> ([u8; 32], BlockLocation) {
+        ([i; 32], BlockLocation { blk_index: 0, blk_offset: 8 + i as u64 * 300, blocksize: 285 })
+    }
+
+    #[test]
+    fn test_block_queue() {
+        let queue = Arc::new(BlockQueue::new((0..4).map(entry).collect(), 2));
+        assert_eq!(Some(entry(0)), queue.next());
+        assert_eq!(Some(entry(1)), queue.next());
+
+        // Third block is outside the window until the first one is dispatched
+        let q = queue.clone();
+        let handle = thread::spawn(move || q.next());
+        thread::sleep(Duration::from_millis(20));
+        queue.consume();
+        assert_eq!(Some(entry(2)), handle.join().unwrap());
+
+        queue.consume();
+        assert_eq!(Some(entry(3)), queue.next());
+        assert_eq!(None, queue.next());
+    }
+
+    #[test]
+    fn test_find_next_block() {
+        let coin_type = CoinType::default();
+        let tx = utils::hex_to_vec(concat!(
+            "01000000", "01", "1111111111111111111111111111111111111111111111111111111111111111",
+            "00000000", "00", "ffffffff", "01", "e803000000000000", "00", "00000000"));
+        let mut bytes = vec![0xde, 0xad, 0xbe, 0xef, 0x00];
+        bytes.extend_from_slice(&utils::le::u32_to_array(coin_type.magic));
+        bytes.extend_from_slice(&utils::le::u32_to_array(81 + tx.len() as u32));
+        bytes.extend_from_slice(&[0u8; 80]);
+        bytes.push(1);
+        bytes.extend_from_slice(&tx);
+        // Block with a bogus blocksize followed by zero padding
+        bytes.extend_from_slice(&utils::le::u32_to_array(coin_type.magic));
+        bytes.extend_from_slice(&[0xff; 4]);
+        bytes.extend_from_slice(&[0u8; 16]);
+
+        let mut mmap = MmapMut::map_anon(bytes.len()).unwrap();
+        mmap.copy_from_slice(&bytes);
+        let data = BlkMmap::from(mmap.make_read_only().unwrap());
+        assert!(!is_valid_block(&data, 0, &coin_type));
+        assert!(is_valid_block(&data, 5, &coin_type));
+        assert_eq!(Some(5), find_next_block(&data, 0, &coin_type));
+        assert_eq!(None, find_next_block(&data, 6, &coin_type));
     }
 }
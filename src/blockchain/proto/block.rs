@@ -1,9 +1,10 @@
 use std::fmt;
 
-use blockchain::proto::Hashed;
+use blockchain::proto::{Hashed, ToRaw};
 use blockchain::proto::varuint::VarUint;
 use blockchain::proto::tx::Tx;
 use blockchain::proto::header::BlockHeader;
+use blockchain::proto::undo::{BlockUndo, SpentOutput};
 use blockchain::utils::{merkle_root, arr_to_hex_swapped};
 
 
@@ -17,6 +18,7 @@ pub struct Block {
     pub header: Hashed<BlockHeader>,
     pub tx_count: VarUint,
     pub txs: Vec<Hashed<Tx>>,
+    pub undo: Option<BlockUndo>, // Spent outputs from rev*.dat files (only with --undo)
 }
 
 impl Block {
@@ -37,6 +39,7 @@ impl Block {
             txs: txs.into_iter()
                 .map(|tx| Hashed::double_sha256(tx))
                 .collect(),
+            undo: None,
         }
     }
 
@@ -48,6 +51,42 @@ impl Block {
             .collect::<Vec<[u8; 32]>>())
     }
 
+    /// Returns the offset of each transaction relative to blk_offset (start of the block header)
+    pub fn tx_offsets(&self) -> Vec<u32> {
+        let mut offset = 80 + self.tx_count.to_bytes().len();
+        let mut offsets = Vec::with_capacity(self.txs.len());
+        for tx in &self.txs {
+            offsets.push(offset as u32);
+            offset += tx.value.size();
+        }
+        offsets
+    }
+
+    /// Returns the outputs spent by the inputs of the transaction at `tx_index`.
+    /// None if the block doesn't contain undo data.
+    pub fn spent_outputs(&self, tx_index: usize) -> Option<&[SpentOutput]> {
+        match (tx_index, &self.undo) {
+            (0, &Some(_)) => Some(&[]),
+            (i, &Some(ref undo)) => undo.txs.get(i - 1).map(|spent| &spent[..]),
+            (_, &None) => None
+        }
+    }
+
+    /// Calculates the fee of the transaction at `tx_index`, requires undo data
+    pub fn fee(&self, tx_index: usize) -> Option<u64> {
+        let spent = match self.spent_outputs(tx_index) {
+            Some(spent) if tx_index > 0 => spent,
+            Some(_) => return Some(0),
+            None => return None
+        };
+        let in_value: u64 = spent.iter().map(|s| s.out.out.value).sum();
+        let out_value: u64 = match self.txs.get(tx_index) {
+            Some(tx) => tx.value.outputs.iter().map(|o| o.out.value).sum(),
+            None => return None
+        };
+        in_value.checked_sub(out_value)
+    }
+
     /// Calculates merkle root and verifies it against the field in BlockHeader
     pub fn verify_merkle_root(&self) -> bool {
         let comp_merkle_root = self.compute_merkle_root();
@@ -0,0 +1,153 @@
+use blockchain::proto::opcodes;
+use blockchain::proto::script::StackElement;
+
+/// Protocol identifier pushed after OP_FALSE OP_IF
+const PROTOCOL_ID: &'static [u8] = b"ord";
+
+/// Field tags
+const TAG_BODY: &'static [u8] = &[];
+const TAG_CONTENT_TYPE: &'static [u8] = &[1];
+const TAG_CONTENT_ENCODING: &'static [u8] = &[9];
+
+/// Inscription decoded from an envelope:
+/// OP_FALSE OP_IF "ord" [<tag> <value>...] [OP_0 <body chunks...>] OP_ENDIF
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Inscription {
+    pub content_type: Option<Vec<u8>>,
+    pub content_encoding: Option<Vec<u8>>,
+    /// Concatenated body chunks, None if the envelope has no body tag
+    pub body: Option<Vec<u8>>
+}
+
+impl Inscription {
+    /// Returns the content type as string (lossy)
+    #[inline]
+    pub fn content_type_str(&self) -> String {
+        match self.content_type {
+            Some(ref ct) => String::from_utf8_lossy(ct).into_owned(),
+            None => String::new()
+        }
+    }
+
+    /// Returns the body size in bytes
+    #[inline]
+    pub fn body_len(&self) -> usize {
+        self.body.as_ref().map(|b| b.len()).unwrap_or(0)
+    }
+
+    /// Decodes the pushes between the protocol id and OP_ENDIF
+    fn from_pushes(pushes: &[Vec<u8>]) -> Inscription {
+        let mut inscription = Inscription::default();
+        let mut i = 0;
+        while i < pushes.len() {
+            let tag = &pushes[i][..];
+            if tag == TAG_BODY {
+                let mut body = Vec::new();
+                for chunk in &pushes[i + 1..] {
+                    body.extend_from_slice(chunk);
+                }
+                inscription.body = Some(body);
+                break;
+            }
+            // Incomplete field
+            if i + 1 >= pushes.len() {
+                break;
+            }
+            let value = &pushes[i + 1];
+            // The first occurrence of a field wins
+            if tag == TAG_CONTENT_TYPE && inscription.content_type.is_none() {
+                inscription.content_type = Some(value.clone());
+            } else if tag == TAG_CONTENT_ENCODING && inscription.content_encoding.is_none() {
+                inscription.content_encoding = Some(value.clone());
+            }
+            i += 2;
+        }
+        inscription
+    }
+}
+
+#[inline]
+fn is_op(element: &StackElement, opcode: opcodes::All) -> bool {
+    match *element {
+        StackElement::Op(op) => op == opcode,
+        StackElement::Data(_) => false
+    }
+}
+
+/// Extracts all inscription envelopes from the tokenized tapscript.
+/// Envelopes containing non-push opcodes or without OP_ENDIF are skipped.
+pub fn parse_envelopes(elements: &[StackElement]) -> Vec<Inscription> {
+    let mut inscriptions = Vec::new();
+    let mut i = 0;
+    while i + 2 < elements.len() {
+        let is_envelope = is_op(&elements[i], opcodes::All::OP_PUSHBYTES_0) &&
+                          is_op(&elements[i + 1], opcodes::All::OP_IF) &&
+                          elements[i + 2].push_value().map(|id| id == PROTOCOL_ID).unwrap_or(false);
+        if !is_envelope {
+            i += 1;
+            continue;
+        }
+        i += 3;
+        let mut pushes = Vec::new();
+        while i < elements.len() {
+            if is_op(&elements[i], opcodes::All::OP_ENDIF) {
+                inscriptions.push(Inscription::from_pushes(&pushes));
+                break;
+            }
+            match elements[i].push_value() {
+                Some(value) => pushes.push(value),
+                None => break
+            }
+            i += 1;
+        }
+    }
+    inscriptions
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::proto::script::ScriptEvaluator;
+    use blockchain::utils::hex_to_vec;
+
+    fn parse_hex(script: &str) -> Vec<Inscription> {
+        parse_envelopes(&ScriptEvaluator::new(&hex_to_vec(script)).eval().unwrap().elements)
+    }
+
+    #[test]
+    fn test_parse_envelope() {
+        // <pubkey> OP_CHECKSIG OP_FALSE OP_IF "ord" OP_1 "text/plain" OP_0 "hello" " world" OP_ENDIF
+        let script = format!("20{}ac{}", "02".repeat(32), concat!(
+            "0063036f7264", "51", "0a746578742f706c61696e",
+            "00", "0568656c6c6f", "0620776f726c64", "68"));
+        let inscriptions = parse_hex(&script);
+        assert_eq!(1, inscriptions.len());
+        assert_eq!("text/plain", inscriptions[0].content_type_str());
+        assert_eq!(Some(b"hello world".to_vec()), inscriptions[0].body);
+        assert_eq!(11, inscriptions[0].body_len());
+        assert!(inscriptions[0].content_encoding.is_none());
+    }
+
+    #[test]
+    fn test_parse_multiple_envelopes() {
+        // Second envelope has no body, third one is missing OP_ENDIF
+        let script = concat!("0063036f7264", "00", "01aa", "68",
+                             "0063036f7264", "0101", "0161", "68",
+                             "0063036f7264", "0101");
+        let inscriptions = parse_hex(script);
+        assert_eq!(2, inscriptions.len());
+        assert_eq!(Some(vec![0xaa]), inscriptions[0].body);
+        assert!(inscriptions[0].content_type.is_none());
+        assert_eq!(Some(b"a".to_vec()), inscriptions[1].content_type);
+        assert!(inscriptions[1].body.is_none());
+    }
+
+    #[test]
+    fn test_parse_invalid_envelope() {
+        // OP_DUP inside the envelope
+        assert!(parse_hex("0063036f7264007668").is_empty());
+        // Wrong protocol id
+        assert!(parse_hex("0063036f726500016168").is_empty());
+    }
+}
@@ -0,0 +1,1517 @@
+use std::fmt;
+use std::error;
+use std::cmp;
+
+use blockchain::proto::ToRaw;
+use blockchain::proto::opcodes::All;
+use blockchain::proto::script::{ScriptEvaluator, ScriptError};
+use blockchain::proto::tx::Tx;
+use blockchain::proto::varuint::VarUint;
+use blockchain::utils::{le, sha1, sha256, ridemp160};
+
+/// Script verification flags. Same bits and names as in Bitcoin Core's interpreter.h
+pub const SCRIPT_VERIFY_NONE: u32 = 0;
+pub const SCRIPT_VERIFY_P2SH: u32 = 1 << 0;
+pub const SCRIPT_VERIFY_STRICTENC: u32 = 1 << 1;
+pub const SCRIPT_VERIFY_DERSIG: u32 = 1 << 2;
+pub const SCRIPT_VERIFY_LOW_S: u32 = 1 << 3;
+pub const SCRIPT_VERIFY_NULLDUMMY: u32 = 1 << 4;
+pub const SCRIPT_VERIFY_SIGPUSHONLY: u32 = 1 << 5;
+pub const SCRIPT_VERIFY_MINIMALDATA: u32 = 1 << 6;
+pub const SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_NOPS: u32 = 1 << 7;
+pub const SCRIPT_VERIFY_CLEANSTACK: u32 = 1 << 8;
+pub const SCRIPT_VERIFY_CHECKLOCKTIMEVERIFY: u32 = 1 << 9;
+pub const SCRIPT_VERIFY_CHECKSEQUENCEVERIFY: u32 = 1 << 10;
+pub const SCRIPT_VERIFY_WITNESS: u32 = 1 << 11;
+pub const SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM: u32 = 1 << 12;
+pub const SCRIPT_VERIFY_MINIMALIF: u32 = 1 << 13;
+pub const SCRIPT_VERIFY_NULLFAIL: u32 = 1 << 14;
+pub const SCRIPT_VERIFY_WITNESS_PUBKEYTYPE: u32 = 1 << 15;
+pub const SCRIPT_VERIFY_CONST_SCRIPTCODE: u32 = 1 << 16;
+pub const SCRIPT_VERIFY_TAPROOT: u32 = 1 << 17;
+
+/// Flags enforced by consensus for blocks after segwit activation
+pub const MANDATORY_SCRIPT_VERIFY_FLAGS: u32 = SCRIPT_VERIFY_P2SH | SCRIPT_VERIFY_DERSIG |
+    SCRIPT_VERIFY_NULLDUMMY | SCRIPT_VERIFY_CHECKLOCKTIMEVERIFY |
+    SCRIPT_VERIFY_CHECKSEQUENCEVERIFY | SCRIPT_VERIFY_WITNESS;
+
+/// Flags used by Bitcoin Core for mempool acceptance (standardness)
+pub const STANDARD_SCRIPT_VERIFY_FLAGS: u32 = MANDATORY_SCRIPT_VERIFY_FLAGS |
+    SCRIPT_VERIFY_STRICTENC | SCRIPT_VERIFY_MINIMALDATA |
+    SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_NOPS | SCRIPT_VERIFY_CLEANSTACK |
+    SCRIPT_VERIFY_MINIMALIF | SCRIPT_VERIFY_NULLFAIL | SCRIPT_VERIFY_LOW_S |
+    SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM |
+    SCRIPT_VERIFY_WITNESS_PUBKEYTYPE | SCRIPT_VERIFY_CONST_SCRIPTCODE;
+
+const FLAG_NAMES: [(&'static str, u32); 19] = [
+    ("NONE", SCRIPT_VERIFY_NONE),
+    ("P2SH", SCRIPT_VERIFY_P2SH),
+    ("STRICTENC", SCRIPT_VERIFY_STRICTENC),
+    ("DERSIG", SCRIPT_VERIFY_DERSIG),
+    ("LOW_S", SCRIPT_VERIFY_LOW_S),
+    ("NULLDUMMY", SCRIPT_VERIFY_NULLDUMMY),
+    ("SIGPUSHONLY", SCRIPT_VERIFY_SIGPUSHONLY),
+    ("MINIMALDATA", SCRIPT_VERIFY_MINIMALDATA),
+    ("DISCOURAGE_UPGRADABLE_NOPS", SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_NOPS),
+    ("CLEANSTACK", SCRIPT_VERIFY_CLEANSTACK),
+    ("CHECKLOCKTIMEVERIFY", SCRIPT_VERIFY_CHECKLOCKTIMEVERIFY),
+    ("CHECKSEQUENCEVERIFY", SCRIPT_VERIFY_CHECKSEQUENCEVERIFY),
+    ("WITNESS", SCRIPT_VERIFY_WITNESS),
+    ("DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM", SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM),
+    ("MINIMALIF", SCRIPT_VERIFY_MINIMALIF),
+    ("NULLFAIL", SCRIPT_VERIFY_NULLFAIL),
+    ("WITNESS_PUBKEYTYPE", SCRIPT_VERIFY_WITNESS_PUBKEYTYPE),
+    ("CONST_SCRIPTCODE", SCRIPT_VERIFY_CONST_SCRIPTCODE),
+    ("TAPROOT", SCRIPT_VERIFY_TAPROOT),
+];
+
+/// Parses a comma separated list of flag names (e.g. "P2SH,STRICTENC").
+/// Returns None if an unknown flag name is encountered.
+pub fn parse_flags(names: &str) -> Option<u32> {
+    let mut flags = SCRIPT_VERIFY_NONE;
+    for name in names.split(',').map(|n| n.trim()).filter(|n| !n.is_empty()) {
+        match FLAG_NAMES.iter().find(|&&(n, _)| n == name) {
+            Some(&(_, flag)) => flags |= flag,
+            None => return None
+        }
+    }
+    Some(flags)
+}
+
+// Consensus limits
+const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+const MAX_OPS_PER_SCRIPT: usize = 201;
+const MAX_PUBKEYS_PER_MULTISIG: i64 = 20;
+const MAX_SCRIPT_SIZE: usize = 10000;
+const MAX_STACK_SIZE: usize = 1000;
+const LOCKTIME_THRESHOLD: i64 = 500000000;
+
+const SEQUENCE_FINAL: u32 = 0xffffffff;
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: i64 = 1 << 31;
+const SEQUENCE_LOCKTIME_TYPE_FLAG: i64 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK: i64 = 0x0000ffff;
+
+pub const SIGHASH_ALL: u32 = 1;
+pub const SIGHASH_NONE: u32 = 2;
+pub const SIGHASH_SINGLE: u32 = 3;
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+
+/// Describes why a script failed. The names follow Bitcoin Core's ScriptError_t.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterpreterError {
+    UnknownError,
+    EvalFalse,
+    OpReturn,
+    ScriptSize,
+    PushSize,
+    OpCount,
+    StackSize,
+    SigCount,
+    PubkeyCount,
+    Verify,
+    EqualVerify,
+    CheckMultisigVerify,
+    CheckSigVerify,
+    NumEqualVerify,
+    BadOpcode,
+    DisabledOpcode,
+    InvalidStackOperation,
+    InvalidAltstackOperation,
+    UnbalancedConditional,
+    NegativeLocktime,
+    UnsatisfiedLocktime,
+    SigHashtype,
+    SigDer,
+    MinimalData,
+    SigPushOnly,
+    SigHighS,
+    SigNullDummy,
+    PubkeyType,
+    CleanStack,
+    MinimalIf,
+    SigNullFail,
+    DiscourageUpgradableNops,
+    DiscourageUpgradableWitnessProgram,
+    WitnessProgramWrongLength,
+    WitnessProgramWitnessEmpty,
+    WitnessProgramMismatch,
+    WitnessMalleated,
+    WitnessMalleatedP2SH,
+    WitnessUnexpected,
+    WitnessPubkeyType,
+    OpCodeSeparator,
+    SigFindAndDelete
+}
+
+impl InterpreterError {
+    /// Returns the error name as used in Bitcoin Core's test vectors
+    pub fn name(&self) -> &'static str {
+        match *self {
+            InterpreterError::UnknownError => "UNKNOWN_ERROR",
+            InterpreterError::EvalFalse => "EVAL_FALSE",
+            InterpreterError::OpReturn => "OP_RETURN",
+            InterpreterError::ScriptSize => "SCRIPT_SIZE",
+            InterpreterError::PushSize => "PUSH_SIZE",
+            InterpreterError::OpCount => "OP_COUNT",
+            InterpreterError::StackSize => "STACK_SIZE",
+            InterpreterError::SigCount => "SIG_COUNT",
+            InterpreterError::PubkeyCount => "PUBKEY_COUNT",
+            InterpreterError::Verify => "VERIFY",
+            InterpreterError::EqualVerify => "EQUALVERIFY",
+            InterpreterError::CheckMultisigVerify => "CHECKMULTISIGVERIFY",
+            InterpreterError::CheckSigVerify => "CHECKSIGVERIFY",
+            InterpreterError::NumEqualVerify => "NUMEQUALVERIFY",
+            InterpreterError::BadOpcode => "BAD_OPCODE",
+            InterpreterError::DisabledOpcode => "DISABLED_OPCODE",
+            InterpreterError::InvalidStackOperation => "INVALID_STACK_OPERATION",
+            InterpreterError::InvalidAltstackOperation => "INVALID_ALTSTACK_OPERATION",
+            InterpreterError::UnbalancedConditional => "UNBALANCED_CONDITIONAL",
+            InterpreterError::NegativeLocktime => "NEGATIVE_LOCKTIME",
+            InterpreterError::UnsatisfiedLocktime => "UNSATISFIED_LOCKTIME",
+            InterpreterError::SigHashtype => "SIG_HASHTYPE",
+            InterpreterError::SigDer => "SIG_DER",
+            InterpreterError::MinimalData => "MINIMALDATA",
+            InterpreterError::SigPushOnly => "SIG_PUSHONLY",
+            InterpreterError::SigHighS => "SIG_HIGH_S",
+            InterpreterError::SigNullDummy => "SIG_NULLDUMMY",
+            InterpreterError::PubkeyType => "PUBKEYTYPE",
+            InterpreterError::CleanStack => "CLEANSTACK",
+            InterpreterError::MinimalIf => "MINIMALIF",
+            InterpreterError::SigNullFail => "NULLFAIL",
+            InterpreterError::DiscourageUpgradableNops => "DISCOURAGE_UPGRADABLE_NOPS",
+            InterpreterError::DiscourageUpgradableWitnessProgram => "DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM",
+            InterpreterError::WitnessProgramWrongLength => "WITNESS_PROGRAM_WRONG_LENGTH",
+            InterpreterError::WitnessProgramWitnessEmpty => "WITNESS_PROGRAM_WITNESS_EMPTY",
+            InterpreterError::WitnessProgramMismatch => "WITNESS_PROGRAM_MISMATCH",
+            InterpreterError::WitnessMalleated => "WITNESS_MALLEATED",
+            InterpreterError::WitnessMalleatedP2SH => "WITNESS_MALLEATED_P2SH",
+            InterpreterError::WitnessUnexpected => "WITNESS_UNEXPECTED",
+            InterpreterError::WitnessPubkeyType => "WITNESS_PUBKEYTYPE",
+            InterpreterError::OpCodeSeparator => "OP_CODESEPARATOR",
+            InterpreterError::SigFindAndDelete => "SIG_FINDANDDELETE"
+        }
+    }
+}
+
+impl fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Interpreter error: {}", self.name())
+    }
+}
+
+impl error::Error for InterpreterError {
+    fn description(&self) -> &str {
+        self.name()
+    }
+}
+
+/// Signature version. Determines how script code is hashed and which rules apply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigVersion {
+    Base,
+    WitnessV0
+}
+
+/// Verifies signatures and lock times for the interpreter.
+/// The default implementation rejects everything.
+pub trait SignatureChecker {
+    fn check_sig(&self, _sig: &[u8], _pubkey: &[u8], _script_code: &[u8], _sig_version: SigVersion) -> bool {
+        false
+    }
+
+    fn check_lock_time(&self, _lock_time: i64) -> bool {
+        false
+    }
+
+    fn check_sequence(&self, _sequence: i64) -> bool {
+        false
+    }
+}
+
+/// Checker without transaction context
+pub struct BaseSignatureChecker;
+
+impl SignatureChecker for BaseSignatureChecker {}
+
+/// Pluggable ECDSA backend used by TransactionSignatureChecker.
+/// Gets the DER encoded signature (without sighash byte), the serialized pubkey
+/// and the message hash which was signed.
+pub trait SigVerifier {
+    fn verify_ecdsa(&self, sig: &[u8], pubkey: &[u8], sighash: &[u8; 32]) -> bool;
+}
+
+/// Verifier which treats every signature as invalid.
+/// Useful if only the script structure is of interest.
+pub struct NullSigVerifier;
+
+impl SigVerifier for NullSigVerifier {
+    fn verify_ecdsa(&self, _: &[u8], _: &[u8], _: &[u8; 32]) -> bool {
+        false
+    }
+}
+
+/// Checks signatures and lock times against a spending transaction
+pub struct TransactionSignatureChecker<'a> {
+    tx: &'a Tx,
+    input_index: usize,
+    amount: u64,
+    verifier: &'a SigVerifier
+}
+
+impl<'a> TransactionSignatureChecker<'a> {
+    pub fn new(tx: &'a Tx, input_index: usize, amount: u64, verifier: &'a SigVerifier) -> Self {
+        TransactionSignatureChecker {
+            tx: tx,
+            input_index: input_index,
+            amount: amount,
+            verifier: verifier
+        }
+    }
+}
+
+impl<'a> SignatureChecker for TransactionSignatureChecker<'a> {
+    fn check_sig(&self, sig: &[u8], pubkey: &[u8], script_code: &[u8], sig_version: SigVersion) -> bool {
+        // Strip sighash type byte
+        let (hash_type, der) = match sig.split_last() {
+            Some((&hash_type, der)) => (hash_type as u32, der),
+            None => return false
+        };
+        let sighash = match sig_version {
+            SigVersion::Base => signature_hash(self.tx, self.input_index, script_code, hash_type),
+            SigVersion::WitnessV0 => signature_hash_witness_v0(self.tx, self.input_index, script_code, self.amount, hash_type)
+        };
+        self.verifier.verify_ecdsa(der, pubkey, &sighash)
+    }
+
+    fn check_lock_time(&self, lock_time: i64) -> bool {
+        let tx_lock_time = self.tx.tx_locktime as i64;
+        // Both lock times must be of the same type (block height or timestamp)
+        if !((tx_lock_time < LOCKTIME_THRESHOLD && lock_time < LOCKTIME_THRESHOLD) ||
+             (tx_lock_time >= LOCKTIME_THRESHOLD && lock_time >= LOCKTIME_THRESHOLD)) {
+            return false;
+        }
+        if lock_time > tx_lock_time {
+            return false;
+        }
+        // A final input would disable nLockTime entirely
+        match self.tx.inputs.get(self.input_index) {
+            Some(input) => input.seq_no != SEQUENCE_FINAL,
+            None => false
+        }
+    }
+
+    fn check_sequence(&self, sequence: i64) -> bool {
+        let tx_sequence = match self.tx.inputs.get(self.input_index) {
+            Some(input) => input.seq_no as i64,
+            None => return false
+        };
+        // Relative lock times are only defined for version 2+ transactions
+        if self.tx.tx_version < 2 {
+            return false;
+        }
+        if tx_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return false;
+        }
+        let mask = SEQUENCE_LOCKTIME_TYPE_FLAG | SEQUENCE_LOCKTIME_MASK;
+        let tx_sequence_masked = tx_sequence & mask;
+        let sequence_masked = sequence & mask;
+        if !((tx_sequence_masked < SEQUENCE_LOCKTIME_TYPE_FLAG && sequence_masked < SEQUENCE_LOCKTIME_TYPE_FLAG) ||
+             (tx_sequence_masked >= SEQUENCE_LOCKTIME_TYPE_FLAG && sequence_masked >= SEQUENCE_LOCKTIME_TYPE_FLAG)) {
+            return false;
+        }
+        sequence_masked <= tx_sequence_masked
+    }
+}
+
+/// Computes the legacy signature hash (pre-segwit)
+pub fn signature_hash(tx: &Tx, input_index: usize, script_code: &[u8], hash_type: u32) -> [u8; 32] {
+    let mut one = [0u8; 32];
+    one[0] = 1;
+    if input_index >= tx.inputs.len() {
+        return one;
+    }
+    let base_type = hash_type & 0x1f;
+    // Bitcoin Core signs the value 1 in this case (known bug)
+    if base_type == SIGHASH_SINGLE && input_index >= tx.outputs.len() {
+        return one;
+    }
+    let anyone_can_pay = hash_type & SIGHASH_ANYONECANPAY != 0;
+    let script_code = remove_codeseparators(script_code);
+
+    let mut bytes = Vec::with_capacity(256);
+    bytes.extend_from_slice(&le::u32_to_array(tx.tx_version));
+
+    let n_inputs = if anyone_can_pay { 1 } else { tx.inputs.len() };
+    bytes.extend_from_slice(&VarUint::from(n_inputs).to_bytes());
+    for i in 0..n_inputs {
+        let i = if anyone_can_pay { input_index } else { i };
+        let input = &tx.inputs[i];
+        bytes.extend_from_slice(&input.outpoint.to_bytes());
+        if i == input_index {
+            bytes.extend_from_slice(&VarUint::from(script_code.len()).to_bytes());
+            bytes.extend_from_slice(&script_code);
+        } else {
+            bytes.push(0x00);
+        }
+        if i != input_index && (base_type == SIGHASH_NONE || base_type == SIGHASH_SINGLE) {
+            bytes.extend_from_slice(&le::u32_to_array(0));
+        } else {
+            bytes.extend_from_slice(&le::u32_to_array(input.seq_no));
+        }
+    }
+
+    let n_outputs = match base_type {
+        SIGHASH_NONE => 0,
+        SIGHASH_SINGLE => input_index + 1,
+        _ => tx.outputs.len()
+    };
+    bytes.extend_from_slice(&VarUint::from(n_outputs).to_bytes());
+    for i in 0..n_outputs {
+        if base_type == SIGHASH_SINGLE && i != input_index {
+            // Blank out other outputs: value -1 and empty script
+            bytes.extend_from_slice(&le::u64_to_array(0xffffffffffffffff));
+            bytes.push(0x00);
+        } else {
+            bytes.extend_from_slice(&tx.outputs[i].out.to_bytes());
+        }
+    }
+    bytes.extend_from_slice(&le::u32_to_array(tx.tx_locktime));
+    bytes.extend_from_slice(&le::u32_to_array(hash_type));
+    sha256(&sha256(&bytes))
+}
+
+/// Computes the BIP143 signature hash for witness v0 programs
+pub fn signature_hash_witness_v0(tx: &Tx, input_index: usize, script_code: &[u8], amount: u64, hash_type: u32) -> [u8; 32] {
+    let base_type = hash_type & 0x1f;
+    let anyone_can_pay = hash_type & SIGHASH_ANYONECANPAY != 0;
+
+    let mut hash_prevouts = [0u8; 32];
+    if !anyone_can_pay {
+        let mut bytes = Vec::with_capacity(36 * tx.inputs.len());
+        for input in &tx.inputs {
+            bytes.extend_from_slice(&input.outpoint.to_bytes());
+        }
+        hash_prevouts = sha256(&sha256(&bytes));
+    }
+    let mut hash_sequence = [0u8; 32];
+    if !anyone_can_pay && base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+        let mut bytes = Vec::with_capacity(4 * tx.inputs.len());
+        for input in &tx.inputs {
+            bytes.extend_from_slice(&le::u32_to_array(input.seq_no));
+        }
+        hash_sequence = sha256(&sha256(&bytes));
+    }
+    let mut hash_outputs = [0u8; 32];
+    if base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+        let mut bytes = Vec::new();
+        for output in &tx.outputs {
+            bytes.extend_from_slice(&output.out.to_bytes());
+        }
+        hash_outputs = sha256(&sha256(&bytes));
+    } else if base_type == SIGHASH_SINGLE && input_index < tx.outputs.len() {
+        hash_outputs = sha256(&sha256(&tx.outputs[input_index].out.to_bytes()));
+    }
+
+    let mut bytes = Vec::with_capacity(256);
+    bytes.extend_from_slice(&le::u32_to_array(tx.tx_version));
+    bytes.extend_from_slice(&hash_prevouts);
+    bytes.extend_from_slice(&hash_sequence);
+    if let Some(input) = tx.inputs.get(input_index) {
+        bytes.extend_from_slice(&input.outpoint.to_bytes());
+        bytes.extend_from_slice(&VarUint::from(script_code.len()).to_bytes());
+        bytes.extend_from_slice(script_code);
+        bytes.extend_from_slice(&le::u64_to_array(amount));
+        bytes.extend_from_slice(&le::u32_to_array(input.seq_no));
+    }
+    bytes.extend_from_slice(&hash_outputs);
+    bytes.extend_from_slice(&le::u32_to_array(tx.tx_locktime));
+    bytes.extend_from_slice(&le::u32_to_array(hash_type));
+    sha256(&sha256(&bytes))
+}
+
+/// Verifies a full input: scriptSig, scriptPubKey, P2SH redeem script and witness program.
+pub fn verify_script(script_sig: &[u8], script_pubkey: &[u8], witness: &[Vec<u8>],
+                     flags: u32, checker: &SignatureChecker) -> Result<(), InterpreterError> {
+
+    if flags & SCRIPT_VERIFY_SIGPUSHONLY != 0 && !is_push_only(script_sig) {
+        return Err(InterpreterError::SigPushOnly);
+    }
+
+    let mut stack: Vec<Vec<u8>> = Vec::new();
+    try!(eval_script(&mut stack, script_sig, flags, checker, SigVersion::Base));
+    let stack_copy = if flags & SCRIPT_VERIFY_P2SH != 0 { stack.clone() } else { Vec::new() };
+    try!(eval_script(&mut stack, script_pubkey, flags, checker, SigVersion::Base));
+    match stack.last() {
+        Some(top) if cast_to_bool(top) => (),
+        _ => return Err(InterpreterError::EvalFalse)
+    }
+
+    // Bare witness programs
+    let mut had_witness = false;
+    if flags & SCRIPT_VERIFY_WITNESS != 0 {
+        if let Some((version, program)) = witness_program(script_pubkey) {
+            had_witness = true;
+            if !script_sig.is_empty() {
+                // The scriptSig must be _exactly_ empty, otherwise we reintroduce malleability
+                return Err(InterpreterError::WitnessMalleated);
+            }
+            try!(verify_witness_program(witness, version, program, flags, checker));
+            // Bypass the cleanstack check at the end
+            stack.truncate(1);
+        }
+    }
+
+    // Additional validation for spend-to-script-hash transactions
+    if flags & SCRIPT_VERIFY_P2SH != 0 && is_pay_to_script_hash(script_pubkey) {
+        if !is_push_only(script_sig) {
+            return Err(InterpreterError::SigPushOnly);
+        }
+        stack = stack_copy;
+        let redeem_script = match stack.pop() {
+            Some(script) => script,
+            None => return Err(InterpreterError::EvalFalse)
+        };
+        try!(eval_script(&mut stack, &redeem_script, flags, checker, SigVersion::Base));
+        match stack.last() {
+            Some(top) if cast_to_bool(top) => (),
+            _ => return Err(InterpreterError::EvalFalse)
+        }
+
+        // P2SH wrapped witness programs
+        if flags & SCRIPT_VERIFY_WITNESS != 0 {
+            if let Some((version, program)) = witness_program(&redeem_script) {
+                had_witness = true;
+                // The scriptSig must be _exactly_ a single push of the redeemScript
+                if script_sig != &push_data(&redeem_script)[..] {
+                    return Err(InterpreterError::WitnessMalleatedP2SH);
+                }
+                try!(verify_witness_program(witness, version, program, flags, checker));
+                stack.truncate(1);
+            }
+        }
+    }
+
+    // The CLEANSTACK check is only performed after potential P2SH evaluation
+    if flags & SCRIPT_VERIFY_CLEANSTACK != 0 && stack.len() != 1 {
+        return Err(InterpreterError::CleanStack);
+    }
+
+    if flags & SCRIPT_VERIFY_WITNESS != 0 && !had_witness && !witness.is_empty() {
+        return Err(InterpreterError::WitnessUnexpected);
+    }
+    Ok(())
+}
+
+fn verify_witness_program(witness: &[Vec<u8>], version: u8, program: &[u8], flags: u32,
+                          checker: &SignatureChecker) -> Result<(), InterpreterError> {
+    if version == 0 {
+        if program.len() == 32 {
+            // P2WSH: last witness item is the script, its sha256 must match the program
+            let (witness_script, items) = match witness.split_last() {
+                Some(split) => split,
+                None => return Err(InterpreterError::WitnessProgramWitnessEmpty)
+            };
+            if &sha256(witness_script)[..] != program {
+                return Err(InterpreterError::WitnessProgramMismatch);
+            }
+            return execute_witness_script(items.to_vec(), witness_script, flags, checker);
+        } else if program.len() == 20 {
+            // P2WPKH: executed as OP_DUP OP_HASH160 <program> OP_EQUALVERIFY OP_CHECKSIG
+            if witness.len() != 2 {
+                return Err(InterpreterError::WitnessProgramMismatch);
+            }
+            let mut script = vec![All::OP_DUP as u8, All::OP_HASH160 as u8, 0x14];
+            script.extend_from_slice(program);
+            script.push(All::OP_EQUALVERIFY as u8);
+            script.push(All::OP_CHECKSIG as u8);
+            return execute_witness_script(witness.to_vec(), &script, flags, checker);
+        } else {
+            return Err(InterpreterError::WitnessProgramWrongLength);
+        }
+    }
+    // Taproot (v1) and higher versions are treated as anyone-can-spend here.
+    // Schnorr verification needs the full spent outputs which we don't have.
+    if flags & SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM != 0 {
+        return Err(InterpreterError::DiscourageUpgradableWitnessProgram);
+    }
+    Ok(())
+}
+
+fn execute_witness_script(mut stack: Vec<Vec<u8>>, script: &[u8], flags: u32,
+                          checker: &SignatureChecker) -> Result<(), InterpreterError> {
+    if stack.iter().any(|e| e.len() > MAX_SCRIPT_ELEMENT_SIZE) {
+        return Err(InterpreterError::PushSize);
+    }
+    try!(eval_script(&mut stack, script, flags, checker, SigVersion::WitnessV0));
+    // Scripts inside witness implicitly require cleanstack behaviour
+    if stack.len() != 1 {
+        return Err(InterpreterError::CleanStack);
+    }
+    if !cast_to_bool(&stack[0]) {
+        return Err(InterpreterError::EvalFalse);
+    }
+    Ok(())
+}
+
+/// Executes a single script on the given stack
+pub fn eval_script(stack: &mut Vec<Vec<u8>>, script: &[u8], flags: u32,
+                   checker: &SignatureChecker, sig_version: SigVersion) -> Result<(), InterpreterError> {
+    if script.len() > MAX_SCRIPT_SIZE {
+        return Err(InterpreterError::ScriptSize);
+    }
+    let require_minimal = flags & SCRIPT_VERIFY_MINIMALDATA != 0;
+    let mut altstack: Vec<Vec<u8>> = Vec::new();
+    let mut exec_stack: Vec<bool> = Vec::new();
+    let mut op_count = 0;
+    let mut codehash_begin = 0;
+
+    let mut evaluator = ScriptEvaluator::new(script);
+    while let Some(instruction) = evaluator.next_instruction() {
+        let executing = !exec_stack.contains(&false);
+        let (opcode, data) = match instruction {
+            Ok(i) => i,
+            Err(ScriptError::UnexpectedEof) | Err(ScriptError::InvalidFormat) => return Err(InterpreterError::BadOpcode)
+        };
+        let op = opcode as u8;
+
+        if data.len() > MAX_SCRIPT_ELEMENT_SIZE {
+            return Err(InterpreterError::PushSize);
+        }
+        // Note how OP_RESERVED does not count towards the opcode limit.
+        if op > All::OP_PUSHNUM_16 as u8 {
+            op_count += 1;
+            if op_count > MAX_OPS_PER_SCRIPT {
+                return Err(InterpreterError::OpCount);
+            }
+        }
+        if is_disabled(opcode) {
+            // Disabled opcodes fail the script even in an unexecuted branch
+            return Err(InterpreterError::DisabledOpcode);
+        }
+        if opcode == All::OP_CODESEPARATOR && sig_version == SigVersion::Base &&
+            flags & SCRIPT_VERIFY_CONST_SCRIPTCODE != 0 {
+            return Err(InterpreterError::OpCodeSeparator);
+        }
+
+        if executing && op <= All::OP_PUSHDATA4 as u8 {
+            if require_minimal && !check_minimal_push(data, opcode) {
+                return Err(InterpreterError::MinimalData);
+            }
+            stack.push(Vec::from(data));
+        } else if executing || (All::OP_IF as u8 <= op && op <= All::OP_ENDIF as u8) {
+            match opcode {
+                // Push value
+                All::OP_PUSHNUM_NEG1 | All::OP_PUSHNUM_1 | All::OP_PUSHNUM_2 | All::OP_PUSHNUM_3 |
+                All::OP_PUSHNUM_4 | All::OP_PUSHNUM_5 | All::OP_PUSHNUM_6 | All::OP_PUSHNUM_7 |
+                All::OP_PUSHNUM_8 | All::OP_PUSHNUM_9 | All::OP_PUSHNUM_10 | All::OP_PUSHNUM_11 |
+                All::OP_PUSHNUM_12 | All::OP_PUSHNUM_13 | All::OP_PUSHNUM_14 | All::OP_PUSHNUM_15 |
+                All::OP_PUSHNUM_16 => {
+                    let n = op as i64 - (All::OP_PUSHNUM_1 as i64 - 1);
+                    stack.push(scriptnum_encode(n));
+                }
+
+                // Control
+                All::OP_NOP => (),
+                All::OP_NOP2 => {
+                    // OP_CHECKLOCKTIMEVERIFY
+                    if flags & SCRIPT_VERIFY_CHECKLOCKTIMEVERIFY == 0 {
+                        if flags & SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_NOPS != 0 {
+                            return Err(InterpreterError::DiscourageUpgradableNops);
+                        }
+                    } else {
+                        // Lock times can be 5 bytes long, since we have to handle timestamps beyond 2038
+                        let lock_time = try!(scriptnum_decode(try!(top(stack, 1)), require_minimal, 5));
+                        if lock_time < 0 {
+                            return Err(InterpreterError::NegativeLocktime);
+                        }
+                        if !checker.check_lock_time(lock_time) {
+                            return Err(InterpreterError::UnsatisfiedLocktime);
+                        }
+                    }
+                }
+                All::OP_NOP3 => {
+                    // OP_CHECKSEQUENCEVERIFY
+                    if flags & SCRIPT_VERIFY_CHECKSEQUENCEVERIFY == 0 {
+                        if flags & SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_NOPS != 0 {
+                            return Err(InterpreterError::DiscourageUpgradableNops);
+                        }
+                    } else {
+                        let sequence = try!(scriptnum_decode(try!(top(stack, 1)), require_minimal, 5));
+                        if sequence < 0 {
+                            return Err(InterpreterError::NegativeLocktime);
+                        }
+                        // Relative lock time is disabled if the flag is set, so OP_CSV behaves as a NOP
+                        if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG == 0 && !checker.check_sequence(sequence) {
+                            return Err(InterpreterError::UnsatisfiedLocktime);
+                        }
+                    }
+                }
+                All::OP_NOP1 | All::OP_NOP4 | All::OP_NOP5 | All::OP_NOP6 | All::OP_NOP7 |
+                All::OP_NOP8 | All::OP_NOP9 | All::OP_NOP10 => {
+                    if flags & SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_NOPS != 0 {
+                        return Err(InterpreterError::DiscourageUpgradableNops);
+                    }
+                }
+                All::OP_IF | All::OP_NOTIF => {
+                    let mut value = false;
+                    if executing {
+                        let condition = match stack.pop() {
+                            Some(c) => c,
+                            None => return Err(InterpreterError::UnbalancedConditional)
+                        };
+                        if sig_version == SigVersion::WitnessV0 && flags & SCRIPT_VERIFY_MINIMALIF != 0 {
+                            if condition.len() > 1 || (condition.len() == 1 && condition[0] != 1) {
+                                return Err(InterpreterError::MinimalIf);
+                            }
+                        }
+                        value = cast_to_bool(&condition);
+                        if opcode == All::OP_NOTIF {
+                            value = !value;
+                        }
+                    }
+                    exec_stack.push(value);
+                }
+                All::OP_ELSE => {
+                    match exec_stack.last_mut() {
+                        Some(value) => *value = !*value,
+                        None => return Err(InterpreterError::UnbalancedConditional)
+                    }
+                }
+                All::OP_ENDIF => {
+                    if exec_stack.pop().is_none() {
+                        return Err(InterpreterError::UnbalancedConditional);
+                    }
+                }
+                All::OP_VERIFY => {
+                    if !cast_to_bool(try!(top(stack, 1))) {
+                        return Err(InterpreterError::Verify);
+                    }
+                    stack.pop();
+                }
+                All::OP_RETURN => return Err(InterpreterError::OpReturn),
+
+                // Stack ops
+                All::OP_TOALTSTACK => {
+                    let value = try!(pop(stack));
+                    altstack.push(value);
+                }
+                All::OP_FROMALTSTACK => {
+                    match altstack.pop() {
+                        Some(value) => stack.push(value),
+                        None => return Err(InterpreterError::InvalidAltstackOperation)
+                    }
+                }
+                All::OP_2DROP => {
+                    try!(require(stack, 2));
+                    stack.pop();
+                    stack.pop();
+                }
+                All::OP_2DUP => {
+                    try!(require(stack, 2));
+                    let len = stack.len();
+                    let (a, b) = (stack[len - 2].clone(), stack[len - 1].clone());
+                    stack.push(a);
+                    stack.push(b);
+                }
+                All::OP_3DUP => {
+                    try!(require(stack, 3));
+                    let len = stack.len();
+                    let (a, b, c) = (stack[len - 3].clone(), stack[len - 2].clone(), stack[len - 1].clone());
+                    stack.push(a);
+                    stack.push(b);
+                    stack.push(c);
+                }
+                All::OP_2OVER => {
+                    try!(require(stack, 4));
+                    let len = stack.len();
+                    let (a, b) = (stack[len - 4].clone(), stack[len - 3].clone());
+                    stack.push(a);
+                    stack.push(b);
+                }
+                All::OP_2ROT => {
+                    try!(require(stack, 6));
+                    let len = stack.len();
+                    let a = stack.remove(len - 6);
+                    let b = stack.remove(len - 6);
+                    stack.push(a);
+                    stack.push(b);
+                }
+                All::OP_2SWAP => {
+                    try!(require(stack, 4));
+                    let len = stack.len();
+                    stack.swap(len - 4, len - 2);
+                    stack.swap(len - 3, len - 1);
+                }
+                All::OP_IFDUP => {
+                    let value = try!(top(stack, 1)).clone();
+                    if cast_to_bool(&value) {
+                        stack.push(value);
+                    }
+                }
+                All::OP_DEPTH => {
+                    let depth = stack.len() as i64;
+                    stack.push(scriptnum_encode(depth));
+                }
+                All::OP_DROP => {
+                    try!(pop(stack));
+                }
+                All::OP_DUP => {
+                    let value = try!(top(stack, 1)).clone();
+                    stack.push(value);
+                }
+                All::OP_NIP => {
+                    try!(require(stack, 2));
+                    let len = stack.len();
+                    stack.remove(len - 2);
+                }
+                All::OP_OVER => {
+                    let value = try!(top(stack, 2)).clone();
+                    stack.push(value);
+                }
+                All::OP_PICK | All::OP_ROLL => {
+                    try!(require(stack, 2));
+                    let n = try!(scriptnum_decode(&try!(pop(stack)), require_minimal, 4));
+                    if n < 0 || n >= stack.len() as i64 {
+                        return Err(InterpreterError::InvalidStackOperation);
+                    }
+                    let idx = stack.len() - 1 - n as usize;
+                    let value = if opcode == All::OP_ROLL {
+                        stack.remove(idx)
+                    } else {
+                        stack[idx].clone()
+                    };
+                    stack.push(value);
+                }
+                All::OP_ROT => {
+                    try!(require(stack, 3));
+                    let len = stack.len();
+                    let value = stack.remove(len - 3);
+                    stack.push(value);
+                }
+                All::OP_SWAP => {
+                    try!(require(stack, 2));
+                    let len = stack.len();
+                    stack.swap(len - 2, len - 1);
+                }
+                All::OP_TUCK => {
+                    try!(require(stack, 2));
+                    let len = stack.len();
+                    let value = stack[len - 1].clone();
+                    stack.insert(len - 2, value);
+                }
+                All::OP_SIZE => {
+                    let size = try!(top(stack, 1)).len() as i64;
+                    stack.push(scriptnum_encode(size));
+                }
+
+                // Bitwise logic
+                All::OP_EQUAL | All::OP_EQUALVERIFY => {
+                    try!(require(stack, 2));
+                    let a = try!(pop(stack));
+                    let b = try!(pop(stack));
+                    let equal = a == b;
+                    if opcode == All::OP_EQUALVERIFY {
+                        if !equal {
+                            return Err(InterpreterError::EqualVerify);
+                        }
+                    } else {
+                        stack.push(bool_to_vec(equal));
+                    }
+                }
+
+                // Numeric
+                All::OP_1ADD | All::OP_1SUB | All::OP_NEGATE | All::OP_ABS |
+                All::OP_NOT | All::OP_0NOTEQUAL => {
+                    let n = try!(scriptnum_decode(try!(top(stack, 1)), require_minimal, 4));
+                    let result = match opcode {
+                        All::OP_1ADD => n + 1,
+                        All::OP_1SUB => n - 1,
+                        All::OP_NEGATE => -n,
+                        All::OP_ABS => n.abs(),
+                        All::OP_NOT => (n == 0) as i64,
+                        _ => (n != 0) as i64
+                    };
+                    stack.pop();
+                    stack.push(scriptnum_encode(result));
+                }
+                All::OP_ADD | All::OP_SUB | All::OP_BOOLAND | All::OP_BOOLOR |
+                All::OP_NUMEQUAL | All::OP_NUMEQUALVERIFY | All::OP_NUMNOTEQUAL |
+                All::OP_LESSTHAN | All::OP_GREATERTHAN | All::OP_LESSTHANOREQUAL |
+                All::OP_GREATERTHANOREQUAL | All::OP_MIN | All::OP_MAX => {
+                    try!(require(stack, 2));
+                    let a = try!(scriptnum_decode(try!(top(stack, 2)), require_minimal, 4));
+                    let b = try!(scriptnum_decode(try!(top(stack, 1)), require_minimal, 4));
+                    let result = match opcode {
+                        All::OP_ADD => a + b,
+                        All::OP_SUB => a - b,
+                        All::OP_BOOLAND => (a != 0 && b != 0) as i64,
+                        All::OP_BOOLOR => (a != 0 || b != 0) as i64,
+                        All::OP_NUMEQUAL | All::OP_NUMEQUALVERIFY => (a == b) as i64,
+                        All::OP_NUMNOTEQUAL => (a != b) as i64,
+                        All::OP_LESSTHAN => (a < b) as i64,
+                        All::OP_GREATERTHAN => (a > b) as i64,
+                        All::OP_LESSTHANOREQUAL => (a <= b) as i64,
+                        All::OP_GREATERTHANOREQUAL => (a >= b) as i64,
+                        All::OP_MIN => cmp::min(a, b),
+                        _ => cmp::max(a, b)
+                    };
+                    stack.pop();
+                    stack.pop();
+                    if opcode == All::OP_NUMEQUALVERIFY {
+                        if result == 0 {
+                            return Err(InterpreterError::NumEqualVerify);
+                        }
+                    } else {
+                        stack.push(scriptnum_encode(result));
+                    }
+                }
+                All::OP_WITHIN => {
+                    try!(require(stack, 3));
+                    let x = try!(scriptnum_decode(try!(top(stack, 3)), require_minimal, 4));
+                    let min = try!(scriptnum_decode(try!(top(stack, 2)), require_minimal, 4));
+                    let max = try!(scriptnum_decode(try!(top(stack, 1)), require_minimal, 4));
+                    stack.pop();
+                    stack.pop();
+                    stack.pop();
+                    stack.push(bool_to_vec(min <= x && x < max));
+                }
+
+                // Crypto
+                All::OP_RIPEMD160 | All::OP_SHA1 | All::OP_SHA256 | All::OP_HASH160 | All::OP_HASH256 => {
+                    let value = try!(pop(stack));
+                    let hash = match opcode {
+                        All::OP_RIPEMD160 => ridemp160(&value).to_vec(),
+                        All::OP_SHA1 => sha1(&value).to_vec(),
+                        All::OP_SHA256 => sha256(&value).to_vec(),
+                        All::OP_HASH160 => ridemp160(&sha256(&value)).to_vec(),
+                        _ => sha256(&sha256(&value)).to_vec()
+                    };
+                    stack.push(hash);
+                }
+                All::OP_CODESEPARATOR => {
+                    // Hash starts after the code separator
+                    codehash_begin = evaluator.ip;
+                }
+                All::OP_CHECKSIG | All::OP_CHECKSIGVERIFY => {
+                    try!(require(stack, 2));
+                    let pubkey = try!(pop(stack));
+                    let sig = try!(pop(stack));
+
+                    let mut script_code = Vec::from(&script[codehash_begin..]);
+                    // Drop the signature in pre-segwit scripts but not segwit scripts
+                    if sig_version == SigVersion::Base {
+                        let found = find_and_delete(&mut script_code, &push_data(&sig));
+                        if found > 0 && flags & SCRIPT_VERIFY_CONST_SCRIPTCODE != 0 {
+                            return Err(InterpreterError::SigFindAndDelete);
+                        }
+                    }
+                    try!(check_signature_encoding(&sig, flags));
+                    try!(check_pubkey_encoding(&pubkey, flags, sig_version));
+                    let success = !sig.is_empty() && checker.check_sig(&sig, &pubkey, &script_code, sig_version);
+                    if !success && flags & SCRIPT_VERIFY_NULLFAIL != 0 && !sig.is_empty() {
+                        return Err(InterpreterError::SigNullFail);
+                    }
+                    if opcode == All::OP_CHECKSIGVERIFY {
+                        if !success {
+                            return Err(InterpreterError::CheckSigVerify);
+                        }
+                    } else {
+                        stack.push(bool_to_vec(success));
+                    }
+                }
+                All::OP_CHECKMULTISIG | All::OP_CHECKMULTISIGVERIFY => {
+                    let mut i = 1;
+                    let n_keys = try!(scriptnum_decode(try!(top(stack, i)), require_minimal, 4));
+                    if n_keys < 0 || n_keys > MAX_PUBKEYS_PER_MULTISIG {
+                        return Err(InterpreterError::PubkeyCount);
+                    }
+                    op_count += n_keys as usize;
+                    if op_count > MAX_OPS_PER_SCRIPT {
+                        return Err(InterpreterError::OpCount);
+                    }
+                    let mut ikey = i + 1;
+                    // ikey2 is the position of last non-signature item in the stack. Top stack item = 1.
+                    // With SCRIPT_VERIFY_NULLFAIL, this is used for cleanup if operation fails.
+                    let mut ikey2 = n_keys as usize + 2;
+                    i += n_keys as usize + 1;
+                    let n_sigs = try!(scriptnum_decode(try!(top(stack, i)), require_minimal, 4));
+                    if n_sigs < 0 || n_sigs > n_keys {
+                        return Err(InterpreterError::SigCount);
+                    }
+                    let mut isig = i + 1;
+                    i += n_sigs as usize + 1;
+                    try!(require(stack, i));
+
+                    let mut script_code = Vec::from(&script[codehash_begin..]);
+                    if sig_version == SigVersion::Base {
+                        for k in 0..n_sigs as usize {
+                            let sig = try!(top(stack, isig + k)).clone();
+                            let found = find_and_delete(&mut script_code, &push_data(&sig));
+                            if found > 0 && flags & SCRIPT_VERIFY_CONST_SCRIPTCODE != 0 {
+                                return Err(InterpreterError::SigFindAndDelete);
+                            }
+                        }
+                    }
+
+                    let mut success = true;
+                    let mut keys_left = n_keys;
+                    let mut sigs_left = n_sigs;
+                    while success && sigs_left > 0 {
+                        let sig = try!(top(stack, isig)).clone();
+                        let pubkey = try!(top(stack, ikey)).clone();
+                        // Note how this makes the exact order of pubkey/signature evaluation
+                        // distinguishable by CHECKMULTISIG NOT if the STRICTENC flag is set.
+                        try!(check_signature_encoding(&sig, flags));
+                        try!(check_pubkey_encoding(&pubkey, flags, sig_version));
+                        if !sig.is_empty() && checker.check_sig(&sig, &pubkey, &script_code, sig_version) {
+                            isig += 1;
+                            sigs_left -= 1;
+                        }
+                        ikey += 1;
+                        keys_left -= 1;
+                        // If there are more signatures left than keys left, then too many signatures have failed.
+                        if sigs_left > keys_left {
+                            success = false;
+                        }
+                    }
+
+                    // Clean up stack of actual arguments
+                    while i > 1 {
+                        i -= 1;
+                        // If the operation failed, we require that all signatures must be empty vector
+                        if !success && flags & SCRIPT_VERIFY_NULLFAIL != 0 && ikey2 == 0 &&
+                            !try!(top(stack, 1)).is_empty() {
+                            return Err(InterpreterError::SigNullFail);
+                        }
+                        if ikey2 > 0 {
+                            ikey2 -= 1;
+                        }
+                        stack.pop();
+                    }
+                    // A bug causes CHECKMULTISIG to consume one extra argument whose contents were not checked.
+                    // BIP147 (NULLDUMMY) requires it to be empty.
+                    let dummy = try!(pop(stack));
+                    if flags & SCRIPT_VERIFY_NULLDUMMY != 0 && !dummy.is_empty() {
+                        return Err(InterpreterError::SigNullDummy);
+                    }
+                    if opcode == All::OP_CHECKMULTISIGVERIFY {
+                        if !success {
+                            return Err(InterpreterError::CheckMultisigVerify);
+                        }
+                    } else {
+                        stack.push(bool_to_vec(success));
+                    }
+                }
+                _ => return Err(InterpreterError::BadOpcode)
+            }
+        }
+
+        if stack.len() + altstack.len() > MAX_STACK_SIZE {
+            return Err(InterpreterError::StackSize);
+        }
+    }
+
+    if !exec_stack.is_empty() {
+        return Err(InterpreterError::UnbalancedConditional);
+    }
+    Ok(())
+}
+
+/// Returns witness version and program if the script is a witness program
+pub fn witness_program(script: &[u8]) -> Option<(u8, &[u8])> {
+    if script.len() < 4 || script.len() > 42 {
+        return None;
+    }
+    let version = match script[0] {
+        0x00 => 0,
+        v @ 0x51...0x60 => v - 0x50,
+        _ => return None
+    };
+    if script[1] as usize + 2 == script.len() {
+        Some((version, &script[2..]))
+    } else {
+        None
+    }
+}
+
+/// OP_HASH160 <20 bytes> OP_EQUAL
+pub fn is_pay_to_script_hash(script: &[u8]) -> bool {
+    script.len() == 23 && script[0] == All::OP_HASH160 as u8 &&
+        script[1] == 0x14 && script[22] == All::OP_EQUAL as u8
+}
+
+/// Checks if the script only consists of push operations (including OP_RESERVED)
+pub fn is_push_only(script: &[u8]) -> bool {
+    let mut evaluator = ScriptEvaluator::new(script);
+    while let Some(instruction) = evaluator.next_instruction() {
+        match instruction {
+            Ok((opcode, _)) if opcode as u8 <= All::OP_PUSHNUM_16 as u8 => (),
+            _ => return false
+        }
+    }
+    true
+}
+
+/// Any non-zero value is true, except negative zero
+pub fn cast_to_bool(value: &[u8]) -> bool {
+    for (i, &b) in value.iter().enumerate() {
+        if b != 0 {
+            return !(i == value.len() - 1 && b == 0x80);
+        }
+    }
+    false
+}
+
+/// Decodes a little endian sign-magnitude number as used by the numeric opcodes
+pub fn scriptnum_decode(value: &[u8], require_minimal: bool, max_size: usize) -> Result<i64, InterpreterError> {
+    if value.len() > max_size {
+        return Err(InterpreterError::UnknownError);
+    }
+    if value.is_empty() {
+        return Ok(0);
+    }
+    let last = value[value.len() - 1];
+    if require_minimal && last & 0x7f == 0 {
+        // The most significant byte may only be zero if the next byte has its sign bit set
+        if value.len() <= 1 || value[value.len() - 2] & 0x80 == 0 {
+            return Err(InterpreterError::UnknownError);
+        }
+    }
+    let mut result: i64 = 0;
+    for (i, &b) in value.iter().enumerate() {
+        result |= (b as i64) << (8 * i);
+    }
+    if last & 0x80 != 0 {
+        return Ok(-(result & !(0x80i64 << (8 * (value.len() - 1)))));
+    }
+    Ok(result)
+}
+
+/// Encodes a number into the minimal script representation
+pub fn scriptnum_encode(value: i64) -> Vec<u8> {
+    let mut result = Vec::new();
+    if value == 0 {
+        return result;
+    }
+    let negative = value < 0;
+    let mut abs = value.abs() as u64;
+    while abs > 0 {
+        result.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    // If the most significant byte is >= 0x80 and the value is positive,
+    // push a new zero-byte to make the significant byte < 0x80 again.
+    let last = result.len() - 1;
+    if result[last] & 0x80 != 0 {
+        result.push(if negative { 0x80 } else { 0x00 });
+    } else if negative {
+        result[last] |= 0x80;
+    }
+    result
+}
+
+/// Creates the script bytes for a single minimal-length push of data
+pub fn push_data(data: &[u8]) -> Vec<u8> {
+    let mut script = Vec::with_capacity(data.len() + 5);
+    let len = data.len();
+    if len < All::OP_PUSHDATA1 as usize {
+        script.push(len as u8);
+    } else if len <= 0xff {
+        script.push(All::OP_PUSHDATA1 as u8);
+        script.push(len as u8);
+    } else if len <= 0xffff {
+        script.push(All::OP_PUSHDATA2 as u8);
+        script.extend_from_slice(&le::u16_to_array(len as u16));
+    } else {
+        script.push(All::OP_PUSHDATA4 as u8);
+        script.extend_from_slice(&le::u32_to_array(len as u32));
+    }
+    script.extend_from_slice(data);
+    script
+}
+
+#[inline]
+fn bool_to_vec(value: bool) -> Vec<u8> {
+    if value { vec![1] } else { Vec::new() }
+}
+
+/// Returns the n-th element from the top (1 = top)
+#[inline]
+fn top(stack: &[Vec<u8>], n: usize) -> Result<&Vec<u8>, InterpreterError> {
+    if n == 0 || stack.len() < n {
+        return Err(InterpreterError::InvalidStackOperation);
+    }
+    Ok(&stack[stack.len() - n])
+}
+
+#[inline]
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, InterpreterError> {
+    stack.pop().ok_or(InterpreterError::InvalidStackOperation)
+}
+
+#[inline]
+fn require(stack: &[Vec<u8>], n: usize) -> Result<(), InterpreterError> {
+    if stack.len() < n {
+        return Err(InterpreterError::InvalidStackOperation);
+    }
+    Ok(())
+}
+
+fn is_disabled(opcode: All) -> bool {
+    match opcode {
+        All::OP_CAT | All::OP_SUBSTR | All::OP_LEFT | All::OP_RIGHT | All::OP_INVERT |
+        All::OP_AND | All::OP_OR | All::OP_XOR | All::OP_2MUL | All::OP_2DIV |
+        All::OP_MUL | All::OP_DIV | All::OP_MOD | All::OP_LSHIFT | All::OP_RSHIFT => true,
+        _ => false
+    }
+}
+
+fn check_minimal_push(data: &[u8], opcode: All) -> bool {
+    let op = opcode as u8;
+    if data.is_empty() {
+        // Should have used OP_0
+        return op == All::OP_PUSHBYTES_0 as u8;
+    } else if data.len() == 1 && data[0] >= 1 && data[0] <= 16 {
+        // Should have used OP_1 .. OP_16
+        return op == All::OP_PUSHNUM_1 as u8 + (data[0] - 1);
+    } else if data.len() == 1 && data[0] == 0x81 {
+        // Should have used OP_1NEGATE
+        return op == All::OP_PUSHNUM_NEG1 as u8;
+    } else if data.len() <= 75 {
+        // Must have used a direct push (opcode indicating number of bytes pushed + those bytes)
+        return op as usize == data.len();
+    } else if data.len() <= 255 {
+        return opcode == All::OP_PUSHDATA1;
+    } else if data.len() <= 65535 {
+        return opcode == All::OP_PUSHDATA2;
+    }
+    true
+}
+
+/// Removes all occurences of needle (aligned to opcode boundaries) and returns the number of matches
+fn find_and_delete(script: &mut Vec<u8>, needle: &[u8]) -> usize {
+    if needle.is_empty() {
+        return 0;
+    }
+    let mut result = Vec::with_capacity(script.len());
+    let mut found = 0;
+    let mut pos = 0;
+    {
+        let mut evaluator = ScriptEvaluator::new(&script[..]);
+        loop {
+            // Skip all matches at the current opcode boundary
+            while script.len() - pos >= needle.len() && &script[pos..pos + needle.len()] == needle {
+                pos += needle.len();
+                found += 1;
+            }
+            evaluator.ip = pos;
+            let start = pos;
+            match evaluator.next_instruction() {
+                Some(Ok(_)) => pos = evaluator.ip,
+                // Append everything behind a broken push as is
+                Some(Err(_)) => pos = script.len(),
+                None => break
+            }
+            result.extend_from_slice(&script[start..pos]);
+        }
+    }
+    if found > 0 {
+        *script = result;
+    }
+    found
+}
+
+/// Removes all OP_CODESEPARATOR from script code before hashing
+fn remove_codeseparators(script: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(script.len());
+    let mut evaluator = ScriptEvaluator::new(script);
+    let mut pos = 0;
+    while let Some(instruction) = evaluator.next_instruction() {
+        match instruction {
+            Ok((All::OP_CODESEPARATOR, _)) => (),
+            Ok(_) => result.extend_from_slice(&script[pos..evaluator.ip]),
+            Err(_) => {
+                result.extend_from_slice(&script[pos..]);
+                break;
+            }
+        }
+        pos = evaluator.ip;
+    }
+    result
+}
+
+fn check_signature_encoding(sig: &[u8], flags: u32) -> Result<(), InterpreterError> {
+    // Empty signature. Not strictly DER encoded, but allowed to provide a
+    // compact way to provide an invalid signature for use with CHECK(MULTI)SIG
+    if sig.is_empty() {
+        return Ok(());
+    }
+    if flags & (SCRIPT_VERIFY_DERSIG | SCRIPT_VERIFY_LOW_S | SCRIPT_VERIFY_STRICTENC) != 0 &&
+        !is_valid_signature_encoding(sig) {
+        return Err(InterpreterError::SigDer);
+    }
+    if flags & SCRIPT_VERIFY_LOW_S != 0 && !is_low_der_signature(sig) {
+        return Err(InterpreterError::SigHighS);
+    }
+    if flags & SCRIPT_VERIFY_STRICTENC != 0 {
+        let hash_type = sig[sig.len() - 1] as u32 & !SIGHASH_ANYONECANPAY;
+        if hash_type < SIGHASH_ALL || hash_type > SIGHASH_SINGLE {
+            return Err(InterpreterError::SigHashtype);
+        }
+    }
+    Ok(())
+}
+
+fn check_pubkey_encoding(pubkey: &[u8], flags: u32, sig_version: SigVersion) -> Result<(), InterpreterError> {
+    let compressed = pubkey.len() == 33 && (pubkey[0] == 0x02 || pubkey[0] == 0x03);
+    let uncompressed = pubkey.len() == 65 && pubkey[0] == 0x04;
+    if flags & SCRIPT_VERIFY_STRICTENC != 0 && !compressed && !uncompressed {
+        return Err(InterpreterError::PubkeyType);
+    }
+    // Only compressed keys are accepted in segwit
+    if flags & SCRIPT_VERIFY_WITNESS_PUBKEYTYPE != 0 && sig_version == SigVersion::WitnessV0 && !compressed {
+        return Err(InterpreterError::WitnessPubkeyType);
+    }
+    Ok(())
+}
+
+/// Strict DER check (BIP66). The signature includes the trailing sighash byte.
+/// Format: 0x30 [total-length] 0x02 [R-length] [R] 0x02 [S-length] [S] [sighash]
+fn is_valid_signature_encoding(sig: &[u8]) -> bool {
+    // Minimum and maximum size constraints
+    if sig.len() < 9 || sig.len() > 73 {
+        return false;
+    }
+    // A signature is of type 0x30 (compound) and covers the whole signature
+    if sig[0] != 0x30 || sig[1] as usize != sig.len() - 3 {
+        return false;
+    }
+    let len_r = sig[3] as usize;
+    // Make sure the length of the S element is still inside the signature
+    if 5 + len_r >= sig.len() {
+        return false;
+    }
+    let len_s = sig[5 + len_r] as usize;
+    // Verify that the length of the signature matches the sum of the length of the elements
+    if len_r + len_s + 7 != sig.len() {
+        return false;
+    }
+    // R: integer, non-empty, positive and without unnecessary padding
+    if sig[2] != 0x02 || len_r == 0 || sig[4] & 0x80 != 0 {
+        return false;
+    }
+    if len_r > 1 && sig[4] == 0x00 && sig[5] & 0x80 == 0 {
+        return false;
+    }
+    // S: integer, non-empty, positive and without unnecessary padding
+    if sig[len_r + 4] != 0x02 || len_s == 0 || sig[len_r + 6] & 0x80 != 0 {
+        return false;
+    }
+    if len_s > 1 && sig[len_r + 6] == 0x00 && sig[len_r + 7] & 0x80 == 0 {
+        return false;
+    }
+    true
+}
+
+/// Checks if S is at most half the curve order (BIP62/BIP146)
+fn is_low_der_signature(sig: &[u8]) -> bool {
+    if !is_valid_signature_encoding(sig) {
+        return false;
+    }
+    const HALF_ORDER: [u8; 32] = [
+        0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0];
+    let len_r = sig[3] as usize;
+    let len_s = sig[5 + len_r] as usize;
+    let mut s = &sig[6 + len_r..6 + len_r + len_s];
+    while !s.is_empty() && s[0] == 0 {
+        s = &s[1..];
+    }
+    if s.len() != 32 {
+        return s.len() < 32;
+    }
+    s <= &HALF_ORDER[..]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use rustc_serialize::json::Json;
+
+    use super::*;
+    use blockchain::proto::opcodes::All;
+    use blockchain::proto::tx::{Tx, TxInput, TxOutpoint, TxOutput};
+    use blockchain::proto::varuint::VarUint;
+    use blockchain::proto::Hashed;
+    use blockchain::utils::hex_to_vec;
+    use blockchain::utils::secp256k1::Secp256k1Verifier;
+
+    /// Test vectors in the format of Bitcoin Core's src/test/data/script_tests.json
+    const SCRIPT_TESTS: &'static str = include_str!("../../../tests/data/script_tests.json");
+
+    /// Parses Bitcoin Core's human readable script notation used in the test vectors
+    fn parse_asm(asm: &str) -> Vec<u8> {
+        let mut names: HashMap<String, u8> = HashMap::new();
+        for op in 0x61..0xba {
+            let name = format!("{:?}", All::from(op as u8));
+            names.insert(String::from(&name[3..]), op as u8);
+        }
+        for n in 1..17 {
+            names.insert(format!("{}", n), 0x50 + n as u8);
+        }
+        for &(name, op) in [("0", 0x00), ("FALSE", 0x00), ("1NEGATE", 0x4f), ("RESERVED", 0x50),
+                            ("TRUE", 0x51), ("CHECKLOCKTIMEVERIFY", 0xb1),
+                            ("CHECKSEQUENCEVERIFY", 0xb2), ("PUSHDATA1", 0x4c),
+                            ("PUSHDATA2", 0x4d), ("PUSHDATA4", 0x4e)].iter() {
+            names.insert(String::from(name), op);
+        }
+
+        let mut script = Vec::new();
+        for token in asm.split_whitespace() {
+            let name = if token.starts_with("OP_") { &token[3..] } else { token };
+            if let Some(op) = names.get(name) {
+                script.push(*op);
+            } else if token.starts_with("0x") {
+                // Raw bytes are inserted as they are
+                script.extend_from_slice(&hex_to_vec(&token[2..]));
+            } else if token.starts_with('\'') && token.ends_with('\'') && token.len() >= 2 {
+                script.extend_from_slice(&push_data(token[1..token.len() - 1].as_bytes()));
+            } else if let Ok(n) = token.parse::<i64>() {
+                if n == -1 {
+                    script.push(All::OP_PUSHNUM_NEG1 as u8);
+                } else {
+                    script.extend_from_slice(&push_data(&scriptnum_encode(n)));
+                }
+            } else {
+                panic!("Unable to parse script token: {}", token);
+            }
+        }
+        script
+    }
+
+    /// Creates the crediting and spending transaction as done in Bitcoin Core's script_tests.cpp
+    fn build_spending_tx(script_sig: &[u8], script_pubkey: &[u8], amount: u64) -> Tx {
+        let credit_input = TxInput {
+            outpoint: TxOutpoint { txid: [0u8; 32], index: 0xffffffff },
+            script_len: VarUint::from(2u8),
+            script_sig: vec![0x00, 0x00],
+            seq_no: 0xffffffff,
+            witness: Vec::new()
+        };
+        let credit_output = TxOutput {
+            value: amount,
+            script_len: VarUint::from(script_pubkey.len()),
+            script_pubkey: Vec::from(script_pubkey)
+        };
+        let credit_tx = Hashed::double_sha256(Tx::new(1, VarUint::from(1u8), &[credit_input],
+                                                      VarUint::from(1u8), &[credit_output], 0, 0x00));
+
+        let spend_input = TxInput {
+            outpoint: TxOutpoint { txid: credit_tx.hash, index: 0 },
+            script_len: VarUint::from(script_sig.len()),
+            script_sig: Vec::from(script_sig),
+            seq_no: 0xffffffff,
+            witness: Vec::new()
+        };
+        let spend_output = TxOutput {
+            value: amount,
+            script_len: VarUint::from(0u8),
+            script_pubkey: Vec::new()
+        };
+        Tx::new(1, VarUint::from(1u8), &[spend_input], VarUint::from(1u8), &[spend_output], 0, 0x00)
+    }
+
+    #[test]
+    fn test_script_tests_json() {
+        let tests = Json::from_str(SCRIPT_TESTS).unwrap();
+        let mut n_tests = 0;
+        for test in tests.as_array().unwrap() {
+            let mut fields: Vec<Json> = test.as_array().unwrap().clone();
+            // Comments only have a single string
+            if fields.len() < 4 {
+                continue;
+            }
+            let mut witness = Vec::new();
+            let mut amount = 0;
+            if fields[0].is_array() {
+                let mut items = fields.remove(0).as_array().unwrap().clone();
+                let value = items.pop().unwrap().as_f64().unwrap();
+                amount = (value * 100000000.0).round() as u64;
+                for item in items {
+                    witness.push(hex_to_vec(item.as_string().unwrap()));
+                }
+            }
+            let script_sig = parse_asm(fields[0].as_string().unwrap());
+            let script_pubkey = parse_asm(fields[1].as_string().unwrap());
+            let flags = parse_flags(fields[2].as_string().unwrap()).unwrap();
+            let expected = fields[3].as_string().unwrap();
+
+            let tx = build_spending_tx(&script_sig, &script_pubkey, amount);
+            let verifier = Secp256k1Verifier;
+            let checker = TransactionSignatureChecker::new(&tx, 0, amount, &verifier);
+            let result = match verify_script(&script_sig, &script_pubkey, &witness, flags, &checker) {
+                Ok(()) => "OK",
+                Err(err) => err.name()
+            };
+            assert!(expected == result, "Test failed: {}\n  -> expected: {}\n  -> got:      {}", test, expected, result);
+            n_tests += 1;
+        }
+        assert!(n_tests > 0);
+    }
+
+    #[test]
+    fn test_scriptnum() {
+        for &n in [0, 1, -1, 127, -127, 128, -128, 255, 256, -255, 32767, 2147483647, -2147483647].iter() {
+            assert_eq!(n, scriptnum_decode(&scriptnum_encode(n), true, 5).unwrap());
+        }
+        assert_eq!(vec![0x80, 0x00], scriptnum_encode(128));
+        assert_eq!(vec![0x80, 0x80], scriptnum_encode(-128));
+        // Non-minimal encodings
+        assert_eq!(Err(InterpreterError::UnknownError), scriptnum_decode(&[0x00], true, 4));
+        assert_eq!(Err(InterpreterError::UnknownError), scriptnum_decode(&[0x01, 0x00], true, 4));
+        assert_eq!(Ok(1), scriptnum_decode(&[0x01, 0x00], false, 4));
+        // Overflow
+        assert_eq!(Err(InterpreterError::UnknownError), scriptnum_decode(&[0x01, 0x02, 0x03, 0x04, 0x05], false, 4));
+    }
+
+    #[test]
+    fn test_cast_to_bool() {
+        assert_eq!(false, cast_to_bool(&[]));
+        assert_eq!(false, cast_to_bool(&[0x00, 0x00]));
+        assert_eq!(false, cast_to_bool(&[0x00, 0x80]));
+        assert_eq!(true, cast_to_bool(&[0x80, 0x00]));
+        assert_eq!(true, cast_to_bool(&[0x01]));
+    }
+
+    #[test]
+    fn test_find_and_delete() {
+        let mut script = vec![0x02, 0xaa, 0xbb, 0x76, 0x02, 0xaa, 0xbb];
+        assert_eq!(2, find_and_delete(&mut script, &[0x02, 0xaa, 0xbb]));
+        assert_eq!(vec![0x76], script);
+
+        // Matches have to be aligned to opcode boundaries
+        let mut script = vec![0x03, 0x02, 0xaa, 0xbb];
+        assert_eq!(0, find_and_delete(&mut script, &[0x02, 0xaa, 0xbb]));
+        assert_eq!(vec![0x03, 0x02, 0xaa, 0xbb], script);
+    }
+
+    #[test]
+    fn test_witness_program() {
+        let script = hex_to_vec("0014751e76e8199196d454941c45d1b3a323f1433bd6");
+        let (version, program) = witness_program(&script).unwrap();
+        assert_eq!(0, version);
+        assert_eq!(20, program.len());
+        assert!(witness_program(&hex_to_vec("76a914751e76e8199196d454941c45d1b3a323f1433bd688ac")).is_none());
+    }
+
+    #[test]
+    fn test_signature_hash_single_bug() {
+        let tx = build_spending_tx(&[], &[0x51], 0);
+        let mut one = [0u8; 32];
+        one[0] = 1;
+        // SIGHASH_SINGLE without matching output signs the value 1
+        assert_eq!(one, signature_hash(&tx, 5, &[], SIGHASH_ALL));
+        assert!(one != signature_hash(&tx, 0, &[], SIGHASH_ALL));
+    }
+}
@@ -8,6 +8,12 @@ pub mod tx;
 pub mod varuint;
 pub mod script;
 pub mod opcodes;
+pub mod interpreter;
+pub mod taproot;
+pub mod inscription;
+pub mod opreturn;
+pub mod raw;
+pub mod undo;
 
 
 /// Trait to serialize defined structures
@@ -0,0 +1,151 @@
+use std::fmt;
+
+use crypto::rc4::Rc4;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+
+use blockchain::proto::tx::Tx;
+use blockchain::proto::opreturn::{self, Decoder, ProtocolRecord};
+
+/// Decrypted messages start with this prefix
+const PREFIX: &'static [u8] = b"CNTRPRTY";
+
+/// Counterparty message with its type id and undecoded fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CounterpartyMessage {
+    pub message_type: u32,
+    pub data: Vec<u8>
+}
+
+impl CounterpartyMessage {
+    /// Parses the decrypted message (without prefix).
+    /// Newer messages use a single byte type id, older ones 4 bytes (big endian).
+    pub fn parse(message: &[u8]) -> Option<CounterpartyMessage> {
+        match message.first() {
+            Some(&0) if message.len() >= 4 => {
+                let message_type = (message[0] as u32) << 24 | (message[1] as u32) << 16 |
+                                   (message[2] as u32) << 8 | message[3] as u32;
+                Some(CounterpartyMessage { message_type: message_type, data: Vec::from(&message[4..]) })
+            }
+            Some(&0) | None => None,
+            Some(&t) => Some(CounterpartyMessage { message_type: t as u32, data: Vec::from(&message[1..]) })
+        }
+    }
+
+    /// Returns the name of well known message types
+    pub fn type_name(&self) -> &'static str {
+        match self.message_type {
+            0 => "send",
+            2 => "enhanced_send",
+            3 => "mpma_send",
+            4 => "sweep",
+            10 => "order",
+            11 => "btcpay",
+            12 => "dispenser",
+            13 => "dispense",
+            20 => "issuance",
+            21 => "issuance_subasset",
+            30 => "broadcast",
+            40 => "bet",
+            50 => "dividend",
+            60 => "burn",
+            70 => "cancel",
+            80 | 81 => "rps",
+            110 => "destroy",
+            _ => "unknown"
+        }
+    }
+}
+
+impl fmt::Display for CounterpartyMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "type={} ({}) data={}", self.message_type, self.type_name(), opreturn::hex(&self.data))
+    }
+}
+
+/// Decodes ARC4 obfuscated Counterparty messages.
+/// The key is the txid of the first input (in RPC byte order).
+pub struct CounterpartyDecoder;
+
+impl Decoder for CounterpartyDecoder {
+    fn prefix(&self) -> &'static [u8] {
+        // Payloads are encrypted, so there is no visible prefix
+        &[]
+    }
+
+    fn decode(&self, payload: &[u8], _: &[u8], tx: &Tx) -> Option<ProtocolRecord> {
+        let key = match tx.inputs.first() {
+            Some(input) => input.outpoint.txid.iter().rev().cloned().collect::<Vec<u8>>(),
+            None => return None
+        };
+        // Any payload decrypts to something, it's only a Counterparty message with the prefix
+        let clear = arc4(&key, payload);
+        if clear.len() <= PREFIX.len() || !clear.starts_with(PREFIX) {
+            return None;
+        }
+        CounterpartyMessage::parse(&clear[PREFIX.len()..]).map(ProtocolRecord::Counterparty)
+    }
+}
+
+/// Applies the ARC4 key stream
+pub fn arc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut output = vec![0u8; data.len()];
+    Rc4::new(key).process(data, &mut output);
+    output
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::proto::tx::{TxInput, TxOutpoint};
+    use blockchain::proto::varuint::VarUint;
+
+    #[test]
+    fn test_arc4() {
+        // Well known test vector (key "Key", plaintext "Plaintext")
+        assert_eq!(vec![0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3],
+                   arc4(b"Key", b"Plaintext"));
+        assert_eq!(b"Plaintext".to_vec(), arc4(b"Key", &arc4(b"Key", b"Plaintext")));
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let msg = CounterpartyMessage::parse(&[0x00, 0x00, 0x00, 0x14, 0xaa]).unwrap();
+        assert_eq!(20, msg.message_type);
+        assert_eq!("issuance", msg.type_name());
+        assert_eq!(vec![0xaa], msg.data);
+
+        let msg = CounterpartyMessage::parse(&[0x02, 0xbb]).unwrap();
+        assert_eq!("enhanced_send", msg.type_name());
+        assert_eq!("type=2 (enhanced_send) data=bb", format!("{}", msg));
+
+        assert!(CounterpartyMessage::parse(&[]).is_none());
+        assert!(CounterpartyMessage::parse(&[0x00, 0x00]).is_none());
+    }
+
+    #[test]
+    fn test_decode_requires_prefix() {
+        let mut txid = [0u8; 32];
+        txid[31] = 0x01;
+        let input = TxInput {
+            outpoint: TxOutpoint { txid: txid, index: 0 },
+            script_len: VarUint::from(0u8),
+            script_sig: Vec::new(),
+            seq_no: 0xffffffff,
+            witness: Vec::new()
+        };
+        let tx = Tx::new(1, VarUint::from(1u8), &[input], VarUint::from(0u8), &[], 0, 0x00);
+        let key: Vec<u8> = txid.iter().rev().cloned().collect();
+
+        let payload = arc4(&key, b"CNTRPRTY\x02\xbb");
+        match CounterpartyDecoder.decode(&payload, &[], &tx) {
+            Some(ProtocolRecord::Counterparty(msg)) => assert_eq!(2, msg.message_type),
+            _ => panic!("Expected a counterparty message")
+        }
+        // Decrypted without prefix, only the prefix or encrypted with another key
+        assert!(CounterpartyDecoder.decode(&arc4(&key, b"CNTRPRTX\x02\xbb"), &[], &tx).is_none());
+        assert!(CounterpartyDecoder.decode(&arc4(&key, b"CNTRPRTY"), &[], &tx).is_none());
+        assert!(CounterpartyDecoder.decode(&arc4(b"other", b"CNTRPRTY\x02\xbb"), &[], &tx).is_none());
+        assert!(CounterpartyDecoder.decode(b"CNTRPRTY\x02\xbb", &[], &tx).is_none());
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+
+use blockchain::proto::tx::Tx;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::undo::SpentOutput;
+use blockchain::utils;
+
+pub mod omni;
+pub mod counterparty;
+pub mod openassets;
+pub mod runes;
+
+
+/// Decoded message of an embedded data protocol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolRecord {
+    Omni(omni::OmniTransaction),
+    Counterparty(counterparty::CounterpartyMessage),
+    OpenAssets(openassets::MarkerOutput),
+    Runestone(runes::Runestone)
+}
+
+impl ProtocolRecord {
+    /// Returns the protocol name
+    pub fn protocol(&self) -> &'static str {
+        match *self {
+            ProtocolRecord::Omni(_) => "omni",
+            ProtocolRecord::Counterparty(_) => "counterparty",
+            ProtocolRecord::OpenAssets(_) => "openassets",
+            ProtocolRecord::Runestone(_) => "runes"
+        }
+    }
+}
+
+impl fmt::Display for ProtocolRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolRecord::Omni(ref r) => write!(f, "{}", r),
+            ProtocolRecord::Counterparty(ref r) => write!(f, "{}", r),
+            ProtocolRecord::OpenAssets(ref r) => write!(f, "{}", r),
+            ProtocolRecord::Runestone(ref r) => write!(f, "{}", r)
+        }
+    }
+}
+
+/// Embedded data found in a transaction
+#[derive(Debug, Clone)]
+pub struct DecodedOutput {
+    /// Output index carrying the data
+    pub index: usize,
+    /// Raw payload (concatenated pushes for OP_RETURN outputs)
+    pub payload: Vec<u8>,
+    /// None if no registered decoder recognised the payload
+    pub record: Option<ProtocolRecord>
+}
+
+/// Implement this trait to add support for another protocol
+pub trait Decoder {
+    /// Payload prefix this decoder is keyed on.
+    /// An empty prefix is tried for each payload not claimed by a prefixed decoder.
+    fn prefix(&self) -> &'static [u8];
+
+    /// Decodes the payload of an OP_RETURN output
+    fn decode(&self, payload: &[u8], script_pubkey: &[u8], tx: &Tx) -> Option<ProtocolRecord>;
+
+    /// Decodes data which is not carried in OP_RETURN outputs (e.g. bare multisig encoding).
+    /// `spent` holds the outputs spent by the inputs if undo data is available.
+    fn decode_tx(&self, _tx: &Tx, _spent: Option<&[SpentOutput]>, _version_id: u8) -> Option<DecodedOutput> {
+        None
+    }
+}
+
+/// Holds all known decoders
+pub struct DecoderRegistry {
+    decoders: Vec<Box<Decoder>>
+}
+
+impl DecoderRegistry {
+    /// Creates an empty registry
+    pub fn new() -> DecoderRegistry {
+        DecoderRegistry { decoders: Vec::new() }
+    }
+
+    pub fn register(&mut self, decoder: Box<Decoder>) {
+        self.decoders.push(decoder);
+    }
+
+    /// Decodes an OP_RETURN payload.
+    /// Decoders with a matching prefix are preferred over catch-all decoders.
+    pub fn decode_payload(&self, payload: &[u8], script_pubkey: &[u8], tx: &Tx) -> Option<ProtocolRecord> {
+        let prefixed = self.decoders.iter()
+            .filter(|d| !d.prefix().is_empty() && payload.starts_with(d.prefix()));
+        let catch_all = self.decoders.iter()
+            .filter(|d| d.prefix().is_empty());
+        for decoder in prefixed.chain(catch_all) {
+            if let Some(record) = decoder.decode(payload, script_pubkey, tx) {
+                return Some(record);
+            }
+        }
+        None
+    }
+
+    /// Returns all embedded data of the transaction, decoded if possible
+    pub fn decode_tx(&self, tx: &Tx, spent: Option<&[SpentOutput]>, version_id: u8) -> Vec<DecodedOutput> {
+        let mut decoded = Vec::new();
+        for (i, output) in tx.outputs.iter().enumerate() {
+            if let ScriptPattern::DataOutput(ref payload) = output.script.pattern {
+                decoded.push(DecodedOutput {
+                    index: i,
+                    payload: payload.clone(),
+                    record: self.decode_payload(payload, &output.out.script_pubkey, tx)
+                });
+            }
+        }
+        for decoder in &self.decoders {
+            if let Some(output) = decoder.decode_tx(tx, spent, version_id) {
+                decoded.push(output);
+            }
+        }
+        decoded
+    }
+}
+
+impl Default for DecoderRegistry {
+    /// Creates a registry with all built-in decoders
+    fn default() -> DecoderRegistry {
+        let mut registry = DecoderRegistry::new();
+        registry.register(Box::new(omni::OmniDecoder));
+        registry.register(Box::new(openassets::OpenAssetsDecoder));
+        registry.register(Box::new(runes::RunesDecoder));
+        registry.register(Box::new(counterparty::CounterpartyDecoder));
+        registry
+    }
+}
+
+/// Reads an unsigned LEB128 integer. Returns None on overflow or unexpected end.
+pub fn read_leb128(data: &[u8], pos: &mut usize) -> Option<u128> {
+    let mut value: u128 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = match data.get(*pos) {
+            Some(b) => *b,
+            None => return None
+        };
+        *pos += 1;
+        let bits = (byte & 0x7f) as u128;
+        if shift > 127 || (shift == 126 && bits > 0x03) {
+            return None;
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
+
+/// Formats bytes as hex, used by the record Display implementations
+#[inline]
+fn hex(data: &[u8]) -> String {
+    utils::arr_to_hex(data)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::read_leb128;
+
+    #[test]
+    fn test_read_leb128() {
+        let mut pos = 0;
+        assert_eq!(Some(0), read_leb128(&[0x00], &mut pos));
+        pos = 0;
+        assert_eq!(Some(624485), read_leb128(&[0xe5, 0x8e, 0x26], &mut pos));
+        assert_eq!(3, pos);
+        pos = 0;
+        assert_eq!(None, read_leb128(&[0x80], &mut pos));
+
+        // u128::MAX
+        let mut max = vec![0xff; 18];
+        max.push(0x03);
+        pos = 0;
+        assert_eq!(Some(u128::max_value()), read_leb128(&max, &mut pos));
+        max[18] = 0x07;
+        pos = 0;
+        assert_eq!(None, read_leb128(&max, &mut pos));
+    }
+}
@@ -0,0 +1,269 @@
+use std::fmt;
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use blockchain::proto::tx::Tx;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::undo::SpentOutput;
+use blockchain::proto::opreturn::{Decoder, DecodedOutput, ProtocolRecord};
+use blockchain::utils;
+
+/// Class C transactions embed the payload in an OP_RETURN output prefixed with "omni"
+const MARKER: &'static [u8] = b"omni";
+/// Class A/B transactions have to pay the Exodus address
+const EXODUS_ADDRESSES: [&'static str; 2] = ["1EXoDusjGwvnjZUyKkxZ4UHEf77z6A5S4P",    // Bitcoin
+                                              "mpexoDuSkGGqvqrkrjiFng38QPkJQVFyqv"];   // TestNet3
+/// Each Class B data key holds a sequence number and 30 bytes of payload
+const PACKET_SIZE: usize = 31;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OmniClass {
+    /// Obfuscated payload in bare multisig keys
+    B,
+    /// Payload in OP_RETURN
+    C
+}
+
+/// Omni Layer transaction header and simple send details
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OmniTransaction {
+    pub class: OmniClass,
+    pub version: u16,
+    pub tx_type: u16,
+    pub property_id: Option<u32>,
+    pub amount: Option<u64>,
+    /// Complete payload including version and type
+    pub payload: Vec<u8>
+}
+
+impl OmniTransaction {
+    /// Parses the payload: <version u16> <type u16> [fields...] (big endian)
+    pub fn parse(class: OmniClass, payload: &[u8]) -> Option<OmniTransaction> {
+        let mut cursor = Cursor::new(payload);
+        let version = match cursor.read_u16::<BigEndian>() {
+            Ok(v) => v,
+            Err(_) => return None
+        };
+        let tx_type = match cursor.read_u16::<BigEndian>() {
+            Ok(t) => t,
+            Err(_) => return None
+        };
+        let mut tx = OmniTransaction {
+            class: class,
+            version: version,
+            tx_type: tx_type,
+            property_id: None,
+            amount: None,
+            payload: Vec::from(payload)
+        };
+        // Simple Send and Send To Owners: <property id u32> <amount u64>
+        if tx_type == 0 || tx_type == 3 {
+            if let (Ok(property_id), Ok(amount)) = (cursor.read_u32::<BigEndian>(), cursor.read_u64::<BigEndian>()) {
+                tx.property_id = Some(property_id);
+                tx.amount = Some(amount);
+            }
+        }
+        Some(tx)
+    }
+
+    /// Returns the name of well known transaction types
+    pub fn type_name(&self) -> &'static str {
+        match self.tx_type {
+            0 => "Simple Send",
+            3 => "Send To Owners",
+            4 => "Send All",
+            20 => "DEx Sell Offer",
+            22 => "DEx Accept Offer",
+            25 => "MetaDEx Trade",
+            50 => "Create Property Fixed",
+            51 => "Create Property Variable",
+            53 => "Close Crowdsale",
+            54 => "Create Property Managed",
+            55 => "Grant Property Tokens",
+            56 => "Revoke Property Tokens",
+            70 => "Change Issuer Address",
+            185 => "Freeze Property Tokens",
+            186 => "Unfreeze Property Tokens",
+            _ => "Unknown"
+        }
+    }
+}
+
+impl fmt::Display for OmniTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "class={:?} version={} type={} ({})", self.class, self.version, self.tx_type, self.type_name()));
+        if let (Some(property_id), Some(amount)) = (self.property_id, self.amount) {
+            try!(write!(f, " property={} amount={}", property_id, amount));
+        }
+        Ok(())
+    }
+}
+
+/// Decodes Class B and Class C Omni transactions
+pub struct OmniDecoder;
+
+impl Decoder for OmniDecoder {
+    fn prefix(&self) -> &'static [u8] {
+        MARKER
+    }
+
+    fn decode(&self, payload: &[u8], _: &[u8], _: &Tx) -> Option<ProtocolRecord> {
+        OmniTransaction::parse(OmniClass::C, &payload[MARKER.len()..]).map(ProtocolRecord::Omni)
+    }
+
+    fn decode_tx(&self, tx: &Tx, spent: Option<&[SpentOutput]>, _version_id: u8) -> Option<DecodedOutput> {
+        let pays_exodus = tx.outputs.iter()
+            .any(|o| o.script.addresses().iter().any(|a| EXODUS_ADDRESSES.contains(a)));
+        if !pays_exodus {
+            return None;
+        }
+        // The obfuscation key is derived from the sender address, which needs the spent outputs
+        let sender = match spent.and_then(sender) {
+            Some(sender) => sender,
+            None => {
+                debug!(target: "omni", "Skipping Class B transaction, the sender is unknown without undo data.");
+                return None;
+            }
+        };
+
+        // The first key of each multisig output belongs to the sender, the others carry data
+        let mut index = None;
+        let mut packets = Vec::new();
+        for (i, output) in tx.outputs.iter().enumerate() {
+            if let ScriptPattern::Pay2MultiSig { ref pubkeys, .. } = output.script.pattern {
+                index = index.or(Some(i));
+                for key in pubkeys.iter().skip(1).filter(|k| k.len() == 33) {
+                    packets.push(&key[1..1 + PACKET_SIZE]);
+                }
+            }
+        }
+        let index = match index {
+            Some(i) => i,
+            None => return None
+        };
+        let payload = deobfuscate(&sender, &packets);
+        OmniTransaction::parse(OmniClass::B, &payload).map(|record| DecodedOutput {
+            index: index,
+            payload: payload,
+            record: Some(ProtocolRecord::Omni(record))
+        })
+    }
+}
+
+/// Returns the address which contributed the most value to the inputs, the first one on a tie
+fn sender(spent: &[SpentOutput]) -> Option<String> {
+    let mut values: Vec<(&str, u64)> = Vec::new();
+    for spent in spent {
+        let address = spent.out.script.address();
+        if address.is_empty() {
+            continue;
+        }
+        match values.iter().position(|v| v.0 == address) {
+            Some(idx) => values[idx].1 += spent.out.out.value,
+            None => values.push((address, spent.out.out.value))
+        }
+    }
+    let max = match values.iter().map(|v| v.1).max() {
+        Some(max) => max,
+        None => return None
+    };
+    values.into_iter().find(|v| v.1 == max).map(|v| String::from(v.0))
+}
+
+/// Reverses the Class B obfuscation:
+/// Packet n is XORed with the n-th iteration of sha256 over the uppercase hex string,
+/// starting with the sender address. The first byte of each packet is its sequence number,
+/// packets are ordered by it since the outputs don't have to be in sequence.
+pub fn deobfuscate(sender: &str, packets: &[&[u8]]) -> Vec<u8> {
+    let mut hashes = Vec::with_capacity(packets.len());
+    let mut seed = String::from(sender);
+    for _ in 0..packets.len() {
+        let hash = utils::sha256(seed.as_bytes());
+        seed = utils::arr_to_hex(&hash).to_uppercase();
+        hashes.push(hash);
+    }
+    // Sequence number n is only revealed by the n-th hash
+    let mut ordered: Vec<Option<Vec<u8>>> = vec![None; packets.len()];
+    for packet in packets {
+        for (i, hash) in hashes.iter().enumerate() {
+            if ordered[i].is_none() && packet[0] ^ hash[0] == (i + 1) as u8 {
+                ordered[i] = Some(packet.iter().zip(hash.iter()).map(|(p, h)| p ^ h).collect());
+                break;
+            }
+        }
+    }
+    let mut payload = Vec::with_capacity(packets.len() * (PACKET_SIZE - 1));
+    for clear in ordered.iter().take_while(|p| p.is_some()) {
+        payload.extend_from_slice(&clear.as_ref().unwrap()[1..]);
+    }
+    payload
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::utils;
+    use blockchain::proto::tx::{EvaluatedTxOut, TxOutput};
+    use blockchain::proto::varuint::VarUint;
+
+    #[test]
+    fn test_parse_simple_send() {
+        // Simple send of 0.5 TetherUS (property 31)
+        let payload = utils::hex_to_vec("000000000000001f0000000002faf080");
+        let tx = OmniTransaction::parse(OmniClass::C, &payload).unwrap();
+        assert_eq!(0, tx.version);
+        assert_eq!("Simple Send", tx.type_name());
+        assert_eq!(Some(31), tx.property_id);
+        assert_eq!(Some(50000000), tx.amount);
+        assert_eq!("class=C version=0 type=0 (Simple Send) property=31 amount=50000000", format!("{}", tx));
+
+        assert!(OmniTransaction::parse(OmniClass::C, &[0x00, 0x00, 0x00]).is_none());
+    }
+
+    #[test]
+    fn test_deobfuscate() {
+        let sender = "1MCHESTptvd2LnNp7wmr2sGTpRomteAkq8";
+        let clear = utils::hex_to_vec("01000000000000000200000000000000010000000000000000000000000000");
+        // Obfuscate the packet with the same key stream
+        let hash = utils::sha256(sender.as_bytes());
+        let packet: Vec<u8> = clear.iter().zip(hash.iter()).map(|(p, h)| p ^ h).collect();
+        let payload = deobfuscate(sender, &[&packet]);
+        assert_eq!(&clear[1..], &payload[..]);
+        let tx = OmniTransaction::parse(OmniClass::B, &payload).unwrap();
+        assert_eq!(Some(2), tx.property_id);
+        assert_eq!(Some(1), tx.amount);
+
+        // Wrong sequence number
+        assert!(deobfuscate("1EXoDusjGwvnjZUyKkxZ4UHEf77z6A5S4P", &[&packet]).is_empty());
+        // Packets are ordered by their sequence number
+        let second_clear = utils::hex_to_vec("02aabbccddeeff00112233445566778899aabbccddeeff00112233445566");
+        let second_hash = utils::sha256(utils::arr_to_hex(&hash).to_uppercase().as_bytes());
+        let second: Vec<u8> = second_clear.iter().zip(second_hash.iter()).map(|(p, h)| p ^ h).collect();
+        let payload = deobfuscate(sender, &[&second, &packet]);
+        assert_eq!(&clear[1..], &payload[..PACKET_SIZE - 1]);
+        assert_eq!(&second_clear[1..], &payload[PACKET_SIZE - 1..]);
+        // The payload ends at the first missing packet
+        assert!(deobfuscate(sender, &[&second]).is_empty());
+    }
+
+    fn spent(value: u64, h160: u8) -> SpentOutput {
+        let mut script = vec![0x76, 0xa9, 0x14];
+        script.extend_from_slice(&[h160; 20]);
+        script.extend_from_slice(&[0x88, 0xac]);
+        let out = TxOutput { value: value, script_len: VarUint::from(script.len() as u8), script_pubkey: script };
+        SpentOutput { height: 1, is_coinbase: false, out: EvaluatedTxOut::eval_script(out, 0x00) }
+    }
+
+    #[test]
+    fn test_sender() {
+        // The second address contributes 8 in total, the first one 6
+        let inputs = [spent(5, 0x11), spent(5, 0x22), spent(3, 0x22), spent(1, 0x11)];
+        assert_eq!(Some(inputs[1].out.script.address().to_string()), sender(&inputs));
+        // Tie
+        assert_eq!(Some(inputs[0].out.script.address().to_string()), sender(&inputs[..2]));
+        assert_eq!(Some(inputs[1].out.script.address().to_string()), sender(&inputs[1..]));
+        assert_eq!(None, sender(&[]));
+    }
+}
@@ -0,0 +1,94 @@
+use std::fmt;
+use std::io::Cursor;
+
+use blockchain::proto::tx::Tx;
+use blockchain::proto::varuint::VarUint;
+use blockchain::proto::opreturn::{self, Decoder, ProtocolRecord};
+
+/// Marker output: OP_RETURN <tag "OA"> <version 0x0100> <quantities> <metadata>
+const MARKER: &'static [u8] = &[0x4f, 0x41, 0x01, 0x00];
+
+/// Open Assets marker output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarkerOutput {
+    /// Asset quantity for each output (issuance outputs first, then transfer outputs)
+    pub asset_quantities: Vec<u64>,
+    pub metadata: Vec<u8>
+}
+
+impl MarkerOutput {
+    /// Parses the payload following the marker:
+    /// <quantity count varint> <LEB128 quantities...> <metadata length varint> <metadata>
+    pub fn parse(payload: &[u8]) -> Option<MarkerOutput> {
+        let mut cursor = Cursor::new(payload);
+        let count = match VarUint::read_from(&mut cursor) {
+            Ok(c) => c.value,
+            Err(_) => return None
+        };
+        let mut pos = cursor.position() as usize;
+        let mut asset_quantities = Vec::new();
+        for _ in 0..count {
+            match opreturn::read_leb128(payload, &mut pos) {
+                Some(q) if q <= u64::max_value() as u128 => asset_quantities.push(q as u64),
+                _ => return None
+            }
+        }
+        cursor.set_position(pos as u64);
+        let metadata_len = match VarUint::read_from(&mut cursor) {
+            Ok(l) => l.value as usize,
+            Err(_) => return None
+        };
+        let pos = cursor.position() as usize;
+        if payload.len() - pos < metadata_len {
+            return None;
+        }
+        Some(MarkerOutput {
+            asset_quantities: asset_quantities,
+            metadata: Vec::from(&payload[pos..pos + metadata_len])
+        })
+    }
+}
+
+impl fmt::Display for MarkerOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "quantities={} metadata={}",
+               self.asset_quantities.iter().map(|q| q.to_string()).collect::<Vec<String>>().join(","),
+               opreturn::hex(&self.metadata))
+    }
+}
+
+pub struct OpenAssetsDecoder;
+
+impl Decoder for OpenAssetsDecoder {
+    fn prefix(&self) -> &'static [u8] {
+        MARKER
+    }
+
+    fn decode(&self, payload: &[u8], _: &[u8], _: &Tx) -> Option<ProtocolRecord> {
+        MarkerOutput::parse(&payload[MARKER.len()..]).map(ProtocolRecord::OpenAssets)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::utils;
+
+    #[test]
+    fn test_parse_marker() {
+        // Example from the Open Assets protocol specification
+        let payload = utils::hex_to_vec("0300ac02e58e261b753d68747470733a2f2f6370722e736d2f35596753553150672d71");
+        let marker = MarkerOutput::parse(&payload).unwrap();
+        assert_eq!(vec![0, 300, 624485], marker.asset_quantities);
+        assert_eq!(b"u=https://cpr.sm/5YgSU1Pg-q".to_vec(), marker.metadata);
+
+        let marker = MarkerOutput::parse(&utils::hex_to_vec("0201ac0200")).unwrap();
+        assert_eq!("quantities=1,300 metadata=", format!("{}", marker));
+
+        // Metadata shorter than its length prefix
+        assert!(MarkerOutput::parse(&utils::hex_to_vec("0300ac02e58e26ff")).is_none());
+        // Missing quantities
+        assert!(MarkerOutput::parse(&utils::hex_to_vec("0300")).is_none());
+    }
+}
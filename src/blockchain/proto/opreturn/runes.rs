@@ -0,0 +1,503 @@
+use std::fmt;
+use std::char;
+use std::convert::TryFrom;
+
+use blockchain::proto::tx::Tx;
+use blockchain::proto::opcodes;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::undo::SpentOutput;
+use blockchain::proto::opreturn::{self, Decoder, DecodedOutput, ProtocolRecord};
+
+/// Runestones are identified by OP_RETURN OP_13, which is stored as 0x0d in the payload
+const MAGIC_NUMBER: u8 = opcodes::All::OP_PUSHNUM_13 as u8;
+
+// Field tags. Even tags are mandatory: unknown even tags turn the runestone into a cenotaph.
+const TAG_BODY: u128 = 0;
+const TAG_DIVISIBILITY: u128 = 1;
+const TAG_FLAGS: u128 = 2;
+const TAG_SPACERS: u128 = 3;
+const TAG_RUNE: u128 = 4;
+const TAG_SYMBOL: u128 = 5;
+const TAG_PREMINE: u128 = 6;
+const TAG_CAP: u128 = 8;
+const TAG_AMOUNT: u128 = 10;
+const TAG_HEIGHT_START: u128 = 12;
+const TAG_HEIGHT_END: u128 = 14;
+const TAG_OFFSET_START: u128 = 16;
+const TAG_OFFSET_END: u128 = 18;
+const TAG_MINT: u128 = 20;
+const TAG_POINTER: u128 = 22;
+
+const FLAG_ETCHING: u128 = 1 << 0;
+const FLAG_TERMS: u128 = 1 << 1;
+const FLAG_TURBO: u128 = 1 << 2;
+
+/// Block height and transaction index of the etching transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RuneId {
+    pub block: u64,
+    pub tx: u32
+}
+
+impl RuneId {
+    /// Returns None for ids which can't exist (transaction index without block)
+    fn new(block: u64, tx: u32) -> Option<RuneId> {
+        if block == 0 && tx > 0 {
+            return None;
+        }
+        Some(RuneId { block: block, tx: tx })
+    }
+
+    /// Applies the deltas of an edict, None on overflow
+    fn next(&self, block_delta: u128, tx_delta: u128) -> Option<RuneId> {
+        let block_delta = match u64::try_from(block_delta) { Ok(d) => d, Err(_) => return None };
+        let tx_delta = match u32::try_from(tx_delta) { Ok(d) => d, Err(_) => return None };
+        let block = match self.block.checked_add(block_delta) { Some(b) => b, None => return None };
+        let tx = match block_delta {
+            0 => match self.tx.checked_add(tx_delta) { Some(t) => t, None => return None },
+            _ => tx_delta
+        };
+        RuneId::new(block, tx)
+    }
+}
+
+impl fmt::Display for RuneId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.block, self.tx)
+    }
+}
+
+/// Transfer of runes to an output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edict {
+    pub id: RuneId,
+    pub amount: u128,
+    pub output: u32
+}
+
+/// Open mint terms of an etching
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Terms {
+    pub cap: Option<u128>,
+    pub amount: Option<u128>,
+    pub height: (Option<u64>, Option<u64>),
+    pub offset: (Option<u64>, Option<u64>)
+}
+
+/// Creation of a new rune
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Etching {
+    pub rune: Option<u128>,
+    pub divisibility: Option<u8>,
+    pub spacers: Option<u32>,
+    pub symbol: Option<char>,
+    pub premine: Option<u128>,
+    pub terms: Option<Terms>,
+    pub turbo: bool
+}
+
+impl Etching {
+    /// Returns the premine plus all open mints, None on overflow
+    pub fn supply(&self) -> Option<u128> {
+        let (cap, amount) = match self.terms {
+            Some(ref terms) => (terms.cap.unwrap_or(0), terms.amount.unwrap_or(0)),
+            None => (0, 0)
+        };
+        cap.checked_mul(amount).and_then(|mints| self.premine.unwrap_or(0).checked_add(mints))
+    }
+
+    /// Returns the rune name with spacers (•), if present
+    pub fn spaced_name(&self) -> Option<String> {
+        self.rune.map(|rune| {
+            let name = rune_name(rune);
+            let spacers = self.spacers.unwrap_or(0);
+            let mut spaced = String::with_capacity(name.len() * 2);
+            for (i, c) in name.chars().enumerate() {
+                spaced.push(c);
+                if i + 1 < name.len() && spacers & (1 << i) != 0 {
+                    spaced.push('•');
+                }
+            }
+            spaced
+        })
+    }
+}
+
+/// Decoded runestone. Malformed runestones are marked as cenotaph,
+/// which burns all runes of the transaction inputs.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Runestone {
+    pub edicts: Vec<Edict>,
+    pub etching: Option<Etching>,
+    pub mint: Option<RuneId>,
+    pub pointer: Option<u32>,
+    pub cenotaph: bool
+}
+
+impl Runestone {
+    /// Parses the payload following the magic number
+    pub fn parse(payload: &[u8], n_outputs: usize) -> Runestone {
+        let mut runestone = Runestone::default();
+
+        // Decode all integers
+        let mut integers = Vec::new();
+        let mut pos = 0;
+        while pos < payload.len() {
+            match opreturn::read_leb128(payload, &mut pos) {
+                Some(i) => integers.push(i),
+                None => {
+                    runestone.cenotaph = true;
+                    return runestone;
+                }
+            }
+        }
+
+        // Fields are tag/value pairs until the body tag. Values of the same tag are collected in order.
+        let mut fields: Vec<(u128, Vec<u128>)> = Vec::new();
+        let mut i = 0;
+        while i < integers.len() {
+            let tag = integers[i];
+            if tag == TAG_BODY {
+                runestone.edicts = match Runestone::parse_edicts(&integers[i + 1..], n_outputs) {
+                    Some(edicts) => edicts,
+                    None => {
+                        runestone.cenotaph = true;
+                        Vec::new()
+                    }
+                };
+                break;
+            }
+            match integers.get(i + 1) {
+                Some(&value) => match fields.iter().position(|f| f.0 == tag) {
+                    Some(idx) => fields[idx].1.push(value),
+                    None => fields.push((tag, vec![value]))
+                },
+                // Truncated field
+                None => runestone.cenotaph = true
+            }
+            i += 2;
+        }
+
+        let flags = take(&mut fields, TAG_FLAGS, 1, |v| Some(v[0])).unwrap_or(0);
+        if flags & FLAG_ETCHING != 0 {
+            let mut etching = Etching::default();
+            etching.rune = take(&mut fields, TAG_RUNE, 1, |v| Some(v[0]));
+            etching.divisibility = take(&mut fields, TAG_DIVISIBILITY, 1, |v| {
+                u8::try_from(v[0]).ok().and_then(|d| if d <= 38 { Some(d) } else { None })
+            });
+            etching.spacers = take(&mut fields, TAG_SPACERS, 1, |v| {
+                u32::try_from(v[0]).ok().and_then(|s| if s <= 0x07ff_ffff { Some(s) } else { None })
+            });
+            etching.symbol = take(&mut fields, TAG_SYMBOL, 1, |v| u32::try_from(v[0]).ok().and_then(char::from_u32));
+            etching.premine = take(&mut fields, TAG_PREMINE, 1, |v| Some(v[0]));
+            etching.turbo = flags & FLAG_TURBO != 0;
+            if flags & FLAG_TERMS != 0 {
+                let (height, offset) = {
+                    let mut take_u64 = |tag: u128| take(&mut fields, tag, 1, |v| u64::try_from(v[0]).ok());
+                    ((take_u64(TAG_HEIGHT_START), take_u64(TAG_HEIGHT_END)),
+                     (take_u64(TAG_OFFSET_START), take_u64(TAG_OFFSET_END)))
+                };
+                etching.terms = Some(Terms {
+                    cap: take(&mut fields, TAG_CAP, 1, |v| Some(v[0])),
+                    amount: take(&mut fields, TAG_AMOUNT, 1, |v| Some(v[0])),
+                    height: height,
+                    offset: offset
+                });
+            }
+            // The maximum supply has to fit into an u128
+            if etching.supply().is_none() {
+                runestone.cenotaph = true;
+            }
+            runestone.etching = Some(etching);
+        }
+        runestone.mint = take(&mut fields, TAG_MINT, 2, |v| {
+            match (u64::try_from(v[0]), u32::try_from(v[1])) {
+                (Ok(block), Ok(tx)) => RuneId::new(block, tx),
+                _ => None
+            }
+        });
+        runestone.pointer = take(&mut fields, TAG_POINTER, 1, |v| {
+            u32::try_from(v[0]).ok().and_then(|p| if (p as u64) < n_outputs as u64 { Some(p) } else { None })
+        });
+        if flags & !(FLAG_ETCHING | FLAG_TERMS | FLAG_TURBO) != 0 {
+            runestone.cenotaph = true;
+        }
+        // Unrecognised even tags and rejected values of even tags
+        if fields.iter().any(|f| f.0 % 2 == 0) {
+            runestone.cenotaph = true;
+        }
+        runestone
+    }
+
+    /// Parses delta encoded edicts: <block delta> <tx delta> <amount> <output>
+    fn parse_edicts(integers: &[u128], n_outputs: usize) -> Option<Vec<Edict>> {
+        if integers.len() % 4 != 0 {
+            return None;
+        }
+        let mut edicts = Vec::with_capacity(integers.len() / 4);
+        let mut id = RuneId::default();
+        for chunk in integers.chunks(4) {
+            id = match id.next(chunk[0], chunk[1]) {
+                Some(next) => next,
+                None => return None
+            };
+            // Output equal to the output count means split across all non OP_RETURN outputs
+            let output = match u32::try_from(chunk[3]) {
+                Ok(output) if output as u64 <= n_outputs as u64 => output,
+                _ => return None
+            };
+            edicts.push(Edict { id: id, amount: chunk[2], output: output });
+        }
+        Some(edicts)
+    }
+}
+
+impl fmt::Display for Runestone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.cenotaph {
+            try!(write!(f, "cenotaph "));
+        }
+        if let Some(ref etching) = self.etching {
+            try!(write!(f, "etching={} ", etching.spaced_name().unwrap_or_default()));
+        }
+        if let Some(mint) = self.mint {
+            try!(write!(f, "mint={} ", mint));
+        }
+        write!(f, "edicts={}", self.edicts.iter()
+            .map(|e| format!("{}:{}:{}", e.id, e.amount, e.output))
+            .collect::<Vec<String>>().join(","))
+    }
+}
+
+/// Removes the first `n` values of a field if `f` accepts them. Rejected values are kept,
+/// so an even tag turns the runestone into a cenotaph.
+fn take<T, F>(fields: &mut Vec<(u128, Vec<u128>)>, tag: u128, n: usize, f: F) -> Option<T>
+    where F: FnOnce(&[u128]) -> Option<T>
+{
+    let idx = match fields.iter().position(|field| field.0 == tag) {
+        Some(idx) => idx,
+        None => return None
+    };
+    if fields[idx].1.len() < n {
+        return None;
+    }
+    let value = f(&fields[idx].1[..n]);
+    if value.is_some() {
+        fields[idx].1.drain(..n);
+        if fields[idx].1.is_empty() {
+            fields.remove(idx);
+        }
+    }
+    value
+}
+
+/// Extracts the payload following OP_RETURN OP_13. All other instructions have to be data pushes,
+/// returns None for any other opcode (including OP_1 .. OP_16) or a truncated push.
+fn runestone_payload(script_pubkey: &[u8]) -> Option<Vec<u8>> {
+    let mut payload = Vec::new();
+    let mut pos = 2;
+    while pos < script_pubkey.len() {
+        let opcode = script_pubkey[pos];
+        pos += 1;
+        let (len_size, len) = match opcode {
+            0x00...0x4b => (0, opcode as usize),
+            0x4c => (1, script_pubkey.get(pos).map_or(0, |b| *b as usize)),
+            0x4d if pos + 2 <= script_pubkey.len() =>
+                (2, script_pubkey[pos] as usize | (script_pubkey[pos + 1] as usize) << 8),
+            0x4e if pos + 4 <= script_pubkey.len() =>
+                (4, script_pubkey[pos..pos + 4].iter().rev().fold(0, |len, b| len << 8 | *b as usize)),
+            _ => return None
+        };
+        pos += len_size;
+        if pos > script_pubkey.len() || script_pubkey.len() - pos < len {
+            return None;
+        }
+        payload.extend_from_slice(&script_pubkey[pos..pos + len]);
+        pos += len;
+    }
+    Some(payload)
+}
+
+#[inline]
+fn is_runestone_script(script_pubkey: &[u8]) -> bool {
+    script_pubkey.len() >= 2 && script_pubkey[0] == opcodes::All::OP_RETURN as u8 && script_pubkey[1] == MAGIC_NUMBER
+}
+
+/// Converts a rune number to its name (modified base-26: 0 = A, 25 = Z, 26 = AA)
+pub fn rune_name(rune: u128) -> String {
+    if rune == u128::max_value() {
+        return String::from("BCGDENLQRQWDSLRUGSNLBTMFIJAV");
+    }
+    let mut n = rune + 1;
+    let mut name = Vec::new();
+    while n > 0 {
+        name.push(b'A' + ((n - 1) % 26) as u8);
+        n = (n - 1) / 26;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap()
+}
+
+pub struct RunesDecoder;
+
+impl Decoder for RunesDecoder {
+    fn prefix(&self) -> &'static [u8] {
+        &[MAGIC_NUMBER]
+    }
+
+    fn decode(&self, _payload: &[u8], script_pubkey: &[u8], tx: &Tx) -> Option<ProtocolRecord> {
+        // The magic number has to be an opcode and not part of a data push
+        if !is_runestone_script(script_pubkey) {
+            return None;
+        }
+        let runestone = match runestone_payload(script_pubkey) {
+            Some(payload) => Runestone::parse(&payload, tx.outputs.len()),
+            None => Runestone { cenotaph: true, ..Runestone::default() }
+        };
+        Some(ProtocolRecord::Runestone(runestone))
+    }
+
+    /// Runestones with non-push opcodes aren't recognised as data outputs, they are cenotaphs
+    fn decode_tx(&self, tx: &Tx, _spent: Option<&[SpentOutput]>, _version_id: u8) -> Option<DecodedOutput> {
+        tx.outputs.iter().enumerate()
+            .find(|&(_, o)| is_runestone_script(&o.out.script_pubkey) && match o.script.pattern {
+                ScriptPattern::DataOutput(_) => false,
+                _ => true
+            })
+            .map(|(i, o)| DecodedOutput {
+                index: i,
+                payload: o.out.script_pubkey[2..].to_vec(),
+                record: Some(ProtocolRecord::Runestone(Runestone { cenotaph: true, ..Runestone::default() }))
+            })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::proto::tx::TxOutput;
+    use blockchain::proto::varuint::VarUint;
+
+    #[test]
+    fn test_rune_name() {
+        assert_eq!("A", rune_name(0));
+        assert_eq!("Z", rune_name(25));
+        assert_eq!("AA", rune_name(26));
+        assert_eq!("UNCOMMONGOODS", rune_name(2055900680524219742));
+    }
+
+    #[test]
+    fn test_parse_etching() {
+        // Flags: etching + terms, rune: UNCOMMONGOODS, spacers: 128 (UNCOMMON•GOODS),
+        // symbol: ⧉, amount: 1
+        let mut payload = vec![0x02, 0x03, 0x04];
+        payload.extend_from_slice(&[0xde, 0x8a, 0x85, 0xe1, 0xeb, 0xd8, 0x81, 0xc4, 0x1c]);
+        payload.extend_from_slice(&[0x03, 0x80, 0x01, 0x05, 0xc9, 0x53, 0x0a, 0x01]);
+        let runestone = Runestone::parse(&payload, 2);
+        assert!(!runestone.cenotaph);
+        let etching = runestone.etching.unwrap();
+        assert_eq!(Some(String::from("UNCOMMON•GOODS")), etching.spaced_name());
+        assert_eq!(Some('⧉'), etching.symbol);
+        assert_eq!(Some(1), etching.terms.unwrap().amount);
+    }
+
+    #[test]
+    fn test_parse_edicts() {
+        // Mint 840000:3 and two edicts: 840000:3 -> output 1, 840001:0 -> output 0
+        let payload = vec![0x14, 0xc0, 0xa2, 0x33, 0x14, 0x03,
+                           0x00,
+                           0xc0, 0xa2, 0x33, 0x03, 0x0a, 0x01,
+                           0x01, 0x00, 0x05, 0x00];
+        let runestone = Runestone::parse(&payload, 2);
+        assert!(!runestone.cenotaph);
+        assert_eq!(Some(RuneId { block: 840000, tx: 3 }), runestone.mint);
+        assert_eq!(vec![Edict { id: RuneId { block: 840000, tx: 3 }, amount: 10, output: 1 },
+                        Edict { id: RuneId { block: 840001, tx: 0 }, amount: 5, output: 0 }],
+                   runestone.edicts);
+        assert_eq!("mint=840000:3 edicts=840000:3:10:1,840001:0:5:0", format!("{}", runestone));
+
+        // Output out of range
+        assert!(Runestone::parse(&[0x00, 0x00, 0x00, 0x01, 0x03], 2).cenotaph);
+        // Unknown even tag
+        assert!(Runestone::parse(&[0x7e, 0x00], 2).cenotaph);
+        // Truncated varint
+        assert!(Runestone::parse(&[0x80], 2).cenotaph);
+    }
+
+    #[test]
+    fn test_edict_overflow() {
+        // Transaction index overflows u32 in the second edict
+        let runestone = Runestone::parse(&[0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x01, 0x00,
+                                           0x00, 0x01, 0x01, 0x00], 2);
+        assert!(runestone.cenotaph);
+        assert!(runestone.edicts.is_empty());
+        // Block delta exceeds u64, output exceeds u32
+        assert!(Runestone::parse(&[0x00, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x02, 0x00, 0x01, 0x00], 2).cenotaph);
+        assert!(Runestone::parse(&[0x00, 0x01, 0x00, 0x01, 0x80, 0x80, 0x80, 0x80, 0x10], 2).cenotaph);
+        // Rune id without block
+        assert!(Runestone::parse(&[0x00, 0x00, 0x01, 0x01, 0x00], 2).cenotaph);
+    }
+
+    #[test]
+    fn test_invalid_pointer_and_mint() {
+        assert_eq!(Some(1), Runestone::parse(&[0x16, 0x01], 2).pointer);
+        let runestone = Runestone::parse(&[0x16, 0x02], 2);
+        assert!(runestone.cenotaph);
+        assert_eq!(None, runestone.pointer);
+        // 2^32 must not be truncated to output 0
+        assert!(Runestone::parse(&[0x16, 0x80, 0x80, 0x80, 0x80, 0x10], 2).cenotaph);
+
+        // Rune id without block, missing tx index and tx index exceeding u32
+        assert!(Runestone::parse(&[0x14, 0x00, 0x14, 0x05], 2).cenotaph);
+        assert!(Runestone::parse(&[0x14, 0x01], 2).cenotaph);
+        assert!(Runestone::parse(&[0x14, 0x01, 0x14, 0x80, 0x80, 0x80, 0x80, 0x10], 2).cenotaph);
+    }
+
+    #[test]
+    fn test_supply_overflow() {
+        // Etching with terms, premine 1, cap and amount 2^127
+        let mut payload = vec![0x02, 0x03, 0x06, 0x01, 0x08];
+        let mut big = vec![0x80; 18];
+        big.push(0x02);
+        payload.extend_from_slice(&big);
+        payload.push(0x0a);
+        payload.extend_from_slice(&big);
+        assert!(Runestone::parse(&payload, 2).cenotaph);
+    }
+
+    fn tx(scripts: &[&[u8]]) -> Tx {
+        let outputs: Vec<TxOutput> = scripts.iter().map(|s| TxOutput {
+            value: 0, script_len: VarUint::from(s.len() as u8), script_pubkey: s.to_vec()
+        }).collect();
+        Tx::new(1, VarUint::from(0u8), &[], VarUint::from(outputs.len() as u8), &outputs, 0, 0x00)
+    }
+
+    #[test]
+    fn test_non_push_opcode() {
+        let decoder = RunesDecoder;
+        let runestone = |tx: &Tx, i: usize| match decoder.decode(&[], &tx.outputs[i].out.script_pubkey, tx) {
+            Some(ProtocolRecord::Runestone(r)) => r,
+            _ => panic!("Expected a runestone")
+        };
+        // OP_RETURN OP_13 <16 01>
+        let valid = tx(&[&[0x6a, 0x5d, 0x02, 0x16, 0x01], &[0x51]]);
+        assert_eq!(Some(1), runestone(&valid, 0).pointer);
+        // OP_1 is a data output, but not a valid runestone push
+        assert!(runestone(&tx(&[&[0x6a, 0x5d, 0x51]]), 0).cenotaph);
+        // Truncated push
+        assert!(runestone(&tx(&[&[0x6a, 0x5d, 0x4c]]), 0).cenotaph);
+        // Data push is no runestone
+        assert!(decoder.decode(&[], &[0x6a, 0x01, 0x5d], &valid).is_none());
+
+        // OP_CHECKSIG is no data output
+        let tx = tx(&[&[0x51], &[0x6a, 0x5d, 0x01, 0x00, 0xac]]);
+        let decoded = decoder.decode_tx(&tx, None, 0x00).unwrap();
+        assert_eq!(1, decoded.index);
+        match decoded.record {
+            Some(ProtocolRecord::Runestone(ref r)) => assert!(r.cenotaph),
+            _ => panic!("Expected a runestone")
+        }
+        assert!(decoder.decode_tx(&valid, None, 0x00).is_none());
+    }
+}
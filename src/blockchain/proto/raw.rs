@@ -0,0 +1,510 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use byteorder::{ByteOrder, LittleEndian};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use errors::{OpError, OpErrorKind, OpResult};
+use blockchain::proto::block::Block;
+use blockchain::proto::header::BlockHeader;
+use blockchain::proto::script;
+use blockchain::proto::tx::TxOutpoint;
+use blockchain::utils::{merkle_root, sha256, arr_to_hex_swapped};
+use blockchain::utils::blkfile::{BlkFile, BlkMmap};
+use blockchain::utils::reader::BlockchainRead;
+
+
+/// Unparsed block which borrows its data from a memory mapped blk file.
+/// Nothing is decoded until a field is accessed, see RawTx.
+pub struct RawBlock {
+    pub blk_index: u32,
+    pub blk_offset: usize,
+    pub blocksize: u32,
+    pub hash: [u8; 32],
+    version_id: u8,
+    data: BlkMmap,
+    data_offset: usize  // Position of the block in data
+}
+
+impl RawBlock {
+    pub fn new(data: BlkMmap, blk_index: u32, blk_offset: usize, blocksize: u32, version_id: u8) -> OpResult<RawBlock> {
+        RawBlock::with_data(data, blk_offset, blk_index, blk_offset, blocksize, version_id)
+    }
+
+    /// Reads a single block with plain file reads instead of mapping the blk file.
+    /// Used for the blk file which is written by a running node, it preallocates and truncates the file.
+    pub fn read(blk_file: &BlkFile, blk_offset: usize, blocksize: u32, version_id: u8) -> OpResult<RawBlock> {
+        if blk_offset as u64 + blocksize as u64 > blk_file.size {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                "Block at blk{:05}.dat @ {} exceeds the file", blk_file.index, blk_offset)));
+        }
+        let mut file = try!(File::open(&blk_file.path));
+        try!(file.seek(SeekFrom::Start(blk_offset as u64)));
+        let mut bytes = vec![0u8; blocksize as usize];
+        try!(file.read_exact(&mut bytes));
+        RawBlock::with_data(BlkMmap::from(bytes), 0, blk_file.index, blk_offset, blocksize, version_id)
+    }
+
+    fn with_data(data: BlkMmap, data_offset: usize, blk_index: u32, blk_offset: usize, blocksize: u32,
+                 version_id: u8) -> OpResult<RawBlock> {
+        if blocksize < 80 || data_offset + blocksize as usize > data.as_ref().len() {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                "Block at blk{:05}.dat @ {} exceeds the file", blk_index, blk_offset)));
+        }
+        let hash = sha256(&sha256(&data.as_ref()[data_offset..data_offset + 80]));
+        Ok(RawBlock {
+            blk_index: blk_index,
+            blk_offset: blk_offset,
+            blocksize: blocksize,
+            hash: hash,
+            version_id: version_id,
+            data: data,
+            data_offset: data_offset
+        })
+    }
+
+    /// Serialized block, starting with the header
+    #[inline]
+    pub fn bytes(&self) -> &[u8] {
+        &self.data.as_ref()[self.data_offset..self.data_offset + self.blocksize as usize]
+    }
+
+    pub fn header(&self) -> OpResult<BlockHeader> {
+        let mut reader = &self.bytes()[..80];
+        reader.read_block_header()
+    }
+
+    /// Iterates over all transactions without decoding them
+    pub fn txs(&self) -> OpResult<RawTxs> {
+        let mut reader = SliceReader::new(self.bytes(), 80);
+        let tx_count = try!(reader.read_varint());
+        Ok(RawTxs { reader: reader, remaining: tx_count })
+    }
+
+    /// Decodes the whole block, see BlockchainRead::read_block
+    pub fn parse(&self) -> OpResult<Block> {
+        let mut reader = self.bytes();
+        reader.read_block(self.blk_index, self.blk_offset, self.blocksize, self.version_id)
+    }
+
+    /// Checks that all transactions can be read and exactly fill the block
+    pub fn verify_size(&self) -> OpResult<()> {
+        let mut txs = try!(self.txs());
+        while let Some(tx) = txs.next() {
+            try!(tx);
+        }
+        if txs.reader.pos != self.blocksize as usize {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                "Transactions of block at blk{:05}.dat @ {} don't match blocksize", self.blk_index, self.blk_offset)));
+        }
+        Ok(())
+    }
+
+    /// Calculates merkle root and verifies it against the field in BlockHeader
+    pub fn verify_merkle_root(&self) -> OpResult<bool> {
+        let mut txids = Vec::new();
+        for tx in try!(self.txs()) {
+            txids.push(try!(tx).txid());
+        }
+        let comp_merkle_root = merkle_root(&txids);
+        let merkle_root = &self.bytes()[36..68];
+        if comp_merkle_root != merkle_root {
+            warn!(target: "block", "Invalid merkle_root!\n  -> expected: {}\n  -> computed: {}\n",
+                  &arr_to_hex_swapped(merkle_root),
+                  &arr_to_hex_swapped(&comp_merkle_root));
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+/// Iterator over the transactions of a RawBlock
+pub struct RawTxs<'a> {
+    reader: SliceReader<'a>,
+    remaining: u64
+}
+
+impl<'a> Iterator for RawTxs<'a> {
+    type Item = OpResult<RawTx<'a>>;
+
+    fn next(&mut self) -> Option<OpResult<RawTx<'a>>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let tx = RawTx::read(&mut self.reader);
+        if tx.is_err() {
+            self.remaining = 0;
+        }
+        Some(tx)
+    }
+}
+
+/// Serialized transaction. Only the boundaries of inputs, outputs and witnesses are known,
+/// scripts are borrowed slices of the blk file.
+pub struct RawTx<'a> {
+    pub offset: u32,        // Offset relative to the block header
+    bytes: &'a [u8],
+    in_count: u64,
+    out_count: u64,
+    inputs_start: usize,    // Position of the first input
+    outputs_start: usize,   // Position of the first output
+    witness_start: usize,   // Position after the last output
+    segwit: bool
+}
+
+impl<'a> RawTx<'a> {
+    /// Validates the transaction boundaries, so the input and output iterators can't fail
+    fn read(reader: &mut SliceReader<'a>) -> OpResult<RawTx<'a>> {
+        let start = reader.pos;
+        try!(reader.take(4)); // version
+        let mut in_count = try!(reader.read_varint());
+        let segwit = in_count == 0;
+        if segwit {
+            // BIP144 marker is followed by flag
+            try!(reader.take(1));
+            in_count = try!(reader.read_varint());
+        }
+        let inputs_start = reader.pos - start;
+        for _ in 0..in_count {
+            try!(RawTxIn::read(reader));
+        }
+        let out_count = try!(reader.read_varint());
+        let outputs_start = reader.pos - start;
+        for _ in 0..out_count {
+            try!(RawTxOut::read(reader));
+        }
+        let witness_start = reader.pos - start;
+        if segwit {
+            for _ in 0..in_count {
+                for _ in 0..try!(reader.read_varint()) {
+                    let len = try!(reader.read_varint());
+                    try!(reader.take(len as usize));
+                }
+            }
+        }
+        try!(reader.take(4)); // locktime
+        Ok(RawTx {
+            offset: start as u32,
+            bytes: &reader.buf[start..reader.pos],
+            in_count: in_count,
+            out_count: out_count,
+            inputs_start: inputs_start,
+            outputs_start: outputs_start,
+            witness_start: witness_start,
+            segwit: segwit
+        })
+    }
+
+    /// Serialized transaction including witness data
+    #[inline]
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    #[inline]
+    pub fn version(&self) -> u32 {
+        LittleEndian::read_u32(&self.bytes[..4])
+    }
+
+    #[inline]
+    pub fn locktime(&self) -> u32 {
+        LittleEndian::read_u32(&self.bytes[self.bytes.len() - 4..])
+    }
+
+    #[inline]
+    pub fn is_segwit(&self) -> bool {
+        self.segwit
+    }
+
+    #[inline]
+    pub fn in_count(&self) -> u64 {
+        self.in_count
+    }
+
+    #[inline]
+    pub fn out_count(&self) -> u64 {
+        self.out_count
+    }
+
+    /// Computes the txid, witness data is excluded (BIP144)
+    pub fn txid(&self) -> [u8; 32] {
+        if !self.segwit {
+            return sha256(&sha256(self.bytes));
+        }
+        let mut out = [0u8; 32];
+        let mut hasher = Sha256::new();
+        hasher.input(&self.bytes[..4]);
+        hasher.input(&self.bytes[6..self.witness_start]);
+        hasher.input(&self.bytes[self.bytes.len() - 4..]);
+        hasher.result(&mut out);
+        sha256(&out)
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.in_count == 1 && self.inputs().next()
+            .map_or(false, |i| i.prev_txid == &[0u8; 32][..] && i.prev_index == 0xFFFFFFFF)
+    }
+
+    pub fn inputs(&self) -> RawTxIns<'a> {
+        RawTxIns {
+            reader: SliceReader::new(self.bytes, self.inputs_start),
+            remaining: self.in_count
+        }
+    }
+
+    pub fn outputs(&self) -> RawTxOuts<'a> {
+        RawTxOuts {
+            reader: SliceReader::new(self.bytes, self.outputs_start),
+            remaining: self.out_count
+        }
+    }
+}
+
+/// Transaction input borrowed from the blk file
+pub struct RawTxIn<'a> {
+    pub prev_txid: &'a [u8],
+    pub prev_index: u32,
+    pub script_sig: &'a [u8],
+    pub seq_no: u32
+}
+
+impl<'a> RawTxIn<'a> {
+    fn read(reader: &mut SliceReader<'a>) -> OpResult<RawTxIn<'a>> {
+        let prev_txid = try!(reader.take(32));
+        let prev_index = try!(reader.read_u32());
+        let script_len = try!(reader.read_varint());
+        Ok(RawTxIn {
+            prev_txid: prev_txid,
+            prev_index: prev_index,
+            script_sig: try!(reader.take(script_len as usize)),
+            seq_no: try!(reader.read_u32())
+        })
+    }
+
+    pub fn outpoint(&self) -> TxOutpoint {
+        let mut txid = [0u8; 32];
+        txid.copy_from_slice(self.prev_txid);
+        TxOutpoint { txid: txid, index: self.prev_index }
+    }
+}
+
+/// Transaction output borrowed from the blk file
+pub struct RawTxOut<'a> {
+    pub value: u64,
+    pub script_pubkey: &'a [u8]
+}
+
+impl<'a> RawTxOut<'a> {
+    fn read(reader: &mut SliceReader<'a>) -> OpResult<RawTxOut<'a>> {
+        let value = try!(reader.read_u64());
+        let script_len = try!(reader.read_varint());
+        Ok(RawTxOut {
+            value: value,
+            script_pubkey: try!(reader.take(script_len as usize))
+        })
+    }
+
+    #[inline]
+    pub fn eval_script(&self, version_id: u8) -> script::EvaluatedScript {
+        script::eval_from_bytes(self.script_pubkey, version_id)
+    }
+}
+
+/// Iterator over the inputs of a RawTx. Bounds are already checked in RawTx::read.
+pub struct RawTxIns<'a> {
+    reader: SliceReader<'a>,
+    remaining: u64
+}
+
+impl<'a> Iterator for RawTxIns<'a> {
+    type Item = RawTxIn<'a>;
+
+    fn next(&mut self) -> Option<RawTxIn<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        RawTxIn::read(&mut self.reader).ok()
+    }
+}
+
+/// Iterator over the outputs of a RawTx. Bounds are already checked in RawTx::read.
+pub struct RawTxOuts<'a> {
+    reader: SliceReader<'a>,
+    remaining: u64
+}
+
+impl<'a> Iterator for RawTxOuts<'a> {
+    type Item = RawTxOut<'a>;
+
+    fn next(&mut self) -> Option<RawTxOut<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        RawTxOut::read(&mut self.reader).ok()
+    }
+}
+
+/// Cursor over a byte slice which hands out borrowed sub slices
+struct SliceReader<'a> {
+    buf: &'a [u8],
+    pos: usize
+}
+
+impl<'a> SliceReader<'a> {
+    fn new(buf: &'a [u8], pos: usize) -> SliceReader<'a> {
+        SliceReader { buf: buf, pos: pos }
+    }
+
+    #[inline]
+    fn take(&mut self, n: usize) -> OpResult<&'a [u8]> {
+        if n > self.buf.len() - self.pos {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Unexpected end of block"));
+        }
+        let slice = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    #[inline]
+    fn read_u8(&mut self) -> OpResult<u8> {
+        Ok(try!(self.take(1))[0])
+    }
+
+    #[inline]
+    fn read_u32(&mut self) -> OpResult<u32> {
+        Ok(LittleEndian::read_u32(try!(self.take(4))))
+    }
+
+    #[inline]
+    fn read_u64(&mut self) -> OpResult<u64> {
+        Ok(LittleEndian::read_u64(try!(self.take(8))))
+    }
+
+    /// See VarUint::read_from
+    fn read_varint(&mut self) -> OpResult<u64> {
+        Ok(match try!(self.read_u8()) {
+            0xfd => LittleEndian::read_u16(try!(self.take(2))) as u64,
+            0xfe => try!(self.read_u32()) as u64,
+            0xff => try!(self.read_u64()),
+            n => n as u64
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+    use memmap::MmapMut;
+    use blockchain::utils::{self, arr_to_hex_swapped};
+
+    /// Block with a single transaction at offset 8 of a blk file
+    fn raw_block(header: &[u8], tx: &[u8]) -> RawBlock {
+        let mut bytes = Vec::from(header);
+        bytes.push(1);
+        bytes.extend_from_slice(tx);
+        let mut mmap = MmapMut::map_anon(bytes.len() + 8).unwrap();
+        mmap[8..].copy_from_slice(&bytes);
+        RawBlock::new(BlkMmap::from(mmap.make_read_only().unwrap()), 0, 8, bytes.len() as u32, 0).unwrap()
+    }
+
+    #[test]
+    fn test_genesis_block() {
+        let header = utils::hex_to_vec(concat!(
+            "01000000", "0000000000000000000000000000000000000000000000000000000000000000",
+            "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a",
+            "29ab5f49", "ffff001d", "1dac2b7c"));
+        let coinbase = utils::hex_to_vec(concat!(
+            "01000000", "01", "0000000000000000000000000000000000000000000000000000000000000000", "ffffffff",
+            "4d", "04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72",
+            "206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73", "ffffffff",
+            "01", "00f2052a01000000", "43", "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649",
+            "f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac", "00000000"));
+        let block = raw_block(&header, &coinbase);
+        assert_eq!("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f", arr_to_hex_swapped(&block.hash));
+        assert_eq!(2083236893, block.header().unwrap().nonce);
+        assert!(block.verify_merkle_root().unwrap());
+        assert!(block.verify_size().is_ok());
+
+        let mut txs = block.txs().unwrap();
+        let tx = txs.next().unwrap().unwrap();
+        assert!(txs.next().is_none());
+        assert_eq!(81, tx.offset);
+        assert_eq!(204, tx.size());
+        assert!(!tx.is_segwit());
+        assert!(tx.is_coinbase());
+        assert_eq!("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b", arr_to_hex_swapped(&tx.txid()));
+        let output = tx.outputs().next().unwrap();
+        assert_eq!(5000000000, output.value);
+        assert_eq!(67, output.script_pubkey.len());
+
+        // Compare with the decoding reader
+        let parsed = block.parse().unwrap();
+        assert_eq!(parsed.header.hash, block.hash);
+        assert_eq!(parsed.txs[0].hash, tx.txid());
+    }
+
+    #[test]
+    fn test_read() {
+        let block = raw_block(&[0x11u8; 80], &[0x22u8; 20]);
+        let mut bytes = vec![0u8; 8];
+        bytes.extend_from_slice(block.bytes());
+        let path = env::temp_dir().join("blk.read.dat");
+        fs::write(&path, &bytes).unwrap();
+        let blk_file = BlkFile::new(path.clone(), 0, bytes.len() as u64);
+
+        let read = RawBlock::read(&blk_file, 8, block.blocksize, 0).unwrap();
+        assert_eq!(block.hash, read.hash);
+        assert_eq!(block.bytes(), read.bytes());
+        assert_eq!(8, read.blk_offset);
+        assert!(RawBlock::read(&blk_file, 9, block.blocksize, 0).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_segwit_tx() {
+        // See reader::tests::test_segwit_tx_size
+        let raw_tx = utils::hex_to_vec(concat!(
+            "01000000", "0001", "01", "1111111111111111111111111111111111111111111111111111111111111111",
+            "00000000", "00", "ffffffff", "01", "e803000000000000",
+            "16", "00142222222222222222222222222222222222222222",
+            "02", "03aabbcc", "01dd", "00000000"));
+        let block = raw_block(&[0u8; 80], &raw_tx);
+        let tx = block.txs().unwrap().next().unwrap().unwrap();
+        assert_eq!(raw_tx.len(), tx.size());
+        assert!(tx.is_segwit());
+        assert!(!tx.is_coinbase());
+        assert_eq!(1, tx.version());
+        assert_eq!(0, tx.locktime());
+
+        let parsed = block.parse().unwrap();
+        let parsed_tx = &parsed.txs[0];
+        assert_eq!(parsed_tx.hash, tx.txid());
+        let input = tx.inputs().next().unwrap();
+        assert_eq!(parsed_tx.value.inputs[0].outpoint, input.outpoint());
+        assert_eq!(0xffffffff, input.seq_no);
+        assert!(input.script_sig.is_empty());
+        let outputs: Vec<RawTxOut> = tx.outputs().collect();
+        assert_eq!(1, outputs.len());
+        assert_eq!(1000, outputs[0].value);
+        assert_eq!(&parsed_tx.value.outputs[0].out.script_pubkey[..], outputs[0].script_pubkey);
+    }
+
+    #[test]
+    fn test_truncated_block() {
+        let block = raw_block(&[0u8; 80], &[0x01, 0x00, 0x00, 0x00, 0x01, 0x00]);
+        assert!(block.txs().unwrap().next().unwrap().is_err());
+        assert!(block.parse().is_err());
+        assert!(block.verify_size().is_err());
+    }
+}
@@ -5,7 +5,10 @@ use std::convert::From;
 use rust_base58::{ToBase58};
 
 use blockchain::proto::opcodes;
+use blockchain::proto::interpreter;
+use blockchain::proto::taproot::{self, TaprootSpend};
 use blockchain::utils::{self, sha256, ridemp160};
+use blockchain::utils::bech32;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ScriptError {
@@ -35,15 +38,16 @@ impl error::Error for ScriptError {
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ScriptPattern {
     /// Null Data
-    /// Pubkey Script: OP_RETURN <0 to 80 bytes of data> (formerly 40 bytes)
+    /// Pubkey Script: OP_RETURN [push...] (0 to 80 bytes of data, formerly 40 bytes)
     /// Null data scripts cannot be spent, so there's no signature script.
-    DataOutput(String),
+    /// Holds the concatenated bytes of all pushes. OP_1 .. OP_16 contribute their value as byte.
+    DataOutput(Vec<u8>),
 
     /// Pay to Multisig [BIP11]
     /// Pubkey script: <m> <A pubkey>[B pubkey][C pubkey...] <n> OP_CHECKMULTISIG
     /// Signature script: OP_0 <A sig>[B sig][C sig...]
-    /// TODO: Implement Pay2MultiSig: For now only 2n3 MultiSigs are detected
-    Pay2MultiSig,
+    /// Holds m (required signatures), n (total keys) and the raw public keys.
+    Pay2MultiSig { m: u8, n: u8, pubkeys: Vec<Vec<u8>> },
 
     /// Pay to Public Key (p2pk) scripts are a simplified form of the p2pkh,
     /// but aren't commonly used in new transactions anymore,
@@ -61,17 +65,25 @@ pub enum ScriptPattern {
     /// Signature script: <sig>[sig][sig...] <redeemScript>
     Pay2ScriptHash,
 
-    /// Sign Multisig script [BIP11]
-    //SignMultiSig,
+    /// Pay to Witness Public Key Hash [p2wpkh/BIP141]
+    /// Pubkey script: OP_0 <20 byte key hash>
+    /// Witness: <sig> <pubkey>
+    Pay2WitnessPublicKeyHash,
 
-    /// Sign Public Key (obsolete)
-    //SignPublicKey,
+    /// Pay to Witness Script Hash [p2wsh/BIP141]
+    /// Pubkey script: OP_0 <32 byte script hash>
+    /// Witness: [items...] <witnessScript>
+    Pay2WitnessScriptHash,
 
-    /// Sign Public Key Hash [P2PKH]
-    //SignKeyHash,
+    /// Pay to Taproot [p2tr/BIP341]
+    /// Pubkey script: OP_1 <32 byte x-only output key>
+    /// Witness: <schnorr sig> (key path) or [items...] <tapscript> <control block> (script path),
+    /// optionally followed by an annex
+    Pay2Taproot,
 
-    /// Sign Script Hash [P2SH/BIP16]
-    //SignScriptHash,
+    /// Witness program with unknown version or length (reserved for future upgrades)
+    /// Pubkey script: OP_n <2 to 40 bytes>
+    WitnessProgram(u8),
 
     /// The script is valid but does not conform to the standard templates.
     /// Such scripts are always accepted if they are mined into blocks, but
@@ -85,16 +97,83 @@ impl fmt::Display for ScriptPattern {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ScriptPattern::DataOutput(_) => write!(f, "DataOutput (OP_RETURN)"),
-            ScriptPattern::Pay2MultiSig => write!(f, "Pay2MultiSig"),
+            ScriptPattern::Pay2MultiSig { m, n, .. } => write!(f, "Pay2MultiSig ({}-of-{})", m, n),
             ScriptPattern::Pay2PublicKey => write!(f, "Pay2PublicKey"),
             ScriptPattern::Pay2PublicKeyHash => write!(f, "Pay2PublicKeyHash"),
             ScriptPattern::Pay2ScriptHash => write!(f, "Pay2ScriptHash"),
+            ScriptPattern::Pay2WitnessPublicKeyHash => write!(f, "Pay2WitnessPublicKeyHash"),
+            ScriptPattern::Pay2WitnessScriptHash => write!(f, "Pay2WitnessScriptHash"),
+            ScriptPattern::Pay2Taproot => write!(f, "Pay2Taproot"),
+            ScriptPattern::WitnessProgram(version) => write!(f, "WitnessProgram (v{})", version),
             ScriptPattern::NotRecognised => write!(f, "NotRecognised"),
             ScriptPattern::Error(ref err) => write!(f, "ScriptError: {}", err)
         }
     }
 }
 
+/// Spend type of an input, derived from scriptSig and witness.
+/// Without the spent output this is a best guess based on the structure of the pushed data.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum InputPattern {
+    /// Coinbase input, does not spend anything
+    Coinbase,
+
+    /// Sign Public Key (obsolete)
+    /// Signature script: <sig>
+    SignPublicKey,
+
+    /// Sign Public Key Hash [P2PKH]
+    /// Signature script: <sig> <pubkey>
+    SignPublicKeyHash,
+
+    /// Sign Multisig script [BIP11]
+    /// Signature script: OP_0 <A sig>[B sig][C sig...]
+    SignMultiSig,
+
+    /// Sign Script Hash [P2SH/BIP16]
+    /// Signature script: [items...] <redeemScript>
+    SignScriptHash,
+
+    /// Sign Witness Public Key Hash [P2WPKH/BIP141]
+    /// Witness: <sig> <pubkey>
+    SignWitnessPublicKeyHash,
+
+    /// Sign Witness Script Hash [P2WSH/BIP141]
+    /// Witness: [items...] <witnessScript>
+    SignWitnessScriptHash,
+
+    /// P2WPKH nested in P2SH
+    /// Signature script: <0 <20 byte key hash>>, Witness: <sig> <pubkey>
+    SignNestedWitnessPublicKeyHash,
+
+    /// P2WSH nested in P2SH
+    /// Signature script: <0 <32 byte script hash>>, Witness: [items...] <witnessScript>
+    SignNestedWitnessScriptHash,
+
+    /// Taproot key path spend [BIP341]
+    /// Witness: <schnorr sig> [annex]
+    SignTaprootKeyPath,
+
+    /// Taproot script path spend [BIP341]
+    /// Witness: [items...] <script> <control block> [annex]
+    SignTaprootScriptPath,
+
+    /// The input doesn't match any known spend type
+    NotRecognised,
+
+    Error(ScriptError),
+}
+
+impl fmt::Display for InputPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InputPattern::Error(ref err) => write!(f, "ScriptError: {}", err),
+            ref p @ _ => write!(f, "{:?}", p)
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum StackElement {
     Op(opcodes::All),
     Data(Vec<u8>)
@@ -110,6 +189,23 @@ impl StackElement {
             StackElement::Data(ref d) => Ok(d.clone())
         }
     }
+
+    /// Returns the bytes pushed by this element, or None if it isn't a push.
+    /// Empty pushes are stored as opcodes, OP_1NEGATE and OP_1 .. OP_16 push their value.
+    pub fn push_value(&self) -> Option<Vec<u8>> {
+        match *self {
+            StackElement::Data(ref d) => Some(d.clone()),
+            StackElement::Op(opcode) => match (opcode, opcode.classify()) {
+                (opcodes::All::OP_PUSHDATA1, _) |
+                (opcodes::All::OP_PUSHDATA2, _) |
+                (opcodes::All::OP_PUSHDATA4, _) |
+                (_, opcodes::Class::PushBytes(_)) => Some(Vec::new()),
+                (_, opcodes::Class::PushNum(-1)) => Some(vec![0x81]),
+                (_, opcodes::Class::PushNum(n)) => Some(vec![n as u8]),
+                _ => None
+            }
+        }
+    }
 }
 
 //TODO: find a better solution
@@ -147,6 +243,7 @@ impl fmt::Debug for StackElement {
 }
 
 /// Simple stack structure to match against patterns
+#[derive(Clone)]
 pub struct Stack {
     pub pattern: ScriptPattern,
     pub elements: Vec<StackElement>
@@ -160,12 +257,140 @@ impl fmt::Debug for Stack {
     }
 }
 
+/// Type of a destination an output pays to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DestinationKind {
+    PubKey,
+    PubKeyHash,
+    ScriptHash,
+    /// Holds the witness version
+    WitnessProgram(u8)
+}
+
+/// Single destination of an output, e.g. one key of a multisig
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Destination {
+    pub kind: DestinationKind,
+    pub data: Vec<u8>,      // Raw public key, hash or witness program
+    pub address: String     // Rendered address (base58check or bech32)
+}
+
+impl Destination {
+    /// Public key destination, rendered as P2PKH address
+    pub fn from_pubkey(pub_key: &[u8], version_id: u8) -> Destination {
+        Destination {
+            kind: DestinationKind::PubKey,
+            data: Vec::from(pub_key),
+            address: public_key_to_addr(pub_key, version_id)
+        }
+    }
+
+    pub fn from_pubkey_hash(h160: &[u8], version_id: u8) -> Destination {
+        Destination {
+            kind: DestinationKind::PubKeyHash,
+            data: Vec::from(h160),
+            address: hash_160_to_address(h160, version_id)
+        }
+    }
+
+    pub fn from_script_hash(h160: &[u8]) -> Destination {
+        Destination {
+            kind: DestinationKind::ScriptHash,
+            data: Vec::from(h160),
+            address: hash_160_to_address(h160, 5)
+        }
+    }
+
+    /// Witness program, rendered as bech32 address if the coin has a known prefix
+    pub fn from_witness_program(version: u8, program: &[u8], version_id: u8) -> Destination {
+        let address = match bech32_hrp(version_id) {
+            Some(hrp) => bech32::encode_segwit_address(hrp, version, program),
+            None => String::new()
+        };
+        Destination {
+            kind: DestinationKind::WitnessProgram(version),
+            data: Vec::from(program),
+            address: address
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct EvaluatedScript {
-    pub address: String,
+    /// All destinations involved in this script (e.g. one per key for multisig)
+    pub destinations: Vec<Destination>,
     pub pattern: ScriptPattern
 }
 
+impl EvaluatedScript {
+    #[inline]
+    fn new(destinations: Vec<Destination>, pattern: ScriptPattern) -> EvaluatedScript {
+        EvaluatedScript { destinations: destinations, pattern: pattern }
+    }
+
+    /// Returns the rendered addresses of all destinations
+    #[inline]
+    pub fn addresses(&self) -> Vec<&str> {
+        self.destinations.iter().map(|d| d.address.as_str()).collect()
+    }
+
+    /// Returns the address if the script pays to exactly one destination, otherwise an empty string
+    #[inline]
+    pub fn address(&self) -> &str {
+        match self.destinations.len() {
+            1 => &self.destinations[0].address,
+            _ => ""
+        }
+    }
+
+    /// Returns the addresses of scripts with several destinations (e.g. multisig) separated by `,`,
+    /// otherwise an empty string
+    #[inline]
+    pub fn multi_addresses(&self) -> String {
+        match self.destinations.len() {
+            0 | 1 => String::new(),
+            _ => self.addresses().join(",")
+        }
+    }
+
+    /// Returns false if the destinations may belong to different owners (multisig)
+    #[inline]
+    pub fn has_single_owner(&self) -> bool {
+        match self.pattern {
+            ScriptPattern::Pay2MultiSig { n, .. } => n == 1,
+            _ => true
+        }
+    }
+}
+
+/// Evaluated input with all data extracted from scriptSig and witness
+#[derive(Clone)]
+pub struct EvaluatedInput {
+    pub pattern: InputPattern,
+    pub signatures: Vec<Vec<u8>>,
+    pub pubkeys: Vec<Vec<u8>>,
+    /// Redeem script (P2SH), witness script (P2WSH) or tapscript leaf
+    pub script: Option<Vec<u8>>,
+    /// Spent destination, if it can be derived without the spent output
+    pub destination: Option<Destination>,
+    /// Control block, annex and tapscript details for taproot spends
+    pub taproot: Option<TaprootSpend>
+}
+
+impl EvaluatedInput {
+    #[inline]
+    pub fn new(pattern: InputPattern) -> EvaluatedInput {
+        EvaluatedInput {
+            pattern: pattern,
+            signatures: Vec::new(),
+            pubkeys: Vec::new(),
+            script: None,
+            destination: None,
+            taproot: None
+        }
+    }
+}
+
 /// Evaluates scripts
 pub struct ScriptEvaluator<'a> {
     bytes: &'a [u8],
@@ -184,70 +409,61 @@ impl<'a> ScriptEvaluator<'a> {
     pub fn eval(&mut self) -> Result<Stack, ScriptError> {
 
         let mut elements = Vec::with_capacity(10);
-        //print!("Script(");
-        while self.ip < self.n_bytes {
-
-            let opcode = opcodes::All::from(self.bytes[self.ip]);
-            let opcode_class = opcode.classify();
-            let data_len = try!(self.maybe_push_data(opcode, opcode_class));
-            self.ip += 1;
-
-            if data_len > 0 {
-                //print!(" ");
-                if self.ip + data_len > self.n_bytes {
-                    //println!("!ip: {}, data_len: {}, n_bytes: {}!", self.ip, data_len, self.n_bytes);
-                    return Err(ScriptError::UnexpectedEof);
-                } else {
-                    let data = Vec::from(&self.bytes[self.ip..self.ip + data_len]);
-                    //print!("{}", utils::arr_to_hex(&data));
-                    elements.push(StackElement::Data(data));
-                    self.ip += data_len;
-                }
-            } else if opcode_class != opcodes::Class::NoOp {
+        while let Some(instruction) = self.next_instruction() {
+            let (opcode, data) = try!(instruction);
+            if !data.is_empty() {
+                elements.push(StackElement::Data(Vec::from(data)));
+            } else if opcode.classify() != opcodes::Class::NoOp {
                 elements.push(StackElement::Op(opcode));
-                //print!("{:?} ", opcode);
             }
         }
-        //println!(")\n");
-        let pattern = ScriptEvaluator::eval_script_pattern(&elements);
+        let pattern = match interpreter::witness_program(self.bytes) {
+            Some((version, program)) => ScriptEvaluator::eval_witness_pattern(version, program),
+            None => ScriptEvaluator::eval_script_pattern(&elements)
+        };
         Ok(Stack { elements: elements, pattern: pattern })
     }
 
+    /// Reads the next opcode and the bytes it pushes (empty for non-push opcodes).
+    /// Returns None if the end of the script is reached.
+    pub fn next_instruction(&mut self) -> Option<Result<(opcodes::All, &'a [u8]), ScriptError>> {
+        if self.ip >= self.n_bytes {
+            return None;
+        }
+        let opcode = opcodes::All::from(self.bytes[self.ip]);
+        self.ip += 1;
+        let data_len = match self.maybe_push_data(opcode, opcode.classify()) {
+            Ok(len) => len,
+            Err(err) => return Some(Err(err))
+        };
+        if self.ip + data_len > self.n_bytes {
+            return Some(Err(ScriptError::UnexpectedEof));
+        }
+        let data = &self.bytes[self.ip..self.ip + data_len];
+        self.ip += data_len;
+        Some(Ok((opcode, data)))
+    }
+
     /// Checks Opcode if should to push some bytes
     /// Especially opcodes between 0x00 and 0x4e
     fn maybe_push_data(&mut self, opcode: opcodes::All, opcode_class: opcodes::Class) -> Result<usize, ScriptError> {
-        let data_len = if let opcodes::Class::PushBytes(n) = opcode_class {
-            n as usize
-        } else {
-            match opcode {
-                opcodes::All::OP_PUSHDATA1 => {
-                    if self.ip + 1 > self.n_bytes {
-                        return Err(ScriptError::UnexpectedEof);
-                    }
-                    let val = try!(ScriptEvaluator::read_uint(&self.bytes[self.ip..], 1));
-                    self.ip += 1;
-                    val
-                }
-                opcodes::All::OP_PUSHDATA2 => {
-                    if self.ip + 2 > self.n_bytes {
-                        return Err(ScriptError::UnexpectedEof);
-                    }
-                    let val = try!(ScriptEvaluator::read_uint(&self.bytes[self.ip..], 2));
-                    self.ip += 2;
-                    val
+        let n_len_bytes = match opcode {
+            opcodes::All::OP_PUSHDATA1 => 1,
+            opcodes::All::OP_PUSHDATA2 => 2,
+            opcodes::All::OP_PUSHDATA4 => 4,
+            _ => {
+                if let opcodes::Class::PushBytes(n) = opcode_class {
+                    return Ok(n as usize);
                 }
-                opcodes::All::OP_PUSHDATA4 => {
-                    if self.ip + 4 > self.n_bytes {
-                        return Err(ScriptError::UnexpectedEof);
-                    }
-                    let val = try!(ScriptEvaluator::read_uint(&self.bytes[self.ip..], 4));
-                    self.ip += 4;
-                    val
-                }
-                _ => 0
+                return Ok(0);
             }
         };
-        Ok(data_len)
+        if self.ip + n_len_bytes > self.n_bytes {
+            return Err(ScriptError::UnexpectedEof);
+        }
+        let val = try!(ScriptEvaluator::read_uint(&self.bytes[self.ip..], n_len_bytes));
+        self.ip += n_len_bytes;
+        Ok(val)
     }
 
     fn eval_script_pattern(elements: &[StackElement]) -> ScriptPattern {
@@ -278,26 +494,21 @@ impl<'a> ScriptEvaluator<'a> {
         }
 
         // Data output
-        // pubkey: OP_RETURN <0 to 40 bytes of data>
-        let data_output = [StackElement::Op(opcodes::All::OP_RETURN),
-                           StackElement::Data(Vec::new())];
-        if ScriptEvaluator::match_stack_pattern(&elements, &data_output) {
-            if let Ok(data) = elements[1].data() {
-                return ScriptPattern::DataOutput(String::from_utf8_lossy(&data).into_owned());
-            } else {
-                return ScriptPattern::Error(ScriptError::InvalidFormat);
+        // pubkey: OP_RETURN [push...]
+        if elements.first() == Some(&StackElement::Op(opcodes::All::OP_RETURN)) {
+            let mut data = Vec::new();
+            for element in &elements[1..] {
+                match element.push_value() {
+                    Some(value) => data.extend(value),
+                    None => return ScriptPattern::NotRecognised
+                }
             }
+            return ScriptPattern::DataOutput(data);
         }
 
-        //TODO: implement n to m multisig
-        let multisig_2n3 = [StackElement::Op(opcodes::All::OP_PUSHNUM_2),
-                            StackElement::Data(Vec::new()),
-                            StackElement::Data(Vec::new()),
-                            StackElement::Data(Vec::new()),
-                            StackElement::Op(opcodes::All::OP_PUSHNUM_3),
-                            StackElement::Op(opcodes::All::OP_CHECKMULTISIG)];
-        if ScriptEvaluator::match_stack_pattern(&elements, &multisig_2n3) {
-            return ScriptPattern::Pay2MultiSig;
+        // Pay to Multisig: <m> <A pubkey>[B pubkey][C pubkey...] <n> OP_CHECKMULTISIG
+        if let Some(pattern) = ScriptEvaluator::eval_multisig_pattern(&elements) {
+            return pattern;
         }
         /* TODO:
         // The Genesis Block, self-payments, and pay-by-IP-address payments look like:
@@ -310,6 +521,59 @@ impl<'a> ScriptEvaluator<'a> {
         return ScriptPattern::NotRecognised;
     }
 
+    fn eval_witness_pattern(version: u8, program: &[u8]) -> ScriptPattern {
+        match (version, program.len()) {
+            (0, 20) => ScriptPattern::Pay2WitnessPublicKeyHash,
+            (0, 32) => ScriptPattern::Pay2WitnessScriptHash,
+            (1, 32) => ScriptPattern::Pay2Taproot,
+            (v, _) => ScriptPattern::WitnessProgram(v)
+        }
+    }
+
+    /// Matches any m-of-n multisig with 1 <= m <= n <= 16, as Bitcoin Core's solver does
+    fn eval_multisig_pattern(elements: &[StackElement]) -> Option<ScriptPattern> {
+        if elements.len() < 4 {
+            return None;
+        }
+        let len = elements.len();
+        if elements[len - 1] != StackElement::Op(opcodes::All::OP_CHECKMULTISIG) {
+            return None;
+        }
+        let (m, n) = match (ScriptEvaluator::small_int(&elements[0]),
+                            ScriptEvaluator::small_int(&elements[len - 2])) {
+            (Some(m), Some(n)) => (m, n),
+            _ => return None
+        };
+        if m < 1 || m > n || n as usize != len - 3 {
+            return None;
+        }
+        let mut pubkeys = Vec::with_capacity(n as usize);
+        for element in &elements[1..len - 2] {
+            match *element {
+                // Only accept valid public key sizes (compressed or uncompressed)
+                StackElement::Data(ref key) if key.len() == 33 || key.len() == 65 => pubkeys.push(key.clone()),
+                _ => return None
+            }
+        }
+        Some(ScriptPattern::Pay2MultiSig { m: m, n: n, pubkeys: pubkeys })
+    }
+
+    /// Decodes OP_1 .. OP_16, data pushes aren't accepted
+    #[inline]
+    fn small_int(element: &StackElement) -> Option<u8> {
+        match *element {
+            StackElement::Op(opcode) => {
+                let op = opcode as u8;
+                if op >= opcodes::All::OP_PUSHNUM_1 as u8 && op <= opcodes::All::OP_PUSHNUM_16 as u8 {
+                    Some(op - opcodes::All::OP_PUSHNUM_1 as u8 + 1)
+                } else {
+                    None
+                }
+            }
+            StackElement::Data(_) => None
+        }
+    }
+
     /// Read a script-encoded unsigned integer.
     #[inline]
     fn read_uint(data: &[u8], size: usize) -> Result<usize, ScriptError> {
@@ -345,7 +609,7 @@ impl<'a> ScriptEvaluator<'a> {
 pub fn eval_from_bytes(bytes: &[u8], version_id: u8) -> EvaluatedScript {
     match ScriptEvaluator::new(bytes).eval() {
         Ok(stack) => eval_from_stack(stack, version_id) ,
-        Err(err) => EvaluatedScript { address: String::new(), pattern: ScriptPattern::Error(err) }
+        Err(err) => EvaluatedScript::new(Vec::new(), ScriptPattern::Error(err))
     }
 }
 
@@ -355,63 +619,217 @@ pub fn eval_from_stack(stack: Stack, version_id: u8) -> EvaluatedScript {
     // Wrap everything in a closure to early catch try!()
     match (|| -> Result<EvaluatedScript, ScriptError> {
 
-        let script = match stack.pattern {
-            ref p @ ScriptPattern::Pay2PublicKey => {
+        let destinations = match stack.pattern {
+            ScriptPattern::Pay2PublicKey => {
                 let pub_key = try!(stack.elements[0].data());
-                EvaluatedScript {
-                    address: public_key_to_addr(&pub_key, version_id),
-                    pattern: p.clone()
-                }
+                vec![Destination::from_pubkey(&pub_key, version_id)]
             }
-            ref p @ ScriptPattern::Pay2PublicKeyHash => {
+            ScriptPattern::Pay2PublicKeyHash => {
                 let h160 = try!(stack.elements[2].data());
-                EvaluatedScript {
-                    address: hash_160_to_address(&h160, version_id),
-                    pattern: p.clone()
-                }
+                vec![Destination::from_pubkey_hash(&h160, version_id)]
             }
-            ref p @ ScriptPattern::Pay2ScriptHash => {
+            ScriptPattern::Pay2ScriptHash => {
                 let h160 = try!(stack.elements[1].data());
-                EvaluatedScript {
-                    address: hash_160_to_address(&h160, 5),
-                    pattern: p.clone()
-                }
+                vec![Destination::from_script_hash(&h160)]
             }
-            ScriptPattern::DataOutput(ref data) => {
-                EvaluatedScript {
-                    address: String::new(),
-                    pattern: ScriptPattern::DataOutput(data.clone())
-                }
+            ScriptPattern::Pay2WitnessPublicKeyHash |
+            ScriptPattern::Pay2WitnessScriptHash => {
+                let program = try!(stack.elements[1].data());
+                vec![Destination::from_witness_program(0, &program, version_id)]
             }
-            ref p @ ScriptPattern::Pay2MultiSig => {
-                try!(stack.elements[1].data());
-                EvaluatedScript {
-                    address: String::new(),
-                    pattern: p.clone()
-                }
+            ScriptPattern::Pay2Taproot => {
+                // Destination data is the x-only output key
+                let program = try!(stack.elements[1].data());
+                vec![Destination::from_witness_program(1, &program, version_id)]
             }
-            ref p @ ScriptPattern::NotRecognised => {
-                EvaluatedScript {
-                    address: String::new(),
-                    pattern: p.clone()
-                }
+            ScriptPattern::WitnessProgram(version) => {
+                let program = try!(stack.elements[1].data());
+                vec![Destination::from_witness_program(version, &program, version_id)]
             }
-            ref p @ _ => {
-                EvaluatedScript {
-                    address: String::new(),
-                    pattern: p.clone()
-                }
+            ScriptPattern::Pay2MultiSig { ref pubkeys, .. } => {
+                // One P2PKH address for each involved key
+                pubkeys.iter().map(|k| Destination::from_pubkey(k, version_id)).collect()
             }
+            _ => Vec::new()
         };
-        Ok(script)
+        Ok(EvaluatedScript::new(destinations, stack.pattern.clone()))
     })() {
         Ok(script) => script,
-        Err(e) => {
-            EvaluatedScript {
-                address: String::new(),
-                pattern: ScriptPattern::Error(e)
-            }
+        Err(e) => EvaluatedScript::new(Vec::new(), ScriptPattern::Error(e))
+    }
+}
+
+/// Evaluates scriptSig and witness of an input.
+/// Taproot spends are only identified if the spent scriptPubKey is known.
+pub fn eval_input(script_sig: &[u8], witness: &[Vec<u8>], spent_script: Option<&[u8]>, version_id: u8) -> EvaluatedInput {
+    let pushes = match read_pushes(script_sig) {
+        Ok(Some(pushes)) => pushes,
+        Ok(None) => return EvaluatedInput::new(InputPattern::NotRecognised),
+        Err(err) => return EvaluatedInput::new(InputPattern::Error(err))
+    };
+    if witness.is_empty() {
+        eval_legacy_input(pushes, version_id)
+    } else if pushes.is_empty() {
+        match spent_script {
+            Some(spent_script) if taproot::is_taproot_output(spent_script) => eval_taproot_input(witness, spent_script),
+            _ => eval_witness_input(witness, version_id, false)
+        }
+    } else if pushes.len() == 1 && interpreter::witness_program(&pushes[0]).is_some() {
+        // Witness program nested in P2SH, the spent address is the P2SH one
+        let mut input = eval_witness_input(witness, version_id, true);
+        input.destination = Some(Destination::from_script_hash(&ridemp160(&sha256(&pushes[0]))));
+        input
+    } else {
+        EvaluatedInput::new(InputPattern::NotRecognised)
+    }
+}
+
+/// Evaluates inputs without witness data
+fn eval_legacy_input(mut pushes: Vec<Vec<u8>>, version_id: u8) -> EvaluatedInput {
+    if pushes.is_empty() {
+        return EvaluatedInput::new(InputPattern::NotRecognised);
+    }
+    // <sig>
+    if pushes.len() == 1 && is_signature(&pushes[0]) {
+        let mut input = EvaluatedInput::new(InputPattern::SignPublicKey);
+        input.signatures = pushes;
+        return input;
+    }
+    // <sig> <pubkey>
+    if pushes.len() == 2 && is_signature(&pushes[0]) && is_public_key(&pushes[1]) {
+        let mut input = EvaluatedInput::new(InputPattern::SignPublicKeyHash);
+        input.destination = Some(Destination::from_pubkey_hash(&ridemp160(&sha256(&pushes[1])), version_id));
+        input.pubkeys = vec![pushes.pop().unwrap()];
+        input.signatures = pushes;
+        return input;
+    }
+    // OP_0 <sig>...
+    if pushes.len() >= 2 && pushes[0].is_empty() && pushes[1..].iter().all(|p| is_signature(p)) {
+        let mut input = EvaluatedInput::new(InputPattern::SignMultiSig);
+        input.signatures = pushes.split_off(1);
+        return input;
+    }
+    // [items...] <redeemScript>
+    let redeem_script = pushes.pop().unwrap();
+    if redeem_script.is_empty() || is_signature(&redeem_script) || is_public_key(&redeem_script) {
+        return EvaluatedInput::new(InputPattern::NotRecognised);
+    }
+    let mut input = eval_redeem_script(InputPattern::SignScriptHash, redeem_script, &pushes);
+    if let Some(ref script) = input.script {
+        input.destination = Some(Destination::from_script_hash(&ridemp160(&sha256(script))));
+    }
+    input
+}
+
+/// Evaluates the witness of native or P2SH nested segwit inputs
+fn eval_witness_input(witness: &[Vec<u8>], version_id: u8, nested: bool) -> EvaluatedInput {
+    // <sig> <pubkey>
+    if witness.len() == 2 && witness[1].len() == 33 && is_public_key(&witness[1]) {
+        let pattern = if nested { InputPattern::SignNestedWitnessPublicKeyHash } else { InputPattern::SignWitnessPublicKeyHash };
+        let mut input = EvaluatedInput::new(pattern);
+        input.signatures = vec![witness[0].clone()];
+        input.pubkeys = vec![witness[1].clone()];
+        if !nested {
+            input.destination = Some(Destination::from_witness_program(0, &ridemp160(&sha256(&witness[1])), version_id));
         }
+        return input;
+    }
+
+    // [items...] <witnessScript>
+    let (witness_script, items) = witness.split_last().unwrap();
+    let pattern = if nested { InputPattern::SignNestedWitnessScriptHash } else { InputPattern::SignWitnessScriptHash };
+    let mut input = eval_redeem_script(pattern, witness_script.clone(), items);
+    if !nested {
+        input.destination = Some(Destination::from_witness_program(0, &sha256(witness_script), version_id));
+    }
+    input
+}
+
+/// Evaluates the witness of an input spending a P2TR output
+fn eval_taproot_input(witness: &[Vec<u8>], spent_script: &[u8]) -> EvaluatedInput {
+    let spend = match taproot::eval_witness(witness, spent_script) {
+        Some(spend) => spend,
+        None => return EvaluatedInput::new(InputPattern::NotRecognised)
+    };
+    let (stack, _) = taproot::split_annex(witness);
+    let mut input = match spend.script_path {
+        None => {
+            let mut input = EvaluatedInput::new(InputPattern::SignTaprootKeyPath);
+            input.signatures = vec![stack[0].clone()];
+            input
+        }
+        Some(ref script_path) => {
+            let mut input = EvaluatedInput::new(InputPattern::SignTaprootScriptPath);
+            input.script = Some(script_path.script.clone());
+            input.signatures = stack[..stack.len() - 2].iter()
+                .filter(|item| item.len() == 64 || item.len() == 65)
+                .cloned()
+                .collect();
+            input
+        }
+    };
+    input.taproot = Some(spend);
+    input
+}
+
+/// Extracts signatures from the given items and public keys from the redeem script
+fn eval_redeem_script(pattern: InputPattern, redeem_script: Vec<u8>, items: &[Vec<u8>]) -> EvaluatedInput {
+    let mut input = EvaluatedInput::new(pattern);
+    input.signatures = items.iter().filter(|item| is_signature(item)).cloned().collect();
+    if let Ok(stack) = ScriptEvaluator::new(&redeem_script).eval() {
+        match stack.pattern {
+            ScriptPattern::Pay2MultiSig { pubkeys, .. } => input.pubkeys = pubkeys,
+            ScriptPattern::Pay2PublicKey => input.pubkeys = stack.elements[0].data().into_iter().collect(),
+            _ => ()
+        }
+    }
+    input.script = Some(redeem_script);
+    input
+}
+
+/// Returns all data pushed by a push-only script (OP_0 and OP_1 .. OP_16 included).
+/// Returns None if the script contains other opcodes.
+fn read_pushes(script: &[u8]) -> Result<Option<Vec<Vec<u8>>>, ScriptError> {
+    let mut pushes = Vec::new();
+    let mut evaluator = ScriptEvaluator::new(script);
+    while let Some(instruction) = evaluator.next_instruction() {
+        let (opcode, data) = try!(instruction);
+        match (opcode, opcode.classify()) {
+            (opcodes::All::OP_PUSHDATA1, _) |
+            (opcodes::All::OP_PUSHDATA2, _) |
+            (opcodes::All::OP_PUSHDATA4, _) |
+            (_, opcodes::Class::PushBytes(_)) => pushes.push(Vec::from(data)),
+            (_, opcodes::Class::PushNum(-1)) => pushes.push(vec![0x81]),
+            (_, opcodes::Class::PushNum(n)) => pushes.push(vec![n as u8]),
+            _ => return Ok(None)
+        }
+    }
+    Ok(Some(pushes))
+}
+
+/// Checks for a DER encoded ECDSA signature followed by the sighash byte
+#[inline]
+fn is_signature(data: &[u8]) -> bool {
+    data.len() >= 9 && data.len() <= 73 && data[0] == 0x30 && data[1] as usize == data.len() - 3
+}
+
+/// Checks for a compressed (33 bytes) or uncompressed (65 bytes) public key
+#[inline]
+fn is_public_key(data: &[u8]) -> bool {
+    match data.len() {
+        33 => data[0] == 0x02 || data[0] == 0x03,
+        65 => data[0] == 0x04,
+        _ => false
+    }
+}
+
+/// Returns the human readable part for segwit addresses based on the address version id
+pub fn bech32_hrp(version_id: u8) -> Option<&'static str> {
+    match version_id {
+        0x00 => Some("bc"),     // Bitcoin
+        0x6f => Some("tb"),     // TestNet3
+        0x30 => Some("ltc"),    // Litecoin
+        _ => None
     }
 }
 
@@ -437,7 +855,9 @@ fn hash_160_to_address(h160: &[u8], version: u8) -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::{ScriptEvaluator, ScriptPattern, ScriptError, eval_from_stack, eval_from_bytes};
+    use super::{ScriptEvaluator, ScriptPattern, ScriptError, DestinationKind, InputPattern,
+                eval_from_stack, eval_from_bytes, eval_input};
+    use blockchain::utils::hex_to_vec;
 
     #[test]
     fn test_bitcoin_script_p2pkh() {
@@ -452,7 +872,8 @@ mod tests {
         assert_eq!("OP_DUP OP_HASH160 12ab8dc588ca9d5787dde7eb29569da63c3a238c OP_EQUALVERIFY OP_CHECKSIG", format!("{:?}", stack));
 
         let script = eval_from_stack(stack, 0x00);
-        assert_eq!(script.address, "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv");
+        assert_eq!(script.addresses(), vec!["12higDjoCCNXSA95xZMWUdPvXNmkAduhWv"]);
+        assert_eq!(script.destinations[0].kind, DestinationKind::PubKeyHash);
         assert_eq!(script.pattern, ScriptPattern::Pay2PublicKeyHash);
     }
 
@@ -477,7 +898,13 @@ mod tests {
             format!("{:?}", stack));
 
         let script = eval_from_stack(stack, 0x00);
-        assert_eq!(script.address, "1LEWwJkDj8xriE87ALzQYcHjTmD8aqDj1f");
+        assert_eq!(script.destinations.len(), 1);
+        assert_eq!(script.destinations[0].address, "1LEWwJkDj8xriE87ALzQYcHjTmD8aqDj1f");
+        assert_eq!("1LEWwJkDj8xriE87ALzQYcHjTmD8aqDj1f", script.address());
+        assert_eq!("", script.multi_addresses());
+        assert_eq!(script.destinations[0].data.len(), 65);
+        assert_eq!(script.destinations[0].kind, DestinationKind::PubKey);
+        assert!(script.has_single_owner());
         assert_eq!(script.pattern, ScriptPattern::Pay2PublicKey);
     }
 
@@ -510,7 +937,59 @@ mod tests {
                    03e3818b65bcc73a7d64064106a859cc1a5a728c4345ff0b641209fba0d90de6e9 \
                    021f2f6e1e50cb6a953935c3601284925decd3fd21bc445712576873fb8c6ebc18 OP_PUSHNUM_3 OP_CHECKMULTISIG",
                    format!("{:?}", stack));
-        assert_eq!(stack.pattern, ScriptPattern::Pay2MultiSig);
+        let script = eval_from_stack(stack, 0x00);
+        assert_eq!(script.destinations.len(), 3);
+        assert_eq!(script.destinations[0].kind, DestinationKind::PubKey);
+        assert!(!script.has_single_owner());
+        // No single address, the destinations are listed separately
+        assert_eq!("", script.address());
+        assert_eq!(script.addresses().join(","), script.multi_addresses());
+        match script.pattern {
+            ScriptPattern::Pay2MultiSig { m, n, ref pubkeys } => {
+                assert_eq!((2, 3), (m, n));
+                assert_eq!(3, pubkeys.len());
+                assert_eq!(0x02, pubkeys[0][0]);
+            }
+            _ => panic!("Expected Pay2MultiSig, got {}", script.pattern)
+        }
+        assert_eq!(format!("{}", script.pattern), "Pay2MultiSig (2-of-3)");
+    }
+
+    #[test]
+    fn test_bitcoin_script_p2ms_generic() {
+        let key = [0x02; 33];
+        // OP_1 <key> OP_1 OP_CHECKMULTISIG
+        let mut bytes = vec![0x51, 0x21];
+        bytes.extend_from_slice(&key);
+        bytes.extend_from_slice(&[0x51, 0xae]);
+        let script = eval_from_bytes(&bytes, 0x00);
+        assert_eq!(script.pattern, ScriptPattern::Pay2MultiSig { m: 1, n: 1, pubkeys: vec![key.to_vec()] });
+        assert_eq!(script.destinations.len(), 1);
+
+        // m > n is not a valid multisig
+        bytes[0] = 0x52;
+        assert_eq!(eval_from_bytes(&bytes, 0x00).pattern, ScriptPattern::NotRecognised);
+
+        // n doesn't match the number of keys
+        let mut bytes = vec![0x51, 0x21];
+        bytes.extend_from_slice(&key);
+        bytes.extend_from_slice(&[0x52, 0xae]);
+        assert_eq!(eval_from_bytes(&bytes, 0x00).pattern, ScriptPattern::NotRecognised);
+
+        // 1-of-17 with n as data push
+        let mut bytes = vec![0x51];
+        for _ in 0..17 {
+            bytes.push(0x21);
+            bytes.extend_from_slice(&key);
+        }
+        bytes.extend_from_slice(&[0x01, 0x11, 0xae]);
+        assert_eq!(eval_from_bytes(&bytes, 0x00).pattern, ScriptPattern::NotRecognised);
+
+        // m and n as single byte pushes instead of OP_1
+        let mut bytes = vec![0x01, 0x01, 0x21];
+        bytes.extend_from_slice(&key);
+        bytes.extend_from_slice(&[0x01, 0x01, 0xae]);
+        assert_eq!(eval_from_bytes(&bytes, 0x00).pattern, ScriptPattern::NotRecognised);
     }
 
     #[test]
@@ -526,10 +1005,33 @@ mod tests {
         assert_eq!("OP_HASH160 e9c3dd0c07aac76179ebc76a6c78d4d67c6c160a OP_EQUAL", format!("{:?}", stack));
 
         let script = eval_from_stack(stack, 0x00);
-        assert_eq!(script.address, "3P14159f73E4gFr7JterCCQh9QjiTjiZrG");
+        assert_eq!(script.addresses(), vec!["3P14159f73E4gFr7JterCCQh9QjiTjiZrG"]);
         assert_eq!(script.pattern, ScriptPattern::Pay2ScriptHash);
     }
 
+    #[test]
+    fn test_bitcoin_script_witness() {
+        let script = eval_from_bytes(&hex_to_vec("0014751e76e8199196d454941c45d1b3a323f1433bd6"), 0x00);
+        assert_eq!(script.pattern, ScriptPattern::Pay2WitnessPublicKeyHash);
+        assert_eq!(script.addresses(), vec!["bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"]);
+        assert_eq!(script.destinations[0].kind, DestinationKind::WitnessProgram(0));
+
+        let script = eval_from_bytes(&hex_to_vec("00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262"), 0x6f);
+        assert_eq!(script.pattern, ScriptPattern::Pay2WitnessScriptHash);
+        assert_eq!(script.addresses(), vec!["tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7"]);
+
+        // BIP341 wallet test vector
+        let script = eval_from_bytes(&hex_to_vec("512053a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343"), 0x00);
+        assert_eq!(script.pattern, ScriptPattern::Pay2Taproot);
+        assert_eq!(script.addresses(), vec!["bc1p2wsldez5mud2yam29q22wgfh9439spgduvct83k3pm50fcxa5dps59h4z5"]);
+        assert_eq!(script.destinations[0].kind, DestinationKind::WitnessProgram(1));
+        assert_eq!(script.destinations[0].data.len(), 32);
+
+        let script = eval_from_bytes(&hex_to_vec("6002751e"), 0x00);
+        assert_eq!(script.pattern, ScriptPattern::WitnessProgram(16));
+        assert_eq!(script.addresses(), vec!["bc1sw50qgdz25j"]);
+    }
+
     #[test]
     fn test_bitcoin_script_data_output() {
         // Raw output script: 6a13636861726c6579206c6f766573206865696469
@@ -542,8 +1044,19 @@ mod tests {
         assert_eq!("OP_RETURN 636861726c6579206c6f766573206865696469", format!("{:?}", stack));
 
         let script = eval_from_stack(stack, 0x00);
-        assert_eq!(script.address, "");
-        assert_eq!(script.pattern, ScriptPattern::DataOutput(String::from("charley loves heidi")));
+        assert!(script.destinations.is_empty());
+        assert_eq!(script.pattern, ScriptPattern::DataOutput(b"charley loves heidi".to_vec()));
+
+        // Binary data and multiple pushes: OP_RETURN OP_13 0x00ff OP_0 0x01
+        let script = eval_from_bytes(&hex_to_vec("6a5d0200ff000101"), 0x00);
+        assert_eq!(script.pattern, ScriptPattern::DataOutput(vec![0x0d, 0x00, 0xff, 0x01]));
+
+        let script = eval_from_bytes(&[0x6a], 0x00);
+        assert_eq!(script.pattern, ScriptPattern::DataOutput(Vec::new()));
+
+        // OP_RETURN followed by a non-push opcode
+        let script = eval_from_bytes(&hex_to_vec("6a0101ac"), 0x00);
+        assert_eq!(script.pattern, ScriptPattern::NotRecognised);
     }
 
     #[test]
@@ -556,7 +1069,7 @@ mod tests {
         assert_eq!("OP_IFDUP OP_IF OP_2SWAP OP_VERIFY OP_2OVER OP_DEPTH", format!("{:?}", stack));
 
         let script = eval_from_stack(stack, 0x00);
-        assert_eq!(script.address, "");
+        assert!(script.destinations.is_empty());
         assert_eq!(script.pattern, ScriptPattern::NotRecognised);
     }
 
@@ -564,7 +1077,105 @@ mod tests {
     fn test_bitcoin_bogus_script() {
         let bytes = [0x4c, 0xFF, 0x00];
         let script = eval_from_bytes(&bytes, 0x00);
-        assert_eq!(script.address, "");
+        assert!(script.destinations.is_empty());
         assert_eq!(script.pattern, ScriptPattern::Error(ScriptError::UnexpectedEof));
     }
+
+    // Dummy DER signature (with sighash byte) and the generator point as public key
+    const SIG: &'static str = "300602010102010101";
+    const PUBKEY: &'static str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    #[test]
+    fn test_input_p2pkh() {
+        let script_sig = hex_to_vec(&format!("09{}21{}", SIG, PUBKEY));
+        let input = eval_input(&script_sig, &[], None, 0x00);
+        assert_eq!(input.pattern, InputPattern::SignPublicKeyHash);
+        assert_eq!(input.signatures, vec![hex_to_vec(SIG)]);
+        assert_eq!(input.pubkeys, vec![hex_to_vec(PUBKEY)]);
+        assert_eq!(input.destination.unwrap().address, "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH");
+    }
+
+    #[test]
+    fn test_input_p2pk_and_multisig() {
+        let input = eval_input(&hex_to_vec(&format!("09{}", SIG)), &[], None, 0x00);
+        assert_eq!(input.pattern, InputPattern::SignPublicKey);
+        assert!(input.destination.is_none());
+
+        let input = eval_input(&hex_to_vec(&format!("0009{}09{}", SIG, SIG)), &[], None, 0x00);
+        assert_eq!(input.pattern, InputPattern::SignMultiSig);
+        assert_eq!(input.signatures.len(), 2);
+    }
+
+    #[test]
+    fn test_input_p2sh_multisig() {
+        // OP_0 <sig> <OP_1 <pubkey> OP_1 OP_CHECKMULTISIG>
+        let redeem_script = format!("5121{}51ae", PUBKEY);
+        let script_sig = hex_to_vec(&format!("0009{}25{}", SIG, redeem_script));
+        let input = eval_input(&script_sig, &[], None, 0x00);
+        assert_eq!(input.pattern, InputPattern::SignScriptHash);
+        assert_eq!(input.signatures, vec![hex_to_vec(SIG)]);
+        assert_eq!(input.pubkeys, vec![hex_to_vec(PUBKEY)]);
+        assert_eq!(input.script, Some(hex_to_vec(&redeem_script)));
+        assert_eq!(input.destination.unwrap().kind, DestinationKind::ScriptHash);
+    }
+
+    #[test]
+    fn test_input_witness() {
+        let witness = vec![hex_to_vec(SIG), hex_to_vec(PUBKEY)];
+        let input = eval_input(&[], &witness, None, 0x00);
+        assert_eq!(input.pattern, InputPattern::SignWitnessPublicKeyHash);
+        assert_eq!(input.destination.unwrap().address, "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4");
+
+        // P2SH-P2WPKH
+        let script_sig = hex_to_vec("160014751e76e8199196d454941c45d1b3a323f1433bd6");
+        let input = eval_input(&script_sig, &witness, None, 0x00);
+        assert_eq!(input.pattern, InputPattern::SignNestedWitnessPublicKeyHash);
+        assert_eq!(input.destination.unwrap().address, "3JvL6Ymt8MVWiCNHC7oWU6nLeHNJKLZGLN");
+
+        // P2WSH with OP_1 as witness script
+        let input = eval_input(&[], &[vec![], vec![0x51]], None, 0x00);
+        assert_eq!(input.pattern, InputPattern::SignWitnessScriptHash);
+        assert_eq!(input.script, Some(vec![0x51]));
+        assert_eq!(input.destination.unwrap().kind, DestinationKind::WitnessProgram(0));
+    }
+
+    #[test]
+    fn test_input_taproot() {
+        let mut p2tr = vec![0x51, 0x20];
+        p2tr.extend_from_slice(&[0x04; 32]);
+        let p2tr = Some(&p2tr[..]);
+        let sig = vec![0x01; 64];
+        let input = eval_input(&[], &[sig.clone()], p2tr, 0x00);
+        assert_eq!(input.pattern, InputPattern::SignTaprootKeyPath);
+        assert!(input.destination.is_none());
+        // Without the spent output a single item is taken as witness script
+        assert_eq!(eval_input(&[], &[sig.clone()], None, 0x00).pattern, InputPattern::SignWitnessScriptHash);
+
+        // With annex
+        let input = eval_input(&[], &[sig.clone(), vec![0x50, 0x00]], p2tr, 0x00);
+        assert_eq!(input.pattern, InputPattern::SignTaprootKeyPath);
+        assert_eq!(input.taproot.unwrap().annex, Some(vec![0x50, 0x00]));
+
+        // Control block which would also be a valid compressed public key
+        let mut control_block = vec![0x02];
+        control_block.extend_from_slice(&[0x02; 32]);
+        let input = eval_input(&[], &[vec![0x51], control_block.clone()], p2tr, 0x00);
+        assert_eq!(input.pattern, InputPattern::SignTaprootScriptPath);
+        let input = eval_input(&[], &[sig.clone(), vec![0x51], control_block], p2tr, 0x00);
+        assert_eq!(input.pattern, InputPattern::SignTaprootScriptPath);
+        assert_eq!(input.script, Some(vec![0x51]));
+        assert_eq!(input.signatures, vec![sig.clone()]);
+        let taproot = input.taproot.unwrap();
+        assert!(taproot.annex.is_none());
+        assert_eq!(taproot.script_path.unwrap().control_block.internal_key, [0x02; 32]);
+
+        assert_eq!(eval_input(&[], &[sig, vec![0x51], vec![0xc0]], p2tr, 0x00).pattern, InputPattern::NotRecognised);
+    }
+
+    #[test]
+    fn test_input_not_recognised() {
+        // OP_DUP is not a push
+        assert_eq!(eval_input(&[0x76], &[], None, 0x00).pattern, InputPattern::NotRecognised);
+        assert_eq!(eval_input(&[0x4c, 0xff], &[], None, 0x00).pattern, InputPattern::Error(ScriptError::UnexpectedEof));
+    }
 }
@@ -0,0 +1,239 @@
+use blockchain::proto::ToRaw;
+use blockchain::proto::interpreter;
+use blockchain::proto::script::{ScriptEvaluator, ScriptError, Stack};
+use blockchain::proto::varuint::VarUint;
+use blockchain::utils::sha256;
+
+/// Leaf version for tapscript (BIP342)
+pub const TAPROOT_LEAF_TAPSCRIPT: u8 = 0xc0;
+/// Annex is identified by its first byte
+pub const ANNEX_TAG: u8 = 0x50;
+
+const CONTROL_BLOCK_BASE_SIZE: usize = 33;
+const CONTROL_BLOCK_NODE_SIZE: usize = 32;
+const CONTROL_BLOCK_MAX_NODES: usize = 128;
+
+/// Decoded taproot control block
+/// Format: <leaf version | output key parity> <32 byte internal key> <32 byte hashes...>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlBlock {
+    pub leaf_version: u8,
+    pub output_key_parity: u8,
+    pub internal_key: [u8; 32],     // x-only internal key
+    pub merkle_path: Vec<[u8; 32]>
+}
+
+impl ControlBlock {
+    /// Parses the control block. Returns None if the size is invalid.
+    pub fn parse(data: &[u8]) -> Option<ControlBlock> {
+        if data.len() < CONTROL_BLOCK_BASE_SIZE ||
+           data.len() > CONTROL_BLOCK_BASE_SIZE + CONTROL_BLOCK_MAX_NODES * CONTROL_BLOCK_NODE_SIZE ||
+           (data.len() - CONTROL_BLOCK_BASE_SIZE) % CONTROL_BLOCK_NODE_SIZE != 0 {
+            return None;
+        }
+        let mut internal_key = [0u8; 32];
+        internal_key.copy_from_slice(&data[1..CONTROL_BLOCK_BASE_SIZE]);
+        let merkle_path = data[CONTROL_BLOCK_BASE_SIZE..]
+            .chunks(CONTROL_BLOCK_NODE_SIZE)
+            .map(|chunk| {
+                let mut node = [0u8; 32];
+                node.copy_from_slice(chunk);
+                node
+            })
+            .collect();
+        Some(ControlBlock {
+            leaf_version: data[0] & 0xfe,
+            output_key_parity: data[0] & 0x01,
+            internal_key: internal_key,
+            merkle_path: merkle_path
+        })
+    }
+
+    /// Computes the merkle root of the script tree for the given leaf hash
+    pub fn merkle_root(&self, leaf_hash: &[u8; 32]) -> [u8; 32] {
+        let mut k = *leaf_hash;
+        for node in &self.merkle_path {
+            // Children are sorted lexicographically
+            let mut data = Vec::with_capacity(64);
+            if &k[..] < &node[..] {
+                data.extend_from_slice(&k);
+                data.extend_from_slice(node);
+            } else {
+                data.extend_from_slice(node);
+                data.extend_from_slice(&k);
+            }
+            k = tagged_hash("TapBranch", &data);
+        }
+        k
+    }
+}
+
+/// Script path details of a taproot spend
+#[derive(Clone)]
+pub struct ScriptPathSpend {
+    pub control_block: ControlBlock,
+    pub script: Vec<u8>,
+    /// Tokenized leaf script, None for leaf versions other than tapscript
+    pub tapscript: Option<Result<Stack, ScriptError>>,
+    pub leaf_hash: [u8; 32]
+}
+
+/// Taproot specific data of an input
+#[derive(Clone)]
+pub struct TaprootSpend {
+    pub annex: Option<Vec<u8>>,
+    /// None for key path spends
+    pub script_path: Option<ScriptPathSpend>
+}
+
+impl TaprootSpend {
+    #[inline]
+    pub fn is_key_path(&self) -> bool {
+        self.script_path.is_none()
+    }
+}
+
+/// Removes the annex from the witness stack, if present (BIP341)
+pub fn split_annex(witness: &[Vec<u8>]) -> (&[Vec<u8>], Option<&Vec<u8>>) {
+    match witness.split_last() {
+        Some((last, rest)) if !rest.is_empty() && last.first() == Some(&ANNEX_TAG) => (rest, Some(last)),
+        _ => (witness, None)
+    }
+}
+
+/// Returns true if the script is a witness v1 program with a 32 byte output key (P2TR)
+pub fn is_taproot_output(script_pubkey: &[u8]) -> bool {
+    match interpreter::witness_program(script_pubkey) {
+        Some((1, program)) => program.len() == 32,
+        _ => false
+    }
+}
+
+/// Evaluates the witness of an input which spends `spent_script` (BIP341).
+/// Returns None if the spent output isn't P2TR or the witness is invalid for a taproot spend.
+pub fn eval_witness(witness: &[Vec<u8>], spent_script: &[u8]) -> Option<TaprootSpend> {
+    if !is_taproot_output(spent_script) {
+        return None;
+    }
+    let (stack, annex) = split_annex(witness);
+    let annex = annex.cloned();
+    match stack.len() {
+        0 => return None,
+        // The only item is the signature, its validity is not checked here
+        1 => return Some(TaprootSpend { annex: annex, script_path: None }),
+        _ => ()
+    }
+    let control_block = match ControlBlock::parse(&stack[stack.len() - 1]) {
+        Some(cb) => cb,
+        None => return None
+    };
+    let script = stack[stack.len() - 2].clone();
+    let leaf_hash = leaf_hash(control_block.leaf_version, &script);
+    // Unknown leaf versions are unencumbered, their scripts aren't tapscript
+    let tapscript = match control_block.leaf_version {
+        TAPROOT_LEAF_TAPSCRIPT => Some(ScriptEvaluator::new(&script).eval()),
+        _ => None
+    };
+    Some(TaprootSpend {
+        annex: annex,
+        script_path: Some(ScriptPathSpend {
+            control_block: control_block,
+            script: script,
+            tapscript: tapscript,
+            leaf_hash: leaf_hash
+        })
+    })
+}
+
+/// Computes the leaf hash: tagged_hash("TapLeaf", leaf_version || compact_size(script) || script)
+pub fn leaf_hash(leaf_version: u8, script: &[u8]) -> [u8; 32] {
+    let mut data = Vec::with_capacity(script.len() + 10);
+    data.push(leaf_version);
+    data.extend_from_slice(&VarUint::from(script.len()).to_bytes());
+    data.extend_from_slice(script);
+    tagged_hash("TapLeaf", &data)
+}
+
+/// BIP340 tagged hash: sha256(sha256(tag) || sha256(tag) || msg)
+pub fn tagged_hash(tag: &str, msg: &[u8]) -> [u8; 32] {
+    let tag_hash = sha256(tag.as_bytes());
+    let mut data = Vec::with_capacity(64 + msg.len());
+    data.extend_from_slice(&tag_hash);
+    data.extend_from_slice(&tag_hash);
+    data.extend_from_slice(msg);
+    sha256(&data)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::utils::{arr_to_hex, hex_to_vec};
+
+    #[test]
+    fn test_control_block() {
+        let mut data = vec![0xc1];
+        data.extend_from_slice(&[0x02; 32]);
+        data.extend_from_slice(&[0x03; 32]);
+        let cb = ControlBlock::parse(&data).unwrap();
+        assert_eq!(TAPROOT_LEAF_TAPSCRIPT, cb.leaf_version);
+        assert_eq!(1, cb.output_key_parity);
+        assert_eq!([0x02; 32], cb.internal_key);
+        assert_eq!(vec![[0x03; 32]], cb.merkle_path);
+
+        // Invalid sizes
+        assert!(ControlBlock::parse(&data[..32]).is_none());
+        assert!(ControlBlock::parse(&data[..34]).is_none());
+    }
+
+    #[test]
+    fn test_leaf_hash() {
+        // BIP341 wallet test vector (scriptPubKey index 1)
+        let script = hex_to_vec("20b617298552a72ade070667e86ca63b8f5789a9fe8731ef91202a91c9f3459007ac");
+        assert_eq!("c525714a7f49c28aedbbba78c005931a81c234b2f6c99a73e4d06082adc8bf2b",
+                   arr_to_hex(&leaf_hash(TAPROOT_LEAF_TAPSCRIPT, &script)));
+    }
+
+    #[test]
+    fn test_eval_witness() {
+        let mut p2tr = vec![0x51, 0x20];
+        p2tr.extend_from_slice(&[0x04; 32]);
+        let sig = vec![0x01; 64];
+        let spend = eval_witness(&[sig.clone(), vec![ANNEX_TAG, 0x01]], &p2tr).unwrap();
+        assert!(spend.is_key_path());
+        assert_eq!(Some(vec![ANNEX_TAG, 0x01]), spend.annex);
+        // Any single item is a key path spend, regardless of its size
+        assert!(eval_witness(&[vec![0x01; 10]], &p2tr).unwrap().is_key_path());
+        assert!(eval_witness(&[vec![ANNEX_TAG]], &p2tr).unwrap().is_key_path());
+        assert!(eval_witness(&[], &p2tr).is_none());
+
+        // Signatures spending P2WSH or other witness versions aren't taproot
+        let mut p2wsh = vec![0x00, 0x20];
+        p2wsh.extend_from_slice(&[0x04; 32]);
+        assert!(eval_witness(&[sig.clone()], &p2wsh).is_none());
+        assert!(eval_witness(&[sig.clone()], &p2tr[..33]).is_none());
+
+        let mut control_block = vec![0xc0];
+        control_block.extend_from_slice(&[0x02; 32]);
+        let spend = eval_witness(&[sig.clone(), vec![0x51], control_block.clone()], &p2tr).unwrap();
+        assert!(!spend.is_key_path());
+        assert!(spend.annex.is_none());
+        let script_path = spend.script_path.unwrap();
+        assert_eq!(vec![0x51], script_path.script);
+        assert!(script_path.control_block.merkle_path.is_empty());
+        // Without merkle path, the leaf hash is the merkle root
+        assert_eq!(script_path.leaf_hash, script_path.control_block.merkle_root(&script_path.leaf_hash));
+        assert_eq!("OP_PUSHNUM_1", format!("{:?}", script_path.tapscript.unwrap().unwrap()));
+
+        // Other leaf versions are accepted but not tokenized
+        control_block[0] = 0xc3;
+        let script_path = eval_witness(&[vec![0x51], control_block.clone()], &p2tr).unwrap().script_path.unwrap();
+        assert_eq!(0xc2, script_path.control_block.leaf_version);
+        assert_eq!(1, script_path.control_block.output_key_parity);
+        assert!(script_path.tapscript.is_none());
+
+        // Invalid control block size
+        control_block.push(0x00);
+        assert!(eval_witness(&[vec![0x51], control_block], &p2tr).is_none());
+    }
+}
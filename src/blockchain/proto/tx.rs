@@ -42,6 +42,22 @@ impl Tx {
         }
         return false;
     }
+
+    /// Returns the serialized size in bytes, including witness data (BIP144)
+    pub fn size(&self) -> usize {
+        let mut size = self.to_bytes().len();
+        if self.inputs.iter().any(|i| !i.witness.is_empty()) {
+            // Marker and flag
+            size += 2;
+            for input in &self.inputs {
+                size += VarUint::from(input.witness.len()).to_bytes().len();
+                for item in &input.witness {
+                    size += VarUint::from(item.len()).to_bytes().len() + item.len();
+                }
+            }
+        }
+        size
+    }
 }
 
 impl fmt::Debug for Tx {
@@ -116,6 +132,19 @@ pub struct TxInput {
     pub script_len: VarUint,
     pub script_sig: Vec<u8>,
     pub seq_no: u32,
+    pub witness: Vec<Vec<u8>>, // Witness stack (empty for non-segwit transactions)
+}
+
+impl TxInput {
+    /// Evaluates scriptSig and witness to determine the spend type.
+    /// `spent_script` is the scriptPubKey of the spent output, if known (required to identify taproot spends).
+    #[inline]
+    pub fn eval_script(&self, spent_script: Option<&[u8]>, version_id: u8) -> script::EvaluatedInput {
+        if self.outpoint.txid == [0u8; 32] && self.outpoint.index == 0xFFFFFFFF {
+            return script::EvaluatedInput::new(script::InputPattern::Coinbase);
+        }
+        script::eval_input(&self.script_sig, &self.witness, spent_script, version_id)
+    }
 }
 
 impl ToRaw for TxInput {
@@ -137,6 +166,7 @@ impl fmt::Debug for TxInput {
             .field("script_len", &self.script_len)
             .field("script_sig", &self.script_sig)
             .field("seq_no", &self.seq_no)
+            .field("witness_items", &self.witness.len())
             .finish()
     }
 }
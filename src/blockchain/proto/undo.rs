@@ -0,0 +1,470 @@
+use std::io::{self, Read};
+
+use byteorder::ReadBytesExt;
+
+use errors::{OpError, OpErrorKind, OpResult};
+use blockchain::proto::tx::{EvaluatedTxOut, TxOutput};
+use blockchain::proto::varuint::VarUint;
+
+/// Larger scripts are unspendable and stored as a single OP_RETURN by Bitcoin Core
+const MAX_SCRIPT_SIZE: u64 = 10000;
+
+/// Output which is spent by a transaction input, restored from the undo data in rev*.dat files
+#[derive(Clone)]
+pub struct SpentOutput {
+    pub height: u32,        // Height of the block which created the output
+    pub is_coinbase: bool,  // Output was created by a coinbase transaction
+    pub out: EvaluatedTxOut
+}
+
+/// Spent outputs of a block (CBlockUndo). Contains one entry for each transaction
+/// except the coinbase, with the spent outputs in the same order as the inputs.
+pub struct BlockUndo {
+    pub txs: Vec<Vec<SpentOutput>>
+}
+
+impl BlockUndo {
+    /// Decodes a serialized CBlockUndo
+    pub fn read<R: Read>(reader: &mut R, version_id: u8) -> OpResult<BlockUndo> {
+        let tx_count = try!(VarUint::read_from(reader)).value;
+        let mut txs = Vec::with_capacity(tx_count as usize);
+        for _ in 0..tx_count {
+            let out_count = try!(VarUint::read_from(reader)).value;
+            let mut outputs = Vec::with_capacity(out_count as usize);
+            for _ in 0..out_count {
+                outputs.push(try!(BlockUndo::read_spent_output(reader, version_id)));
+            }
+            txs.push(outputs);
+        }
+        Ok(BlockUndo { txs: txs })
+    }
+
+    /// Decodes a single Coin (see TxInUndoFormatter in Bitcoin Core)
+    fn read_spent_output<R: Read>(reader: &mut R, version_id: u8) -> OpResult<SpentOutput> {
+        let code = try!(read_varint(reader));
+        let height = (code >> 1) as u32;
+        if height > 0 {
+            // Transaction version of the old undo format, always 0 nowadays
+            try!(read_varint(reader));
+        }
+        Ok(SpentOutput {
+            height: height,
+            is_coinbase: code & 1 == 1,
+            out: try!(read_compressed_txout(reader, version_id))
+        })
+    }
+}
+
+/// Reads an output with compressed amount and script, as stored in undo data and the chainstate
+pub fn read_compressed_txout<R: Read>(reader: &mut R, version_id: u8) -> OpResult<EvaluatedTxOut> {
+    let value = decompress_amount(try!(read_varint(reader)));
+    let script_pubkey = try!(read_compressed_script(reader));
+    let out = TxOutput {
+        value: value,
+        script_len: VarUint::from(script_pubkey.len()),
+        script_pubkey: script_pubkey
+    };
+    Ok(EvaluatedTxOut::eval_script(out, version_id))
+}
+
+/// Reads Bitcoin Core's VARINT. Unlike VarUint it uses 7 bits per byte, with the MSB as continuation flag.
+/// Each continuation byte adds one, so there is exactly one encoding for each number.
+pub fn read_varint<R: Read>(reader: &mut R) -> OpResult<u64> {
+    let mut n: u64 = 0;
+    loop {
+        let byte = try!(reader.read_u8());
+        if n > (u64::max_value() >> 7) {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg("VARINT exceeds 64 bits"));
+        }
+        n = (n << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        n += 1;
+    }
+}
+
+/// Reverts Bitcoin Core's amount compression, which exploits that most amounts are round numbers
+pub fn decompress_amount(x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    let mut x = x - 1;
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = match e < 9 {
+        true => {
+            let d = (x % 9) + 1;
+            x /= 9;
+            x * 10 + d
+        }
+        false => x + 1
+    };
+    while e > 0 {
+        n *= 10;
+        e -= 1;
+    }
+    n
+}
+
+/// Reads a script in Bitcoin Core's compressed format.
+/// Sizes 0 to 5 denote common script templates, larger sizes are followed by the raw script.
+pub fn read_compressed_script<R: Read>(reader: &mut R) -> OpResult<Vec<u8>> {
+    let size = try!(read_varint(reader));
+    let mut script = Vec::new();
+    match size {
+        // P2PKH: OP_DUP OP_HASH160 <20 bytes> OP_EQUALVERIFY OP_CHECKSIG
+        0x00 => {
+            script.extend_from_slice(&[0x76, 0xa9, 0x14]);
+            script.extend_from_slice(&try!(read_bytes(reader, 20)));
+            script.extend_from_slice(&[0x88, 0xac]);
+        }
+        // P2SH: OP_HASH160 <20 bytes> OP_EQUAL
+        0x01 => {
+            script.extend_from_slice(&[0xa9, 0x14]);
+            script.extend_from_slice(&try!(read_bytes(reader, 20)));
+            script.push(0x87);
+        }
+        // P2PK with compressed public key: <33 bytes> OP_CHECKSIG
+        0x02 | 0x03 => {
+            script.extend_from_slice(&[0x21, size as u8]);
+            script.extend_from_slice(&try!(read_bytes(reader, 32)));
+            script.push(0xac);
+        }
+        // P2PK with uncompressed public key, only the x coordinate and the parity of y are stored
+        0x04 | 0x05 => {
+            let x = try!(read_bytes(reader, 32));
+            let pubkey = match decompress_pubkey(&x, size == 0x05) {
+                Some(pubkey) => pubkey,
+                None => return Err(OpError::new(OpErrorKind::ValidateError)
+                    .join_msg("Invalid public key in compressed script"))
+            };
+            script.push(0x41);
+            script.extend_from_slice(&pubkey);
+            script.push(0xac);
+        }
+        // Oversized scripts are skipped without reading them into memory
+        _ if size - 6 > MAX_SCRIPT_SIZE => {
+            let len = size - 6;
+            if try!(io::copy(&mut reader.by_ref().take(len), &mut io::sink())) != len {
+                return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Truncated script in compressed format"));
+            }
+            script.push(0x6a);
+        }
+        _ => script = try!(read_bytes(reader, (size - 6) as usize))
+    }
+    Ok(script)
+}
+
+/// Writes Bitcoin Core's VARINT, see read_varint
+pub fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    let mut tmp = [0u8; 10];
+    let mut len = 0;
+    loop {
+        tmp[len] = (n & 0x7f) as u8 | if len > 0 { 0x80 } else { 0x00 };
+        if n <= 0x7f {
+            break;
+        }
+        n = (n >> 7) - 1;
+        len += 1;
+    }
+    out.extend(tmp[..len + 1].iter().rev());
+}
+
+/// Compresses an amount, see decompress_amount
+pub fn compress_amount(mut n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    let mut e = 0;
+    while n % 10 == 0 && e < 9 {
+        n /= 10;
+        e += 1;
+    }
+    match e < 9 {
+        true => {
+            let d = n % 10;
+            n /= 10;
+            1 + (n * 9 + d - 1) * 10 + e
+        }
+        false => 1 + (n - 1) * 10 + 9
+    }
+}
+
+/// Writes a script in Bitcoin Core's compressed format, see read_compressed_script
+pub fn write_compressed_script(out: &mut Vec<u8>, script: &[u8]) {
+    match script.len() {
+        25 if script[..3] == [0x76, 0xa9, 0x14] && script[23..] == [0x88, 0xac] => {
+            out.push(0x00);
+            out.extend_from_slice(&script[3..23]);
+            return;
+        }
+        23 if script[..2] == [0xa9, 0x14] && script[22] == 0x87 => {
+            out.push(0x01);
+            out.extend_from_slice(&script[2..22]);
+            return;
+        }
+        35 if script[0] == 0x21 && (script[1] == 0x02 || script[1] == 0x03) && script[34] == 0xac => {
+            out.extend_from_slice(&script[1..34]);
+            return;
+        }
+        // Only valid public keys can be restored from their x coordinate
+        67 if script[0] == 0x41 && script[1] == 0x04 && script[66] == 0xac => {
+            let odd = script[65] & 1 == 1;
+            if decompress_pubkey(&script[2..34], odd).map_or(false, |pubkey| pubkey[..] == script[1..66]) {
+                out.push(if odd { 0x05 } else { 0x04 });
+                out.extend_from_slice(&script[2..34]);
+                return;
+            }
+        }
+        _ => ()
+    }
+    write_varint(out, script.len() as u64 + 6);
+    out.extend_from_slice(script);
+}
+
+#[inline]
+fn read_bytes<R: Read>(reader: &mut R, len: usize) -> OpResult<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    try!(reader.read_exact(&mut bytes));
+    Ok(bytes)
+}
+
+/// Restores an uncompressed secp256k1 public key from its x coordinate by solving y² = x³ + 7
+pub fn decompress_pubkey(x: &[u8], odd: bool) -> Option<[u8; 65]> {
+    let x = field::from_be_bytes(x);
+    if field::geq(&x, &field::P) {
+        return None;
+    }
+    let y2 = field::add_small(&field::mul(&field::mul(&x, &x), &x), 7);
+    let mut y = field::pow(&y2, &field::SQRT_EXP);
+    if field::mul(&y, &y) != y2 {
+        return None;
+    }
+    if (y[0] & 1 == 1) != odd {
+        y = field::sub(&field::P, &y);
+    }
+    let mut pubkey = [0u8; 65];
+    pubkey[0] = 0x04;
+    pubkey[1..33].copy_from_slice(&field::to_be_bytes(&x));
+    pubkey[33..].copy_from_slice(&field::to_be_bytes(&y));
+    Some(pubkey)
+}
+
+/// Arithmetic modulo the secp256k1 field prime, just enough to decompress public keys.
+/// Numbers are stored as four 64 bit limbs, least significant first.
+mod field {
+    use byteorder::{BigEndian, ByteOrder};
+
+    pub type U256 = [u64; 4];
+
+    pub const P: U256 = [0xfffffffefffffc2f, 0xffffffffffffffff, 0xffffffffffffffff, 0xffffffffffffffff];
+    /// (P + 1) / 4, since P = 3 (mod 4) the square root of a is a^((P + 1) / 4)
+    pub const SQRT_EXP: U256 = [0xffffffffbfffff0c, 0xffffffffffffffff, 0xffffffffffffffff, 0x3fffffffffffffff];
+
+    pub fn from_be_bytes(bytes: &[u8]) -> U256 {
+        let mut n = [0u64; 4];
+        for i in 0..4 {
+            n[3 - i] = BigEndian::read_u64(&bytes[i * 8..]);
+        }
+        n
+    }
+
+    pub fn to_be_bytes(n: &U256) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for i in 0..4 {
+            BigEndian::write_u64(&mut bytes[i * 8..], n[3 - i]);
+        }
+        bytes
+    }
+
+    pub fn geq(a: &U256, b: &U256) -> bool {
+        for i in (0..4).rev() {
+            if a[i] != b[i] {
+                return a[i] > b[i];
+            }
+        }
+        true
+    }
+
+    pub fn sub(a: &U256, b: &U256) -> U256 {
+        let mut n = [0u64; 4];
+        let mut borrow = false;
+        for i in 0..4 {
+            let (v, b1) = a[i].overflowing_sub(b[i]);
+            let (v, b2) = v.overflowing_sub(borrow as u64);
+            n[i] = v;
+            borrow = b1 || b2;
+        }
+        n
+    }
+
+    /// Reduces a 512 bit number, using 2^256 = 2^32 + 977 (mod P)
+    fn reduce(t: &[u64; 8]) -> U256 {
+        const C: u128 = 0x1000003d1;
+        let mut n = [t[0], t[1], t[2], t[3]];
+        let mut hi = [t[4], t[5], t[6], t[7]];
+        loop {
+            let mut carry = 0u128;
+            for i in 0..4 {
+                let v = n[i] as u128 + hi[i] as u128 * C + carry;
+                n[i] = v as u64;
+                carry = v >> 64;
+            }
+            if carry == 0 {
+                break;
+            }
+            hi = [carry as u64, (carry >> 64) as u64, 0, 0];
+        }
+        if geq(&n, &P) {
+            n = sub(&n, &P);
+        }
+        n
+    }
+
+    pub fn mul(a: &U256, b: &U256) -> U256 {
+        let mut t = [0u64; 8];
+        for i in 0..4 {
+            let mut carry = 0u128;
+            for j in 0..4 {
+                let v = t[i + j] as u128 + a[i] as u128 * b[j] as u128 + carry;
+                t[i + j] = v as u64;
+                carry = v >> 64;
+            }
+            t[i + 4] = carry as u64;
+        }
+        reduce(&t)
+    }
+
+    pub fn add_small(a: &U256, b: u64) -> U256 {
+        let mut t = [a[0], a[1], a[2], a[3], 0, 0, 0, 0];
+        let mut carry = b as u128;
+        for i in 0..4 {
+            let v = t[i] as u128 + carry;
+            t[i] = v as u64;
+            carry = v >> 64;
+        }
+        t[4] = carry as u64;
+        reduce(&t)
+    }
+
+    pub fn pow(a: &U256, e: &U256) -> U256 {
+        let mut n = [1, 0, 0, 0];
+        for i in (0..256).rev() {
+            n = mul(&n, &n);
+            if (e[i / 64] >> (i % 64)) & 1 == 1 {
+                n = mul(&n, a);
+            }
+        }
+        n
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::utils;
+
+    #[test]
+    fn test_read_varint() {
+        assert_eq!(0, read_varint(&mut &[0x00][..]).unwrap());
+        assert_eq!(0x7f, read_varint(&mut &[0x7f][..]).unwrap());
+        assert_eq!(0x80, read_varint(&mut &[0x80, 0x00][..]).unwrap());
+        assert_eq!(0x3fff, read_varint(&mut &[0xfe, 0x7f][..]).unwrap());
+        assert_eq!(0x4000, read_varint(&mut &[0xff, 0x00][..]).unwrap());
+        assert!(read_varint(&mut &[0x80][..]).is_err());
+    }
+
+    #[test]
+    fn test_decompress_amount() {
+        // See compress_tests.cpp in Bitcoin Core
+        assert_eq!(0, decompress_amount(0x0));
+        assert_eq!(1, decompress_amount(0x1));
+        assert_eq!(1000000, decompress_amount(0x7));
+        assert_eq!(100000000, decompress_amount(0x9));
+        assert_eq!(5000000000, decompress_amount(0x32));
+        assert_eq!(2100000000000000, decompress_amount(0x1406f40));
+    }
+
+    #[test]
+    fn test_decompress_script() {
+        let mut p2pkh = vec![0x00];
+        p2pkh.extend_from_slice(&[0x11; 20]);
+        assert_eq!(utils::hex_to_vec("76a914111111111111111111111111111111111111111188ac"),
+                   read_compressed_script(&mut &p2pkh[..]).unwrap());
+
+        let raw = vec![0x06 + 2, 0x6a, 0x00];
+        assert_eq!(vec![0x6a, 0x00], read_compressed_script(&mut &raw[..]).unwrap());
+
+        // Oversized scripts are replaced by OP_RETURN, the reader continues after them
+        let mut oversized = Vec::new();
+        write_varint(&mut oversized, 6 + MAX_SCRIPT_SIZE + 1);
+        oversized.extend_from_slice(&[0x00; MAX_SCRIPT_SIZE as usize + 1]);
+        oversized.push(0xff);
+        let mut reader = &oversized[..];
+        assert_eq!(vec![0x6a], read_compressed_script(&mut reader).unwrap());
+        assert_eq!(&[0xff], reader);
+        // Truncated or bogus sizes fail without allocating
+        let mut bogus = Vec::new();
+        write_varint(&mut bogus, u32::max_value() as u64);
+        assert!(read_compressed_script(&mut &bogus[..]).is_err());
+
+        // Genesis coinbase output, the y coordinate is odd
+        let pubkey = utils::hex_to_vec(concat!(
+            "04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb6",
+            "49f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f"));
+        let mut p2pk = vec![0x05];
+        p2pk.extend_from_slice(&pubkey[1..33]);
+        let script = read_compressed_script(&mut &p2pk[..]).unwrap();
+        assert_eq!(&pubkey[..], &script[1..66]);
+        assert_eq!(0xac, script[66]);
+        assert!(decompress_pubkey(&pubkey[1..33], false).unwrap()[33..] != pubkey[33..]);
+    }
+
+    #[test]
+    fn test_compress() {
+        let mut out = Vec::new();
+        for &n in &[0, 0x7f, 0x80, 0x3fff, 0x4000, u64::max_value()] {
+            out.clear();
+            write_varint(&mut out, n);
+            assert_eq!(n, read_varint(&mut &out[..]).unwrap());
+        }
+        for &n in &[0, 1, 1000000, 5000000000, 2100000000000000, 123456789] {
+            assert_eq!(n, decompress_amount(compress_amount(n)));
+        }
+        assert_eq!(0x32, compress_amount(5000000000));
+
+        let pubkey = concat!(
+            "04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb6",
+            "49f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f");
+        let scripts = [
+            (format!("41{}ac", pubkey), 33),
+            // Not on the curve
+            (format!("41{}ac", &pubkey.replace("d5f", "d5e")), 68),
+            (String::from("76a914111111111111111111111111111111111111111188ac"), 21),
+            (String::from("a914111111111111111111111111111111111111111187"), 21),
+            (String::from("00141111111111111111111111111111111111111111"), 23)];
+        for &(ref hex, size) in &scripts {
+            let script = utils::hex_to_vec(hex);
+            out.clear();
+            write_compressed_script(&mut out, &script);
+            assert_eq!(size, out.len());
+            assert_eq!(script, read_compressed_script(&mut &out[..]).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_block_undo() {
+        // One transaction spending a coinbase output of block 100 (2 * 100 + 1 = 0xc9)
+        let mut bytes = vec![0x01, 0x01, 0x80, 0x49, 0x00, 0x32, 0x01];
+        bytes.extend_from_slice(&[0x22; 20]);
+        let undo = BlockUndo::read(&mut &bytes[..], 0x00).unwrap();
+        assert_eq!(1, undo.txs.len());
+        let spent = &undo.txs[0][0];
+        assert_eq!(100, spent.height);
+        assert!(spent.is_coinbase);
+        assert_eq!(5000000000, spent.out.out.value);
+        assert_eq!(23, spent.out.out.script_pubkey.len());
+    }
+}
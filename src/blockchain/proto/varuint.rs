@@ -67,6 +67,18 @@ impl From<u64> for VarUint {
     }
 }
 
+/// Picks the shortest encoding for the given length
+impl From<usize> for VarUint {
+    fn from(value: usize) -> Self {
+        match value as u64 {
+            0...0xfc => VarUint::from(value as u8),
+            0xfd...0xffff => VarUint::from(value as u16),
+            0x10000...0xffffffff => VarUint::from(value as u32),
+            _ => VarUint::from(value as u64)
+        }
+    }
+}
+
 impl ToRaw for VarUint {
     fn to_bytes(&self) -> Vec<u8> {
         self.buf.clone()
@@ -133,6 +145,13 @@ mod tests {
         assert_eq!(vec![0xff, 0x00, 0x00, 0x84, 0xe2, 0x50, 0x6c, 0xe6, 0x7c], test.to_bytes());
     }
 
+    #[test]
+    fn test_varuint_usize() {
+        assert_eq!(vec![0xfc], VarUint::from(252usize).to_bytes());
+        assert_eq!(vec![0xfd, 0xfd, 0x00], VarUint::from(253usize).to_bytes());
+        assert_eq!(vec![0xfe, 0x00, 0x00, 0x01, 0x00], VarUint::from(0x10000usize).to_bytes());
+    }
+
     #[test]
     fn test_varuint_read() {
         let mut cursor = io::Cursor::new([0xfe, 0x55, 0xa1, 0xae, 0xc6]);
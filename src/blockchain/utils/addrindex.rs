@@ -0,0 +1,157 @@
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use errors::OpResult;
+use blockchain::utils;
+use blockchain::utils::sortedindex::{IndexRecord, SortedIndex, SortedIndexWriter};
+
+/// Sorted index file, see sortedindex for the layout
+const MAGIC: &'static [u8; 8] = b"ADDRIDX1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Direction {
+    /// Output paying to the address
+    Received = 0,
+    /// Input spending an output of the address
+    Spent = 1
+}
+
+/// Single history entry of an address
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AddrRecord {
+    /// Hash160 of the address string, see `key_for`
+    pub key: [u8; 20],
+    pub height: u32,
+    pub txid: [u8; 32],
+    /// vout for received, vin for spent records
+    pub index: u32,
+    pub direction: Direction,
+    pub value: u64
+}
+
+impl IndexRecord for AddrRecord {
+    const SIZE: u64 = 20 + 4 + 32 + 4 + 1 + 8;
+
+    #[inline]
+    fn key_byte(&self) -> u8 {
+        self.key[0]
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        try!(writer.write_all(&self.key));
+        try!(writer.write_u32::<LittleEndian>(self.height));
+        try!(writer.write_all(&self.txid));
+        try!(writer.write_u32::<LittleEndian>(self.index));
+        try!(writer.write_u8(self.direction as u8));
+        writer.write_u64::<LittleEndian>(self.value)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<AddrRecord> {
+        let mut key = [0u8; 20];
+        try!(reader.read_exact(&mut key));
+        let height = try!(reader.read_u32::<LittleEndian>());
+        let mut txid = [0u8; 32];
+        try!(reader.read_exact(&mut txid));
+        let index = try!(reader.read_u32::<LittleEndian>());
+        let direction = match try!(reader.read_u8()) {
+            0 => Direction::Received,
+            _ => Direction::Spent
+        };
+        let value = try!(reader.read_u64::<LittleEndian>());
+        Ok(AddrRecord { key: key, height: height, txid: txid, index: index, direction: direction, value: value })
+    }
+}
+
+/// Returns the index key for an address (or script hash) string
+#[inline]
+pub fn key_for(address: &str) -> [u8; 20] {
+    utils::ridemp160(&utils::sha256(address.as_bytes()))
+}
+
+/// Collects address records, see SortedIndexWriter
+pub struct AddrIndexWriter {
+    writer: SortedIndexWriter<AddrRecord>
+}
+
+impl AddrIndexWriter {
+    pub fn new(tmp_folder: PathBuf) -> AddrIndexWriter {
+        AddrIndexWriter { writer: SortedIndexWriter::new(MAGIC, "addrindex", tmp_folder) }
+    }
+
+    #[inline]
+    pub fn push(&mut self, record: AddrRecord) -> OpResult<()> {
+        self.writer.push(record)
+    }
+
+    /// Writes the index file. Returns the number of written records.
+    pub fn finish(self, path: &Path) -> OpResult<u64> {
+        self.writer.finish(path)
+    }
+}
+
+/// Read access to an index file written by AddrIndexWriter
+pub struct AddrIndex {
+    index: SortedIndex<AddrRecord>
+}
+
+impl AddrIndex {
+    pub fn open(path: &Path) -> OpResult<AddrIndex> {
+        Ok(AddrIndex { index: try!(SortedIndex::open(MAGIC, path)) })
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.index.len()
+    }
+
+    /// Returns all records of the given key, ordered by height
+    pub fn lookup(&mut self, key: &[u8; 20]) -> OpResult<Vec<AddrRecord>> {
+        self.index.find_all(key[0], |record| record.key.cmp(key))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn record(key: &str, height: u32, direction: Direction, value: u64) -> AddrRecord {
+        AddrRecord { key: key_for(key), height: height, txid: [height as u8; 32], index: 0, direction: direction, value: value }
+    }
+
+    #[test]
+    fn test_write_and_lookup() {
+        let folder = env::temp_dir().join("addrindex-test");
+        fs::create_dir_all(&folder).unwrap();
+
+        let mut writer = AddrIndexWriter::new(folder.clone());
+        let addresses = ["1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", "3P14159f73E4gFr7JterCCQh9QjiTjiZrG", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"];
+        for (i, address) in addresses.iter().enumerate() {
+            writer.push(record(address, 10 + i as u32, Direction::Spent, 50)).unwrap();
+            writer.push(record(address, i as u32, Direction::Received, 100)).unwrap();
+        }
+        // Force a second run
+        writer.writer.flush_run().unwrap();
+        writer.push(record(addresses[0], 20, Direction::Received, 7)).unwrap();
+
+        let path = folder.join("addrindex.bin");
+        assert_eq!(7, writer.finish(&path).unwrap());
+
+        let mut index = AddrIndex::open(&path).unwrap();
+        assert_eq!(7, index.len());
+        let records = index.lookup(&key_for(addresses[0])).unwrap();
+        assert_eq!(vec![0, 10, 20], records.iter().map(|r| r.height).collect::<Vec<u32>>());
+        assert_eq!(vec![Direction::Received, Direction::Spent, Direction::Received],
+                   records.iter().map(|r| r.direction).collect::<Vec<Direction>>());
+        for address in &addresses[1..] {
+            assert_eq!(2, index.lookup(&key_for(address)).unwrap().len());
+        }
+        assert!(index.lookup(&key_for("unknown")).unwrap().is_empty());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
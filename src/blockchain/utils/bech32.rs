@@ -0,0 +1,95 @@
+/// Bech32 (BIP173) and Bech32m (BIP350) encoding for segwit addresses
+
+const CHARSET: &'static [u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+
+fn polymod(values: &[u8]) -> u32 {
+    let mut chk: u32 = 1;
+    for &v in values {
+        let b = chk >> 25;
+        chk = (chk & 0x1ffffff) << 5 ^ v as u32;
+        for (i, g) in GENERATOR.iter().enumerate() {
+            if (b >> i) & 1 == 1 {
+                chk ^= *g;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut result: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    result.push(0);
+    result.extend(hrp.bytes().map(|b| b & 0x1f));
+    result
+}
+
+/// Regroups bits, e.g. from 8 bit bytes to 5 bit groups (with padding)
+fn convert_bits(data: &[u8], from: u32, to: u32) -> Vec<u8> {
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let max = (1 << to) - 1;
+    let mut result = Vec::with_capacity(data.len() * from as usize / to as usize + 1);
+    for &value in data {
+        acc = (acc << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            result.push(((acc >> bits) & max) as u8);
+        }
+    }
+    if bits > 0 {
+        result.push(((acc << (to - bits)) & max) as u8);
+    }
+    result
+}
+
+/// Encodes 5 bit values with the given human readable part
+fn encode(hrp: &str, data: &[u8], constant: u32) -> String {
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0u8; 6]);
+    let checksum = polymod(&values) ^ constant;
+
+    let mut result = String::with_capacity(hrp.len() + 1 + data.len() + 6);
+    result.push_str(hrp);
+    result.push('1');
+    for &d in data {
+        result.push(CHARSET[d as usize] as char);
+    }
+    for i in 0..6 {
+        result.push(CHARSET[((checksum >> (5 * (5 - i))) & 0x1f) as usize] as char);
+    }
+    result
+}
+
+/// Creates a segwit address. Version 0 uses Bech32, all later versions Bech32m.
+pub fn encode_segwit_address(hrp: &str, version: u8, program: &[u8]) -> String {
+    let mut data = vec![version];
+    data.extend(convert_bits(program, 8, 5));
+    let constant = if version == 0 { BECH32_CONST } else { BECH32M_CONST };
+    encode(hrp, &data, constant)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::encode_segwit_address;
+    use blockchain::utils::hex_to_vec;
+
+    #[test]
+    fn test_encode_segwit_address() {
+        // Test vectors from BIP173 and BIP350
+        assert_eq!("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+                   encode_segwit_address("bc", 0, &hex_to_vec("751e76e8199196d454941c45d1b3a323f1433bd6")));
+        assert_eq!("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+                   encode_segwit_address("tb", 0, &hex_to_vec("1863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262")));
+        assert_eq!("bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y",
+                   encode_segwit_address("bc", 1, &hex_to_vec("751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6")));
+        assert_eq!("bc1sw50qgdz25j",
+                   encode_segwit_address("bc", 16, &hex_to_vec("751e")));
+    }
+}
@@ -1,9 +1,11 @@
 use std::convert::From;
 use std::iter::FromIterator;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs::{self, File};
 use std::collections::VecDeque;
+use std::sync::Arc;
 
+use memmap::Mmap;
 use seek_bufread::BufReader;
 
 use errors::{OpError, OpErrorKind, OpResult};
@@ -25,12 +27,26 @@ impl BlkFile {
         }
     }
 
+    /// Opens blk file with the given index in path
+    pub fn open(path: &Path, index: u32) -> OpResult<BlkFile> {
+        let path = path.join(format!("blk{:05}.dat", index));
+        let size = try!(fs::metadata(&path)).len();
+        Ok(BlkFile::new(path, index, size))
+    }
+
     /// Returns a BufferedMemoryReader to reduce io wait.
     pub fn get_reader(&self) -> OpResult<BufReader<File>> {
         let f = try!(File::open(&self.path));
         Ok(BufReader::with_capacity(100000000, f))
     }
 
+    /// Maps the whole file into memory. Parsed data can borrow from it without copying.
+    pub fn mmap(&self) -> OpResult<BlkMmap> {
+        let f = try!(File::open(&self.path));
+        let mmap = try!(unsafe { Mmap::map(&f) });
+        Ok(BlkMmap::from(mmap))
+    }
+
     /// Collects all blk*.dat paths in the given directory
     pub fn from_path(path: PathBuf, min_blk_idx: u32) -> OpResult<VecDeque<BlkFile>> {
 
@@ -84,6 +100,30 @@ impl BlkFile {
     }
 }
 
+/// Memory mapped blk file, cheap to clone and shared between threads.
+/// Can also hold data which was read from the file, see RawBlock::read
+#[derive(Clone)]
+pub struct BlkMmap(Arc<AsRef<[u8]> + Send + Sync>);
+
+impl From<Mmap> for BlkMmap {
+    fn from(mmap: Mmap) -> BlkMmap {
+        BlkMmap(Arc::new(mmap))
+    }
+}
+
+impl From<Vec<u8>> for BlkMmap {
+    fn from(bytes: Vec<u8>) -> BlkMmap {
+        BlkMmap(Arc::new(bytes))
+    }
+}
+
+impl AsRef<[u8]> for BlkMmap {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        (*self.0).as_ref()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
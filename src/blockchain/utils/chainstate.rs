@@ -0,0 +1,151 @@
+use std::io::Read;
+use std::path::Path;
+
+use errors::{OpError, OpErrorKind, OpResult};
+use blockchain::proto::tx::EvaluatedTxOut;
+use blockchain::proto::undo::{read_varint, read_compressed_txout};
+use blockchain::utils::leveldb::{LevelDb, DbIter};
+
+/// Key of the obfuscation key, a length prefixed string
+const OBFUSCATE_KEY_KEY: &'static [u8] = b"\x0e\x00obfuscate_key";
+/// Key of the block hash up to which the chainstate is valid
+const DB_BEST_BLOCK: u8 = b'B';
+/// Prefix of unspent outputs: 'C' <txid> <VARINT(index)>
+const DB_COIN: u8 = b'C';
+/// Prefix of unspent transactions in the format prior to Bitcoin Core 0.15
+const DB_COINS: u8 = b'c';
+
+/// Unspent output from the chainstate or a UTXO snapshot
+pub struct Coin {
+    pub txid: [u8; 32],
+    pub index: u32,
+    pub height: u32,        // Height of the block which created the output
+    pub is_coinbase: bool,
+    pub out: EvaluatedTxOut
+}
+
+/// Bitcoin Core's UTXO set, stored in LevelDB in the `chainstate` directory.
+/// Values are XOR-ed with an obfuscation key to prevent false positives of anti virus software.
+pub struct ChainState {
+    db: LevelDb,
+    version_id: u8
+}
+
+impl ChainState {
+    pub fn open(dir: &Path, version_id: u8) -> OpResult<ChainState> {
+        Ok(ChainState { db: try!(LevelDb::open(dir)), version_id: version_id })
+    }
+
+    /// Iterates over all unspent outputs, ordered by txid (internal byte order) and index
+    pub fn coins(&self) -> OpResult<CoinIter> {
+        Ok(CoinIter {
+            entries: try!(self.db.iter()),
+            version_id: self.version_id,
+            obfuscate_key: Vec::new(),
+            best_block: None
+        })
+    }
+}
+
+pub struct CoinIter {
+    entries: DbIter,
+    version_id: u8,
+    obfuscate_key: Vec<u8>,
+    pub best_block: Option<[u8; 32]>    // Available after the first coin was read
+}
+
+impl Iterator for CoinIter {
+    type Item = OpResult<Coin>;
+
+    fn next(&mut self) -> Option<OpResult<Coin>> {
+        // Obfuscation key and best block are sorted before all coins
+        while let Some(entry) = self.entries.next() {
+            let (key, value) = match entry {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err))
+            };
+            if key == OBFUSCATE_KEY_KEY {
+                self.obfuscate_key = value.into_iter().skip(1).collect();
+                continue;
+            }
+            match key.first() {
+                Some(&DB_BEST_BLOCK) => {
+                    let value = deobfuscate(&self.obfuscate_key, value);
+                    if value.len() == 32 {
+                        let mut hash = [0u8; 32];
+                        hash.copy_from_slice(&value);
+                        self.best_block = Some(hash);
+                    }
+                }
+                Some(&DB_COIN) => {
+                    let value = deobfuscate(&self.obfuscate_key, value);
+                    return Some(decode_coin(&key, &value, self.version_id));
+                }
+                Some(&DB_COINS) => return Some(Err(OpError::new(OpErrorKind::ValidateError).join_msg(
+                    "Chainstate uses the format prior to Bitcoin Core 0.15. Start a newer node once to upgrade it."))),
+                _ => ()
+            }
+        }
+        None
+    }
+}
+
+fn deobfuscate(obfuscate_key: &[u8], mut value: Vec<u8>) -> Vec<u8> {
+    if !obfuscate_key.is_empty() {
+        for (i, byte) in value.iter_mut().enumerate() {
+            *byte ^= obfuscate_key[i % obfuscate_key.len()];
+        }
+    }
+    value
+}
+
+/// Decodes a coin from the chainstate, the key is 'C' <txid> <VARINT(index)>
+fn decode_coin(key: &[u8], value: &[u8], version_id: u8) -> OpResult<Coin> {
+    if key.len() < 34 {
+        return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Invalid coin key in chainstate"));
+    }
+    let mut txid = [0u8; 32];
+    txid.copy_from_slice(&key[1..33]);
+    let index = try!(read_varint(&mut &key[33..]));
+    read_coin(&mut &value[..], txid, index as u32, version_id)
+}
+
+/// Reads a serialized Coin: <VARINT(height * 2 + coinbase)> <compressed txout>
+pub fn read_coin<R: Read>(reader: &mut R, txid: [u8; 32], index: u32, version_id: u8) -> OpResult<Coin> {
+    let code = try!(read_varint(reader));
+    Ok(Coin {
+        txid: txid,
+        index: index,
+        height: (code >> 1) as u32,
+        is_coinbase: code & 1 == 1,
+        out: try!(read_compressed_txout(reader, version_id))
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_coin() {
+        let obfuscate_key = [0x10, 0x20, 0x30, 0x40, 0x50, 0x60, 0x70, 0x80];
+        let mut key = vec![DB_COIN];
+        key.extend_from_slice(&[0x11; 32]);
+        key.push(0x01);
+        // Height 100 (coinbase), 50 BTC to P2SH
+        let mut value = vec![0x80, 0x49, 0x32, 0x01];
+        value.extend_from_slice(&[0x22; 20]);
+        let obfuscated = deobfuscate(&obfuscate_key, value.clone());
+        assert_eq!(value, deobfuscate(&obfuscate_key, obfuscated));
+
+        let coin = decode_coin(&key, &value, 0x00).unwrap();
+        assert_eq!([0x11; 32], coin.txid);
+        assert_eq!(1, coin.index);
+        assert_eq!(100, coin.height);
+        assert!(coin.is_coinbase);
+        assert_eq!(5000000000, coin.out.out.value);
+        assert_eq!(23, coin.out.out.script_pubkey.len());
+        assert!(decode_coin(&key[..20], &value, 0x00).is_err());
+    }
+}
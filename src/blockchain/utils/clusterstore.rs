@@ -0,0 +1,365 @@
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hasher;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str;
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use memmap::Mmap;
+use twox_hash::XxHash;
+
+use errors::{OpError, OpErrorKind, OpResult};
+use blockchain::utils::utxoset::OutPoint;
+
+/// File layout (little endian):
+/// <magic> <version u32> followed by one segment per save:
+/// <n_addresses u32> <n_unions u32> <body_len u64> <checksum u64> [<len u8> <address>]... [<x u32> <y u32> <heuristic u8>]...
+/// Addresses are numbered in order of appearance across all segments, a union joins the clusters
+/// of two address ids and records the heuristic which caused it. The checksum is a XxHash64 over the body.
+const MAGIC: &'static [u8; 8] = b"RBPCLUST";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 8 + 4;
+const SEGMENT_HEADER_SIZE: usize = 4 + 4 + 8 + 8;
+
+/// Unspent outputs with the tags of their addresses, saved next to the cluster store:
+/// <magic> <version u32> <count u64> <height u64> [<txid> <index u32> <value u64> <script_type u8> <n u8> [<tag u32>]...]... <checksum u64>
+/// The height is the next block to process, it has to match the ChainStorage when resuming.
+/// The checksum is a XxHash64 over all records.
+const OUTPOINTS_MAGIC: &'static [u8; 8] = b"RBPOUTPT";
+const OUTPOINTS_VERSION: u32 = 1;
+
+/// Entry of a cluster store, in the order it was saved
+pub enum ClusterRecord<'a> {
+    Address(&'a str),
+    Union(u32, u32, u8)
+}
+
+/// Append-only store of interned addresses and the unions between them, see the layout above.
+/// Each save only appends the changes since the previous one.
+pub struct ClusterStore {
+    path: PathBuf,
+    len: u64,               // End of the last complete segment
+    pub n_addresses: u32,
+    pub n_unions: u64
+}
+
+impl ClusterStore {
+    /// Creates an empty store, an existing file is overwritten
+    pub fn create(path: &Path) -> OpResult<ClusterStore> {
+        let mut file = try!(File::create(path));
+        try!(file.write_all(MAGIC));
+        try!(file.write_u32::<LittleEndian>(VERSION));
+        try!(file.sync_all());
+        Ok(ClusterStore { path: PathBuf::from(path), len: HEADER_SIZE as u64, n_addresses: 0, n_unions: 0 })
+    }
+
+    /// Opens an existing store and passes all records to `f`. The file is memory mapped while reading.
+    /// An incomplete segment at the end (e.g. from an aborted save) is ignored and overwritten by the next save,
+    /// this includes a final segment with a checksum mismatch.
+    pub fn open<F>(path: &Path, mut f: F) -> OpResult<ClusterStore> where F: FnMut(ClusterRecord) {
+        let file = try!(File::open(path));
+        let mmap = try!(unsafe { Mmap::map(&file) });
+        if mmap.len() < HEADER_SIZE || &mmap[..8] != MAGIC {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                "{} is not a cluster store", path.display())));
+        }
+        let version = LittleEndian::read_u32(&mmap[8..]);
+        if version != VERSION {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                "Unsupported cluster store version {} in {}", version, path.display())));
+        }
+
+        let mut store = ClusterStore { path: PathBuf::from(path), len: HEADER_SIZE as u64, n_addresses: 0, n_unions: 0 };
+        let mut offset = HEADER_SIZE;
+        while offset + SEGMENT_HEADER_SIZE <= mmap.len() {
+            let header = &mmap[offset..offset + SEGMENT_HEADER_SIZE];
+            let n_addresses = LittleEndian::read_u32(&header[0..]);
+            let n_unions = LittleEndian::read_u32(&header[4..]);
+            let body_len = LittleEndian::read_u64(&header[8..]) as usize;
+            let start = offset + SEGMENT_HEADER_SIZE;
+            if body_len == 0 || start + body_len > mmap.len() {
+                break;
+            }
+            let body = &mmap[start..start + body_len];
+            if checksum(body) != LittleEndian::read_u64(&header[16..]) {
+                // The header of the last segment was written, but not all of its body
+                if start + body_len == mmap.len() {
+                    break;
+                }
+                return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                    "Checksum mismatch in cluster store {} at offset {}", path.display(), offset)));
+            }
+            try!(store.read_segment(body, n_addresses, n_unions, &mut f)
+                .map_err(|e| tag_err!(e, "Corrupted segment in cluster store {} at offset {}", path.display(), offset)));
+            offset = start + body_len;
+            store.len = offset as u64;
+        }
+        if offset < mmap.len() {
+            warn!(target: "clusterstore", "Ignoring incomplete segment at the end of {}", path.display());
+        }
+        Ok(store)
+    }
+
+    fn read_segment<F>(&mut self, body: &[u8], n_addresses: u32, n_unions: u32, f: &mut F) -> OpResult<()>
+        where F: FnMut(ClusterRecord)
+    {
+        let mut pos = 0;
+        for _ in 0..n_addresses {
+            let len = match body.get(pos) {
+                Some(len) => *len as usize,
+                None => return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Unexpected end of segment"))
+            };
+            if pos + 1 + len > body.len() {
+                return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Unexpected end of segment"));
+            }
+            let address = try!(str::from_utf8(&body[pos + 1..pos + 1 + len])
+                .map_err(|_| OpError::new(OpErrorKind::ValidateError).join_msg("Invalid address")));
+            f(ClusterRecord::Address(address));
+            self.n_addresses += 1;
+            pos += 1 + len;
+        }
+        if body.len() - pos != n_unions as usize * 9 {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Invalid segment length"));
+        }
+        for union in body[pos..].chunks(9) {
+            let x = LittleEndian::read_u32(&union[..4]);
+            let y = LittleEndian::read_u32(&union[4..8]);
+            if x >= self.n_addresses || y >= self.n_addresses {
+                return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Union of unknown address"));
+            }
+            f(ClusterRecord::Union(x, y, union[8]));
+        }
+        self.n_unions += n_unions as u64;
+        Ok(())
+    }
+
+    /// Appends the addresses and unions added since the previous save.
+    /// Returns the number of bytes written.
+    pub fn append(&mut self, addresses: &[&str], unions: &[(u32, u32, u8)]) -> OpResult<usize> {
+        if addresses.is_empty() && unions.is_empty() {
+            return Ok(0);
+        }
+        if let Some(address) = addresses.iter().find(|a| a.len() > u8::max_value() as usize) {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                "Address too long for the cluster store: {}", address)));
+        }
+
+        let mut file = try!(OpenOptions::new().write(true).open(&self.path));
+        // Drop an incomplete segment of an aborted save
+        try!(file.set_len(self.len));
+        try!(file.seek(SeekFrom::Start(self.len + SEGMENT_HEADER_SIZE as u64)));
+
+        // The body is streamed, the segment header is written once its length and checksum are known
+        let mut hasher = XxHash::with_seed(0);
+        let mut body_len = 0;
+        {
+            let mut writer = BufWriter::with_capacity(1 << 20, &mut file);
+            let mut buf = Vec::with_capacity(256);
+            for address in addresses {
+                buf.clear();
+                buf.push(address.len() as u8);
+                buf.extend_from_slice(address.as_bytes());
+                hasher.write(&buf);
+                try!(writer.write_all(&buf));
+                body_len += buf.len();
+            }
+            for &(x, y, heuristic) in unions {
+                let mut union = [0u8; 9];
+                LittleEndian::write_u32(&mut union[..4], x);
+                LittleEndian::write_u32(&mut union[4..8], y);
+                union[8] = heuristic;
+                hasher.write(&union);
+                try!(writer.write_all(&union));
+                body_len += union.len();
+            }
+            try!(writer.flush());
+        }
+        // The header is zero until the body is on disk, so an aborted save is detected on open
+        try!(file.sync_data());
+        let mut header = [0u8; SEGMENT_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header[0..], addresses.len() as u32);
+        LittleEndian::write_u32(&mut header[4..], unions.len() as u32);
+        LittleEndian::write_u64(&mut header[8..], body_len as u64);
+        LittleEndian::write_u64(&mut header[16..], hasher.finish());
+        try!(file.seek(SeekFrom::Start(self.len)));
+        try!(file.write_all(&header));
+        try!(file.sync_all());
+
+        let written = SEGMENT_HEADER_SIZE + body_len;
+        self.len += written as u64;
+        self.n_addresses += addresses.len() as u32;
+        self.n_unions += unions.len() as u64;
+        debug!(target: "clusterstore", "Appended {} addresses and {} unions to {}",
+               addresses.len(), unions.len(), self.path.display());
+        Ok(written)
+    }
+}
+
+/// Writes the unspent outputs tracked by the clusterizer up to `height`, the file is replaced atomically
+pub fn save_outpoints<'a, I>(path: &Path, height: u64, count: u64, outpoints: I) -> OpResult<()>
+    where I: Iterator<Item=(&'a OutPoint, u64, u8, &'a [u32])>
+{
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut writer = BufWriter::with_capacity(1 << 20, try!(File::create(&tmp_path)));
+        try!(writer.write_all(OUTPOINTS_MAGIC));
+        try!(writer.write_u32::<LittleEndian>(OUTPOINTS_VERSION));
+        try!(writer.write_u64::<LittleEndian>(count));
+        try!(writer.write_u64::<LittleEndian>(height));
+        let mut hasher = XxHash::with_seed(0);
+        let mut record = Vec::with_capacity(64);
+        let mut n_written = 0;
+        for (&(ref txid, index), value, script_type, tags) in outpoints {
+            record.clear();
+            record.extend_from_slice(txid);
+            try!(record.write_u32::<LittleEndian>(index));
+            try!(record.write_u64::<LittleEndian>(value));
+            record.push(script_type);
+            record.push(tags.len() as u8);
+            for tag in tags {
+                try!(record.write_u32::<LittleEndian>(*tag));
+            }
+            hasher.write(&record);
+            try!(writer.write_all(&record));
+            n_written += 1;
+        }
+        if n_written != count {
+            return Err(OpError::new(OpErrorKind::RuntimeError).join_msg("Outpoint count mismatch"));
+        }
+        try!(writer.write_u64::<LittleEndian>(hasher.finish()));
+        try!(try!(writer.into_inner().map_err(|e| e.into_error())).sync_all());
+    }
+    try!(fs::rename(&tmp_path, path));
+    Ok(())
+}
+
+/// Reads unspent outputs written by `save_outpoints` and passes them to `f`.
+/// Returns the number of outputs and the height.
+pub fn load_outpoints<F>(path: &Path, mut f: F) -> OpResult<(u64, u64)> where F: FnMut(OutPoint, u64, u8, &[u32]) {
+    let mut reader = BufReader::with_capacity(1 << 20, try!(File::open(path)));
+    let mut magic = [0u8; 8];
+    try!(reader.read_exact(&mut magic));
+    let version = try!(reader.read_u32::<LittleEndian>());
+    if &magic != OUTPOINTS_MAGIC || version != OUTPOINTS_VERSION {
+        return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+            "Unsupported outpoint file {}", path.display())));
+    }
+    let count = try!(reader.read_u64::<LittleEndian>());
+    let height = try!(reader.read_u64::<LittleEndian>());
+    let mut hasher = XxHash::with_seed(0);
+    let mut record = [0u8; 32 + 4 + 8 + 1 + 1];
+    let mut tags = Vec::new();
+    for _ in 0..count {
+        try!(reader.read_exact(&mut record));
+        hasher.write(&record);
+        let mut txid = [0u8; 32];
+        txid.copy_from_slice(&record[..32]);
+        let index = LittleEndian::read_u32(&record[32..36]);
+        let (value, script_type) = (LittleEndian::read_u64(&record[36..44]), record[44]);
+        let mut raw_tags = vec![0u8; record[45] as usize * 4];
+        try!(reader.read_exact(&mut raw_tags));
+        hasher.write(&raw_tags);
+        tags.clear();
+        tags.extend(raw_tags.chunks(4).map(LittleEndian::read_u32));
+        f((txid, index), value, script_type, &tags);
+    }
+    if try!(reader.read_u64::<LittleEndian>()) != hasher.finish() {
+        return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+            "Checksum mismatch in {}", path.display())));
+    }
+    Ok((count, height))
+}
+
+#[inline]
+fn checksum(body: &[u8]) -> u64 {
+    let mut hasher = XxHash::with_seed(0);
+    hasher.write(body);
+    hasher.finish()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn read_all(path: &Path) -> (ClusterStore, Vec<String>) {
+        let mut records = Vec::new();
+        let store = ClusterStore::open(path, |record| records.push(match record {
+            ClusterRecord::Address(address) => String::from(address),
+            ClusterRecord::Union(x, y, heuristic) => format!("{}-{}/{}", x, y, heuristic)
+        })).unwrap();
+        (store, records)
+    }
+
+    #[test]
+    fn test_cluster_store() {
+        let path = env::temp_dir().join(format!("clusterstore-test-{}.bin", ::std::process::id()));
+        let mut store = ClusterStore::create(&path).unwrap();
+        store.append(&["a", "bb"], &[(0, 1, 0)]).unwrap();
+        store.append(&[], &[]).unwrap();
+        store.append(&["c"], &[(2, 1, 3)]).unwrap();
+
+        let (mut store, records) = read_all(&path);
+        assert_eq!(vec!["a", "bb", "0-1/0", "c", "2-1/3"], records);
+        assert_eq!((3, 2), (store.n_addresses, store.n_unions));
+
+        // Aborted save, the incomplete segment is replaced by the next one
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[0u8; SEGMENT_HEADER_SIZE + 3]).unwrap();
+        let (_, records) = read_all(&path);
+        assert_eq!(5, records.len());
+        store.append(&["d"], &[]).unwrap();
+        assert_eq!(len + SEGMENT_HEADER_SIZE as u64 + 2, fs::metadata(&path).unwrap().len());
+        let (_, records) = read_all(&path);
+        assert_eq!("d", records[5]);
+
+        // The body of the last segment wasn't written completely, it is replaced as well
+        let mut bytes = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+        let (mut store, records) = read_all(&path);
+        assert_eq!(5, records.len());
+        store.append(&["e"], &[]).unwrap();
+        let (_, records) = read_all(&path);
+        assert_eq!("e", records[5]);
+        // A checksum mismatch within the file is an error
+        bytes[last] ^= 0xff;
+        bytes[HEADER_SIZE + SEGMENT_HEADER_SIZE] ^= 0xff;
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+        assert!(ClusterStore::open(&path, |_| ()).is_err());
+        bytes[HEADER_SIZE + SEGMENT_HEADER_SIZE] ^= 0xff;
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+        let (mut store, _) = read_all(&path);
+
+        // Unions must refer to known addresses
+        store.append(&[], &[(0, 9, 0)]).unwrap();
+        assert!(ClusterStore::open(&path, |_| ()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_outpoints() {
+        let path = env::temp_dir().join(format!("outpoints-test-{}.bin", ::std::process::id()));
+        let outpoints: Vec<(OutPoint, u64, u8, Vec<u32>)> =
+            vec![(([0x11; 32], 0), 5000, 2, vec![7]), (([0x22; 32], 3), 1, 1, vec![1, 2])];
+        save_outpoints(&path, 1234, 2, outpoints.iter().map(|&(ref op, value, script_type, ref tags)| {
+            (op, value, script_type, &tags[..])
+        })).unwrap();
+        let mut loaded = Vec::new();
+        assert_eq!((2, 1234), load_outpoints(&path, |op, value, script_type, tags| {
+            loaded.push((op, value, script_type, tags.to_vec()))
+        }).unwrap());
+        assert_eq!(outpoints, loaded);
+
+        let mut bytes = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes[30] ^= 0xff;
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+        assert!(load_outpoints(&path, |_, _, _, _| ()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
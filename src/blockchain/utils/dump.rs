@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::Json;
+
+use blockchain::proto::Hashed;
+use blockchain::proto::block::Block;
+use blockchain::proto::tx::Tx;
+use blockchain::utils::{arr_to_hex, arr_to_hex_swapped};
+
+/// Prints a human readable dump of a single transaction
+pub fn print_tx(tx: &Hashed<Tx>, version_id: u8) {
+    println!("txid:     {}", arr_to_hex_swapped(&tx.hash));
+    println!("version:  {}", tx.value.tx_version);
+    println!("locktime: {}", tx.value.tx_locktime);
+    println!("size:     {}", tx.value.size());
+    println!("inputs:");
+    for (i, input) in tx.value.inputs.iter().enumerate() {
+        // (vin, outpoint, spend type)
+        println!("   {};{}{}", i, input.outpoint, input.eval_script(None, version_id).pattern);
+    }
+    println!("outputs:");
+    for (i, output) in tx.value.outputs.iter().enumerate() {
+        // (vout, value, pattern, addresses)
+        println!("   {};{:.8};{};{}", i, output.out.value as f64 * 1E-8,
+                 output.script.pattern, output.script.addresses().join(","));
+    }
+}
+
+/// Prints a human readable dump of a block and all its transactions
+pub fn print_block(block: &Block, height: usize, version_id: u8) {
+    let header = &block.header.value;
+    println!("hash:        {}", arr_to_hex_swapped(&block.header.hash));
+    println!("height:      {}", height);
+    println!("location:    blk{:05}.dat @ {}", block.blk_index, block.blk_offset);
+    println!("size:        {}", block.blocksize);
+    println!("version:     0x{:08x}", header.version);
+    println!("prev_hash:   {}", arr_to_hex_swapped(&header.prev_hash));
+    println!("merkle_root: {}", arr_to_hex_swapped(&header.merkle_root));
+    println!("timestamp:   {}", header.timestamp);
+    println!("bits:        0x{:08x}", header.bits);
+    println!("nonce:       {}", header.nonce);
+    println!("tx_count:    {}", block.tx_count.value);
+    for tx in &block.txs {
+        println!("");
+        print_tx(tx, version_id);
+    }
+}
+
+/// Converts a transaction into a JSON object, similar to bitcoind's decoderawtransaction
+pub fn tx_to_json(tx: &Hashed<Tx>, version_id: u8) -> Json {
+    let mut inputs = Vec::with_capacity(tx.value.inputs.len());
+    for input in &tx.value.inputs {
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("txid"), Json::String(arr_to_hex_swapped(&input.outpoint.txid)));
+        obj.insert(String::from("vout"), Json::U64(input.outpoint.index as u64));
+        obj.insert(String::from("scriptSig"), Json::String(arr_to_hex(&input.script_sig)));
+        obj.insert(String::from("sequence"), Json::U64(input.seq_no as u64));
+        obj.insert(String::from("type"), Json::String(input.eval_script(None, version_id).pattern.to_string()));
+        if !input.witness.is_empty() {
+            obj.insert(String::from("witness"), Json::Array(input.witness.iter()
+                .map(|item| Json::String(arr_to_hex(item)))
+                .collect()));
+        }
+        inputs.push(Json::Object(obj));
+    }
+
+    let mut outputs = Vec::with_capacity(tx.value.outputs.len());
+    for (i, output) in tx.value.outputs.iter().enumerate() {
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("n"), Json::U64(i as u64));
+        obj.insert(String::from("value"), Json::U64(output.out.value));
+        obj.insert(String::from("scriptPubKey"), Json::String(arr_to_hex(&output.out.script_pubkey)));
+        obj.insert(String::from("type"), Json::String(output.script.pattern.to_string()));
+        obj.insert(String::from("addresses"), Json::Array(output.script.addresses().iter()
+            .map(|a| Json::String(String::from(*a)))
+            .collect()));
+        outputs.push(Json::Object(obj));
+    }
+
+    let mut obj = BTreeMap::new();
+    obj.insert(String::from("txid"), Json::String(arr_to_hex_swapped(&tx.hash)));
+    obj.insert(String::from("version"), Json::U64(tx.value.tx_version as u64));
+    obj.insert(String::from("locktime"), Json::U64(tx.value.tx_locktime as u64));
+    obj.insert(String::from("size"), Json::U64(tx.value.size() as u64));
+    obj.insert(String::from("vin"), Json::Array(inputs));
+    obj.insert(String::from("vout"), Json::Array(outputs));
+    Json::Object(obj)
+}
+
+/// Converts a block into a JSON object, similar to bitcoind's getblock with verbosity 2
+pub fn block_to_json(block: &Block, height: usize, version_id: u8) -> Json {
+    let header = &block.header.value;
+    let mut obj = BTreeMap::new();
+    obj.insert(String::from("hash"), Json::String(arr_to_hex_swapped(&block.header.hash)));
+    obj.insert(String::from("height"), Json::U64(height as u64));
+    obj.insert(String::from("size"), Json::U64(block.blocksize as u64));
+    obj.insert(String::from("version"), Json::U64(header.version as u64));
+    obj.insert(String::from("previousblockhash"), Json::String(arr_to_hex_swapped(&header.prev_hash)));
+    obj.insert(String::from("merkleroot"), Json::String(arr_to_hex_swapped(&header.merkle_root)));
+    obj.insert(String::from("time"), Json::U64(header.timestamp as u64));
+    obj.insert(String::from("bits"), Json::String(format!("{:08x}", header.bits)));
+    obj.insert(String::from("nonce"), Json::U64(header.nonce as u64));
+    obj.insert(String::from("tx"), Json::Array(block.txs.iter()
+        .map(|tx| tx_to_json(tx, version_id))
+        .collect()));
+    Json::Object(obj)
+}
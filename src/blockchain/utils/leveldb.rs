@@ -0,0 +1,617 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+use memmap::Mmap;
+
+use errors::{OpError, OpErrorKind, OpResult};
+
+/// Magic number at the end of each table file
+const TABLE_MAGIC: u64 = 0xdb4775248b80fb57;
+const FOOTER_SIZE: usize = 48;
+/// Logs (MANIFEST and write-ahead logs) are split into blocks of this size
+const LOG_BLOCK_SIZE: usize = 32768;
+const LOG_HEADER_SIZE: usize = 7;
+const NUM_LEVELS: usize = 7;
+
+/// User key, sequence number and value (None if the key was deleted)
+type Entry = (Vec<u8>, u64, Option<Vec<u8>>);
+
+/// Read-only view of a LevelDB database, e.g. Bitcoin Core's chainstate.
+/// Only supports iterating over all entries in key order, which merges the live tables
+/// listed in the MANIFEST with the write-ahead log. The database must not be opened by another process.
+pub struct LevelDb {
+    levels: Vec<Vec<PathBuf>>,  // Live tables of each level, levels > 0 are sorted by key
+    memtable: Vec<Entry>        // Entries of the write-ahead log which are not compacted yet
+}
+
+impl LevelDb {
+    pub fn open(dir: &Path) -> OpResult<LevelDb> {
+        let current = match fs::read_to_string(dir.join("CURRENT")) {
+            Ok(current) => current,
+            Err(err) => return Err(tag_err!(OpError::from(err), "Not a LevelDB directory: {}", dir.display()))
+        };
+        let manifest = try!(fs::read(dir.join(current.trim())));
+
+        // Replay all version edits to get the live tables
+        let mut tables: Vec<(usize, u64, Vec<u8>)> = Vec::new();
+        let mut log_number = 0;
+        let mut prev_log_number = 0;
+        for record in try!(read_log(&manifest)) {
+            let mut reader = &record[..];
+            while !reader.is_empty() {
+                match try!(read_varint(&mut reader)) {
+                    1 => { try!(read_slice(&mut reader)); }                 // Comparator
+                    2 => log_number = try!(read_varint(&mut reader)),
+                    3 | 4 => { try!(read_varint(&mut reader)); }            // Next file number, last sequence
+                    5 => {                                                  // Compaction pointer
+                        try!(read_varint(&mut reader));
+                        try!(read_slice(&mut reader));
+                    }
+                    6 => {                                                  // Deleted table
+                        let level = try!(read_varint(&mut reader)) as usize;
+                        let number = try!(read_varint(&mut reader));
+                        tables.retain(|t| t.0 != level || t.1 != number);
+                    }
+                    7 => {                                                  // New table
+                        let level = try!(read_varint(&mut reader)) as usize;
+                        let number = try!(read_varint(&mut reader));
+                        try!(read_varint(&mut reader));
+                        let smallest = try!(read_slice(&mut reader)).to_vec();
+                        try!(read_slice(&mut reader));
+                        tables.push((level, number, smallest));
+                    }
+                    9 => prev_log_number = try!(read_varint(&mut reader)),
+                    tag => return Err(OpError::new(OpErrorKind::ValidateError)
+                        .join_msg(&format!("Unknown tag in LevelDB manifest: {}", tag)))
+                }
+            }
+        }
+        tables.sort_by(|a, b| a.2.cmp(&b.2));
+        let mut levels = vec![Vec::new(); NUM_LEVELS];
+        for (level, number, _) in tables {
+            if level >= NUM_LEVELS {
+                return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Invalid level in LevelDB manifest"));
+            }
+            let path = dir.join(format!("{:06}.ldb", number));
+            levels[level].push(match path.exists() {
+                true => path,
+                false => dir.join(format!("{:06}.sst", number))
+            });
+        }
+
+        // Entries which are only in the write-ahead logs
+        let mut memtable = Vec::new();
+        for entry in try!(fs::read_dir(dir)) {
+            let path = try!(entry).path();
+            let number = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) if name.ends_with(".log") => name[..name.len() - 4].parse::<u64>().ok(),
+                _ => None
+            };
+            match number {
+                Some(n) if n >= log_number || n == prev_log_number => {
+                    for batch in try!(read_log(&try!(fs::read(&path)))) {
+                        try!(read_write_batch(&batch, &mut memtable));
+                    }
+                }
+                _ => ()
+            }
+        }
+        memtable.sort_by(|a, b| cmp_entries(a, b));
+        debug!(target: "leveldb", "Opened {} with {} tables and {} log entries",
+               dir.display(), levels.iter().map(|l| l.len()).sum::<usize>(), memtable.len());
+        Ok(LevelDb { levels: levels, memtable: memtable })
+    }
+
+    /// Iterates over all entries in key order
+    pub fn iter(&self) -> OpResult<DbIter> {
+        let mut sources: Vec<Box<Iterator<Item = OpResult<Entry>>>> = Vec::new();
+        sources.push(Box::new(self.memtable.clone().into_iter().map(Ok)));
+        // Level 0 tables may overlap, all other levels are one sorted run
+        for path in &self.levels[0] {
+            sources.push(Box::new(try!(TableIter::open(path))));
+        }
+        for level in &self.levels[1..] {
+            sources.push(Box::new(LevelIter { files: level.iter().cloned().collect(), table: None }));
+        }
+        let mut iter = DbIter {
+            values: vec![None; sources.len()],
+            sources: sources,
+            heap: BinaryHeap::new(),
+            last_key: None
+        };
+        for i in 0..iter.sources.len() {
+            try!(iter.advance(i));
+        }
+        Ok(iter)
+    }
+}
+
+/// Orders by user key and newest entry first
+fn cmp_entries(a: &Entry, b: &Entry) -> Ordering {
+    a.0.cmp(&b.0).then(b.1.cmp(&a.1))
+}
+
+/// Merges all sources, only the newest entry of each key is returned and deleted keys are skipped
+pub struct DbIter {
+    sources: Vec<Box<Iterator<Item = OpResult<Entry>>>>,
+    values: Vec<Option<Vec<u8>>>,                           // Value of the current entry of each source
+    heap: BinaryHeap<Reverse<(Vec<u8>, Reverse<u64>, usize)>>,
+    last_key: Option<Vec<u8>>
+}
+
+impl DbIter {
+    fn advance(&mut self, source: usize) -> OpResult<()> {
+        match self.sources[source].next() {
+            Some(Ok((key, seq, value))) => {
+                self.values[source] = value;
+                self.heap.push(Reverse((key, Reverse(seq), source)));
+            }
+            Some(Err(err)) => return Err(err),
+            None => ()
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for DbIter {
+    type Item = OpResult<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<OpResult<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            let (key, source) = match self.heap.pop() {
+                Some(Reverse((key, _, source))) => (key, source),
+                None => return None
+            };
+            let value = self.values[source].take();
+            if let Err(err) = self.advance(source) {
+                return Some(Err(err));
+            }
+            // Older versions of the previous key
+            if self.last_key.as_ref() == Some(&key) {
+                continue;
+            }
+            self.last_key = Some(key.clone());
+            if let Some(value) = value {
+                return Some(Ok((key, value)));
+            }
+        }
+    }
+}
+
+/// Iterates over the sorted, non-overlapping tables of a level. Tables are opened on demand.
+struct LevelIter {
+    files: VecDeque<PathBuf>,
+    table: Option<TableIter>
+}
+
+impl Iterator for LevelIter {
+    type Item = OpResult<Entry>;
+
+    fn next(&mut self) -> Option<OpResult<Entry>> {
+        loop {
+            let entry = match self.table {
+                Some(ref mut table) => table.next(),
+                None => None
+            };
+            if entry.is_some() {
+                return entry;
+            }
+            let path = match self.files.pop_front() {
+                Some(path) => path,
+                None => return None
+            };
+            match TableIter::open(&path) {
+                Ok(table) => self.table = Some(table),
+                Err(err) => return Some(Err(tag_err!(err, "Unable to open table: {}", path.display())))
+            }
+        }
+    }
+}
+
+/// Iterates over all entries of a table file (sstable)
+struct TableIter {
+    data: Mmap,
+    blocks: VecDeque<(usize, usize)>,   // Offset and size of the remaining data blocks
+    entries: ::std::vec::IntoIter<Entry>
+}
+
+impl TableIter {
+    fn open(path: &Path) -> OpResult<TableIter> {
+        let file = try!(File::open(path));
+        let data = try!(unsafe { Mmap::map(&file) });
+        if data.len() < FOOTER_SIZE || LittleEndian::read_u64(&data[data.len() - 8..]) != TABLE_MAGIC {
+            return Err(OpError::new(OpErrorKind::ValidateError)
+                .join_msg(&format!("Invalid table file: {}", path.display())));
+        }
+        let mut footer = &data[data.len() - FOOTER_SIZE..];
+        try!(read_block_handle(&mut footer));   // Metaindex
+        let index = try!(read_block(&data, try!(read_block_handle(&mut footer))));
+        let mut blocks = VecDeque::new();
+        for (_, handle) in try!(read_block_entries(&index)) {
+            blocks.push_back(try!(read_block_handle(&mut &handle[..])));
+        }
+        Ok(TableIter { data: data, blocks: blocks, entries: Vec::new().into_iter() })
+    }
+
+    fn read_next_block(&mut self) -> OpResult<bool> {
+        let handle = match self.blocks.pop_front() {
+            Some(handle) => handle,
+            None => return Ok(false)
+        };
+        let block = try!(read_block(&self.data, handle));
+        let mut entries = Vec::new();
+        for (key, value) in try!(read_block_entries(&block)) {
+            // Internal keys end with the sequence number and the value type
+            if key.len() < 8 {
+                return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Invalid internal key in table"));
+            }
+            let trailer = LittleEndian::read_u64(&key[key.len() - 8..]);
+            let value = match trailer & 0xff {
+                1 => Some(value),
+                _ => None
+            };
+            entries.push((key[..key.len() - 8].to_vec(), trailer >> 8, value));
+        }
+        self.entries = entries.into_iter();
+        Ok(true)
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = OpResult<Entry>;
+
+    fn next(&mut self) -> Option<OpResult<Entry>> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            match self.read_next_block() {
+                Ok(true) => (),
+                Ok(false) => return None,
+                Err(err) => return Some(Err(err))
+            }
+        }
+    }
+}
+
+fn read_block_handle(reader: &mut &[u8]) -> OpResult<(usize, usize)> {
+    let offset = try!(read_varint(reader)) as usize;
+    let size = try!(read_varint(reader)) as usize;
+    Ok((offset, size))
+}
+
+/// Reads a table block, followed by the compression type and a checksum
+fn read_block(data: &[u8], handle: (usize, usize)) -> OpResult<Vec<u8>> {
+    let (offset, size) = handle;
+    if offset + size + 5 > data.len() {
+        return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Table block exceeds the file"));
+    }
+    let contents = &data[offset..offset + size];
+    match data[offset + size] {
+        0 => Ok(contents.to_vec()),
+        1 => snappy_decompress(contents),
+        t => Err(OpError::new(OpErrorKind::ValidateError)
+            .join_msg(&format!("Unsupported compression type in table: {}", t)))
+    }
+}
+
+/// Decodes all key value pairs of a block. Keys share a prefix with the previous key,
+/// the restart points at the end of the block are only needed for seeking.
+fn read_block_entries(block: &[u8]) -> OpResult<Vec<(Vec<u8>, Vec<u8>)>> {
+    if block.len() < 4 {
+        return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Table block is too small"));
+    }
+    let n_restarts = LittleEndian::read_u32(&block[block.len() - 4..]) as usize;
+    let end = match block.len().checked_sub(4 + 4 * n_restarts) {
+        Some(end) => end,
+        None => return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Invalid restart array in table block"))
+    };
+    let mut reader = &block[..end];
+    let mut entries = Vec::new();
+    let mut key: Vec<u8> = Vec::new();
+    while !reader.is_empty() {
+        let shared = try!(read_varint(&mut reader)) as usize;
+        let non_shared = try!(read_varint(&mut reader)) as usize;
+        let value_len = try!(read_varint(&mut reader)) as usize;
+        if shared > key.len() || non_shared + value_len > reader.len() {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Invalid entry in table block"));
+        }
+        key.truncate(shared);
+        key.extend_from_slice(&reader[..non_shared]);
+        entries.push((key.clone(), reader[non_shared..non_shared + value_len].to_vec()));
+        reader = &reader[non_shared + value_len..];
+    }
+    Ok(entries)
+}
+
+/// Reads all complete records of a log file. Records may be fragmented over multiple blocks,
+/// an incomplete or corrupted record at the end is ignored.
+fn read_log(data: &[u8]) -> OpResult<Vec<Vec<u8>>> {
+    let mut records = Vec::new();
+    let mut fragments: Vec<u8> = Vec::new();
+    let mut pos = 0;
+    while pos + LOG_HEADER_SIZE <= data.len() {
+        let block_left = LOG_BLOCK_SIZE - pos % LOG_BLOCK_SIZE;
+        if block_left < LOG_HEADER_SIZE {
+            // Trailer
+            pos += block_left;
+            continue;
+        }
+        let checksum = LittleEndian::read_u32(&data[pos..]);
+        let len = LittleEndian::read_u16(&data[pos + 4..]) as usize;
+        let record_type = data[pos + 6];
+        let start = pos + LOG_HEADER_SIZE;
+        if record_type == 0 {
+            // Preallocated space
+            pos += block_left;
+            continue;
+        }
+        if start + len > data.len() || unmask_crc(checksum) != crc32c(&data[pos + 6..start + len]) {
+            warn!(target: "leveldb", "Ignoring incomplete log record at offset {}", pos);
+            break;
+        }
+        let payload = &data[start..start + len];
+        pos = start + len;
+        match record_type {
+            1 => records.push(payload.to_vec()),
+            2 => fragments = payload.to_vec(),
+            3 => fragments.extend_from_slice(payload),
+            4 => {
+                fragments.extend_from_slice(payload);
+                records.push(fragments.split_off(0));
+            }
+            t => return Err(OpError::new(OpErrorKind::ValidateError)
+                .join_msg(&format!("Invalid log record type: {}", t)))
+        }
+    }
+    Ok(records)
+}
+
+/// Decodes a write batch from the write-ahead log:
+/// <sequence u64> <count u32> followed by puts (1, key, value) and deletions (0, key)
+fn read_write_batch(batch: &[u8], entries: &mut Vec<Entry>) -> OpResult<()> {
+    if batch.len() < 12 {
+        return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Write batch is too small"));
+    }
+    let seq = LittleEndian::read_u64(batch);
+    let count = LittleEndian::read_u32(&batch[8..]) as u64;
+    let mut reader = &batch[12..];
+    for i in 0..count {
+        let value_type = try!(read_bytes(&mut reader, 1))[0];
+        let key = try!(read_slice(&mut reader)).to_vec();
+        let value = match value_type {
+            1 => Some(try!(read_slice(&mut reader)).to_vec()),
+            _ => None
+        };
+        entries.push((key, seq + i, value));
+    }
+    Ok(())
+}
+
+/// Reads a LEB128 varint (least significant group first), unlike Bitcoin Core's VARINT
+fn read_varint(reader: &mut &[u8]) -> OpResult<u64> {
+    let mut n: u64 = 0;
+    for shift in 0..10 {
+        let byte = try!(read_bytes(reader, 1))[0];
+        n |= ((byte & 0x7f) as u64) << (shift * 7);
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(OpError::new(OpErrorKind::ValidateError).join_msg("Invalid varint"))
+}
+
+fn read_slice<'a>(reader: &mut &'a [u8]) -> OpResult<&'a [u8]> {
+    let len = try!(read_varint(reader)) as usize;
+    read_bytes(reader, len)
+}
+
+fn read_bytes<'a>(reader: &mut &'a [u8], len: usize) -> OpResult<&'a [u8]> {
+    if len > reader.len() {
+        return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Unexpected end of LevelDB data"));
+    }
+    let (bytes, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(bytes)
+}
+
+/// Decompresses a snappy block. Bitcoin Core disables compression, but other databases may use it.
+fn snappy_decompress(data: &[u8]) -> OpResult<Vec<u8>> {
+    let mut reader = data;
+    let len = try!(read_varint(&mut reader)) as usize;
+    let mut out: Vec<u8> = Vec::with_capacity(len);
+    while !reader.is_empty() {
+        let tag = try!(read_bytes(&mut reader, 1))[0];
+        let (copy_len, offset) = match tag & 0x03 {
+            0 => {
+                let mut literal_len = (tag >> 2) as usize;
+                if literal_len >= 60 {
+                    let bytes = try!(read_bytes(&mut reader, literal_len - 59));
+                    literal_len = bytes.iter().rev().fold(0, |n, &b| (n << 8) | b as usize);
+                }
+                out.extend_from_slice(try!(read_bytes(&mut reader, literal_len + 1)));
+                continue;
+            }
+            1 => (((tag >> 2) & 0x07) as usize + 4,
+                  ((tag as usize >> 5) << 8) | try!(read_bytes(&mut reader, 1))[0] as usize),
+            2 => ((tag >> 2) as usize + 1, LittleEndian::read_u16(try!(read_bytes(&mut reader, 2))) as usize),
+            _ => ((tag >> 2) as usize + 1, LittleEndian::read_u32(try!(read_bytes(&mut reader, 4))) as usize)
+        };
+        if offset == 0 || offset > out.len() {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Invalid snappy copy offset"));
+        }
+        // Copies may overlap with their own output
+        let start = out.len() - offset;
+        for i in 0..copy_len {
+            let byte = out[start + i];
+            out.push(byte);
+        }
+    }
+    if out.len() != len {
+        return Err(OpError::new(OpErrorKind::ValidateError).join_msg("Invalid snappy block length"));
+    }
+    Ok(out)
+}
+
+/// CRC-32C (Castagnoli) as used for LevelDB log records
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0x82f63b78,
+                _ => crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[inline]
+fn unmask_crc(masked: u32) -> u32 {
+    let rot = masked.wrapping_sub(0xa282ead8);
+    (rot >> 17) | (rot << 15)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use super::*;
+    use blockchain::utils;
+
+    fn varint(mut n: u64, out: &mut Vec<u8>) {
+        while n >= 0x80 {
+            out.push((n as u8) | 0x80);
+            n >>= 7;
+        }
+        out.push(n as u8);
+    }
+
+    fn mask_crc(crc: u32) -> u32 {
+        ((crc >> 15) | (crc << 17)).wrapping_add(0xa282ead8)
+    }
+
+    /// Log file with a single record per entry
+    fn log(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for record in records {
+            let mut typed = vec![1u8];
+            typed.extend_from_slice(record);
+            data.extend_from_slice(&utils::le::u32_to_array(mask_crc(crc32c(&typed))));
+            data.extend_from_slice(&utils::le::u16_to_array(record.len() as u16));
+            data.extend_from_slice(&typed);
+        }
+        data
+    }
+
+    fn block(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for &(ref key, ref value) in entries {
+            varint(0, &mut data);
+            varint(key.len() as u64, &mut data);
+            varint(value.len() as u64, &mut data);
+            data.extend_from_slice(key);
+            data.extend_from_slice(value);
+        }
+        data.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0]);
+        data
+    }
+
+    /// Table with a single data block
+    fn table(entries: &[(Vec<u8>, u64, Option<Vec<u8>>)]) -> Vec<u8> {
+        let internal: Vec<(Vec<u8>, Vec<u8>)> = entries.iter().map(|&(ref key, seq, ref value)| {
+            let mut ikey = key.clone();
+            ikey.extend_from_slice(&utils::le::u64_to_array((seq << 8) | value.is_some() as u64));
+            (ikey, value.clone().unwrap_or(Vec::new()))
+        }).collect();
+        let mut data = block(&internal);
+        let data_size = data.len() as u64;
+        data.extend_from_slice(&[0u8; 5]);
+
+        let mut handle = Vec::new();
+        varint(0, &mut handle);
+        varint(data_size, &mut handle);
+        let index = block(&[(internal.last().unwrap().0.clone(), handle)]);
+        let index_offset = data.len() as u64;
+        data.extend_from_slice(&index);
+        data.extend_from_slice(&[0u8; 5]);
+
+        let mut footer = Vec::new();
+        varint(0, &mut footer);
+        varint(0, &mut footer);
+        varint(index_offset, &mut footer);
+        varint(index.len() as u64, &mut footer);
+        footer.resize(40, 0);
+        footer.extend_from_slice(&utils::le::u64_to_array(TABLE_MAGIC));
+        data.extend_from_slice(&footer);
+        data
+    }
+
+    fn new_table_edit(level: u64, number: u64, smallest: &[u8]) -> Vec<u8> {
+        let mut edit = Vec::new();
+        for &n in &[7, level, number, 100, smallest.len() as u64 + 8] {
+            varint(n, &mut edit);
+        }
+        edit.extend_from_slice(smallest);
+        edit.extend_from_slice(&[0u8; 8]);
+        varint(8, &mut edit);
+        edit.extend_from_slice(&[0u8; 8]);
+        edit
+    }
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(0xe3069283, crc32c(b"123456789"));
+        assert_eq!(0x12345678, unmask_crc(mask_crc(0x12345678)));
+    }
+
+    #[test]
+    fn test_snappy_decompress() {
+        let compressed = [0x0c, 0x08, b'a', b'b', b'c', 0x15, 0x03];
+        assert_eq!(b"abcabcabcabc".to_vec(), snappy_decompress(&compressed).unwrap());
+        assert!(snappy_decompress(&[0x0c, 0x15, 0x03]).is_err());
+    }
+
+    #[test]
+    fn test_leveldb() {
+        let dir = env::temp_dir().join("rusty-blockparser-leveldb");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+
+        // Level 1 contains a, b and c. Level 0 overwrites b and deletes c
+        fs::write(dir.join("000005.ldb"), table(&[
+            (b"a".to_vec(), 1, Some(b"1".to_vec())),
+            (b"b".to_vec(), 2, Some(b"2".to_vec())),
+            (b"c".to_vec(), 3, Some(b"3".to_vec()))])).unwrap();
+        fs::write(dir.join("000007.ldb"), table(&[
+            (b"b".to_vec(), 4, Some(b"4".to_vec())),
+            (b"c".to_vec(), 5, None)])).unwrap();
+        // This table was removed by a compaction
+        fs::write(dir.join("000006.ldb"), table(&[(b"z".to_vec(), 1, Some(b"0".to_vec()))])).unwrap();
+
+        let mut edit = vec![2, 8];
+        edit.extend(new_table_edit(1, 5, b"a"));
+        edit.extend(new_table_edit(0, 6, b"z"));
+        edit.extend(new_table_edit(0, 7, b"b"));
+        edit.extend_from_slice(&[6, 0, 6]);
+        fs::write(dir.join("MANIFEST-000002"), log(&[edit])).unwrap();
+        fs::write(dir.join("CURRENT"), "MANIFEST-000002\n").unwrap();
+
+        // The write-ahead log adds d and deletes a
+        let mut batch = Vec::new();
+        batch.extend_from_slice(&utils::le::u64_to_array(10));
+        batch.extend_from_slice(&utils::le::u32_to_array(2));
+        batch.extend_from_slice(&[1, 1, b'd', 1, b'5', 0, 1, b'a']);
+        fs::write(dir.join("000008.log"), log(&[batch])).unwrap();
+
+        let db = LevelDb::open(&dir).unwrap();
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = db.iter().unwrap().map(|e| e.unwrap()).collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(vec![(b"b".to_vec(), b"4".to_vec()), (b"d".to_vec(), b"5".to_vec())], entries);
+    }
+}
@@ -1,6 +1,7 @@
 use std::env;
 use std::path::PathBuf;
 
+use crypto::sha1::Sha1;
 use crypto::sha2::Sha256;
 use crypto::digest::Digest;
 use crypto::ripemd160::Ripemd160;
@@ -8,9 +9,20 @@ use rustc_serialize::hex::{ToHex, FromHex};
 
 use blockchain::parser::types::{CoinType};
 
+pub mod addrindex;
+pub mod bech32;
 pub mod blkfile;
-pub mod csv;
+pub mod chainstate;
+pub mod clusterstore;
+pub mod dump;
+pub mod leveldb;
 pub mod reader;
+pub mod revfile;
+pub mod secp256k1;
+pub mod sortedindex;
+pub mod txindex;
+pub mod utxoset;
+pub mod utxosnapshot;
 
 #[inline]
 pub fn ridemp160(data: &[u8]) -> [u8; 20]{
@@ -30,6 +42,15 @@ pub fn sha256(data: &[u8]) -> [u8; 32] {
     return out;
 }
 
+#[inline]
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut out = [0u8; 20];
+    let mut hasher = Sha1::new();
+    hasher.input(data);
+    hasher.result(&mut out);
+    return out;
+}
+
 /// Simple slice merge
 #[inline]
 pub fn merge_slices(a: &[u8], b: &[u8]) -> Vec<u8> {
@@ -127,6 +148,16 @@ pub fn hex_to_arr32_swapped(hex_str: &str) -> [u8; 32] {
     return arr;
 }
 
+/// Quotes a field of the `;` separated csv files if it contains a separator, quote or line break.
+/// Quotes inside the field are doubled (RFC 4180).
+pub fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ';' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        String::from(field)
+    }
+}
+
 /// Returns default directory. TODO: test on windows
 pub fn get_absolute_blockchain_dir(coin_type: &CoinType) -> PathBuf {
     PathBuf::from(env::home_dir().expect("Unable to get home path from env!"))
@@ -199,6 +230,13 @@ mod tests {
         assert_eq!(ridemp160(test.as_bytes()), expected);
     }
 
+    #[test]
+    fn test_csv_field() {
+        assert_eq!("text/plain", csv_field("text/plain"));
+        assert_eq!("\"text/plain;charset=utf-8\"", csv_field("text/plain;charset=utf-8"));
+        assert_eq!("\"a\"\"b\"", csv_field("a\"b"));
+    }
+
     #[test]
     fn test_merge_slices() {
         let test1 = [0x8c, 0xb1, 0xdf, 0x74, 0xdb, 0xe9, 0x80, 0xc6];
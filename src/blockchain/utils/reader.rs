@@ -1,4 +1,5 @@
-use std::io::{self};
+use std::cmp;
+use std::io::{self, Read};
 use std::borrow::BorrowMut;
 
 use byteorder::{LittleEndian, ReadBytesExt};
@@ -9,6 +10,12 @@ use blockchain::proto::block::Block;
 use blockchain::proto::header::BlockHeader;
 use blockchain::proto::tx::{Tx, TxOutpoint, TxInput, TxOutput};
 
+/// Witness items allocated up front, the count is read from untrusted data
+const MAX_WITNESS_PREALLOC: u64 = 64;
+/// Transactions, inputs and outputs allocated up front, their counts are read from untrusted data too
+const MAX_COUNT_PREALLOC: u64 = 4096;
+/// Bytes allocated up front for scripts and witness items, larger ones grow while they are read
+const MAX_VEC_PREALLOC: u64 = 0x10000;
 
 /// Trait for structured reading of blockchain data
 pub trait BlockchainRead: io::Read {
@@ -18,9 +25,19 @@ pub trait BlockchainRead: io::Read {
         Ok(arr)
     }
 
-    fn read_u8_vec(&mut self, count: u32) -> OpResult<Vec<u8>> {
-        let mut arr = vec![0u8; count as usize];
-        try!(self.read_exact(arr.borrow_mut()));
+    fn read_u8_vec(&mut self, count: u64) -> OpResult<Vec<u8>> {
+        if count <= MAX_VEC_PREALLOC {
+            let mut arr = vec![0u8; count as usize];
+            try!(self.read_exact(arr.borrow_mut()));
+            return Ok(arr);
+        }
+        // The length is read from untrusted data, only allocate what is actually there
+        let mut arr = Vec::with_capacity(MAX_VEC_PREALLOC as usize);
+        try!(Read::take(&mut *self, count).read_to_end(&mut arr));
+        if (arr.len() as u64) < count {
+            return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                "Length {} exceeds the remaining data ({} bytes)", count, arr.len())));
+        }
         Ok(arr)
     }
 
@@ -31,8 +48,10 @@ pub trait BlockchainRead: io::Read {
                   blocksize: u32,
                   version_id: u8) -> OpResult<Block> {
         let header = try!(self.read_block_header());
-        let tx_count = try!(VarUint::read_from(self));
-        let txs = try!(self.read_txs(tx_count.value, version_id));
+        // Lengths within the block can't reach into the following data
+        let mut reader = Read::take(&mut *self, (blocksize as u64).saturating_sub(80));
+        let tx_count = try!(VarUint::read_from(&mut reader));
+        let txs = try!(reader.read_txs(tx_count.value, version_id));
         Ok(Block::new(blk_index, blk_offset, blocksize, header, tx_count, txs))
     }
 
@@ -47,7 +66,7 @@ pub trait BlockchainRead: io::Read {
     }
 
     fn read_txs(&mut self, tx_count: u64, version_id: u8) -> OpResult<Vec<Tx>> {
-        let mut txs: Vec<Tx> = Vec::with_capacity(tx_count as usize);
+        let mut txs: Vec<Tx> = Vec::with_capacity(cmp::min(tx_count, MAX_COUNT_PREALLOC) as usize);
         for _ in 0..tx_count {
             let tx_version = try!(self.read_u32::<LittleEndian>());
             let marker = try!(self.read_u8());
@@ -65,17 +84,19 @@ pub trait BlockchainRead: io::Read {
                     _ => return Err(OpError::new(OpErrorKind::RuntimeError).join_msg("Invalid VarUint value")),
                 };
             }
-            let inputs = try!(self.read_tx_inputs(in_count.value));
+            let mut inputs = try!(self.read_tx_inputs(in_count.value));
             let out_count = try!(VarUint::read_from(self));
             let outputs = try!(self.read_tx_outputs(out_count.value));
             if marker == 0x00 {
-                // SegWit hack
-                for _ in 0..in_count.value {
+                // Witness data, one stack per input
+                for input in inputs.iter_mut() {
                     let item_count = try!(VarUint::read_from(self));
+                    let mut witness = Vec::with_capacity(cmp::min(item_count.value, MAX_WITNESS_PREALLOC) as usize);
                     for _ in 0..item_count.value {
                         let witness_len = try!(VarUint::read_from(self));
-                        let _ = try!(self.read_u8_vec(witness_len.value as u32));
+                        witness.push(try!(self.read_u8_vec(witness_len.value)));
                     }
+                    input.witness = witness;
                 }
             }
             let tx_locktime = try!(self.read_u32::<LittleEndian>());
@@ -98,11 +119,11 @@ pub trait BlockchainRead: io::Read {
     }
 
     fn read_tx_inputs(&mut self, input_count: u64) -> OpResult<Vec<TxInput>> {
-        let mut inputs: Vec<TxInput> = Vec::with_capacity(input_count as usize);
+        let mut inputs: Vec<TxInput> = Vec::with_capacity(cmp::min(input_count, MAX_COUNT_PREALLOC) as usize);
         for _ in 0..input_count {
             let outpoint = try!(self.read_tx_outpoint());
             let script_len = try!(VarUint::read_from(self));
-            let script_sig = try!(self.read_u8_vec(script_len.value as u32));
+            let script_sig = try!(self.read_u8_vec(script_len.value));
             let seq_no = try!(self.read_u32::<LittleEndian>());
 
             let input = TxInput {
@@ -110,6 +131,7 @@ pub trait BlockchainRead: io::Read {
                 script_len: script_len,
                 script_sig: script_sig,
                 seq_no: seq_no,
+                witness: Vec::new(),
             };
             inputs.push(input);
         }
@@ -117,11 +139,11 @@ pub trait BlockchainRead: io::Read {
     }
 
     fn read_tx_outputs(&mut self, output_count: u64) -> OpResult<Vec<TxOutput>> {
-        let mut outputs: Vec<TxOutput> = Vec::with_capacity(output_count as usize);
+        let mut outputs: Vec<TxOutput> = Vec::with_capacity(cmp::min(output_count, MAX_COUNT_PREALLOC) as usize);
         for _ in 0..output_count {
             let value = try!(self.read_u64::<LittleEndian>());
             let script_len = try!(VarUint::read_from(self));
-            let script_pubkey = try!(self.read_u8_vec(script_len.value as u32));
+            let script_pubkey = try!(self.read_u8_vec(script_len.value));
 
             let output = TxOutput {
                 value: value,
@@ -145,7 +167,7 @@ mod tests {
     use std::io::{Cursor};
     use byteorder::{LittleEndian, ReadBytesExt};
     use seek_bufread::BufReader;
-    use blockchain::utils::{arr_to_hex_swapped, arr_to_hex};
+    use blockchain::utils::{self, arr_to_hex_swapped, arr_to_hex};
     use blockchain::proto::script;
     use blockchain::parser::types::{Coin, Bitcoin};
 
@@ -272,7 +294,11 @@ mod tests {
                                 arr_to_hex(&script_pubkey));
         assert_eq!(0x00000000,  block.txs[0].value.tx_locktime);
 
-        assert_eq!("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", script::eval_from_bytes(script_pubkey, Bitcoin.version_id()).address);
+        // Tx location within the block
+        assert_eq!(vec![81],    block.tx_offsets());
+        assert_eq!(204,         block.txs[0].value.size());
+
+        assert_eq!("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", script::eval_from_bytes(script_pubkey, Bitcoin.version_id()).destinations[0].address);
 
                    /******* Genesis block raw data for reference (Most fields are little endian) *******
 version            0x01000000   big endian??
@@ -296,4 +322,64 @@ tx.out.script_pubkey      0x4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a6
 tx.lock_time       0x00000000
                    *********************************************************************************************************/
     }
+
+    #[test]
+    fn test_segwit_tx_size() {
+        let raw_tx = utils::hex_to_vec(concat!(
+            "01000000", "0001", "01", "1111111111111111111111111111111111111111111111111111111111111111",
+            "00000000", "00", "ffffffff", "01", "e803000000000000",
+            "16", "00142222222222222222222222222222222222222222",
+            "02", "03aabbcc", "01dd", "00000000"));
+        let mut reader = Cursor::new(raw_tx.clone());
+        let txs = reader.read_txs(1, Bitcoin.version_id()).unwrap();
+        assert_eq!(vec![vec![0xaa, 0xbb, 0xcc], vec![0xdd]], txs[0].inputs[0].witness);
+        assert_eq!(raw_tx.len(), txs[0].size());
+        assert_eq!(raw_tx.len() as u64, reader.position());
+    }
+
+    #[test]
+    fn test_witness_item_count_overflow() {
+        // Witness stack claiming 2^64 - 1 items
+        let raw_tx = utils::hex_to_vec(concat!(
+            "01000000", "0001", "01", "1111111111111111111111111111111111111111111111111111111111111111",
+            "00000000", "00", "ffffffff", "01", "e803000000000000",
+            "16", "00142222222222222222222222222222222222222222",
+            "ffffffffffffffffff", "01dd", "00000000"));
+        assert!(Cursor::new(raw_tx).read_txs(1, Bitcoin.version_id()).is_err());
+    }
+
+    #[test]
+    fn test_read_u8_vec_bounds() {
+        let data = vec![0x01; 0x20000];
+        assert_eq!(vec![0x01; 3], (&data[..]).read_u8_vec(3).unwrap());
+        assert_eq!(data, (&data[..]).read_u8_vec(data.len() as u64).unwrap());
+        // Not allocated up front
+        assert!((&data[..]).read_u8_vec(1 << 40).is_err());
+        assert!((&data[..10]).read_u8_vec(11).is_err());
+    }
+
+    #[test]
+    fn test_witness_item_exceeds_block() {
+        // Segwit transaction with one input, no outputs and a single witness item of 2^40 bytes
+        let mut tx = vec![0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01];
+        tx.extend_from_slice(&[0x00; 36]);
+        tx.extend_from_slice(&[0x00, 0xff, 0xff, 0xff, 0xff, 0x00, 0x01, 0xff]);
+        tx.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00]);
+        let mut block = vec![0x00; 80];
+        block.push(0x01);
+        block.extend_from_slice(&tx);
+        let blocksize = block.len() as u32;
+        // Followed by more data than the block, which must not be read as part of the item
+        block.extend_from_slice(&[0x00; 0x20000]);
+        assert!((&block[..]).read_block(0, 8, blocksize, 0x00).is_err());
+    }
+
+    #[test]
+    fn test_tx_count_overflow() {
+        // Header followed by a tx count of 2^64 - 1
+        let mut block = vec![0x00; 80];
+        block.extend_from_slice(&[0xff; 9]);
+        let blocksize = block.len() as u32;
+        assert!((&block[..]).read_block(0, 8, blocksize, 0x00).is_err());
+    }
 }
@@ -0,0 +1,194 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use memmap::Mmap;
+
+use errors::{OpError, OpErrorKind, OpResult};
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::undo::BlockUndo;
+use blockchain::proto::varuint::VarUint;
+use blockchain::utils::{sha256, arr_to_hex_swapped};
+
+/// Position of a serialized CBlockUndo within a rev file
+struct UndoRecord {
+    offset: usize,
+    size: usize,
+    tx_count: u64   // Number of transactions without the coinbase
+}
+
+/// Memory mapped rev file with the positions of all undo records.
+/// Layout of each record: <magic> <size u32> <CBlockUndo> <checksum>
+struct RevFile {
+    index: u32,
+    mmap: Mmap,
+    records: Vec<UndoRecord>,
+    next: usize     // Record after the latest match, blocks are mostly read in the same order
+}
+
+impl RevFile {
+    fn open(dir: &Path, index: u32, magic: u32) -> OpResult<RevFile> {
+        let path = dir.join(format!("rev{:05}.dat", index));
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) => return Err(tag_err!(OpError::from(err), "Unable to open {}", path.display()))
+        };
+        let mmap = try!(unsafe { Mmap::map(&file) });
+
+        let mut records = Vec::new();
+        let mut offset = 0;
+        // The file ends with zero padding or at the first incomplete record
+        while offset + 8 <= mmap.len() && LittleEndian::read_u32(&mmap[offset..]) == magic {
+            let size = LittleEndian::read_u32(&mmap[offset + 4..]) as usize;
+            let start = offset + 8;
+            if start + size + 32 > mmap.len() {
+                break;
+            }
+            let tx_count = try!(VarUint::read_from(&mut &mmap[start..start + size])).value;
+            records.push(UndoRecord { offset: start, size: size, tx_count: tx_count });
+            offset = start + size + 32;
+        }
+        debug!(target: "revfile", "Found {} undo records in rev{:05}.dat", records.len(), index);
+        Ok(RevFile { index: index, mmap: mmap, records: records, next: 0 })
+    }
+
+    /// Finds the record of a block by its checksum, which is the double sha256
+    /// over the previous block hash and the undo data. Returns offset and size.
+    fn find(&mut self, prev_hash: &[u8; 32], tx_count: u64) -> Option<(usize, usize)> {
+        let n_records = self.records.len();
+        for i in (0..n_records).map(|i| (self.next + i) % n_records) {
+            let record = &self.records[i];
+            if record.tx_count != tx_count {
+                continue;
+            }
+            let end = record.offset + record.size;
+            let mut hasher = Sha256::new();
+            hasher.input(prev_hash);
+            hasher.input(&self.mmap[record.offset..end]);
+            let mut hash = [0u8; 32];
+            hasher.result(&mut hash);
+            if sha256(&hash) == &self.mmap[end..end + 32] {
+                self.next = i + 1;
+                return Some((record.offset, record.size));
+            }
+        }
+        None
+    }
+}
+
+/// Reads the spent outputs of blocks from rev*.dat files.
+/// Bitcoin Core writes the undo data of a block to the rev file with the same index as its blk file,
+/// the exact position is only stored in the block index (LevelDB).
+pub struct RevReader {
+    blockchain_dir: PathBuf,
+    coin_type: CoinType,
+    file: Option<RevFile>   // Currently mapped rev file
+}
+
+impl RevReader {
+    pub fn new(blockchain_dir: PathBuf, coin_type: CoinType) -> RevReader {
+        RevReader {
+            blockchain_dir: blockchain_dir,
+            coin_type: coin_type,
+            file: None
+        }
+    }
+
+    /// Reads the undo data of the given block
+    pub fn read_undo(&mut self, block: &Block) -> OpResult<BlockUndo> {
+        let prev_hash = block.header.value.prev_hash;
+        // The genesis block doesn't spend anything
+        if prev_hash == [0u8; 32] {
+            return Ok(BlockUndo { txs: Vec::new() });
+        }
+        let tx_count = block.txs.len().saturating_sub(1) as u64;
+
+        let mut fresh = false;
+        if self.file.as_ref().map_or(true, |f| f.index != block.blk_index) {
+            self.file = Some(try!(RevFile::open(&self.blockchain_dir, block.blk_index, self.coin_type.magic)));
+            fresh = true;
+        }
+        let mut range = transform!(self.file.as_mut()).find(&prev_hash, tx_count);
+        // The node may have appended the record after the file was mapped
+        if range.is_none() && !fresh {
+            self.file = Some(try!(RevFile::open(&self.blockchain_dir, block.blk_index, self.coin_type.magic)));
+            range = transform!(self.file.as_mut()).find(&prev_hash, tx_count);
+        }
+        let (offset, size) = match range {
+            Some(range) => range,
+            None => return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                "No undo data for block {} in rev{:05}.dat", arr_to_hex_swapped(&block.header.hash), block.blk_index)))
+        };
+
+        let file = transform!(self.file.as_ref());
+        let undo = try!(BlockUndo::read(&mut &file.mmap[offset..offset + size], self.coin_type.version_id));
+        for (tx, spent) in block.txs.iter().skip(1).zip(&undo.txs) {
+            if tx.value.inputs.len() != spent.len() {
+                return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                    "Undo data of block {} doesn't match its inputs", arr_to_hex_swapped(&block.header.hash))));
+            }
+        }
+        Ok(undo)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use super::*;
+    use blockchain::utils;
+    use blockchain::utils::reader::BlockchainRead;
+
+    /// Block with a coinbase and a transaction with a single input
+    fn test_block() -> Block {
+        let tx = utils::hex_to_vec(concat!(
+            "01000000", "01", "1111111111111111111111111111111111111111111111111111111111111111",
+            "00000000", "00", "ffffffff", "01", "e803000000000000", "00", "00000000"));
+        let mut bytes = vec![0x01, 0x00, 0x00, 0x00];
+        bytes.extend_from_slice(&[0x33; 32]);
+        bytes.extend_from_slice(&[0u8; 44]);
+        bytes.push(2);
+        bytes.extend_from_slice(&tx);
+        bytes.extend_from_slice(&tx);
+        (&bytes[..]).read_block(0, 8, bytes.len() as u32, 0x00).unwrap()
+    }
+
+    fn record(magic: u32, prev_hash: &[u8], undo: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&utils::le::u32_to_array(magic));
+        bytes.extend_from_slice(&utils::le::u32_to_array(undo.len() as u32));
+        bytes.extend_from_slice(undo);
+        bytes.extend_from_slice(&sha256(&sha256(&utils::merge_slices(prev_hash, undo))));
+        bytes
+    }
+
+    #[test]
+    fn test_read_undo() {
+        let coin_type = CoinType::default();
+        let mut undo = vec![0x01, 0x01, 0x80, 0x49, 0x00, 0x32, 0x01];
+        undo.extend_from_slice(&[0x22; 20]);
+
+        // Record of another block with the same transaction count comes first
+        let mut content = record(coin_type.magic, &[0x44; 32], &undo);
+        content.extend_from_slice(&record(coin_type.magic, &[0x33; 32], &undo));
+        content.extend_from_slice(&[0u8; 64]);
+        let dir = env::temp_dir().join("rusty-blockparser-revfile");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("rev00000.dat"), &content).unwrap();
+
+        let mut reader = RevReader::new(dir.clone(), coin_type);
+        let result = reader.read_undo(&test_block());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let block_undo = result.unwrap();
+        assert_eq!(2, reader.file.as_ref().unwrap().next);
+        assert_eq!(1, block_undo.txs.len());
+        assert_eq!(100, block_undo.txs[0][0].height);
+        assert_eq!(5000000000, block_undo.txs[0][0].out.out.value);
+    }
+}
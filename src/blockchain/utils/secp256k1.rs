@@ -0,0 +1,295 @@
+/// ECDSA verification over secp256k1 for the script interpreter.
+/// Only public data is processed, so the arithmetic doesn't need to be constant time.
+
+use num_bigint::BigUint;
+
+use blockchain::proto::interpreter::SigVerifier;
+
+const P: &'static [u8] = b"fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f";
+const N: &'static [u8] = b"fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141";
+const GX: &'static [u8] = b"79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+const GY: &'static [u8] = b"483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+
+/// Verifies signatures like Bitcoin Core's CPubKey::Verify: the DER encoding is parsed laxly,
+/// high S values are accepted and hybrid public keys are allowed.
+/// Stricter encoding rules are up to the interpreter flags.
+pub struct Secp256k1Verifier;
+
+impl SigVerifier for Secp256k1Verifier {
+    fn verify_ecdsa(&self, sig: &[u8], pubkey: &[u8], sighash: &[u8; 32]) -> bool {
+        let curve = Curve::new();
+        let pubkey = match curve.parse_pubkey(pubkey) {
+            Some(point) => point,
+            None => return false
+        };
+        match parse_der_lax(sig) {
+            Some((r, s)) => curve.verify(&pubkey, &r, &s, sighash),
+            None => false
+        }
+    }
+}
+
+/// Point in jacobian coordinates (x / z^2, y / z^3), z = 0 is the point at infinity
+#[derive(Clone)]
+struct Point {
+    x: BigUint,
+    y: BigUint,
+    z: BigUint
+}
+
+struct Curve {
+    p: BigUint,
+    n: BigUint,
+    g: Point,
+    zero: BigUint,
+    one: BigUint
+}
+
+impl Curve {
+    fn new() -> Curve {
+        let hex = |s| BigUint::parse_bytes(s, 16).unwrap();
+        Curve {
+            p: hex(P),
+            n: hex(N),
+            g: Point { x: hex(GX), y: hex(GY), z: BigUint::from(1u32) },
+            zero: BigUint::from(0u32),
+            one: BigUint::from(1u32)
+        }
+    }
+
+    #[inline]
+    fn mul(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a * b) % &self.p
+    }
+
+    #[inline]
+    fn sub(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a + &self.p - b) % &self.p
+    }
+
+    fn infinity(&self) -> Point {
+        Point { x: self.one.clone(), y: self.one.clone(), z: self.zero.clone() }
+    }
+
+    /// Parses a compressed, uncompressed or hybrid public key
+    fn parse_pubkey(&self, pubkey: &[u8]) -> Option<Point> {
+        let (x, y) = match (pubkey.len(), pubkey.first()) {
+            (33, Some(&prefix)) if prefix == 0x02 || prefix == 0x03 => {
+                let x = BigUint::from_bytes_be(&pubkey[1..]);
+                if x >= self.p {
+                    return None;
+                }
+                // y = (x^3 + 7)^((p + 1) / 4), p = 3 mod 4
+                let rhs = (self.mul(&self.mul(&x, &x), &x) + BigUint::from(7u32)) % &self.p;
+                let exp = (&self.p + &self.one) >> 2;
+                let mut y = rhs.modpow(&exp, &self.p);
+                if self.mul(&y, &y) != rhs {
+                    return None;
+                }
+                if y.bit(0) != (prefix == 0x03) {
+                    y = self.sub(&self.zero, &y);
+                }
+                (x, y)
+            }
+            (65, Some(&prefix)) if prefix == 0x04 || prefix == 0x06 || prefix == 0x07 => {
+                let x = BigUint::from_bytes_be(&pubkey[1..33]);
+                let y = BigUint::from_bytes_be(&pubkey[33..]);
+                if x >= self.p || y >= self.p {
+                    return None;
+                }
+                let rhs = (self.mul(&self.mul(&x, &x), &x) + BigUint::from(7u32)) % &self.p;
+                if self.mul(&y, &y) != rhs {
+                    return None;
+                }
+                // Hybrid keys also encode the parity of y in the prefix
+                if prefix != 0x04 && y.bit(0) != (prefix == 0x07) {
+                    return None;
+                }
+                (x, y)
+            }
+            _ => return None
+        };
+        Some(Point { x: x, y: y, z: self.one.clone() })
+    }
+
+    fn double(&self, a: &Point) -> Point {
+        if a.z == self.zero || a.y == self.zero {
+            return self.infinity();
+        }
+        let y2 = self.mul(&a.y, &a.y);
+        let s = self.mul(&BigUint::from(4u32), &self.mul(&a.x, &y2));
+        let m = self.mul(&BigUint::from(3u32), &self.mul(&a.x, &a.x));
+        let x = self.sub(&self.mul(&m, &m), &((&s << 1) % &self.p));
+        let y = self.sub(&self.mul(&m, &self.sub(&s, &x)), &self.mul(&BigUint::from(8u32), &self.mul(&y2, &y2)));
+        let z = self.mul(&BigUint::from(2u32), &self.mul(&a.y, &a.z));
+        Point { x: x, y: y, z: z }
+    }
+
+    fn add(&self, a: &Point, b: &Point) -> Point {
+        if a.z == self.zero {
+            return b.clone();
+        }
+        if b.z == self.zero {
+            return a.clone();
+        }
+        let (az2, bz2) = (self.mul(&a.z, &a.z), self.mul(&b.z, &b.z));
+        let u1 = self.mul(&a.x, &bz2);
+        let u2 = self.mul(&b.x, &az2);
+        let s1 = self.mul(&a.y, &self.mul(&bz2, &b.z));
+        let s2 = self.mul(&b.y, &self.mul(&az2, &a.z));
+        if u1 == u2 {
+            return match s1 == s2 {
+                true => self.double(a),
+                false => self.infinity()
+            };
+        }
+        let h = self.sub(&u2, &u1);
+        let r = self.sub(&s2, &s1);
+        let h2 = self.mul(&h, &h);
+        let h3 = self.mul(&h2, &h);
+        let u1h2 = self.mul(&u1, &h2);
+        let x = self.sub(&self.sub(&self.mul(&r, &r), &h3), &((&u1h2 << 1) % &self.p));
+        let y = self.sub(&self.mul(&r, &self.sub(&u1h2, &x)), &self.mul(&s1, &h3));
+        let z = self.mul(&h, &self.mul(&a.z, &b.z));
+        Point { x: x, y: y, z: z }
+    }
+
+    /// Computes u1 * G + u2 * q with a single double-and-add pass (Shamir's trick)
+    fn mul_add(&self, u1: &BigUint, u2: &BigUint, q: &Point) -> Point {
+        let gq = self.add(&self.g, q);
+        let mut result = self.infinity();
+        for i in (0..u1.bits().max(u2.bits())).rev() {
+            result = self.double(&result);
+            result = match (u1.bit(i), u2.bit(i)) {
+                (true, true) => self.add(&result, &gq),
+                (true, false) => self.add(&result, &self.g),
+                (false, true) => self.add(&result, q),
+                (false, false) => result
+            };
+        }
+        result
+    }
+
+    fn verify(&self, q: &Point, r: &BigUint, s: &BigUint, sighash: &[u8; 32]) -> bool {
+        if *r == self.zero || *s == self.zero || *r >= self.n || *s >= self.n {
+            return false;
+        }
+        let z = BigUint::from_bytes_be(sighash) % &self.n;
+        let w = s.modpow(&(&self.n - BigUint::from(2u32)), &self.n);
+        let u1 = (&z * &w) % &self.n;
+        let u2 = (r * &w) % &self.n;
+        let point = self.mul_add(&u1, &u2, q);
+        if point.z == self.zero {
+            return false;
+        }
+        let z2 = self.mul(&point.z, &point.z);
+        let x = self.mul(&point.x, &z2.modpow(&(&self.p - BigUint::from(2u32)), &self.p));
+        x % &self.n == *r
+    }
+}
+
+/// Parses a DER signature with the same leniency as Bitcoin Core's ecdsa_signature_parse_der_lax.
+/// Values which exceed 32 bytes are invalid and returned as None.
+fn parse_der_lax(sig: &[u8]) -> Option<(BigUint, BigUint)> {
+    let mut pos = 0;
+    // Sequence tag and length, the length is ignored
+    if sig.get(pos) != Some(&0x30) {
+        return None;
+    }
+    pos += 1;
+    let len_byte = match sig.get(pos) {
+        Some(b) => *b as usize,
+        None => return None
+    };
+    pos += 1;
+    if len_byte & 0x80 != 0 {
+        if len_byte - 0x80 > sig.len() - pos {
+            return None;
+        }
+        pos += len_byte - 0x80;
+    }
+
+    let mut values = Vec::with_capacity(2);
+    for _ in 0..2 {
+        // Integer tag and length
+        if sig.get(pos) != Some(&0x02) {
+            return None;
+        }
+        pos += 1;
+        let mut len = match sig.get(pos) {
+            Some(b) => *b as usize,
+            None => return None
+        };
+        pos += 1;
+        if len & 0x80 != 0 {
+            let mut n_bytes = len - 0x80;
+            if n_bytes > sig.len() - pos {
+                return None;
+            }
+            while n_bytes > 0 && sig[pos] == 0 {
+                pos += 1;
+                n_bytes -= 1;
+            }
+            if n_bytes >= 8 {
+                return None;
+            }
+            len = 0;
+            while n_bytes > 0 {
+                len = (len << 8) + sig[pos] as usize;
+                pos += 1;
+                n_bytes -= 1;
+            }
+        }
+        if len > sig.len() - pos {
+            return None;
+        }
+        values.push(&sig[pos..pos + len]);
+        pos += len;
+    }
+
+    let mut ints = Vec::with_capacity(2);
+    for value in values {
+        let start = value.iter().position(|b| *b != 0).unwrap_or(value.len());
+        if value.len() - start > 32 {
+            return None;
+        }
+        ints.push(BigUint::from_bytes_be(&value[start..]));
+    }
+    let s = ints.pop().unwrap();
+    let r = ints.pop().unwrap();
+    Some((r, s))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::utils::hex_to_vec;
+
+    #[test]
+    fn test_verify_ecdsa() {
+        // P2PK vector of script_tests.json, signed with the private key 1
+        let pubkey = hex_to_vec("0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8");
+        let sig = hex_to_vec("304402200a5c6163f07b8d3b013c4d1d6dba25e780b39658d79ba37af7057a3b7f15ffa102201fd9b4eaa9943f734928b99a83592c2e7bf342ea2680f6a2bb705167966b7420");
+        let mut sighash = [0u8; 32];
+        sighash.copy_from_slice(&hex_to_vec("cd3808fc5cbf9f2190d9b867b4498f234212984b8c879f296cf8e792b622a2f4"));
+        let verifier = Secp256k1Verifier;
+        assert!(verifier.verify_ecdsa(&sig, &pubkey, &sighash));
+        // Compressed form of the same key
+        assert!(verifier.verify_ecdsa(&sig, &hex_to_vec("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"), &sighash));
+        assert!(!verifier.verify_ecdsa(&sig, &hex_to_vec("0379be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"), &sighash));
+        sighash[0] ^= 1;
+        assert!(!verifier.verify_ecdsa(&sig, &pubkey, &sighash));
+    }
+
+    #[test]
+    fn test_parse_der_lax() {
+        let (r, s) = parse_der_lax(&hex_to_vec("3006020101020102")).unwrap();
+        assert_eq!((BigUint::from(1u32), BigUint::from(2u32)), (r, s));
+        // Padded values and long form lengths are accepted
+        let (r, s) = parse_der_lax(&hex_to_vec("30810002030000010282000102")).unwrap();
+        assert_eq!((BigUint::from(1u32), BigUint::from(2u32)), (r, s));
+        assert!(parse_der_lax(&hex_to_vec("3006020101")).is_none());
+        assert!(parse_der_lax(&hex_to_vec("3106020101020102")).is_none());
+    }
+}
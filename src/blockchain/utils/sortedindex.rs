@@ -0,0 +1,329 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use errors::{OpError, OpResult};
+
+/// File layout:
+/// <magic> <record count u64> <fanout table: 256 x u64> <sorted records...>
+/// The fanout table holds the number of the first record for each first key byte,
+/// so a lookup only searches the records with the same first key byte.
+/// All records have the same size, so the file can also be memory mapped and searched in place.
+pub const HEADER_SIZE: u64 = 8 + 8 + 256 * 8;
+
+/// Number of records sorted in memory before they get written to a temporary run file
+const RUN_SIZE: usize = 1 << 20;
+/// Number of run files merged at once, more runs are merged in several passes to bound the open files
+const MAX_RUNS: usize = 64;
+
+/// Fixed size record of a sorted index file
+pub trait IndexRecord: Ord + Sized {
+    /// Serialized size in bytes
+    const SIZE: u64;
+
+    /// First byte of the sort key, used for the fanout table
+    fn key_byte(&self) -> u8;
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Self>;
+}
+
+/// Collects records and writes them as sorted runs.
+/// The runs are merged into the final index on `finish`, so memory usage stays bounded.
+pub struct SortedIndexWriter<T: IndexRecord> {
+    magic: &'static [u8; 8],
+    name: &'static str,
+    tmp_folder: PathBuf,
+    buffer: Vec<T>,
+    runs: Vec<PathBuf>,
+    max_runs: usize,
+    n_written: usize    // Number of run files written so far, used for unique names
+}
+
+impl<T: IndexRecord> SortedIndexWriter<T> {
+    /// `name` is used as prefix for the temporary run files
+    pub fn new(magic: &'static [u8; 8], name: &'static str, tmp_folder: PathBuf) -> SortedIndexWriter<T> {
+        SortedIndexWriter {
+            magic: magic,
+            name: name,
+            tmp_folder: tmp_folder,
+            buffer: Vec::with_capacity(RUN_SIZE),
+            runs: Vec::new(),
+            max_runs: MAX_RUNS,
+            n_written: 0
+        }
+    }
+
+    pub fn push(&mut self, record: T) -> OpResult<()> {
+        self.buffer.push(record);
+        if self.buffer.len() >= RUN_SIZE {
+            try!(self.flush_run());
+        }
+        Ok(())
+    }
+
+    pub fn flush_run(&mut self) -> OpResult<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.buffer.sort();
+        let path = self.run_path();
+        let mut writer = BufWriter::new(try!(File::create(&path)));
+        for record in self.buffer.drain(..) {
+            try!(record.write_to(&mut writer));
+        }
+        try!(writer.flush());
+        self.runs.push(path);
+        Ok(())
+    }
+
+    fn run_path(&mut self) -> PathBuf {
+        self.n_written += 1;
+        self.tmp_folder.join(format!("{}.run{}.tmp", self.name, self.n_written - 1))
+    }
+
+    /// Merges all runs into the index file and removes them.
+    /// Returns the number of written records.
+    pub fn finish(mut self, path: &Path) -> OpResult<u64> {
+        try!(self.flush_run());
+
+        // Merge groups of runs until all of them can be opened at once
+        while self.runs.len() > self.max_runs {
+            debug!(target: "sortedindex", "Merging {} runs in groups of {} ...", self.runs.len(), self.max_runs);
+            let runs = mem::replace(&mut self.runs, Vec::new());
+            for group in runs.chunks(self.max_runs) {
+                if group.len() == 1 {
+                    self.runs.push(group[0].clone());
+                    continue;
+                }
+                let run_path = self.run_path();
+                {
+                    let mut writer = BufWriter::new(try!(File::create(&run_path)));
+                    try!(merge_runs(group, |record: T| Ok(try!(record.write_to(&mut writer)))));
+                    try!(writer.flush());
+                }
+                for run in group {
+                    fs::remove_file(run).ok();
+                }
+                self.runs.push(run_path);
+            }
+        }
+
+        let mut writer = BufWriter::new(try!(File::create(path)));
+        try!(writer.write_all(&[0u8; HEADER_SIZE as usize]));
+        let mut fanout = [0u64; 256];
+        let mut count = 0u64;
+        let mut last_byte = None;
+        try!(merge_runs(&self.runs, |record: T| {
+            let first_byte = record.key_byte();
+            if last_byte != Some(first_byte) {
+                let start = last_byte.map(|b: u8| b as usize + 1).unwrap_or(0);
+                for b in start..first_byte as usize + 1 {
+                    fanout[b] = count;
+                }
+                last_byte = Some(first_byte);
+            }
+            try!(record.write_to(&mut writer));
+            count += 1;
+            Ok(())
+        }));
+        let start = last_byte.map(|b| b as usize + 1).unwrap_or(0);
+        for b in start..256 {
+            fanout[b] = count;
+        }
+
+        // Write header
+        try!(writer.seek(SeekFrom::Start(0)));
+        try!(writer.write_all(self.magic));
+        try!(writer.write_u64::<LittleEndian>(count));
+        for offset in fanout.iter() {
+            try!(writer.write_u64::<LittleEndian>(*offset));
+        }
+        try!(writer.flush());
+
+        for run in &self.runs {
+            fs::remove_file(run).ok();
+        }
+        Ok(count)
+    }
+}
+
+/// Passes the records of all runs to `f` in sorted order
+fn merge_runs<T, F>(runs: &[PathBuf], mut f: F) -> OpResult<()>
+    where T: IndexRecord, F: FnMut(T) -> OpResult<()>
+{
+    let mut readers = Vec::with_capacity(runs.len());
+    for run in runs {
+        readers.push(BufReader::new(try!(File::open(run))));
+    }
+    // Min-heap over the current head of each run
+    let mut heap: BinaryHeap<HeapEntry<T>> = BinaryHeap::with_capacity(readers.len());
+    for (i, reader) in readers.iter_mut().enumerate() {
+        if let Some(record) = try!(read_next(reader)) {
+            heap.push(HeapEntry(record, i));
+        }
+    }
+    while let Some(HeapEntry(record, i)) = heap.pop() {
+        try!(f(record));
+        if let Some(next) = try!(read_next(&mut readers[i])) {
+            heap.push(HeapEntry(next, i));
+        }
+    }
+    Ok(())
+}
+
+/// Reads the next record, None at EOF
+fn read_next<T: IndexRecord, R: Read>(reader: &mut R) -> OpResult<Option<T>> {
+    match T::read_from(reader) {
+        Ok(record) => Ok(Some(record)),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(OpError::from(err))
+    }
+}
+
+/// Reversed ordering to use BinaryHeap as min-heap
+#[derive(PartialEq, Eq)]
+struct HeapEntry<T>(T, usize);
+
+impl<T: Ord> Ord for HeapEntry<T> {
+    fn cmp(&self, other: &HeapEntry<T>) -> Ordering {
+        other.0.cmp(&self.0).then_with(|| other.1.cmp(&self.1))
+    }
+}
+
+impl<T: Ord> PartialOrd for HeapEntry<T> {
+    fn partial_cmp(&self, other: &HeapEntry<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Read access to an index file written by SortedIndexWriter
+pub struct SortedIndex<T: IndexRecord> {
+    file: File,
+    count: u64,
+    fanout: [u64; 256],
+    _record: PhantomData<T>
+}
+
+impl<T: IndexRecord> SortedIndex<T> {
+    pub fn open(magic: &'static [u8; 8], path: &Path) -> OpResult<SortedIndex<T>> {
+        let mut file = try!(File::open(path));
+        let mut buf = [0u8; 8];
+        try!(file.read_exact(&mut buf));
+        if &buf != magic {
+            return Err(OpError::from(format!("Invalid index file: {}", path.display())));
+        }
+        let count = try!(file.read_u64::<LittleEndian>());
+        let mut fanout = [0u64; 256];
+        for offset in fanout.iter_mut() {
+            *offset = try!(file.read_u64::<LittleEndian>());
+        }
+        Ok(SortedIndex { file: file, count: count, fanout: fanout, _record: PhantomData })
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.count
+    }
+
+    fn read_record(&mut self, n: u64) -> OpResult<T> {
+        try!(self.file.seek(SeekFrom::Start(HEADER_SIZE + n * T::SIZE)));
+        Ok(try!(T::read_from(&mut self.file)))
+    }
+
+    /// Returns all consecutive records for which `cmp` returns Equal.
+    /// `key_byte` is the first byte of the searched key and `cmp` compares a record against it.
+    pub fn find_all<F>(&mut self, key_byte: u8, cmp: F) -> OpResult<Vec<T>>
+        where F: Fn(&T) -> Ordering {
+        // Narrow down the search space with the fanout table
+        let mut base = self.fanout[key_byte as usize];
+        let mut tail = match key_byte {
+            0xff => self.count,
+            b => self.fanout[b as usize + 1]
+        };
+        // Lower bound binary search
+        while base < tail {
+            let mid = base + (tail - base) / 2;
+            let record = try!(self.read_record(mid));
+            trace!(target: "sortedindex", "base = {}, mid = {}, tail = {}", base, mid, tail);
+            if cmp(&record) == Ordering::Less {
+                base = mid + 1;
+            } else {
+                tail = mid;
+            }
+        }
+
+        let mut records = Vec::new();
+        if base >= self.count {
+            return Ok(records);
+        }
+        try!(self.file.seek(SeekFrom::Start(HEADER_SIZE + base * T::SIZE)));
+        let mut reader = BufReader::new(&mut self.file);
+        for _ in base..self.count {
+            let record = try!(T::read_from(&mut reader));
+            if cmp(&record) != Ordering::Equal {
+                break;
+            }
+            records.push(record);
+        }
+        Ok(records)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    impl IndexRecord for u32 {
+        const SIZE: u64 = 4;
+
+        fn key_byte(&self) -> u8 {
+            (*self >> 24) as u8
+        }
+
+        fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+            writer.write_u32::<LittleEndian>(*self)
+        }
+
+        fn read_from<R: Read>(reader: &mut R) -> io::Result<u32> {
+            reader.read_u32::<LittleEndian>()
+        }
+    }
+
+    #[test]
+    fn test_multi_pass_merge() {
+        let folder = env::temp_dir().join(format!("sortedindex-test-{}", ::std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+
+        let mut writer: SortedIndexWriter<u32> = SortedIndexWriter::new(b"SORTTEST", "test", folder.clone());
+        writer.max_runs = 3;
+        // 10 runs, merged into 4 and then into 2 runs before the final merge
+        for run in 0..10u32 {
+            for i in 0..20u32 {
+                writer.push((i * 10 + run) << 20).unwrap();
+            }
+            writer.flush_run().unwrap();
+        }
+        let path = folder.join("test.bin");
+        assert_eq!(200, writer.finish(&path).unwrap());
+        assert_eq!(1, fs::read_dir(&folder).unwrap().count());
+
+        let mut index: SortedIndex<u32> = SortedIndex::open(b"SORTTEST", &path).unwrap();
+        assert_eq!(200, index.len());
+        for n in 0..200u64 {
+            assert_eq!((n as u32) << 20, index.read_record(n).unwrap());
+        }
+        let key = 57u32 << 20;
+        assert_eq!(vec![key], index.find_all((key >> 24) as u8, |r| r.cmp(&key)).unwrap());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use errors::OpResult;
+use blockchain::utils::sortedindex::{IndexRecord, SortedIndex, SortedIndexWriter};
+
+/// Sorted index file, see sortedindex for the layout
+const MAGIC: &'static [u8; 8] = b"TXINDEX1";
+
+/// Location of a single transaction
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TxLocation {
+    pub txid: [u8; 32],
+    /// Index of the blk file (e.g. 42 for blk00042.dat)
+    pub blk_index: u32,
+    /// Offset of the block header within the blk file
+    pub block_offset: u64,
+    /// Offset of the transaction relative to the block header
+    pub tx_offset: u32,
+    pub height: u32
+}
+
+impl TxLocation {
+    /// Absolute offset of the transaction within the blk file
+    #[inline]
+    pub fn file_offset(&self) -> u64 {
+        self.block_offset + self.tx_offset as u64
+    }
+}
+
+impl IndexRecord for TxLocation {
+    const SIZE: u64 = 32 + 4 + 8 + 4 + 4;
+
+    #[inline]
+    fn key_byte(&self) -> u8 {
+        self.txid[0]
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        try!(writer.write_all(&self.txid));
+        try!(writer.write_u32::<LittleEndian>(self.blk_index));
+        try!(writer.write_u64::<LittleEndian>(self.block_offset));
+        try!(writer.write_u32::<LittleEndian>(self.tx_offset));
+        writer.write_u32::<LittleEndian>(self.height)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<TxLocation> {
+        let mut txid = [0u8; 32];
+        try!(reader.read_exact(&mut txid));
+        Ok(TxLocation {
+            txid: txid,
+            blk_index: try!(reader.read_u32::<LittleEndian>()),
+            block_offset: try!(reader.read_u64::<LittleEndian>()),
+            tx_offset: try!(reader.read_u32::<LittleEndian>()),
+            height: try!(reader.read_u32::<LittleEndian>())
+        })
+    }
+}
+
+/// Collects transaction locations, see SortedIndexWriter
+pub struct TxIndexWriter {
+    writer: SortedIndexWriter<TxLocation>
+}
+
+impl TxIndexWriter {
+    pub fn new(tmp_folder: PathBuf) -> TxIndexWriter {
+        TxIndexWriter { writer: SortedIndexWriter::new(MAGIC, "txindex", tmp_folder) }
+    }
+
+    #[inline]
+    pub fn push(&mut self, location: TxLocation) -> OpResult<()> {
+        self.writer.push(location)
+    }
+
+    /// Writes the index file. Returns the number of written records.
+    pub fn finish(self, path: &Path) -> OpResult<u64> {
+        self.writer.finish(path)
+    }
+}
+
+/// Read access to an index file written by TxIndexWriter
+pub struct TxIndex {
+    index: SortedIndex<TxLocation>
+}
+
+impl TxIndex {
+    pub fn open(path: &Path) -> OpResult<TxIndex> {
+        Ok(TxIndex { index: try!(SortedIndex::open(MAGIC, path)) })
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.index.len()
+    }
+
+    /// Returns the location of the given txid (internal byte order).
+    /// Duplicate txids (BIP30) resolve to the first occurrence in blk file order.
+    pub fn lookup(&mut self, txid: &[u8; 32]) -> OpResult<Option<TxLocation>> {
+        let mut locations = try!(self.index.find_all(txid[0], |location| location.txid.cmp(txid)));
+        if locations.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(locations.swap_remove(0)))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn location(txid: u8, height: u32) -> TxLocation {
+        TxLocation { txid: [txid; 32], blk_index: height / 10, block_offset: 8 + height as u64 * 1000, tx_offset: 81, height: height }
+    }
+
+    #[test]
+    fn test_write_and_lookup() {
+        let folder = env::temp_dir().join("txindex-test");
+        fs::create_dir_all(&folder).unwrap();
+
+        let mut writer = TxIndexWriter::new(folder.clone());
+        for i in 0..50u32 {
+            writer.push(location((i * 5) as u8, i)).unwrap();
+        }
+        let path = folder.join("txindex.bin");
+        assert_eq!(50, writer.finish(&path).unwrap());
+
+        let mut index = TxIndex::open(&path).unwrap();
+        assert_eq!(50, index.len());
+        let found = index.lookup(&[35; 32]).unwrap().unwrap();
+        assert_eq!(location(35, 7), found);
+        assert_eq!(7008 + 81, found.file_offset());
+        assert_eq!(location(0, 0), index.lookup(&[0; 32]).unwrap().unwrap());
+        assert_eq!(location(245, 49), index.lookup(&[245; 32]).unwrap().unwrap());
+        assert!(index.lookup(&[36; 32]).unwrap().is_none());
+        assert!(index.lookup(&[255; 32]).unwrap().is_none());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
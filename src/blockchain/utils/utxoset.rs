@@ -0,0 +1,399 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::hash::BuildHasherDefault;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use twox_hash::XxHash;
+
+use errors::{OpError, OpErrorKind, OpResult};
+use blockchain::proto::tx::TxOutput;
+use blockchain::proto::undo::{compress_amount, write_compressed_script, write_varint};
+use blockchain::utils::chainstate::{self, Coin};
+
+/// Txid and output index
+pub type OutPoint = ([u8; 32], u32);
+
+/// Larger scripts are unspendable, see Bitcoin Core's CScript::IsUnspendable
+const MAX_SCRIPT_SIZE: usize = 10000;
+/// Approximate memory used by a map entry besides the coin itself (key, pointer, table and allocator overhead)
+const ENTRY_OVERHEAD: usize = 80;
+/// Runs are merged into a single one once there are more of them
+const MAX_RUNS: usize = 16;
+
+/// Tracks unspent outputs with binary keys. Each coin is stored in Bitcoin Core's compressed
+/// format (<VARINT(height * 2 + coinbase)> <compressed amount> <compressed script>), which takes
+/// 24 bytes for most outputs. Provably unspendable outputs (OP_RETURN) are not tracked.
+///
+/// With a memory limit the set is written to sorted run files once the limit is exceeded.
+/// Spends of outputs which are only on disk are recorded as tombstones, `finish` merges
+/// all runs and skips spent outputs. Lookups of coins on disk are not supported.
+/// Once there are more than `MAX_RUNS` run files, they are compacted into one.
+pub struct UtxoSet {
+    coins: HashMap<OutPoint, Box<[u8]>, BuildHasherDefault<XxHash>>,
+    spent: HashSet<OutPoint, BuildHasherDefault<XxHash>>,   // Spent outputs which are in a run file
+    mem_usage: usize,
+    max_memory: Option<usize>,
+    tmp_folder: PathBuf,
+    runs: Vec<PathBuf>,
+    max_runs: usize,
+    n_written: usize    // Number of run files written so far, used for unique names
+}
+
+impl UtxoSet {
+    /// Keeps all unspent outputs in memory
+    pub fn new() -> UtxoSet {
+        UtxoSet {
+            coins: Default::default(),
+            spent: Default::default(),
+            mem_usage: 0,
+            max_memory: None,
+            tmp_folder: PathBuf::new(),
+            runs: Vec::new(),
+            max_runs: MAX_RUNS,
+            n_written: 0
+        }
+    }
+
+    /// Writes the set to run files in `tmp_folder` whenever it exceeds `max_memory` bytes
+    pub fn with_disk(tmp_folder: PathBuf, max_memory: usize) -> UtxoSet {
+        UtxoSet {
+            coins: Default::default(),
+            spent: Default::default(),
+            mem_usage: 0,
+            max_memory: Some(max_memory),
+            tmp_folder: tmp_folder,
+            runs: Vec::new(),
+            max_runs: MAX_RUNS,
+            n_written: 0
+        }
+    }
+
+    pub fn insert(&mut self, txid: [u8; 32], index: u32, height: u32, is_coinbase: bool, out: &TxOutput) -> OpResult<()> {
+        let script = &out.script_pubkey;
+        if script.first() == Some(&0x6a) || script.len() > MAX_SCRIPT_SIZE {
+            return Ok(());
+        }
+        let mut coin = Vec::with_capacity(script.len() + 12);
+        write_varint(&mut coin, (height as u64) * 2 + is_coinbase as u64);
+        write_varint(&mut coin, compress_amount(out.value));
+        write_compressed_script(&mut coin, script);
+
+        let key = (txid, index);
+        if self.spent.remove(&key) {
+            self.mem_usage -= ENTRY_OVERHEAD;
+        }
+        self.mem_usage += coin.len() + ENTRY_OVERHEAD;
+        if let Some(old) = self.coins.insert(key, coin.into_boxed_slice()) {
+            self.mem_usage -= old.len() + ENTRY_OVERHEAD;
+        }
+        self.flush_if_full()
+    }
+
+    pub fn remove(&mut self, txid: [u8; 32], index: u32) -> OpResult<()> {
+        let key = (txid, index);
+        match self.coins.remove(&key) {
+            Some(coin) => self.mem_usage -= coin.len() + ENTRY_OVERHEAD,
+            // Outputs created before the start height are unknown, they are ignored in memory-only mode
+            None if !self.runs.is_empty() => {
+                if self.spent.insert(key) {
+                    self.mem_usage += ENTRY_OVERHEAD;
+                }
+            }
+            None => ()
+        }
+        self.flush_if_full()
+    }
+
+    /// Number of unspent outputs in memory
+    pub fn len(&self) -> usize {
+        self.coins.len()
+    }
+
+    fn flush_if_full(&mut self) -> OpResult<()> {
+        match self.max_memory {
+            Some(max_memory) if self.mem_usage > max_memory => self.flush_run(),
+            _ => Ok(())
+        }
+    }
+
+    fn run_path(&mut self) -> PathBuf {
+        self.n_written += 1;
+        self.tmp_folder.join(format!("utxoset.run{}.tmp", self.n_written - 1))
+    }
+
+    /// Writes all entries sorted by key to a new run file: <txid> <index u32> <len u32> <coin>.
+    /// A length of zero marks a spent output.
+    fn flush_run(&mut self) -> OpResult<()> {
+        let path = self.run_path();
+        debug!(target: "utxoset", "Writing {} unspent and {} spent outputs to {} ...",
+               self.coins.len(), self.spent.len(), path.display());
+        let mut writer = BufWriter::new(try!(File::create(&path)));
+        for (key, coin) in sorted_entries(&mut self.coins, &mut self.spent) {
+            try!(write_entry(&mut writer, &key, coin.as_ref().map(|c| &c[..])));
+        }
+        try!(writer.flush());
+        self.runs.push(path);
+        self.mem_usage = 0;
+        if self.runs.len() > self.max_runs {
+            try!(self.compact());
+        }
+        Ok(())
+    }
+
+    /// Merges all runs into a single one. Spent outputs are dropped, there is no older run left.
+    /// Only called right after a flush, so memory doesn't hold any entries.
+    fn compact(&mut self) -> OpResult<()> {
+        let path = self.run_path();
+        debug!(target: "utxoset", "Merging {} runs into {} ...", self.runs.len(), path.display());
+        let runs = mem::replace(&mut self.runs, Vec::new());
+        {
+            let mut merged = try!(UtxoIter::new(runs, Vec::new(), 0x00));
+            let mut writer = BufWriter::new(try!(File::create(&path)));
+            while let Some((key, coin)) = try!(merged.next_entry()) {
+                try!(write_entry(&mut writer, &key, Some(&coin)));
+            }
+            try!(writer.flush());
+        }
+        self.runs.push(path);
+        Ok(())
+    }
+
+    /// Returns all unspent outputs ordered by txid (internal byte order) and index.
+    /// Run files are removed once the iterator is dropped.
+    pub fn finish(mut self, version_id: u8) -> OpResult<UtxoIter> {
+        // Memory holds the newest state
+        let entries = sorted_entries(&mut self.coins, &mut self.spent);
+        UtxoIter::new(mem::replace(&mut self.runs, Vec::new()), entries, version_id)
+    }
+}
+
+impl Drop for UtxoSet {
+    fn drop(&mut self) {
+        for run in &self.runs {
+            fs::remove_file(run).ok();
+        }
+    }
+}
+
+/// Key with the compressed coin, None if spent
+type Entry = (OutPoint, Option<Box<[u8]>>);
+
+fn write_entry<W: Write>(writer: &mut W, key: &OutPoint, coin: Option<&[u8]>) -> io::Result<()> {
+    try!(writer.write_all(&key.0));
+    try!(writer.write_u32::<LittleEndian>(key.1));
+    match coin {
+        Some(coin) => {
+            try!(writer.write_u32::<LittleEndian>(coin.len() as u32));
+            writer.write_all(coin)
+        }
+        None => writer.write_u32::<LittleEndian>(0)
+    }
+}
+
+fn sorted_entries(coins: &mut HashMap<OutPoint, Box<[u8]>, BuildHasherDefault<XxHash>>,
+                  spent: &mut HashSet<OutPoint, BuildHasherDefault<XxHash>>) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = coins.drain().map(|(key, coin)| (key, Some(coin)))
+        .chain(spent.drain().map(|key| (key, None)))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries
+}
+
+struct RunReader {
+    reader: BufReader<File>
+}
+
+impl RunReader {
+    fn read_entry(&mut self) -> io::Result<Entry> {
+        let mut txid = [0u8; 32];
+        try!(self.reader.read_exact(&mut txid));
+        let index = try!(self.reader.read_u32::<LittleEndian>());
+        let coin = match try!(self.reader.read_u32::<LittleEndian>()) {
+            0 => None,
+            len => {
+                let mut coin = vec![0u8; len as usize];
+                try!(self.reader.read_exact(&mut coin));
+                Some(coin.into_boxed_slice())
+            }
+        };
+        Ok(((txid, index), coin))
+    }
+}
+
+impl Iterator for RunReader {
+    type Item = OpResult<Entry>;
+
+    fn next(&mut self) -> Option<OpResult<Entry>> {
+        match self.read_entry() {
+            Ok(entry) => Some(Ok(entry)),
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(err) => Some(Err(OpError::from(err)))
+        }
+    }
+}
+
+/// Merges all runs, for each output the entry of the newest run counts
+pub struct UtxoIter {
+    sources: Vec<Box<Iterator<Item = OpResult<Entry>>>>,
+    heads: Vec<Option<Box<[u8]>>>,                      // Coin of the current entry of each source
+    heap: BinaryHeap<(Reverse<OutPoint>, usize)>,       // Smallest key first, then the newest source
+    version_id: u8,
+    runs: Vec<PathBuf>
+}
+
+impl UtxoIter {
+    /// Merges the run files, oldest first, with the entries in memory. The runs are removed on drop.
+    fn new(runs: Vec<PathBuf>, entries: Vec<Entry>, version_id: u8) -> OpResult<UtxoIter> {
+        let mut sources: Vec<Box<Iterator<Item = OpResult<Entry>>>> = Vec::new();
+        for path in &runs {
+            sources.push(Box::new(RunReader { reader: BufReader::new(try!(File::open(path))) }));
+        }
+        sources.push(Box::new(entries.into_iter().map(Ok)));
+
+        let mut iter = UtxoIter {
+            heads: vec![None; sources.len()],
+            sources: sources,
+            heap: BinaryHeap::new(),
+            version_id: version_id,
+            runs: runs
+        };
+        for i in 0..iter.sources.len() {
+            try!(iter.advance(i));
+        }
+        Ok(iter)
+    }
+
+    fn advance(&mut self, source: usize) -> OpResult<()> {
+        match self.sources[source].next() {
+            Some(Ok((key, coin))) => {
+                self.heads[source] = coin;
+                self.heap.push((Reverse(key), source));
+            }
+            Some(Err(err)) => return Err(err),
+            None => ()
+        }
+        Ok(())
+    }
+
+    /// Returns the next unspent output with its compressed coin
+    fn next_entry(&mut self) -> OpResult<Option<(OutPoint, Box<[u8]>)>> {
+        while let Some((Reverse(key), source)) = self.heap.pop() {
+            let coin = self.heads[source].take();
+            try!(self.advance(source));
+            // Skip older entries of this output
+            while self.heap.peek().map_or(false, |&(Reverse(ref k), _)| *k == key) {
+                let (_, older) = transform!(self.heap.pop());
+                try!(self.advance(older));
+            }
+            if let Some(coin) = coin {
+                return Ok(Some((key, coin)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_coin(&mut self) -> OpResult<Option<Coin>> {
+        match try!(self.next_entry()) {
+            Some((key, coin)) => Ok(Some(try!(chainstate::read_coin(&mut &coin[..], key.0, key.1, self.version_id)))),
+            None => Ok(None)
+        }
+    }
+}
+
+impl Iterator for UtxoIter {
+    type Item = OpResult<Coin>;
+
+    fn next(&mut self) -> Option<OpResult<Coin>> {
+        match self.next_coin() {
+            Ok(coin) => coin.map(Ok),
+            Err(err) => Some(Err(err))
+        }
+    }
+}
+
+impl Drop for UtxoIter {
+    fn drop(&mut self) {
+        for run in &self.runs {
+            fs::remove_file(run).ok();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use super::*;
+    use blockchain::proto::varuint::VarUint;
+    use blockchain::utils;
+
+    fn output(value: u64, script: &str) -> TxOutput {
+        let script = utils::hex_to_vec(script);
+        TxOutput { value: value, script_len: VarUint::from(script.len()), script_pubkey: script }
+    }
+
+    fn fill(set: &mut UtxoSet) {
+        let p2pkh = output(5000000000, "76a914111111111111111111111111111111111111111188ac");
+        let p2wpkh = output(1234, "00142222222222222222222222222222222222222222");
+        set.insert([0x11; 32], 0, 1, true, &p2pkh).unwrap();
+        set.insert([0x11; 32], 1, 1, true, &output(0, "6a0401020304")).unwrap();
+        set.insert([0x22; 32], 23, 2, false, &p2wpkh).unwrap();
+        set.insert([0x22; 32], 3, 2, false, &p2wpkh).unwrap();
+        set.remove([0x22; 32], 3).unwrap();
+        set.insert([0x33; 32], 0, 3, false, &p2pkh).unwrap();
+        set.remove([0x11; 32], 0).unwrap();
+        // Unknown output
+        set.remove([0x44; 32], 0).unwrap();
+        set.insert([0x00; 32], 7, 4, false, &p2wpkh).unwrap();
+    }
+
+    fn check(set: UtxoSet) {
+        let coins: Vec<Coin> = set.finish(0x00).unwrap().map(|c| c.unwrap()).collect();
+        let keys: Vec<OutPoint> = coins.iter().map(|c| (c.txid, c.index)).collect();
+        assert_eq!(vec![([0x00; 32], 7), ([0x22; 32], 23), ([0x33; 32], 0)], keys);
+        assert_eq!((2, false, 1234), (coins[1].height, coins[1].is_coinbase, coins[1].out.out.value));
+        assert_eq!("12ZEw5Hcv1hTb6YUQJ69y1V7uhcoDz92PH", coins[2].out.script.addresses()[0]);
+    }
+
+    #[test]
+    fn test_utxo_set() {
+        let mut set = UtxoSet::new();
+        fill(&mut set);
+        assert_eq!(3, set.len());
+        check(set);
+    }
+
+    #[test]
+    fn test_utxo_set_disk() {
+        let dir = env::temp_dir().join("rusty-blockparser-utxoset");
+        fs::create_dir_all(&dir).unwrap();
+        // Every change is written to a new run
+        let mut set = UtxoSet::with_disk(dir.clone(), 1);
+        fill(&mut set);
+        assert_eq!(8, set.runs.len());
+        let runs = set.runs.clone();
+        check(set);
+        assert!(runs.iter().all(|run| !run.exists()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_utxo_set_compaction() {
+        let dir = env::temp_dir().join("rusty-blockparser-utxoset-compaction");
+        fs::create_dir_all(&dir).unwrap();
+        let mut set = UtxoSet::with_disk(dir.clone(), 1);
+        set.max_runs = 3;
+        fill(&mut set);
+        // 8 runs, compacted after the 4th and the 7th (the merged run and 3 new ones)
+        assert_eq!(2, set.runs.len());
+        assert_eq!(10, set.n_written);
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+        check(set);
+        assert_eq!(0, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
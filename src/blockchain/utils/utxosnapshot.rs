@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use errors::{OpError, OpErrorKind, OpResult};
+use blockchain::parser::types::CoinType;
+use blockchain::proto::varuint::VarUint;
+use blockchain::utils::arr_to_hex_swapped;
+use blockchain::utils::chainstate::{self, Coin};
+
+/// Magic bytes of the snapshot format since Bitcoin Core 28
+const SNAPSHOT_MAGIC: [u8; 5] = [b'u', b't', b'x', b'o', 0xff];
+const SNAPSHOT_VERSION: u16 = 2;
+
+/// UTXO set written by Bitcoin Core's `dumptxoutset` RPC (assumeutxo).
+/// Header: <magic> <version u16> <network magic> <base block hash> <coin count u64>,
+/// followed by the coins grouped by txid: <txid> <CompactSize(count)> [<CompactSize(index)> <Coin>]...
+/// Snapshots of Bitcoin Core 26 and 27 have no magic, version and network magic,
+/// each coin is stored with its outpoint: <txid> <index u32> <Coin>.
+pub struct UtxoSnapshot<R: Read> {
+    pub base_hash: [u8; 32],    // Hash of the block up to which the UTXO set is valid
+    pub coins_count: u64,
+    reader: R,
+    version_id: u8,
+    legacy: bool,
+    n_read: u64,
+    txid: [u8; 32],             // Txid of the current group
+    n_group_left: u64           // Remaining coins of the current group
+}
+
+impl UtxoSnapshot<BufReader<File>> {
+    pub fn open(path: &Path, coin_type: &CoinType) -> OpResult<UtxoSnapshot<BufReader<File>>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) => return Err(tag_err!(OpError::from(err), "Unable to open {}", path.display()))
+        };
+        UtxoSnapshot::new(BufReader::with_capacity(1 << 20, file), coin_type)
+    }
+}
+
+impl<R: Read> UtxoSnapshot<R> {
+    pub fn new(mut reader: R, coin_type: &CoinType) -> OpResult<UtxoSnapshot<R>> {
+        let mut base_hash = [0u8; 32];
+        try!(reader.read_exact(&mut base_hash[..5]));
+        let legacy = base_hash[..5] != SNAPSHOT_MAGIC;
+        if legacy {
+            try!(reader.read_exact(&mut base_hash[5..]));
+        } else {
+            let version = try!(reader.read_u16::<LittleEndian>());
+            if version != SNAPSHOT_VERSION {
+                return Err(OpError::new(OpErrorKind::ValidateError)
+                    .join_msg(&format!("Unsupported UTXO snapshot version: {}", version)));
+            }
+            let magic = try!(reader.read_u32::<LittleEndian>());
+            if magic != coin_type.magic {
+                return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+                    "UTXO snapshot was created for another network (magic: 0x{:x}, expected: 0x{:x})",
+                    magic, coin_type.magic)));
+            }
+            try!(reader.read_exact(&mut base_hash));
+        }
+        let coins_count = try!(reader.read_u64::<LittleEndian>());
+        debug!(target: "utxosnapshot", "UTXO snapshot at block {} with {} coins{}",
+               arr_to_hex_swapped(&base_hash), coins_count, if legacy { " (legacy format)" } else { "" });
+        Ok(UtxoSnapshot {
+            base_hash: base_hash,
+            coins_count: coins_count,
+            reader: reader,
+            version_id: coin_type.version_id,
+            legacy: legacy,
+            n_read: 0,
+            txid: [0u8; 32],
+            n_group_left: 0
+        })
+    }
+
+    fn read_coin(&mut self) -> OpResult<Coin> {
+        let index = match self.legacy {
+            true => {
+                try!(self.reader.read_exact(&mut self.txid));
+                try!(self.reader.read_u32::<LittleEndian>())
+            }
+            false => {
+                while self.n_group_left == 0 {
+                    try!(self.reader.read_exact(&mut self.txid));
+                    self.n_group_left = try!(VarUint::read_from(&mut self.reader)).value;
+                }
+                self.n_group_left -= 1;
+                try!(VarUint::read_from(&mut self.reader)).value as u32
+            }
+        };
+        chainstate::read_coin(&mut self.reader, self.txid, index, self.version_id)
+    }
+}
+
+impl<R: Read> Iterator for UtxoSnapshot<R> {
+    type Item = OpResult<Coin>;
+
+    fn next(&mut self) -> Option<OpResult<Coin>> {
+        if self.n_read == self.coins_count {
+            return None;
+        }
+        self.n_read += 1;
+        match self.read_coin() {
+            Ok(coin) => Some(Ok(coin)),
+            Err(err) => {
+                let n_read = self.n_read;
+                // Stop after the first error, the position within the file is unknown
+                self.n_read = self.coins_count;
+                Some(Err(tag_err!(err, "Unable to read coin {} of the UTXO snapshot", n_read)))
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::utils;
+
+    /// Coin at height 100 (coinbase) with 50 BTC to P2SH
+    fn coin() -> Vec<u8> {
+        let mut coin = vec![0x80, 0x49, 0x32, 0x01];
+        coin.extend_from_slice(&[0x22; 20]);
+        coin
+    }
+
+    #[test]
+    fn test_read_snapshot() {
+        let coin_type = CoinType::default();
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        bytes.extend_from_slice(&utils::le::u16_to_array(SNAPSHOT_VERSION));
+        bytes.extend_from_slice(&utils::le::u32_to_array(coin_type.magic));
+        bytes.extend_from_slice(&[0x55; 32]);
+        bytes.extend_from_slice(&utils::le::u64_to_array(3));
+        // Two coins of the first transaction, one of the second
+        bytes.extend_from_slice(&[0x11; 32]);
+        bytes.extend_from_slice(&[0x02, 0x00]);
+        bytes.extend(coin());
+        bytes.push(0x05);
+        bytes.extend(coin());
+        bytes.extend_from_slice(&[0x33; 32]);
+        bytes.extend_from_slice(&[0x01, 0xfd, 0x2c, 0x01]);
+        bytes.extend(coin());
+
+        let snapshot = UtxoSnapshot::new(&bytes[..], &coin_type).unwrap();
+        assert_eq!([0x55; 32], snapshot.base_hash);
+        assert_eq!(3, snapshot.coins_count);
+        let coins: Vec<Coin> = snapshot.map(|c| c.unwrap()).collect();
+        assert_eq!(3, coins.len());
+        assert_eq!(([0x11; 32], 0), (coins[0].txid, coins[0].index));
+        assert_eq!(([0x11; 32], 5), (coins[1].txid, coins[1].index));
+        assert_eq!(([0x33; 32], 300), (coins[2].txid, coins[2].index));
+        assert_eq!(100, coins[2].height);
+        assert_eq!(5000000000, coins[2].out.out.value);
+
+        // Truncated
+        let snapshot = UtxoSnapshot::new(&bytes[..bytes.len() - 1], &coin_type).unwrap();
+        assert!(snapshot.last().unwrap().is_err());
+
+        // Another network
+        bytes[7] ^= 0xff;
+        assert!(UtxoSnapshot::new(&bytes[..], &coin_type).is_err());
+    }
+
+    #[test]
+    fn test_read_legacy_snapshot() {
+        let mut bytes = vec![0x55; 32];
+        bytes.extend_from_slice(&utils::le::u64_to_array(1));
+        bytes.extend_from_slice(&[0x11; 32]);
+        bytes.extend_from_slice(&utils::le::u32_to_array(7));
+        bytes.extend(coin());
+
+        let mut snapshot = UtxoSnapshot::new(&bytes[..], &CoinType::default()).unwrap();
+        assert_eq!([0x55; 32], snapshot.base_hash);
+        let coin = snapshot.next().unwrap().unwrap();
+        assert_eq!(([0x11; 32], 7, true), (coin.txid, coin.index, coin.is_coinbase));
+        assert!(snapshot.next().is_none());
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fs;
+use std::hash::BuildHasherDefault;
+use std::path::PathBuf;
+
+use clap::{Arg, ArgMatches, App, SubCommand};
+use twox_hash::XxHash;
+
+use callbacks::Callback;
+use errors::OpResult;
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::tx::EvaluatedTxOut;
+use blockchain::utils;
+use blockchain::utils::chainstate::Coin;
+use blockchain::utils::addrindex::{self, AddrIndex, AddrIndexWriter, AddrRecord, Direction};
+
+
+/// Output which may get spent later on
+struct TrackedOutput {
+    keys: Vec<[u8; 20]>,
+    value: u64
+}
+
+/// Builds an on-disk index from address (or script hash) to transaction history
+pub struct AddrIndexDump {
+    dump_folder:    PathBuf,
+    writer:         Option<AddrIndexWriter>,
+    /// Outputs of this pass, required to resolve the address of spending inputs. Not required with --undo
+    unspent:        HashMap<([u8; 32], u32), TrackedOutput, BuildHasherDefault<XxHash>>,
+
+    start_height:   usize,
+    end_height:     usize,
+    n_records:      u64,
+    /// Inputs spending outputs created before the start height (without --undo)
+    n_unresolved:   u64
+}
+
+impl AddrIndexDump {
+    /// Returns the index keys of an output. Scripts without address are keyed
+    /// on their script hash (reversed sha256 of the script, as used by Electrum).
+    fn output_keys(output: &EvaluatedTxOut) -> Vec<[u8; 20]> {
+        if output.script.destinations.is_empty() {
+            vec![addrindex::key_for(&utils::arr_to_hex_swapped(&utils::sha256(&output.out.script_pubkey)))]
+        } else {
+            output.script.destinations.iter().map(|d| addrindex::key_for(&d.address)).collect()
+        }
+    }
+}
+
+impl Callback for AddrIndexDump {
+
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b> where Self: Sized {
+        SubCommand::with_name("addrindex")
+            .about("Builds an address history index (see `query`)")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(Arg::with_name("dump-folder")
+                .help("Folder to store the index file")
+                .index(1)
+                .required(true))
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self> where Self: Sized {
+        let ref dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap()); // Save to unwrap
+        match (|| -> OpResult<Self> {
+            try!(fs::create_dir_all(dump_folder));
+            let cb = AddrIndexDump {
+                dump_folder:    PathBuf::from(dump_folder),
+                writer:         Some(AddrIndexWriter::new(PathBuf::from(dump_folder))),
+                unspent:        HashMap::with_capacity_and_hasher(1000000, Default::default()),
+                start_height: 0, end_height: 0, n_records: 0, n_unresolved: 0
+            };
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => return Err(
+                tag_err!(e, "Couldn't initialize addrindex with folder: `{}`", dump_folder
+                        .as_path()
+                        .display()))
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "callback", "Using `addrindex` with dump folder: {} ...", &self.dump_folder.display());
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        let writer = self.writer.as_mut().unwrap();
+        for (tx_index, tx) in block.txs.iter().enumerate() {
+            if !tx.value.is_coinbase() {
+                for (vin, input) in tx.value.inputs.iter().enumerate() {
+                    // Spends are resolved from the undo data if available
+                    let spent = match block.spent_outputs(tx_index) {
+                        Some(spent) => TrackedOutput {
+                            keys: AddrIndexDump::output_keys(&spent[vin].out),
+                            value: spent[vin].out.out.value
+                        },
+                        None => match self.unspent.remove(&(input.outpoint.txid, input.outpoint.index)) {
+                            Some(spent) => spent,
+                            None => {
+                                self.n_unresolved += 1;
+                                continue;
+                            }
+                        }
+                    };
+                    for key in spent.keys {
+                        writer.push(AddrRecord {
+                            key: key,
+                            height: block_height as u32,
+                            txid: tx.hash,
+                            index: vin as u32,
+                            direction: Direction::Spent,
+                            value: spent.value
+                        }).expect("Unable to write address index run!");
+                        self.n_records += 1;
+                    }
+                }
+            }
+
+            for (vout, output) in tx.value.outputs.iter().enumerate() {
+                // Unspendable
+                if let ScriptPattern::DataOutput(_) = output.script.pattern {
+                    continue;
+                }
+                let keys = AddrIndexDump::output_keys(output);
+                for key in &keys {
+                    writer.push(AddrRecord {
+                        key: *key,
+                        height: block_height as u32,
+                        txid: tx.hash,
+                        index: vout as u32,
+                        direction: Direction::Received,
+                        value: output.out.value
+                    }).expect("Unable to write address index run!");
+                    self.n_records += 1;
+                }
+                if block.undo.is_none() {
+                    self.unspent.insert((tx.hash, vout as u32), TrackedOutput { keys: keys, value: output.out.value });
+                }
+            }
+        }
+    }
+
+    fn utxo_snapshot(&self) -> bool {
+        true
+    }
+
+    /// Unspent outputs of the snapshot are indexed as received at their height, so spends resolve
+    /// and balances are complete. Outputs which were spent before the snapshot are missing in the history.
+    fn on_snapshot_coin(&mut self, coin: Coin) {
+        if let ScriptPattern::DataOutput(_) = coin.out.script.pattern {
+            return;
+        }
+        let keys = AddrIndexDump::output_keys(&coin.out);
+        let writer = self.writer.as_mut().unwrap();
+        for key in &keys {
+            writer.push(AddrRecord {
+                key: *key,
+                height: coin.height,
+                txid: coin.txid,
+                index: coin.index,
+                direction: Direction::Received,
+                value: coin.out.out.value
+            }).expect("Unable to write address index run!");
+            self.n_records += 1;
+        }
+        self.unspent.insert((coin.txid, coin.index), TrackedOutput { keys: keys, value: coin.out.out.value });
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        let path = self.dump_folder.join(format!("addrindex-{}-{}.bin", self.start_height, self.end_height));
+        info!(target: "callback", "Merging {} records into {} ...", self.n_records, path.display());
+        self.writer.take().unwrap().finish(&path).expect("Unable to write address index!");
+
+        info!(target: "callback", "Done.\nIndexed all {} blocks:\n\
+                                   \t-> records:        {:9}\n\
+                                   \t-> unresolved:     {:9}",
+             self.end_height + 1, self.n_records, self.n_unresolved);
+    }
+}
+
+/// Subcommand to look up addresses in an index written by `addrindex`
+pub fn build_query_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("query")
+        .about("Prints history and balance of addresses from an `addrindex` file")
+        .version("0.1")
+        .author("gcarq <michael.egger@tsn.at>")
+        .arg(Arg::with_name("index")
+            .help("Index file written by addrindex")
+            .index(1)
+            .required(true))
+        .arg(Arg::with_name("address")
+            .help("Address or script hash to look up")
+            .index(2)
+            .multiple(true)
+            .required(true))
+}
+
+/// Looks up all given addresses and prints their history
+pub fn query(matches: &ArgMatches) -> OpResult<()> {
+    let path = PathBuf::from(matches.value_of("index").unwrap()); // Save to unwrap
+    let mut index = match AddrIndex::open(&path) {
+        Ok(index) => index,
+        Err(e) => return Err(tag_err!(e, "Couldn't open address index: `{}`", path.display()))
+    };
+    for address in matches.values_of("address").unwrap() {
+        let records = try!(index.lookup(&addrindex::key_for(address)));
+        let (mut received, mut spent) = (0u64, 0u64);
+        println!("{}:", address);
+        for record in &records {
+            // (height, txid, index, direction, value)
+            let direction = match record.direction {
+                Direction::Received => { received += record.value; "out" }
+                Direction::Spent => { spent += record.value; "in" }
+            };
+            println!("   {};{};{};{};{}", record.height, utils::arr_to_hex_swapped(&record.txid),
+                     record.index, direction, record.value);
+        }
+        println!("   -> transactions:\t{}", records.len());
+        println!("   -> received:\t\t{:.8}", received as f64 * 1E-8);
+        println!("   -> spent:\t\t{:.8}", spent as f64 * 1E-8);
+        // A pass above block 0 can record spends of outputs received before it
+        match received.checked_sub(spent) {
+            Some(balance) => println!("   -> balance:\t\t{:.8}\n", balance as f64 * 1E-8),
+            None => println!("   -> balance:\t\tincomplete, outputs received before the indexed blocks are spent\n")
+        }
+    }
+    Ok(())
+}
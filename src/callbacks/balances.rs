@@ -0,0 +1,483 @@
+use std::cmp;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::{self, File};
+use std::hash::BuildHasherDefault;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{Arg, ArgMatches, App, SubCommand};
+use twox_hash::XxHash;
+
+use callbacks::Callback;
+use errors::{OpError, OpErrorKind, OpResult};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::script::{Destination, DestinationKind, EvaluatedScript, ScriptPattern};
+use blockchain::utils;
+use blockchain::utils::chainstate::Coin;
+
+
+/// Longest witness program (BIP141)
+const MAX_PROGRAM_LEN: usize = 40;
+
+/// Binary address key: <kind> <hash160 or witness program>.
+/// P2PK outputs share the key of the P2PKH address they are rendered as.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct AddrKey {
+    kind: u8,       // 0 = P2PKH, 1 = P2SH, 2 + n = witness version n
+    len: u8,
+    data: [u8; MAX_PROGRAM_LEN]
+}
+
+impl AddrKey {
+    fn new(kind: u8, data: &[u8]) -> Option<AddrKey> {
+        if data.len() > MAX_PROGRAM_LEN {
+            return None;
+        }
+        let mut key = AddrKey { kind: kind, len: data.len() as u8, data: [0u8; MAX_PROGRAM_LEN] };
+        key.data[..data.len()].copy_from_slice(data);
+        Some(key)
+    }
+
+    /// Returns the key of the owner of an output. Outputs without a single owner
+    /// (multisig) or without address (e.g. OP_RETURN) are skipped.
+    fn from_script(script: &EvaluatedScript) -> Option<AddrKey> {
+        if let ScriptPattern::Pay2MultiSig { .. } = script.pattern {
+            return None;
+        }
+        let dest = match script.destinations.first() {
+            Some(dest) => dest,
+            None => return None
+        };
+        match dest.kind {
+            DestinationKind::PubKey => AddrKey::new(0, &utils::ridemp160(&utils::sha256(&dest.data))),
+            DestinationKind::PubKeyHash => AddrKey::new(0, &dest.data),
+            DestinationKind::ScriptHash => AddrKey::new(1, &dest.data),
+            DestinationKind::WitnessProgram(version) => AddrKey::new(2 + version, &dest.data)
+        }
+    }
+
+    #[inline]
+    fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    /// Renders the address, only done when writing a snapshot
+    fn address(&self, version_id: u8) -> String {
+        match self.kind {
+            0 => Destination::from_pubkey_hash(self.data(), version_id).address,
+            1 => Destination::from_script_hash(self.data()).address,
+            n => Destination::from_witness_program(n - 2, self.data(), version_id).address
+        }
+    }
+}
+
+/// Accumulated values of an address
+struct AddrStats {
+    key: AddrKey,
+    balance: u64,
+    received: u64,
+    sent: u64,
+    tx_count: u32,
+    first_seen: u32,
+    last_seen: u32
+}
+
+/// Tracks balance, received and sent value, transaction count and first/last seen height of all
+/// addresses and writes snapshots at the given heights and at the end.
+/// Spent outputs are resolved from the undo data, so `--undo` is required.
+pub struct Balances {
+    dump_folder:    PathBuf,
+    interval:       Option<usize>,
+    heights:        Vec<usize>,
+    top:            Option<usize>,
+    include_empty:  bool,
+    version_id:     u8,
+
+    ids:            HashMap<AddrKey, u32, BuildHasherDefault<XxHash>>,
+    addresses:      Vec<AddrStats>,
+
+    start_height:   usize,
+    last_height:    Option<usize>,
+    n_snapshots:    usize
+}
+
+impl Balances {
+    /// Returns the id of an address, it is added on first sight
+    fn address_id(&mut self, key: AddrKey, height: u32) -> u32 {
+        let addresses = &mut self.addresses;
+        *self.ids.entry(key).or_insert_with(|| {
+            addresses.push(AddrStats {
+                key: key, balance: 0, received: 0, sent: 0, tx_count: 0,
+                first_seen: height, last_seen: height
+            });
+            (addresses.len() - 1) as u32
+        })
+    }
+
+    fn is_snapshot_height(&self, height: usize) -> bool {
+        self.interval.map_or(false, |n| height > 0 && height % n == 0) || self.heights.contains(&height)
+    }
+
+    /// Writes `balances-<height>.csv` and optionally `richlist-<height>.csv`
+    fn write_snapshot(&mut self, height: usize) -> OpResult<()> {
+        let path = self.dump_folder.join(format!("balances-{}.csv", height));
+        let mut writer = BufWriter::with_capacity(4000000, try!(File::create(self.dump_folder.join("balances.csv.tmp"))));
+        try!(writer.write_all(CSV_HEADER.as_bytes()));
+        let mut n_written: u64 = 0;
+        for stats in &self.addresses {
+            if stats.balance > 0 || self.include_empty {
+                try!(write_stats(&mut writer, stats, self.version_id));
+                n_written += 1;
+            }
+        }
+        try!(writer.flush());
+        try!(fs::rename(self.dump_folder.join("balances.csv.tmp"), &path));
+
+        if let Some(n) = self.top {
+            let path = self.dump_folder.join(format!("richlist-{}.csv", height));
+            let mut writer = BufWriter::new(try!(File::create(&path)));
+            try!(writer.write_all(format!("rank;{}", CSV_HEADER).as_bytes()));
+            for (rank, id) in self.richest(n).into_iter().enumerate() {
+                try!(writer.write_all(format!("{};", rank + 1).as_bytes()));
+                try!(write_stats(&mut writer, &self.addresses[id as usize], self.version_id));
+            }
+            try!(writer.flush());
+        }
+        self.n_snapshots += 1;
+        info!(target: "callback", "Wrote balances of {} addresses at height {} to {}",
+              n_written, height, path.display());
+        Ok(())
+    }
+
+    /// Returns the ids of the `n` addresses with the highest balance, in descending order
+    fn richest(&self, n: usize) -> Vec<u32> {
+        // Min-heap of the best n entries, ties are ordered by first sight
+        let mut heap: BinaryHeap<cmp::Reverse<(u64, cmp::Reverse<u32>)>> = BinaryHeap::with_capacity(n + 1);
+        for (id, stats) in self.addresses.iter().enumerate() {
+            if stats.balance == 0 {
+                continue;
+            }
+            heap.push(cmp::Reverse((stats.balance, cmp::Reverse(id as u32))));
+            if heap.len() > n {
+                heap.pop();
+            }
+        }
+        heap.into_sorted_vec().into_iter().map(|cmp::Reverse((_, cmp::Reverse(id)))| id).collect()
+    }
+}
+
+impl Callback for Balances {
+
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b> where Self: Sized {
+        SubCommand::with_name("balances")
+            .about("Writes snapshots of all address balances at the given heights")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(Arg::with_name("dump-folder")
+                .help("Folder to store csv files")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("interval")
+                .long("interval")
+                .value_name("BLOCKS")
+                .help("Writes a snapshot every BLOCKS blocks (e.g. 52560)")
+                .takes_value(true))
+            .arg(Arg::with_name("heights")
+                .long("heights")
+                .value_name("HEIGHTS")
+                .help("Writes snapshots at the given comma separated heights")
+                .takes_value(true))
+            .arg(Arg::with_name("top")
+                .long("top")
+                .value_name("N")
+                .help("Writes a rich list with the N addresses holding the highest balance for each snapshot")
+                .takes_value(true))
+            .arg(Arg::with_name("include-empty")
+                .long("include-empty")
+                .help("Includes addresses with zero balance in the snapshots"))
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self> where Self: Sized {
+        let ref dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap()); // Save to unwrap
+        match (|| -> OpResult<Self> {
+            try!(fs::create_dir_all(dump_folder));
+            let interval = match matches.value_of("interval") {
+                Some(_) => Some(try!(value_t!(matches, "interval", usize).map_err(|e| OpError::from(e.message)))),
+                None => None
+            };
+            if interval == Some(0) {
+                return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg("--interval must be greater than 0"));
+            }
+            let heights = match matches.value_of("heights") {
+                Some(heights) => try!(parse_heights(heights)),
+                None => Vec::new()
+            };
+            let top = match matches.value_of("top") {
+                Some(_) => Some(try!(value_t!(matches, "top", usize).map_err(|e| OpError::from(e.message)))),
+                None => None
+            };
+            let cb = Balances {
+                dump_folder:    PathBuf::from(dump_folder),
+                interval:       interval,
+                heights:        heights,
+                top:            top,
+                include_empty:  matches.is_present("include-empty"),
+                version_id:     0,
+                ids:            HashMap::with_capacity_and_hasher(1000000, Default::default()),
+                addresses:      Vec::with_capacity(1000000),
+                start_height: 0, last_height: None, n_snapshots: 0
+            };
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => return Err(
+                tag_err!(e, "Couldn't initialize balances with folder: `{}`", dump_folder
+                        .as_path()
+                        .display()))
+        }
+    }
+
+    fn on_start(&mut self, coin_type: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        self.version_id = coin_type.version_id;
+        info!(target: "callback", "Using `balances` with dump folder: {} ...", &self.dump_folder.display());
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        let height = block_height as u32;
+        for (tx_index, tx) in block.txs.iter().enumerate() {
+            // Addresses involved in this transaction
+            let mut touched: Vec<u32> = Vec::new();
+
+            let spent = block.spent_outputs(tx_index).expect("Block without undo data, balances requires --undo!");
+            for spent in spent {
+                if let Some(key) = AddrKey::from_script(&spent.out.script) {
+                    let id = self.address_id(key, height);
+                    touched.push(id);
+                    let stats = &mut self.addresses[id as usize];
+                    // Outputs created before the start height weren't credited
+                    stats.balance = stats.balance.saturating_sub(spent.out.out.value);
+                    stats.sent += spent.out.out.value;
+                }
+            }
+
+            for output in &tx.value.outputs {
+                let key = match AddrKey::from_script(&output.script) {
+                    Some(key) => key,
+                    None => continue
+                };
+                let id = self.address_id(key, height);
+                touched.push(id);
+                let stats = &mut self.addresses[id as usize];
+                stats.balance += output.out.value;
+                stats.received += output.out.value;
+            }
+
+            touched.sort();
+            touched.dedup();
+            for id in touched {
+                let stats = &mut self.addresses[id as usize];
+                stats.tx_count += 1;
+                stats.last_seen = height;
+            }
+        }
+
+        self.last_height = Some(block_height);
+        if self.is_snapshot_height(block_height) {
+            self.write_snapshot(block_height).expect("Unable to write balances!");
+        }
+    }
+
+    fn utxo_snapshot(&self) -> bool {
+        true
+    }
+
+    fn requires_undo(&self) -> bool {
+        true
+    }
+
+    /// Unspent outputs of the snapshot are counted as received at their height.
+    /// Values and transactions before the snapshot are missing in received, sent and tx_count.
+    fn on_snapshot_coin(&mut self, coin: Coin) {
+        let key = match AddrKey::from_script(&coin.out.script) {
+            Some(key) => key,
+            None => return
+        };
+        let id = self.address_id(key, coin.height);
+        {
+            let stats = &mut self.addresses[id as usize];
+            stats.balance += coin.out.out.value;
+            stats.received += coin.out.out.value;
+            stats.first_seen = cmp::min(stats.first_seen, coin.height);
+            stats.last_seen = cmp::max(stats.last_seen, coin.height);
+        }
+    }
+
+    fn on_complete(&mut self, _: usize) {
+        if let Some(height) = self.last_height {
+            if !self.is_snapshot_height(height) {
+                self.write_snapshot(height).expect("Unable to write balances!");
+            }
+        }
+        let total: u64 = self.addresses.iter().map(|s| s.balance).sum();
+        info!(target: "callback", "Done.\nTracked balances from height {} to {}:\n\
+                                   \t-> addresses:      {:9}\n\
+                                   \t-> total balance:  {:9}\n\
+                                   \t-> snapshots:      {:9}",
+             self.start_height, self.last_height.unwrap_or(self.start_height),
+             self.addresses.len(), total, self.n_snapshots);
+    }
+}
+
+const CSV_HEADER: &'static str = "address;balance;received;sent;tx_count;first_seen;last_seen\n";
+
+/// Writes a row of a balances or rich list csv file
+fn write_stats<W: Write>(writer: &mut W, stats: &AddrStats, version_id: u8) -> OpResult<()> {
+    try!(writer.write_all(format!("{};{};{};{};{};{};{}\n",
+        stats.key.address(version_id),
+        stats.balance,
+        stats.received,
+        stats.sent,
+        stats.tx_count,
+        stats.first_seen,
+        stats.last_seen).as_bytes()));
+    Ok(())
+}
+
+/// Parses a comma separated list of heights
+fn parse_heights(heights: &str) -> OpResult<Vec<usize>> {
+    heights.split(',')
+        .map(|h| h.trim().parse::<usize>().map_err(|_| OpError::new(OpErrorKind::InvalidArgsError)
+            .join_msg(&format!("Invalid height in --heights: `{}`", h))))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::proto::script;
+    use blockchain::proto::header::BlockHeader;
+    use blockchain::proto::tx::{EvaluatedTxOut, Tx, TxInput, TxOutpoint, TxOutput};
+    use blockchain::proto::undo::{BlockUndo, SpentOutput};
+    use blockchain::proto::varuint::VarUint;
+
+    #[test]
+    fn test_addr_key() {
+        // P2PK and P2PKH of the same key share the address
+        let pub_key = utils::hex_to_vec("0411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3");
+        let mut p2pk = vec![0x41];
+        p2pk.extend(&pub_key);
+        p2pk.push(0xac);
+        let p2pk = AddrKey::from_script(&script::eval_from_bytes(&p2pk, 0x00)).unwrap();
+        let mut p2pkh = vec![0x76, 0xa9, 0x14];
+        p2pkh.extend_from_slice(&utils::ridemp160(&utils::sha256(&pub_key)));
+        p2pkh.extend_from_slice(&[0x88, 0xac]);
+        assert!(p2pk == AddrKey::from_script(&script::eval_from_bytes(&p2pkh, 0x00)).unwrap());
+        assert_eq!("12cbQLTFMXRnSzktFkuoG3eHoMeFtpTu3S", p2pk.address(0x00));
+
+        // P2WPKH
+        let p2wpkh = utils::hex_to_vec("0014751e76e8199196d454941c45d1b3a323f1433bd6");
+        let key = AddrKey::from_script(&script::eval_from_bytes(&p2wpkh, 0x00)).unwrap();
+        assert_eq!((2, 20), (key.kind, key.len));
+        assert_eq!("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", key.address(0x00));
+
+        // OP_RETURN has no address
+        assert!(AddrKey::from_script(&script::eval_from_bytes(&[0x6a, 0x01, 0x01], 0x00)).is_none());
+    }
+
+    #[test]
+    fn test_richest() {
+        let mut cb = Balances {
+            dump_folder: PathBuf::new(), interval: Some(10), heights: vec![15], top: Some(2), include_empty: false,
+            version_id: 0, ids: Default::default(), addresses: Vec::new(),
+            start_height: 0, last_height: None, n_snapshots: 0
+        };
+        for (i, balance) in [5u64, 0, 7, 5, 3].iter().enumerate() {
+            let id = cb.address_id(AddrKey::new(0, &[i as u8; 20]).unwrap(), i as u32);
+            cb.addresses[id as usize].balance = *balance;
+        }
+        assert_eq!(0, cb.address_id(AddrKey::new(0, &[0; 20]).unwrap(), 9));
+        assert_eq!(vec![2, 0], cb.richest(2));
+        assert_eq!(vec![2, 0, 3, 4], cb.richest(10));
+
+        assert!(!cb.is_snapshot_height(0));
+        assert!(cb.is_snapshot_height(10));
+        assert!(cb.is_snapshot_height(15));
+        assert!(!cb.is_snapshot_height(16));
+        assert_eq!(vec![1, 200], parse_heights("1, 200").unwrap());
+        assert!(parse_heights("1,x").is_err());
+    }
+
+    fn p2pkh(owner: u8) -> Vec<u8> {
+        let mut script = vec![0x76, 0xa9, 0x14];
+        script.extend_from_slice(&[owner; 20]);
+        script.extend_from_slice(&[0x88, 0xac]);
+        script
+    }
+
+    fn output(value: u64, owner: u8) -> TxOutput {
+        let script = p2pkh(owner);
+        TxOutput { value: value, script_len: VarUint::from(script.len()), script_pubkey: script }
+    }
+
+    fn tx(inputs: &[([u8; 32], u32)], outputs: &[TxOutput]) -> Tx {
+        let inputs: Vec<TxInput> = inputs.iter().map(|&(txid, index)| TxInput {
+            outpoint: TxOutpoint { txid: txid, index: index },
+            script_len: VarUint::from(0u8), script_sig: Vec::new(), seq_no: 0xffffffff, witness: Vec::new()
+        }).collect();
+        Tx::new(1, VarUint::from(inputs.len()), &inputs, VarUint::from(outputs.len()), outputs, 0, 0x00)
+    }
+
+    fn block(txs: Vec<Tx>, undo: Option<Vec<Vec<SpentOutput>>>) -> Block {
+        let header = BlockHeader::new(1, [0u8; 32], [0u8; 32], 0, 0, 0);
+        let mut block = Block::new(0, 0, 0, header, VarUint::from(txs.len()), txs);
+        block.undo = undo.map(|txs| BlockUndo { txs: txs });
+        block
+    }
+
+    fn balances() -> Balances {
+        Balances {
+            dump_folder: PathBuf::new(), interval: None, heights: Vec::new(), top: None, include_empty: false,
+            version_id: 0, ids: Default::default(), addresses: Vec::new(),
+            start_height: 0, last_height: None, n_snapshots: 0
+        }
+    }
+
+    /// (balance, received, sent, tx_count, first_seen, last_seen)
+    fn stats(cb: &Balances, owner: u8) -> (u64, u64, u64, u32, u32, u32) {
+        let s = &cb.addresses[cb.ids[&AddrKey::new(0, &[owner; 20]).unwrap()] as usize];
+        (s.balance, s.received, s.sent, s.tx_count, s.first_seen, s.last_seen)
+    }
+
+    #[test]
+    fn test_on_block() {
+        let coinbase = [([0u8; 32], 0xffffffff)];
+        let mut cb = balances();
+        cb.on_block(block(vec![tx(&coinbase, &[output(50, 1)])], Some(Vec::new())), 0);
+
+        // Spends the output of address 1 and an output of address 4 created before the start height,
+        // the change goes back to address 1
+        let spent = |value, owner| SpentOutput {
+            height: 0, is_coinbase: false, out: EvaluatedTxOut::eval_script(output(value, owner), 0x00)
+        };
+        let txs = vec![tx(&coinbase, &[output(50, 3)]),
+                       tx(&[([0x11; 32], 0), ([0x44; 32], 0)], &[output(30, 2), output(30, 1)])];
+        cb.on_block(block(txs, Some(vec![vec![spent(50, 1), spent(10, 4)]])), 1);
+
+        assert_eq!((30, 80, 50, 2, 0, 1), stats(&cb, 1));
+        assert_eq!((30, 30, 0, 1, 1, 1), stats(&cb, 2));
+        assert_eq!((50, 50, 0, 1, 1, 1), stats(&cb, 3));
+        assert_eq!((0, 0, 10, 1, 1, 1), stats(&cb, 4));
+        assert_eq!(Some(1), cb.last_height);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_on_block_without_undo() {
+        let mut cb = balances();
+        cb.on_block(block(vec![tx(&[([0u8; 32], 0xffffffff)], &[output(50, 1)])], None), 0);
+    }
+}
@@ -1,35 +1,45 @@
-extern crate csv;
-
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, self};
-use std::io::{LineWriter, Read, Write};
+use std::io::{BufWriter, Read, Write};
 use std::hash::{BuildHasherDefault, Hash};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::slice;
 
 use clap::{Arg, ArgMatches, App, SubCommand};
 use rustc_serialize::json;
 use twox_hash::XxHash;
 
 use callbacks::Callback;
-use errors::{OpError, OpResult};
+use errors::{OpError, OpErrorKind, OpResult};
 
 use blockchain::parser::types::CoinType;
 use blockchain::proto::block::Block;
-use blockchain::proto::tx::TxOutpoint;
-use blockchain::utils::{arr_to_hex_swapped, hex_to_arr32_swapped};
-use blockchain::utils::csv::IndexedCsvFile;
+use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::tx::EvaluatedTxOut;
+use blockchain::utils::arr_to_hex_swapped;
+use blockchain::utils::chainstate::Coin;
+use blockchain::utils::clusterstore::{self, ClusterRecord, ClusterStore};
+use blockchain::utils::utxoset::OutPoint;
 
-const FILES_BLOCKS_SIZE: usize = 10000;
-const MAX_FILES_CACHED: usize = 2;
+/// Entries added since the last save which are kept for the next save, see DisjointSet::new_entries
+const MAX_NEW_ENTRIES: usize = 1 << 22;
 
 /// Tarjan's Union-Find Data structure
-#[derive(RustcDecodable, RustcEncodable)]
 pub struct DisjointSet<T: Clone + Hash + Eq> {
     set_size: usize,
-    parent: Vec<usize>,
-    rank: Vec<usize>,
-    /// Each T entry is mapped onto a usize tag.
-    map: HashMap<T, usize>,
+    parent: Vec<u32>,
+    rank: Vec<u8>,
+    /// Each T entry is mapped onto a u32 tag.
+    map: HashMap<T, u32>,
+    /// Number of entries in the cluster store, unions since the last save with their heuristic
+    n_saved: usize,
+    new_unions: Vec<(u32, u32, u8)>,
+    /// Entries since the last save, so saving doesn't need to scan the map. Dropped once it
+    /// exceeds MAX_NEW_ENTRIES to bound the memory usage, the next save scans the map instead.
+    new_entries: Option<Vec<T>>,
+    /// Parent and rank of the clusters joined by common input ownership alone, so the unions
+    /// a reload without the change heuristics needs are recorded. Split off at the first change union.
+    common_input: Option<(Vec<u32>, Vec<u8>)>,
 }
 
 impl<T> DisjointSet<T>
@@ -41,6 +51,10 @@ impl<T> DisjointSet<T>
             parent: Vec::with_capacity(1000000),
             rank: Vec::with_capacity(1000000),
             map: HashMap::with_capacity(1000000),
+            n_saved: 0,
+            new_unions: Vec::new(),
+            new_entries: Some(Vec::new()),
+            common_input: None,
         }
     }
 
@@ -49,10 +63,26 @@ impl<T> DisjointSet<T>
             return;
         }
 
+        let full = match self.new_entries {
+            Some(ref mut entries) if entries.len() < MAX_NEW_ENTRIES => {
+                entries.push(x.clone());
+                false
+            }
+            Some(_) => true,
+            None => false
+        };
+        if full {
+            self.new_entries = None;
+        }
+
         let len = &mut self.set_size;
-        self.map.insert(x, *len);
-        self.parent.push(*len);
+        self.map.insert(x, *len as u32);
+        self.parent.push(*len as u32);
         self.rank.push(0);
+        if let Some((ref mut parent, ref mut rank)) = self.common_input {
+            parent.push(*len as u32);
+            rank.push(0);
+        }
 
         *len += 1;
     }
@@ -60,7 +90,7 @@ impl<T> DisjointSet<T>
     /// Returns Some(num), num is the tag of subset in which x is.
     /// If x is not in the data structure, it returns None.
     pub fn find(&mut self, x: T) -> Option<usize> {
-        let pos: usize;
+        let pos: u32;
         match self.map.get(&x) {
             Some(p) => {
                 pos = *p;
@@ -69,15 +99,15 @@ impl<T> DisjointSet<T>
         }
 
         let ret = DisjointSet::<T>::find_internal(&mut self.parent, pos);
-        Some(ret)
+        Some(ret as usize)
     }
 
     /// Implements path compression.
-    fn find_internal(p: &mut Vec<usize>, n: usize) -> usize {
-        if p[n] != n {
-            let parent = p[n];
-            p[n] = DisjointSet::<T>::find_internal(p, parent);
-            p[n]
+    fn find_internal(p: &mut Vec<u32>, n: u32) -> u32 {
+        if p[n as usize] != n {
+            let parent = p[n as usize];
+            p[n as usize] = DisjointSet::<T>::find_internal(p, parent);
+            p[n as usize]
         } else {
             n
         }
@@ -87,44 +117,81 @@ impl<T> DisjointSet<T>
     /// If it returns Ok<u32>, it is the tag for unified subset.
     /// If it returns Err(), at least one of x and y is not in the disjoint-set.
     pub fn union(&mut self, x: T, y: T) -> Result<usize, ()> {
-        let x_root;
-        let y_root;
-        let x_rank;
-        let y_rank;
-        match self.find(x) {
-            Some(x_r) => {
-                x_root = x_r;
-                x_rank = self.rank[x_root];
-            }
-            None => {
-                return Err(());
-            }
+        let x_tag = match self.map.get(&x) {
+            Some(x_t) => *x_t,
+            None => return Err(()),
+        };
+        let y_tag = match self.map.get(&y) {
+            Some(y_t) => *y_t,
+            None => return Err(()),
+        };
+        Ok(self.union_tags(x_tag, y_tag, Heuristic::CommonInput as u8))
+    }
+
+    /// Union the subsets to which the entries with tags x and y belong.
+    /// The union is recorded with the heuristic which caused it if the subsets were disjoint.
+    pub fn union_tags(&mut self, x: u32, y: u32, heuristic: u8) -> usize {
+        self.union_tags_by(x, y, &[heuristic])
+    }
+
+    /// Like `union_tags`, but records the union once per heuristic,
+    /// so it is kept as long as any of them is replayed.
+    /// Common input unions are also recorded if they join clusters which are only connected by
+    /// change heuristics, so a reload without them is exact. A reload with only some of the
+    /// change heuristics can still miss a union which another heuristic made redundant.
+    pub fn union_tags_by(&mut self, x: u32, y: u32, heuristics: &[u8]) -> usize {
+        let common_input = heuristics.contains(&(Heuristic::CommonInput as u8));
+        let (root, joined) = self.join(x, y, common_input);
+        if joined {
+            // The entries are recorded rather than the roots, which depend on the unions before
+            self.new_unions.extend(heuristics.iter().map(|h| (x, y, *h)));
         }
+        root as usize
+    }
 
-        match self.find(y) {
-            Some(y_r) => {
-                y_root = y_r;
-                y_rank = self.rank[y_root];
-            }
-            None => {
-                return Err(());
+    /// Joins the subsets of the tags x and y, change unions are kept out of the common input clusters.
+    /// Returns the new root and whether the subsets were disjoint in either of them.
+    fn join(&mut self, x: u32, y: u32, common_input: bool) -> (u32, bool) {
+        let mut joined = false;
+        if !common_input && self.common_input.is_none() {
+            self.common_input = Some((self.parent.clone(), self.rank.clone()));
+        } else if let Some((ref mut parent, ref mut rank)) = self.common_input {
+            if common_input {
+                let x_root = DisjointSet::<T>::find_internal(parent, x);
+                let y_root = DisjointSet::<T>::find_internal(parent, y);
+                joined = x_root != y_root;
+                DisjointSet::<T>::union_roots(parent, rank, x_root, y_root);
             }
         }
+        let x_root = DisjointSet::<T>::find_internal(&mut self.parent, x);
+        let y_root = DisjointSet::<T>::find_internal(&mut self.parent, y);
+        let joined = joined || x_root != y_root;
+        (DisjointSet::<T>::union_roots(&mut self.parent, &mut self.rank, x_root, y_root), joined)
+    }
 
+    /// Returns true if the entry was never joined with another one
+    #[inline]
+    pub fn is_singleton(&self, tag: u32) -> bool {
+        self.parent[tag as usize] == tag && self.rank[tag as usize] == 0
+    }
+
+    fn union_roots(parent: &mut Vec<u32>, rank: &mut Vec<u8>, x_root: u32, y_root: u32) -> u32 {
         // Implements union-by-rank optimization.
         if x_root == y_root {
-            return Ok(x_root)
+            return x_root
         }
 
+        let x_rank = rank[x_root as usize];
+        let y_rank = rank[y_root as usize];
         if x_rank > y_rank {
-            self.parent[y_root] = x_root;
-            return Ok(x_root)
+            parent[y_root as usize] = x_root;
+            return x_root
         } else {
-            self.parent[x_root] = y_root;
+            parent[x_root as usize] = y_root;
             if x_rank == y_rank {
-                self.rank[y_root] += 1;
+                rank[y_root as usize] += 1;
             }
-            return Ok(y_root)
+            return y_root
         }
     }
 
@@ -132,45 +199,318 @@ impl<T> DisjointSet<T>
     pub fn finalize(&mut self) {
         debug!(target: "finalize", "Finalizing clusters.");
         for i in 0..self.set_size {
-            DisjointSet::<T>::find_internal(&mut self.parent, i);
+            DisjointSet::<T>::find_internal(&mut self.parent, i as u32);
         }
         debug!(target: "finalize", "Clusters finalized.");
     }
 }
 
+impl DisjointSet<String> {
+    /// Returns the tag of an address, a new set is created on first sight
+    pub fn intern(&mut self, address: &str) -> u32 {
+        if let Some(tag) = self.map.get(address) {
+            return *tag;
+        }
+        self.make_set(String::from(address));
+        (self.set_size - 1) as u32
+    }
+
+    /// Restores the clusters from a cluster store, unions are replayed in the saved order
+    /// so every address ends up with the same tag. Unions of heuristics rejected by `replay` are skipped.
+    pub fn load<F>(path: &Path, replay: F) -> OpResult<(Self, ClusterStore)> where F: Fn(u8) -> bool {
+        let mut clusters = DisjointSet::new();
+        let mut n_skipped = 0;
+        let store = try!(ClusterStore::open(path, |record| match record {
+            ClusterRecord::Address(address) => clusters.make_set(String::from(address)),
+            ClusterRecord::Union(x, y, heuristic) => {
+                if !replay(heuristic) {
+                    n_skipped += 1;
+                    return;
+                }
+                clusters.join(x, y, heuristic == Heuristic::CommonInput as u8);
+            }
+        }));
+        if n_skipped > 0 {
+            info!(target: "load", "Skipped {} unions of disabled heuristics.", n_skipped);
+        }
+        clusters.n_saved = clusters.set_size;
+        clusters.new_unions.clear();
+        clusters.new_entries = Some(Vec::new());
+        Ok((clusters, store))
+    }
+
+    /// Appends the entries and unions since the last save to the store
+    pub fn save(&mut self, store: &mut ClusterStore) -> OpResult<usize> {
+        let written = {
+            let addresses: Vec<&str> = match self.new_entries {
+                Some(ref entries) => entries.iter().map(|address| address.as_str()).collect(),
+                None => {
+                    debug!(target: "save", "Collecting {} new addresses from the map.", self.set_size - self.n_saved);
+                    let mut new_entries: Vec<(u32, &str)> = self.map.iter()
+                        .filter(|&(_, tag)| *tag as usize >= self.n_saved)
+                        .map(|(address, tag)| (*tag, address.as_str()))
+                        .collect();
+                    new_entries.sort_by_key(|&(tag, _)| tag);
+                    new_entries.into_iter().map(|(_, address)| address).collect()
+                }
+            };
+            try!(store.append(&addresses, &self.new_unions))
+        };
+        self.n_saved = self.set_size;
+        self.new_unions.clear();
+        self.new_entries = Some(Vec::new());
+        Ok(written)
+    }
+
+    /// Converts clusters.dat written by previous versions (JSON).
+    /// The legacy set is finalized, so joining each entry with its parent keeps all tags.
+    fn from_legacy(path: &Path) -> OpResult<Self> {
+        let mut encoded = String::new();
+        let mut file = try!(File::open(path));
+        try!(file.read_to_string(&mut encoded));
+        let legacy = try!(json::decode::<LegacyDisjointSet>(&encoded));
+
+        let mut entries = vec![String::new(); legacy.set_size];
+        for (address, tag) in legacy.map {
+            entries[tag] = address;
+        }
+        let mut clusters = DisjointSet::new();
+        for address in entries {
+            clusters.make_set(address);
+        }
+        for (i, parent) in legacy.parent.into_iter().enumerate() {
+            if parent != i {
+                let x_root = DisjointSet::<String>::find_internal(&mut clusters.parent, i as u32);
+                let y_root = DisjointSet::<String>::find_internal(&mut clusters.parent, parent as u32);
+                if x_root != y_root {
+                    clusters.new_unions.push((x_root, y_root, Heuristic::CommonInput as u8));
+                    DisjointSet::<String>::union_roots(&mut clusters.parent, &mut clusters.rank, x_root, y_root);
+                }
+            }
+        }
+        Ok(clusters)
+    }
+}
+
+/// JSON format of clusters.dat used by previous versions, only used for migration
+#[derive(RustcDecodable, RustcEncodable)]
+struct LegacyDisjointSet {
+    set_size: usize,
+    parent: Vec<usize>,
+    map: HashMap<String, usize>,
+}
+
+/// Heuristics which join addresses into a cluster, the code is stored with each union
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Heuristic {
+    /// All inputs of a transaction belong to the same owner, always applied
+    CommonInput = 0,
+    /// The change goes to the only output address which wasn't seen before
+    FreshAddress = 1,
+    /// Payments are round amounts, the change isn't
+    RoundPayment = 2,
+    /// The change has the same script type as all inputs
+    ScriptType = 3,
+    /// The change is smaller than every input, otherwise that input wouldn't be needed
+    OptimalChange = 4,
+}
+
+/// Change heuristics in the order they are applied, from the most reliable one
+const CHANGE_HEURISTICS: [Heuristic; 4] = [Heuristic::FreshAddress, Heuristic::OptimalChange,
+                                          Heuristic::ScriptType, Heuristic::RoundPayment];
+
+/// Amounts which are a multiple of this (0.001 BTC) count as round payments
+const ROUND_PAYMENT_UNIT: u64 = 100000;
+
+impl Heuristic {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Heuristic::CommonInput => "common-input",
+            Heuristic::FreshAddress => "fresh-address",
+            Heuristic::RoundPayment => "round-payment",
+            Heuristic::ScriptType => "script-type",
+            Heuristic::OptimalChange => "optimal-change",
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Heuristic> {
+        CHANGE_HEURISTICS.iter().chain(&[Heuristic::CommonInput]).find(|h| **h as u8 == code).cloned()
+    }
+
+    /// Parses a comma separated list of change heuristics, `all` enables every one
+    fn parse_list(list: &str) -> OpResult<Vec<Heuristic>> {
+        let mut selected = Vec::new();
+        for name in list.split(',').map(|n| n.trim()) {
+            match CHANGE_HEURISTICS.iter().find(|h| h.name() == name) {
+                Some(heuristic) => selected.push(*heuristic),
+                None if name == "all" => selected.extend_from_slice(&CHANGE_HEURISTICS),
+                None => return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                    .join_msg(&format!("Unknown change heuristic: `{}`", name)))
+            }
+        }
+        Ok(CHANGE_HEURISTICS.iter().filter(|h| selected.contains(h)).cloned().collect())
+    }
+}
+
+/// Value and script type of an output, as compared by the change heuristics
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct OutputInfo {
+    value: u64,
+    script_type: u8,
+}
+
+impl OutputInfo {
+    fn new(output: &EvaluatedTxOut) -> OutputInfo {
+        let script_type = match output.script.pattern {
+            ScriptPattern::Pay2PublicKey => 1,
+            ScriptPattern::Pay2PublicKeyHash => 2,
+            ScriptPattern::Pay2ScriptHash => 3,
+            ScriptPattern::Pay2WitnessPublicKeyHash => 4,
+            ScriptPattern::Pay2WitnessScriptHash => 5,
+            ScriptPattern::Pay2Taproot => 6,
+            _ => 7,
+        };
+        OutputInfo { value: output.out.value, script_type: script_type }
+    }
+}
+
+/// Output of a transaction which may be its change
+#[derive(Clone, Copy, Debug)]
+struct ChangeCandidate {
+    tag: u32,
+    /// The address was first seen in this transaction
+    fresh: bool,
+    info: OutputInfo,
+}
+
+/// Returns the tag of the change output and the heuristics which identified it.
+/// Nothing is returned for CoinJoin-like transactions, if an output goes back to an input address
+/// or if the heuristics point to different outputs.
+fn detect_change(heuristics: &[Heuristic], inputs: &[OutputInfo], input_tags: &HashSet<u32, BuildHasherDefault<XxHash>>,
+                 outputs: &[ChangeCandidate]) -> Option<(u32, Vec<u8>)> {
+    if heuristics.is_empty() || inputs.is_empty() || outputs.len() < 2 {
+        return None;
+    }
+    // The change address is reused, nothing to learn
+    if outputs.iter().any(|o| input_tags.contains(&o.tag)) {
+        return None;
+    }
+    // Several inputs and outputs of equal value are the typical shape of a CoinJoin
+    if inputs.len() > 1 && (1..outputs.len()).any(|i| outputs[..i].iter().any(|o| o.info.value == outputs[i].info.value)) {
+        return None;
+    }
+
+    let mut change = None;
+    let mut codes = Vec::new();
+    for heuristic in heuristics {
+        let found = match *heuristic {
+            Heuristic::CommonInput => None,
+            Heuristic::FreshAddress => single_output(outputs, |o| o.fresh),
+            Heuristic::RoundPayment => single_output(outputs, |o| o.info.value % ROUND_PAYMENT_UNIT != 0),
+            Heuristic::ScriptType => {
+                let script_type = inputs[0].script_type;
+                match inputs.iter().all(|i| i.script_type == script_type) {
+                    true => single_output(outputs, |o| o.info.script_type == script_type),
+                    false => None
+                }
+            }
+            Heuristic::OptimalChange => {
+                let min_input = inputs.iter().map(|i| i.value).min().unwrap();
+                single_output(outputs, |o| o.info.value < min_input)
+            }
+        };
+        if let Some(index) = found {
+            if change.map_or(false, |c| c != index) {
+                trace!(target: "detect_change", "Change heuristics disagree, skipping transaction.");
+                return None;
+            }
+            change = Some(index);
+            codes.push(*heuristic as u8);
+        }
+    }
+    change.map(|index| (outputs[index].tag, codes))
+}
+
+/// Returns the index of the only output matching `f`
+fn single_output<F>(outputs: &[ChangeCandidate], f: F) -> Option<usize> where F: Fn(&ChangeCandidate) -> bool {
+    let mut matching = outputs.iter().enumerate().filter(|&(_, o)| f(o)).map(|(i, _)| i);
+    match (matching.next(), matching.next()) {
+        (Some(index), None) => Some(index),
+        _ => None
+    }
+}
+
 /// Groups addresses into ownership clusters.
+/// Input addresses are resolved from the undo data (`--undo`) or from the outputs tracked during the pass.
 pub struct Clusterizer {
     dump_folder: PathBuf,
-    outputs_csv: Vec<IndexedCsvFile>,
+    /// Enabled change heuristics, applied after the common input ownership
+    heuristics: Vec<Heuristic>,
+    export_unions: bool,
 
-    address_by_txoutpoint_cache: VecDeque<HashMap<TxOutpoint, String, BuildHasherDefault<XxHash>>>,
     clusters: DisjointSet<String>,
+    store: ClusterStore,
+    /// Tags of the addresses of unspent outputs, not required with --undo
+    unspent: HashMap<OutPoint, (u32, OutputInfo), BuildHasherDefault<XxHash>>,
+    /// Unspent outputs with several addresses of the same owner
+    unspent_multi: HashMap<OutPoint, (Vec<u32>, OutputInfo), BuildHasherDefault<XxHash>>,
+    /// Next block height of the loaded outpoints.bin, the scan has to continue there
+    resume_height: Option<usize>,
 
     start_height: usize,
     end_height: usize,
     max_height: usize,
-    file_chunk: usize,
     tx_count: u64,
     in_count: u64,
     out_count: u64,
-    cache_hits: u64,
-    cache_misses: u64,
-    file_accesses: u64,
+    /// Inputs spending outputs which weren't tracked
+    n_unresolved: u64,
+    /// Transactions whose change was joined with the inputs
+    n_change: u64,
 }
 
 impl Clusterizer {
-    /// Serializes clusters to a file
-    fn serialize_clusters(&mut self) -> OpResult<usize> {
-        self.clusters.finalize();
-        let encoded = try!(json::encode(&self.clusters));
-        let temp_file_path = self.dump_folder.join("clusters.dat.new").as_path().to_owned();
-        let file_path = self.dump_folder.join("clusters.dat").as_path().to_owned();
-        let mut file = try!(File::create(temp_file_path.to_owned()));
-        try!(file.write_all(encoded.as_bytes()));
-        try!(fs::rename(temp_file_path, file_path));
-        debug!(target: "serialize_clusters", "Serialized {} clusters to file.",
-                       self.clusters.set_size);
-        Ok(encoded.len())
+    /// Opens clusters.bin in the dump folder, clusters.dat of previous versions is converted once.
+    /// Only the unions of the enabled heuristics are replayed.
+    fn load_clusters(dump_folder: &Path, heuristics: &[Heuristic]) -> OpResult<(DisjointSet<String>, ClusterStore)> {
+        let store_path = dump_folder.join("clusters.bin");
+        let legacy_path = dump_folder.join("clusters.dat");
+        if store_path.exists() {
+            let (clusters, store) = try!(DisjointSet::load(&store_path, |code| {
+                code == Heuristic::CommonInput as u8 || heuristics.iter().any(|h| *h as u8 == code)
+            }));
+            info!(target: "new", "Resuming from {} saved addresses.", clusters.set_size);
+            Ok((clusters, store))
+        } else if legacy_path.exists() {
+            info!(target: "new", "Converting {} to {} ...", legacy_path.display(), store_path.display());
+            let mut clusters = try!(DisjointSet::from_legacy(&legacy_path));
+            // Written to a temporary file first, an aborted conversion is restarted on the next run
+            let tmp_path = dump_folder.join("clusters.bin.tmp");
+            let mut store = try!(ClusterStore::create(&tmp_path));
+            try!(clusters.save(&mut store));
+            try!(fs::rename(&tmp_path, &store_path));
+            let store = try!(ClusterStore::open(&store_path, |_| ()));
+            info!(target: "new", "Converted {} addresses, {} is no longer needed.",
+                  clusters.set_size, legacy_path.display());
+            Ok((clusters, store))
+        } else {
+            Ok((DisjointSet::new(), try!(ClusterStore::create(&store_path))))
+        }
+    }
+
+    /// Appends new addresses and unions to clusters.bin and saves the tracked outputs,
+    /// so an aborted or later run continues from `height`
+    fn serialize_clusters(&mut self, height: usize) -> OpResult<usize> {
+        let written = try!(self.clusters.save(&mut self.store));
+        let count = (self.unspent.len() + self.unspent_multi.len()) as u64;
+        let outpoints = self.unspent.iter()
+            .map(|(outpoint, entry)| (outpoint, entry.1.value, entry.1.script_type, slice::from_ref(&entry.0)))
+            .chain(self.unspent_multi.iter()
+                .map(|(outpoint, entry)| (outpoint, entry.1.value, entry.1.script_type, &entry.0[..])));
+        try!(clusterstore::save_outpoints(&self.dump_folder.join("outpoints.bin"), height as u64, count, outpoints));
+        debug!(target: "serialize_clusters", "Serialized {} clusters and {} unspent outputs to file.",
+                       self.clusters.set_size, count);
+        Ok(written)
     }
 
     /// Export clusters to a CSV file
@@ -182,64 +522,81 @@ impl Clusterizer {
         let temp_file_path = self.dump_folder.join("clusters.csv.new").as_path().to_owned();
         let file_path = self.dump_folder.join("clusters.csv").as_path().to_owned();
         let file = try!(File::create(temp_file_path.to_owned()));
-        let mut writer = LineWriter::new(file);
+        let mut writer = BufWriter::new(file);
+        let mut n_exported = 0;
         for (address, tag) in &self.clusters.map {
-            let line = format!("{};{}\n", address, self.clusters.parent[*tag]);
+            // Addresses of tracked outputs which never shared an input with another address
+            if self.clusters.is_singleton(*tag) {
+                continue;
+            }
+            let line = format!("{};{}\n", address, self.clusters.parent[*tag as usize]);
             try!(writer.write_all(line.as_bytes()));
+            n_exported += 1;
         }
+        try!(writer.flush());
 
         try!(fs::rename(temp_file_path, file_path));
-        debug!(target: "export_clusters_to_csv", "Exported {} clusters to CSV.",
-                       self.clusters.set_size);
-        Ok(self.clusters.set_size)
-    }
-
-    /// Cache the last outputs CSV file
-    fn cache_outputs(&mut self, indexed_file: &mut IndexedCsvFile) -> OpResult<usize> {
-        let mut file_map: HashMap<TxOutpoint, String, BuildHasherDefault<XxHash>> = Default::default();
-        debug!(target: "cache_outputs", "Caching transaction outputs in {:?}...", indexed_file.path);
-        for record in indexed_file.index.records() {
-            let record_vector: Vec<String> = record.unwrap();
-            let tx_outpoint = TxOutpoint {
-                txid: hex_to_arr32_swapped(&record_vector[0]),
-                index: record_vector[1].parse::<u32>().unwrap(),
-            };
+        debug!(target: "export_clusters_to_csv", "Exported {} clusters to CSV.", n_exported);
+        Ok(n_exported)
+    }
 
-            file_map.insert(tx_outpoint, record_vector[2].to_owned());
+    /// Export all recorded unions with the heuristic which caused them to a CSV file,
+    /// including those of heuristics which are currently disabled
+    fn export_unions_to_csv(&self) -> OpResult<usize> {
+        let mut addresses = vec![""; self.clusters.set_size];
+        for (address, tag) in &self.clusters.map {
+            addresses[*tag as usize] = address;
         }
 
-        let addresses_count = file_map.len();
-        self.address_by_txoutpoint_cache.push_back(file_map);
-        debug!(target: "cache_outputs", "Done.");
-        Ok(addresses_count)
+        let temp_file_path = self.dump_folder.join("unions.csv.new");
+        let file_path = self.dump_folder.join("unions.csv");
+        let mut writer = BufWriter::new(try!(File::create(&temp_file_path)));
+        let mut result = Ok(());
+        let mut n_exported = 0;
+        try!(ClusterStore::open(&self.dump_folder.join("clusters.bin"), |record| {
+            if let ClusterRecord::Union(x, y, code) = record {
+                let heuristic = Heuristic::from_code(code).map_or_else(|| code.to_string(), |h| String::from(h.name()));
+                if result.is_ok() {
+                    result = writer.write_all(format!("{};{};{}\n", addresses[x as usize], addresses[y as usize], heuristic).as_bytes());
+                    n_exported += 1;
+                }
+            }
+        }));
+        try!(result);
+        try!(writer.flush());
+
+        try!(fs::rename(temp_file_path, file_path));
+        debug!(target: "export_unions_to_csv", "Exported {} unions to CSV.", n_exported);
+        Ok(n_exported)
     }
 
-    fn get_address_from_txoutpoint(&mut self, tx_outpoint: &TxOutpoint) -> OpResult<String> {
-        for address_cache_slot in self.address_by_txoutpoint_cache.iter().rev() {
-            if let Some(address) = address_cache_slot.get(tx_outpoint) {
-                trace!(target: "get_address_from_txoutpoint", "Cache HIT for tx_outpoint {:#?} = {}.", tx_outpoint, address);
-                self.cache_hits += 1;
-                return Ok(address.to_owned());
-            }
+    /// Returns the tags of an output's addresses.
+    /// Multisig addresses are omitted, because the keys may belong to different owners.
+    fn output_tags(clusters: &mut DisjointSet<String>, output: &EvaluatedTxOut) -> Vec<u32> {
+        if !output.script.has_single_owner() {
+            return Vec::new();
         }
-        trace!(target: "get_address_from_txoutpoint", "Cache MISS for tx_outpoint {:#?}.", tx_outpoint);
-        self.cache_misses += 1;
+        output.script.addresses().into_iter()
+            .filter(|a| !a.is_empty())
+            .map(|a| clusters.intern(a))
+            .collect()
+    }
 
-        let mut file_attempts = 0usize;
-        for outputs_csv in self.outputs_csv.iter_mut().rev().skip(self.address_by_txoutpoint_cache.len()) {
-            file_attempts += 1;
-            match outputs_csv.binary_search(&tx_outpoint.to_string()) {
-                Ok(address) => {
-                    self.file_accesses += file_attempts as u64;
-                    return Ok(address.to_owned());
-                }
-                Err(_) => {
-                    trace!(target: "get_address_from_txoutpoint", "Could not find tx_outpoint {:#?} in {:?}.", tx_outpoint, outputs_csv.path.as_path());
-                }
-            };
-        }
+    /// Tracks an output until it gets spent
+    fn track_output(&mut self, outpoint: OutPoint, output: &EvaluatedTxOut) {
+        let tags = Clusterizer::output_tags(&mut self.clusters, output);
+        self.track_tags(outpoint, tags, OutputInfo::new(output));
+    }
 
-        Err(OpError::from("Not found.".to_owned()))
+    fn track_tags(&mut self, outpoint: OutPoint, mut tags: Vec<u32>, info: OutputInfo) {
+        match tags.len() {
+            0 => (),
+            1 => { self.unspent.insert(outpoint, (tags[0], info)); }
+            _ => {
+                tags.dedup();
+                self.unspent_multi.insert(outpoint, (tags, info));
+            }
+        }
     }
 }
 
@@ -252,7 +609,7 @@ impl Callback for Clusterizer {
             .version("0.1")
             .author("Michele Spagnuolo <mikispag@gmail.com>")
             .arg(Arg::with_name("dump-folder")
-                .help("Folder with sorted tx_out-*-*.csv files, where to store the cluster CSV")
+                .help("Folder to store the clusters (clusters.bin, outpoints.bin and clusters.csv)")
                 .index(1)
                 .required(true))
             .arg(Arg::with_name("max-height")
@@ -260,6 +617,15 @@ impl Callback for Clusterizer {
                 .long("max-height")
                 .takes_value(true)
                 .help("Stop at a specified block height"))
+            .arg(Arg::with_name("change-heuristics")
+                .long("change-heuristics")
+                .value_name("LIST")
+                .takes_value(true)
+                .help("Also joins the change output with the inputs, comma separated list of \
+                       fresh-address, optimal-change, script-type and round-payment, or all"))
+            .arg(Arg::with_name("export-unions")
+                .long("export-unions")
+                .help("Writes all recorded unions and the heuristic which caused them to unions.csv"))
     }
 
     fn new(matches: &ArgMatches) -> OpResult<Self>
@@ -268,35 +634,44 @@ impl Callback for Clusterizer {
         let ref dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap());
         let max_height = value_t!(matches, "max-height", usize).unwrap_or(0);
         match (|| -> OpResult<Self> {
-            let cb = Clusterizer {
+            let heuristics = match matches.value_of("change-heuristics") {
+                Some(list) => try!(Heuristic::parse_list(list)),
+                None => Vec::new()
+            };
+            try!(fs::create_dir_all(dump_folder));
+            let (clusters, store) = try!(Clusterizer::load_clusters(dump_folder, &heuristics));
+            let mut cb = Clusterizer {
                 dump_folder: PathBuf::from(dump_folder),
-                outputs_csv: Vec::new(),
-                address_by_txoutpoint_cache: VecDeque::with_capacity(MAX_FILES_CACHED),
-                clusters: {
-                    let mut encoded = String::new();
-                    let mut new_clusters: DisjointSet<String> = DisjointSet::new();
-
-                    if let Ok(mut file) = File::open(dump_folder.join("clusters.dat")) {
-                        try!(file.read_to_string(&mut encoded));
-                        let clusters = try!(json::decode::<DisjointSet<String>>(&encoded));
-                        info!(target: "new", "Resuming from saved clusters.");
-                        new_clusters = clusters;
-                    }
-
-                    new_clusters
-                },
+                heuristics: heuristics,
+                export_unions: matches.is_present("export-unions"),
+                clusters: clusters,
+                store: store,
+                unspent: HashMap::with_capacity_and_hasher(1000000, Default::default()),
+                unspent_multi: Default::default(),
+                resume_height: None,
 
                 start_height: 0,
                 end_height: 0,
                 max_height: max_height,
-                file_chunk: 0,
                 tx_count: 0,
                 in_count: 0,
                 out_count: 0,
-                cache_hits: 0,
-                cache_misses: 0,
-                file_accesses: 0,
+                n_unresolved: 0,
+                n_change: 0,
             };
+            let outpoints_path = dump_folder.join("outpoints.bin");
+            if outpoints_path.exists() {
+                let (unspent, unspent_multi) = (&mut cb.unspent, &mut cb.unspent_multi);
+                let (count, height) = try!(clusterstore::load_outpoints(&outpoints_path, |outpoint, value, script_type, tags| {
+                    let info = OutputInfo { value: value, script_type: script_type };
+                    match tags.len() {
+                        1 => { unspent.insert(outpoint, (tags[0], info)); }
+                        _ => { unspent_multi.insert(outpoint, (tags.to_vec(), info)); }
+                    }
+                }));
+                cb.resume_height = Some(height as usize);
+                info!(target: "new", "Resuming with {} unspent outputs.", count);
+            }
             Ok(cb)
         })() {
             Ok(s) => return Ok(s),
@@ -311,59 +686,17 @@ impl Callback for Clusterizer {
     fn on_start(&mut self, _: CoinType, block_height: usize) {
         self.start_height = block_height;
         info!(target: "on_start", "Using `clusterizer` with dump folder {:?} and start block {}...", &self.dump_folder, self.start_height);
-
-        for chunk_start in 0..self.start_height / FILES_BLOCKS_SIZE {
-            let csv_file_path = self.dump_folder.join(format!("tx_out-{}-{}.csv", chunk_start * FILES_BLOCKS_SIZE, (chunk_start + 1) * FILES_BLOCKS_SIZE));
-            let csv_file_path_string = csv_file_path.as_path().display();
-            debug!(target: "on_start", "Indexing CSV file: {}...", csv_file_path_string);
-            let mut indexed_file = IndexedCsvFile::new(csv_file_path.to_owned(), b';').expect(&format!("Unable to open outputs CSV file {}!", csv_file_path_string));
-
-            if self.start_height / FILES_BLOCKS_SIZE - chunk_start < MAX_FILES_CACHED {
-                let _ = self.cache_outputs(&mut indexed_file);
-            }
-
-            self.outputs_csv.push(indexed_file);
-            self.file_chunk += 1;
+        if !self.heuristics.is_empty() {
+            let names: Vec<&str> = self.heuristics.iter().map(|h| h.name()).collect();
+            info!(target: "on_start", "Change heuristics: {}.", names.join(", "));
         }
-
-        debug!(target: "on_start", "Done.");
     }
 
     fn on_block(&mut self, block: Block, block_height: usize) {
         debug!(target: "on_block", "Block: {}.", block_height);
-        if self.max_height > 0 && block_height >= self.max_height {
-            debug!(target: "on_block", "Skipping block {} because max-height is set to {}.", block_height, self.max_height);
-            return;
-        }
         if block_height % 100 == 0 {
-            let mut cache_tries: f32 = self.cache_hits as f32 + self.cache_misses as f32;
-            if cache_tries == 0f32 {
-                cache_tries = 1f32;
-            }
-
-            info!(target: "on_block", "Progress: block {}, {} clusters, {} transactions, cache hit ratio: {}/{} ({:.01}%), file accesses: {}.", block_height, self.clusters.set_size, self.tx_count, self.cache_hits, self.cache_hits + self.cache_misses, 100.0 * self.cache_hits as f32/cache_tries, self.file_accesses);
-        }
-
-        let chunk_start = block_height / FILES_BLOCKS_SIZE;
-        if chunk_start == self.file_chunk {
-            // Purge old elements from the address cache
-            if self.address_by_txoutpoint_cache.len() == MAX_FILES_CACHED {
-                self.address_by_txoutpoint_cache.pop_front();
-                debug!(target: "on_block", "Contents of the oldest CSV file removed from the cache.");
-            }
-
-            // Load a new outputs CSV file
-            let csv_file_path = self.dump_folder.join(format!("tx_out-{}-{}.csv", chunk_start * FILES_BLOCKS_SIZE, (chunk_start + 1) * FILES_BLOCKS_SIZE));
-            let csv_file_path_string = csv_file_path.as_path().display();
-            debug!(target: "on_block", "Indexing CSV file: {}...", csv_file_path_string);
-            let mut indexed_file = IndexedCsvFile::new(csv_file_path.to_owned(), b';').expect(&format!("Unable to open outputs CSV file {}!", csv_file_path_string));
-            debug!(target: "on_block", "Done.");
-            self.file_chunk += 1;
-
-            let _ = self.cache_outputs(&mut indexed_file);
-
-            self.outputs_csv.push(indexed_file);
-            debug!(target: "on_block", "Added new indexed outputs CSV file {}.", csv_file_path_string);
+            info!(target: "on_block", "Progress: block {}, {} addresses, {} transactions, {} unspent outputs, {} unresolved inputs.",
+                  block_height, self.clusters.set_size, self.tx_count, self.unspent.len() + self.unspent_multi.len(), self.n_unresolved);
         }
 
         for (tx_index, tx) in block.txs.iter().enumerate() {
@@ -372,61 +705,265 @@ impl Callback for Clusterizer {
             self.in_count += tx.value.in_count.value;
             self.out_count += tx.value.out_count.value;
 
-            // Skip transactions with just one input
-            if tx.value.in_count.value < 2 {
-                trace!(target: "on_block", "Skipping transaction with one input.");
-                continue;
+            let mut tx_inputs: HashSet<u32, BuildHasherDefault<XxHash>> = Default::default();
+            // Spent outputs as seen by the change heuristics, only complete if all of them are known
+            let mut spent_infos = Vec::new();
+            let mut spent_complete = true;
+            if !tx.value.is_coinbase() {
+                match block.spent_outputs(tx_index) {
+                    Some(spent) => {
+                        for spent in spent {
+                            tx_inputs.extend(Clusterizer::output_tags(&mut self.clusters, &spent.out));
+                            spent_infos.push(OutputInfo::new(&spent.out));
+                        }
+                    }
+                    None => {
+                        for input in &tx.value.inputs {
+                            let outpoint = (input.outpoint.txid, input.outpoint.index);
+                            if let Some((tag, info)) = self.unspent.remove(&outpoint) {
+                                tx_inputs.insert(tag);
+                                spent_infos.push(info);
+                            } else if let Some((tags, info)) = self.unspent_multi.remove(&outpoint) {
+                                tx_inputs.extend(tags);
+                                spent_infos.push(info);
+                            } else {
+                                trace!(target: "on_block", "Unknown outpoint {}:{}.",
+                                       arr_to_hex_swapped(&outpoint.0), outpoint.1);
+                                self.n_unresolved += 1;
+                                spent_complete = false;
+                            }
+                        }
+                    }
+                }
             }
 
-            let mut tx_inputs: HashSet<String, BuildHasherDefault<XxHash>> = Default::default();
-            for input in &tx.value.inputs {
-                let tx_outpoint = TxOutpoint {
-                    txid: input.outpoint.txid,
-                    index: input.outpoint.index,
-                };
-
-                match self.get_address_from_txoutpoint(&tx_outpoint) {
-                    Ok(address) => {
-                        tx_inputs.insert(address.to_owned());
+            // Spends are resolved from the undo data, the change heuristics still need the output addresses
+            let track = block.undo.is_none();
+            let n_known = self.clusters.set_size as u32;
+            let mut candidates = Vec::new();
+            if track || !self.heuristics.is_empty() {
+                for (vout, output) in tx.value.outputs.iter().enumerate() {
+                    let tags = Clusterizer::output_tags(&mut self.clusters, output);
+                    let info = OutputInfo::new(output);
+                    match tags.first() {
+                        Some(&tag) => candidates.push(ChangeCandidate { tag: tag, fresh: tag >= n_known, info: info }),
+                        // Payments to multisig or unknown scripts, the change can't be told apart
+                        None if info.value > 0 => spent_complete = false,
+                        None => ()
                     }
-                    Err(e) => {
-                        panic!("Error while retrieving {:#?} from the outputs CSV: {}!", tx_outpoint, e);
+                    if track {
+                        self.track_tags((tx.hash, vout as u32), tags, info);
                     }
-                };
+                }
             }
 
-            // Skip transactions with just one input
             if tx_inputs.len() < 2 {
-                trace!(target: "on_block", "Skipping transaction with one distinct input.");
-                continue;
+                trace!(target: "on_block", "Skipping common input ownership for transaction with one distinct input.");
+            } else {
+                let mut tx_inputs_iter = tx_inputs.iter();
+                let mut last_tag = *tx_inputs_iter.next().unwrap();
+                for tag in tx_inputs_iter {
+                    self.clusters.union_tags(last_tag, *tag, Heuristic::CommonInput as u8);
+                    last_tag = *tag;
+                }
             }
 
-            let mut tx_inputs_iter = tx_inputs.iter();
-            let mut last_address = tx_inputs_iter.next().unwrap().to_owned();
-            self.clusters.make_set(last_address.to_owned());
-            for address in tx_inputs_iter {
-                self.clusters.make_set(address.to_owned());
-                let _ = self.clusters.union(last_address.to_owned(), address.to_owned());
-                last_address = address.to_owned();
+            if !spent_complete || tx_inputs.is_empty() {
+                continue;
+            }
+            if let Some((change, codes)) = detect_change(&self.heuristics, &spent_infos, &tx_inputs, &candidates) {
+                let input = *tx_inputs.iter().next().unwrap();
+                self.clusters.union_tags_by(input, change, &codes);
+                self.n_change += 1;
             }
         }
 
         self.tx_count += block.tx_count.value;
     }
 
+    fn utxo_snapshot(&self) -> bool {
+        true
+    }
+
+    fn on_snapshot_coin(&mut self, coin: Coin) {
+        self.track_output((coin.txid, coin.index), &coin.out);
+    }
+
+    fn checkpoints(&self) -> bool {
+        true
+    }
+
+    fn max_height(&self) -> Option<usize> {
+        match self.max_height {
+            0 => None,
+            max_height => Some(max_height)
+        }
+    }
+
+    fn resume_height(&self) -> Option<usize> {
+        self.resume_height
+    }
+
+    fn on_checkpoint(&mut self, block_height: usize) -> OpResult<bool> {
+        try!(self.serialize_clusters(block_height));
+        Ok(true)
+    }
+
     fn on_complete(&mut self, block_height: usize) {
         self.end_height = block_height;
 
         // Write clusters to file
-        let _ = self.serialize_clusters();
+        self.serialize_clusters(block_height).expect("Unable to save clusters!");
         // Export clusters to CSV
         let _ = self.export_clusters_to_csv();
+        if self.export_unions {
+            if let Err(err) = self.export_unions_to_csv() {
+                error!(target: "on_complete", "Unable to export unions: {}", err);
+            }
+        }
 
         info!(target: "on_complete", "Done.\nProcessed all {} blocks:\n\
-                                   \t-> clusters:     {:9}\n\
+                                   \t-> addresses:    {:9}\n\
                                    \t-> transactions: {:9}\n\
                                    \t-> inputs:       {:9}\n\
-                                   \t-> outputs:      {:9}",
-             self.end_height + 1, self.clusters.set_size, self.tx_count, self.in_count, self.out_count);
+                                   \t-> outputs:      {:9}\n\
+                                   \t-> unresolved:   {:9}\n\
+                                   \t-> change:       {:9}",
+             self.end_height + 1, self.clusters.set_size, self.tx_count, self.in_count, self.out_count, self.n_unresolved,
+             self.n_change);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_save_and_load_clusters() {
+        let dir = env::temp_dir().join(format!("clusterizer-test-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("clusters.bin");
+        let mut store = ClusterStore::create(&path).unwrap();
+
+        let mut clusters: DisjointSet<String> = DisjointSet::new();
+        for address in &["a", "b", "c", "d", "e"] {
+            clusters.make_set(String::from(*address));
+        }
+        clusters.union(String::from("a"), String::from("b")).unwrap();
+        clusters.union(String::from("c"), String::from("d")).unwrap();
+        clusters.save(&mut store).unwrap();
+        // Incremental save, new entries are collected from the map once too many of them were added
+        clusters.make_set(String::from("f"));
+        clusters.new_entries = None;
+        clusters.union(String::from("b"), String::from("d")).unwrap();
+        clusters.union(String::from("a"), String::from("d")).unwrap();
+        clusters.union(String::from("e"), String::from("f")).unwrap();
+        clusters.save(&mut store).unwrap();
+        assert_eq!((6, 4), (store.n_addresses, store.n_unions));
+
+        let (mut loaded, _) = DisjointSet::load(&path, |_| true).unwrap();
+        assert_eq!(6, loaded.set_size);
+        for address in &["a", "b", "c", "d", "e", "f"] {
+            assert_eq!(clusters.find(String::from(*address)), loaded.find(String::from(*address)));
+        }
+        assert_eq!(loaded.find(String::from("a")), loaded.find(String::from("c")));
+        assert!(loaded.find(String::from("a")) != loaded.find(String::from("e")));
+
+        // Unions of a disabled heuristic are skipped
+        let (a, e) = (clusters.find(String::from("a")).unwrap() as u32, clusters.find(String::from("e")).unwrap() as u32);
+        clusters.union_tags_by(e, a, &[Heuristic::RoundPayment as u8, Heuristic::ScriptType as u8]);
+        clusters.save(&mut store).unwrap();
+        assert_eq!(6, store.n_unions);
+        let (mut loaded, _) = DisjointSet::load(&path, |code| code != Heuristic::RoundPayment as u8).unwrap();
+        assert_eq!(loaded.find(String::from("a")), loaded.find(String::from("f")));
+        let (mut loaded, _) = DisjointSet::load(&path, |code| code == Heuristic::CommonInput as u8).unwrap();
+        assert!(loaded.find(String::from("a")) != loaded.find(String::from("f")));
+        assert_eq!(loaded.find(String::from("e")), loaded.find(String::from("f")));
+
+        // clusters.dat of previous versions keeps its tags
+        let legacy = dir.join("clusters.dat");
+        File::create(&legacy).unwrap().write_all(br#"{"set_size":4,"parent":[1,1,1,3],"rank":[0,1,0,0],
+            "map":{"x":0,"y":1,"z":2,"w":3}}"#).unwrap();
+        let mut converted = DisjointSet::from_legacy(&legacy).unwrap();
+        assert_eq!(Some(1), converted.find(String::from("x")));
+        assert_eq!(Some(1), converted.find(String::from("z")));
+        assert_eq!(Some(3), converted.find(String::from("w")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_common_input_reload() {
+        let path = env::temp_dir().join(format!("clusterizer-ci-test-{}.bin", ::std::process::id()));
+        let mut store = ClusterStore::create(&path).unwrap();
+        let addresses = ["a", "b", "c", "d", "e", "f"];
+        let mut with: DisjointSet<String> = DisjointSet::new();
+        let mut without: DisjointSet<String> = DisjointSet::new();
+        for address in &addresses {
+            with.make_set(String::from(*address));
+            without.make_set(String::from(*address));
+        }
+        let ci = Heuristic::CommonInput as u8;
+        // The change unions come first, so some common input unions don't join anything in `with`
+        with.union_tags(0, 2, Heuristic::FreshAddress as u8);
+        with.union_tags_by(3, 4, &[Heuristic::OptimalChange as u8, Heuristic::ScriptType as u8]);
+        for &(x, y) in &[(0, 1), (1, 2), (4, 5), (3, 5)] {
+            with.union_tags(x, y, ci);
+            without.union_tags(x, y, ci);
+        }
+        with.save(&mut store).unwrap();
+
+        let (mut loaded, _) = DisjointSet::load(&path, |code| code == ci).unwrap();
+        let (mut full, _) = DisjointSet::load(&path, |_| true).unwrap();
+        for x in &addresses {
+            for y in &addresses {
+                let (x, y) = (String::from(*x), String::from(*y));
+                assert_eq!(without.find(x.clone()) == without.find(y.clone()),
+                           loaded.find(x.clone()) == loaded.find(y.clone()));
+                assert_eq!(with.find(x.clone()) == with.find(y.clone()),
+                           full.find(x.clone()) == full.find(y.clone()));
+            }
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn candidate(tag: u32, fresh: bool, value: u64, script_type: u8) -> ChangeCandidate {
+        ChangeCandidate { tag: tag, fresh: fresh, info: OutputInfo { value: value, script_type: script_type } }
+    }
+
+    #[test]
+    fn test_detect_change() {
+        let all = Heuristic::parse_list("all").unwrap();
+        let input_tags: HashSet<u32, BuildHasherDefault<XxHash>> = [1, 2].iter().cloned().collect();
+        let inputs = [OutputInfo { value: 3000000, script_type: 4 }, OutputInfo { value: 2000000, script_type: 4 }];
+
+        // Round payment to a known P2PKH address, the change matches the inputs
+        let outputs = [candidate(10, false, 4000000, 2), candidate(11, true, 987654, 4)];
+        assert_eq!(Some((11, vec![1, 4, 3, 2])), detect_change(&all, &inputs, &input_tags, &outputs));
+        let script_type = [Heuristic::ScriptType];
+        assert_eq!(Some((11, vec![3])), detect_change(&script_type, &inputs, &input_tags, &outputs));
+        assert_eq!(None, detect_change(&[], &inputs, &input_tags, &outputs));
+
+        // The heuristics disagree
+        let outputs = [candidate(10, true, 4000000, 2), candidate(11, false, 987654, 4)];
+        assert_eq!(None, detect_change(&all, &inputs, &input_tags, &outputs));
+        let optimal = [Heuristic::OptimalChange, Heuristic::RoundPayment];
+        assert_eq!(Some((11, vec![4, 2])), detect_change(&optimal, &inputs, &input_tags, &outputs));
+
+        // CoinJoin with equal outputs
+        let outputs = [candidate(10, true, 1000000, 4), candidate(11, true, 1000000, 4), candidate(12, true, 1234, 4)];
+        assert_eq!(None, detect_change(&all, &inputs, &input_tags, &outputs));
+
+        // Change back to an input address
+        let outputs = [candidate(10, false, 4000000, 2), candidate(2, false, 987654, 4)];
+        assert_eq!(None, detect_change(&all, &inputs, &input_tags, &outputs));
+
+        // Single output
+        assert_eq!(None, detect_change(&all, &inputs, &input_tags, &outputs[..1]));
+
+        assert_eq!(vec![Heuristic::OptimalChange, Heuristic::RoundPayment],
+                   Heuristic::parse_list("round-payment, optimal-change").unwrap());
+        assert!(Heuristic::parse_list("fresh").is_err());
     }
 }
@@ -1,10 +1,11 @@
 use std::fs::{self, File};
 use std::path::PathBuf;
 use std::io::{BufWriter, Write};
+use std::sync::Arc;
 
 use clap::{Arg, ArgMatches, App, SubCommand};
 
-use callbacks::Callback;
+use callbacks::{BlockMapper, Callback, MapResult};
 use errors::{OpError, OpResult};
 
 use blockchain::proto::tx::{Tx, TxInput, EvaluatedTxOut};
@@ -30,7 +31,62 @@ pub struct CsvDump {
     out_count:      u64
 }
 
+/// Formatted rows of all transactions in a block
+struct CsvRows {
+    txs:        String,
+    txins:      String,
+    txouts:     String,
+    in_count:   u64,
+    out_count:  u64
+}
+
+/// Formats the transactions on the worker threads, only the block row
+/// depends on the height and is written by the dispatcher.
+struct CsvMapper;
+
+impl BlockMapper for CsvMapper {
+    fn map(&self, block: &Block) -> MapResult {
+        Box::new(CsvDump::format_rows(block))
+    }
+}
+
 impl CsvDump {
+    fn format_rows(block: &Block) -> CsvRows {
+        let mut rows = CsvRows {
+            txs: String::new(), txins: String::new(), txouts: String::new(),
+            in_count: 0, out_count: 0
+        };
+        let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
+        for tx in &block.txs {
+            rows.txs.push_str(&tx.as_csv(&block_hash));
+            let txid_str = utils::arr_to_hex_swapped(&tx.hash);
+
+            // serialize inputs
+            for input in &tx.value.inputs {
+                rows.txins.push_str(&input.as_csv(&txid_str));
+            }
+            rows.in_count += tx.value.in_count.value;
+
+            // serialize outputs
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                rows.txouts.push_str(&output.as_csv(&txid_str, i));
+            }
+            rows.out_count += tx.value.out_count.value;
+        }
+        rows
+    }
+
+    fn write_rows(&mut self, block: &Block, rows: CsvRows, block_height: usize) {
+        self.block_writer.write_all(block.as_csv(block_height).as_bytes()).unwrap();
+        self.tx_writer.write_all(rows.txs.as_bytes()).unwrap();
+        self.txin_writer.write_all(rows.txins.as_bytes()).unwrap();
+        self.txout_writer.write_all(rows.txouts.as_bytes()).unwrap();
+
+        self.tx_count += block.tx_count.value;
+        self.in_count += rows.in_count;
+        self.out_count += rows.out_count;
+    }
+
     fn create_writer(cap: usize, path: PathBuf) -> OpResult<BufWriter<File>> {
         let file = match File::create(&path) {
             Ok(f) => f,
@@ -38,6 +94,21 @@ impl CsvDump {
         };
         Ok(BufWriter::with_capacity(cap, file))
     }
+
+    /// Flushes all writers to disk and renames the temp files
+    fn finish_files(&mut self, end_height: usize) -> OpResult<()> {
+        for writer in vec![&mut self.block_writer, &mut self.tx_writer,
+                           &mut self.txin_writer, &mut self.txout_writer] {
+            try!(writer.flush());
+            try!(writer.get_ref().sync_all());
+        }
+        // Keep in sync with c'tor
+        for f in vec!["blocks", "transactions", "tx_in", "tx_out"] {
+            try!(fs::rename(self.dump_folder.as_path().join(format!("{}.csv.tmp", f)),
+                            self.dump_folder.as_path().join(format!("{}-{}-{}.csv", f, self.start_height, end_height))));
+        }
+        Ok(())
+    }
 }
 
 impl Callback for CsvDump {
@@ -81,40 +152,40 @@ impl Callback for CsvDump {
     }
 
     fn on_block(&mut self, block: Block, block_height: usize) {
-        // serialize block
-        self.block_writer.write_all(block.as_csv(block_height).as_bytes()).unwrap();
+        let rows = CsvDump::format_rows(&block);
+        self.write_rows(&block, rows, block_height);
+    }
 
-        // serialize transaction
-        let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
-        for tx in block.txs {
-            self.tx_writer.write_all(tx.as_csv(&block_hash).as_bytes()).unwrap();
-            let txid_str = utils::arr_to_hex_swapped(&tx.hash);
+    fn mapper(&self) -> Option<Arc<BlockMapper>> {
+        Some(Arc::new(CsvMapper))
+    }
 
-            // serialize inputs
-            for input in &tx.value.inputs {
-                self.txin_writer.write_all(input.as_csv(&txid_str).as_bytes()).unwrap();
-            }
-            self.in_count += tx.value.in_count.value;
+    fn reduce(&mut self, block: Block, mapped: MapResult, block_height: usize) {
+        let rows = mapped.downcast::<CsvRows>().expect("Unexpected map result!");
+        self.write_rows(&block, *rows, block_height);
+    }
 
-            // serialize outputs
-            for (i, output) in tx.value.outputs.iter().enumerate() {
-                self.txout_writer.write_all(output.as_csv(&txid_str, i).as_bytes()).unwrap();
-            }
-            self.out_count += tx.value.out_count.value;
-        }
-        self.tx_count += block.tx_count.value;
+    /// Finishes the current files and continues with new ones starting at `block_height`
+    fn checkpoints(&self) -> bool {
+        true
     }
 
-    fn on_complete(&mut self, block_height: usize) {
-        self.end_height = block_height;
+    fn on_checkpoint(&mut self, block_height: usize) -> OpResult<bool> {
+        try!(self.finish_files(block_height));
+        self.start_height = block_height;
 
         // Keep in sync with c'tor
-        for f in vec!["blocks", "transactions", "tx_in", "tx_out"] {
-            // Rename temp files
-            fs::rename(self.dump_folder.as_path().join(format!("{}.csv.tmp", f)),
-                       self.dump_folder.as_path().join(format!("{}-{}-{}.csv", f, self.start_height, self.end_height)))
-                .expect("Unable to rename tmp file!");
-        }
+        let cap = 4000000;
+        self.block_writer = try!(CsvDump::create_writer(cap, self.dump_folder.join("blocks.csv.tmp")));
+        self.tx_writer = try!(CsvDump::create_writer(cap, self.dump_folder.join("transactions.csv.tmp")));
+        self.txin_writer = try!(CsvDump::create_writer(cap, self.dump_folder.join("tx_in.csv.tmp")));
+        self.txout_writer = try!(CsvDump::create_writer(cap, self.dump_folder.join("tx_out.csv.tmp")));
+        Ok(true)
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+        self.finish_files(block_height).expect("Unable to rename tmp file!");
 
         info!(target: "callback", "Done.\nDumped all {} blocks:\n\
                                    \t-> transactions: {:9}\n\
@@ -169,12 +240,13 @@ impl TxInput {
 impl EvaluatedTxOut {
     #[inline]
     fn as_csv(&self, txid: &str, index: usize) -> String {
-        // (@txid, indexOut, value, @scriptPubKey, address)
-        format!("{};{};{};{};{}\n",
+        // (@txid, indexOut, value, @scriptPubKey, address, destinations)
+        format!("{};{};{};{};{};{}\n",
             &txid,
             &index,
             &self.out.value,
             &utils::arr_to_hex(&self.out.script_pubkey),
-            &self.script.address)
+            &self.script.address(),
+            &self.script.multi_addresses())
     }
 }
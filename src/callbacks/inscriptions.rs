@@ -0,0 +1,179 @@
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::io::{BufWriter, Write};
+
+use clap::{Arg, ArgMatches, App, SubCommand};
+
+use callbacks::Callback;
+use errors::{OpError, OpResult};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::inscription;
+use blockchain::proto::taproot;
+use blockchain::utils;
+
+
+/// Extracts inscription envelopes from taproot script path spends.
+/// Bodies are stored content-addressed (sha256) in `<dump-folder>/content`.
+pub struct Inscriptions {
+    dump_folder:    PathBuf,
+    content_folder: PathBuf,
+    writer:         BufWriter<File>,
+
+    start_height:   usize,
+    end_height:     usize,
+    n_inscriptions: u64,
+    n_body_bytes:   u64,
+    /// Total size of all witness items carrying envelopes
+    n_script_bytes: u64,
+    /// Set after a write error, no further blocks are dumped
+    failed:         bool
+}
+
+impl Inscriptions {
+    fn create_writer(path: PathBuf) -> OpResult<BufWriter<File>> {
+        let file = match File::create(&path) {
+            Ok(f) => f,
+            Err(err) => return Err(OpError::from(err))
+        };
+        Ok(BufWriter::with_capacity(4000000, file))
+    }
+
+    /// Writes the body to the content folder, unless it already exists.
+    /// Returns the hex encoded content hash.
+    fn store_body(&self, body: &[u8]) -> OpResult<String> {
+        let hash = utils::arr_to_hex(&utils::sha256(body));
+        let path = self.content_folder.join(&hash);
+        if !path.exists() {
+            // Renamed once complete, a failed write doesn't leave a truncated content file
+            let tmp_path = self.content_folder.join(format!("{}.tmp", hash));
+            let mut file = try!(File::create(&tmp_path));
+            try!(file.write_all(body));
+            try!(fs::rename(&tmp_path, &path));
+        }
+        Ok(hash)
+    }
+
+    /// Writes the inscriptions of all taproot script path spends in the block
+    fn dump_block(&mut self, block: &Block, block_height: usize) -> OpResult<()> {
+        for (tx_index, tx) in block.txs.iter().enumerate() {
+            let spent = block.spent_outputs(tx_index).expect("Block without undo data, inscriptions requires --undo!");
+            let txid_str = utils::arr_to_hex_swapped(&tx.hash);
+            for (vin, (input, spent)) in tx.value.inputs.iter().zip(spent).enumerate() {
+                // Only native taproot inputs carry tapscripts
+                if input.witness.is_empty() || !input.script_sig.is_empty() {
+                    continue;
+                }
+                let script_path = match taproot::eval_witness(&input.witness, &spent.out.out.script_pubkey) {
+                    Some(taproot::TaprootSpend { script_path: Some(script_path), .. }) => script_path,
+                    _ => continue
+                };
+                let stack = match script_path.tapscript {
+                    Some(Ok(stack)) => stack,
+                    _ => continue
+                };
+                let inscriptions = inscription::parse_envelopes(&stack.elements);
+                if inscriptions.is_empty() {
+                    continue;
+                }
+                self.n_script_bytes += script_path.script.len() as u64;
+
+                for inscription in inscriptions {
+                    let body = inscription.body.as_ref().map(|b| &b[..]).unwrap_or(&[]);
+                    // Inscriptions without body have no content file
+                    let hash = match body.is_empty() {
+                        true => String::new(),
+                        false => try!(self.store_body(body))
+                    };
+                    // (txid, vin, content_type, size, height, content_hash)
+                    try!(self.writer.write_all(format!("{};{};{};{};{};{}\n",
+                        &txid_str,
+                        &vin,
+                        &utils::csv_field(&inscription.content_type_str()),
+                        &body.len(),
+                        &block_height,
+                        &hash).as_bytes()));
+                    self.n_inscriptions += 1;
+                    self.n_body_bytes += body.len() as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Callback for Inscriptions {
+
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b> where Self: Sized {
+        SubCommand::with_name("inscriptions")
+            .about("Extracts inscriptions from taproot witnesses")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(Arg::with_name("dump-folder")
+                .help("Folder to store the csv index and inscription contents")
+                .index(1)
+                .required(true))
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self> where Self: Sized {
+        let ref dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap()); // Save to unwrap
+        match (|| -> OpResult<Self> {
+            let content_folder = dump_folder.join("content");
+            try!(fs::create_dir_all(&content_folder));
+            let cb = Inscriptions {
+                dump_folder:    PathBuf::from(dump_folder),
+                content_folder: content_folder,
+                writer:         try!(Inscriptions::create_writer(dump_folder.join("inscriptions.csv.tmp"))),
+                start_height: 0, end_height: 0, n_inscriptions: 0, n_body_bytes: 0, n_script_bytes: 0,
+                failed: false
+            };
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => return Err(
+                tag_err!(e, "Couldn't initialize inscriptions with folder: `{}`", dump_folder
+                        .as_path()
+                        .display()))
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "callback", "Using `inscriptions` with dump folder: {} ...", &self.dump_folder.display());
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        if self.failed {
+            return;
+        }
+        if let Err(err) = self.dump_block(&block, block_height) {
+            error!(target: "callback", "Stopped dumping inscriptions at block {}: {}", block_height, err);
+            self.failed = true;
+        }
+    }
+
+    fn requires_undo(&self) -> bool {
+        true
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+        let tmp_path = self.dump_folder.join("inscriptions.csv.tmp");
+        if self.failed {
+            error!(target: "callback", "Inscriptions are incomplete, keeping {}", tmp_path.display());
+            return;
+        }
+        let path = self.dump_folder.join(format!("inscriptions-{}-{}.csv", self.start_height, self.end_height));
+        if let Err(err) = self.writer.flush().and_then(|_| fs::rename(&tmp_path, &path)) {
+            error!(target: "callback", "Unable to write {}: {}", path.display(), err);
+            return;
+        }
+
+        info!(target: "callback", "Done.\nDumped all {} blocks:\n\
+                                   \t-> inscriptions:  {:9}\n\
+                                   \t-> body bytes:    {:9}\n\
+                                   \t-> script bytes:  {:9}",
+             self.end_height + 1, self.n_inscriptions, self.n_body_bytes, self.n_script_bytes);
+    }
+}
@@ -4,12 +4,32 @@ pub mod csvdump;
 pub mod unspentcsvdump;
 pub mod weakwallets;
 pub mod txoutdump;
+pub mod inscriptions;
+pub mod opreturndump;
+pub mod addrindex;
+pub mod txindex;
+pub mod balances;
+
+use std::any::Any;
+use std::sync::Arc;
 
 use clap::{ArgMatches, App};
 
 use errors::OpResult;
 use blockchain::proto::block::Block;
+use blockchain::proto::raw::RawBlock;
 use blockchain::parser::types::CoinType;
+use blockchain::utils::chainstate::Coin;
+
+/// Result of BlockMapper::map, see Callback::reduce
+pub type MapResult = Box<Any + Send>;
+
+/// Per-block map step of a callback. It is executed on the worker threads,
+/// so CPU-heavy work doesn't block the sequential dispatcher.
+pub trait BlockMapper: Send + Sync {
+    /// Gets called for each block in no particular order
+    fn map(&self, block: &Block) -> MapResult;
+}
 
 /// Implement this trait for a custom Callback.
 /// The parser ensures that the blocks arrive in the correct order.
@@ -31,6 +51,69 @@ pub trait Callback {
     /// Gets called if a new block is available.
     fn on_block(&mut self, block: Block, block_height: usize);
 
+    /// Returns an optional map step which gets executed on the worker threads.
+    /// Gets called after `on_start`. If it returns Some, `reduce` is called instead of `on_block`.
+    fn mapper(&self) -> Option<Arc<BlockMapper>> {
+        None
+    }
+
+    /// Gets called in block height order with the result of the map step.
+    fn reduce(&mut self, block: Block, _mapped: MapResult, block_height: usize) {
+        self.on_block(block, block_height)
+    }
+
+    /// Lightweight callbacks can return true to receive unparsed blocks via `on_raw_block`.
+    /// They borrow their data from the memory mapped blk files and are decoded lazily.
+    fn raw_blocks(&self) -> bool {
+        false
+    }
+
+    /// Gets called instead of `on_block` if `raw_blocks` returns true.
+    fn on_raw_block(&mut self, block: RawBlock, block_height: usize) {
+        let block = block.parse().expect("Unable to parse block!");
+        self.on_block(block, block_height)
+    }
+
+    /// Callbacks which track unspent outputs can return true to start from a UTXO snapshot
+    /// (see `--utxo-snapshot`). The scan then continues after the snapshot block.
+    fn utxo_snapshot(&self) -> bool {
+        false
+    }
+
+    /// Gets called for each unspent output of the snapshot before `on_start`.
+    fn on_snapshot_coin(&mut self, _coin: Coin) {}
+
+    /// Callbacks which resolve spent outputs only from the undo data return true, `--undo` is then required.
+    fn requires_undo(&self) -> bool {
+        false
+    }
+
+    /// Gets called periodically if checkpoints are enabled (see `--checkpoint-blocks`).
+    /// Return Ok(true) once all output up to `block_height` is durable on disk,
+    /// the chain storage is persisted afterwards and an aborted scan resumes from here.
+    /// Callbacks which keep their state in memory can't resume and use the default.
+    fn on_checkpoint(&mut self, _block_height: usize) -> OpResult<bool> {
+        Ok(false)
+    }
+
+    /// Callbacks which implement `on_checkpoint` return true. Required by `--follow`,
+    /// the output of followed blocks is only flushed with checkpoints.
+    fn checkpoints(&self) -> bool {
+        false
+    }
+
+    /// Blocks from this height on are not processed, the scan stops before them and
+    /// the chain storage continues at this height. Can't be used with `--follow`.
+    fn max_height(&self) -> Option<usize> {
+        None
+    }
+
+    /// Height at which the output saved by a previous run continues, if the callback resumes from it.
+    /// The scan has to start there, otherwise it is aborted.
+    fn resume_height(&self) -> Option<usize> {
+        None
+    }
+
     /// Gets called if the dispatcher has finished and all blocks are handled
     fn on_complete(&mut self, block_height: usize);
 }
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::io::{BufWriter, Write};
+
+use clap::{Arg, ArgMatches, App, SubCommand};
+
+use callbacks::Callback;
+use errors::{OpError, OpResult};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::block::Block;
+use blockchain::proto::opreturn::DecoderRegistry;
+use blockchain::utils;
+
+
+/// Dumps OP_RETURN payloads and decoded protocol messages into a CSV file
+pub struct OpReturnDump {
+    dump_folder:    PathBuf,
+    writer:         BufWriter<File>,
+    registry:       DecoderRegistry,
+    version_id:     u8,
+
+    start_height:   usize,
+    end_height:     usize,
+    n_outputs:      u64,
+    /// Number of decoded records per protocol
+    n_protocols:    HashMap<&'static str, u64>
+}
+
+impl OpReturnDump {
+    fn create_writer(path: PathBuf) -> OpResult<BufWriter<File>> {
+        let file = match File::create(&path) {
+            Ok(f) => f,
+            Err(err) => return Err(OpError::from(err))
+        };
+        Ok(BufWriter::with_capacity(4000000, file))
+    }
+
+    /// Flushes the writer to disk and renames the temp file
+    fn finish_file(&mut self, end_height: usize) -> OpResult<()> {
+        try!(self.writer.flush());
+        try!(self.writer.get_ref().sync_all());
+        try!(fs::rename(self.dump_folder.as_path().join("opreturn.csv.tmp"),
+                        self.dump_folder.as_path().join(format!("opreturn-{}-{}.csv", self.start_height, end_height))));
+        Ok(())
+    }
+}
+
+impl Callback for OpReturnDump {
+
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b> where Self: Sized {
+        SubCommand::with_name("opreturndump")
+            .about("Dumps OP_RETURN payloads and decodes known protocols (Omni, Counterparty, OpenAssets, Runes)")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(Arg::with_name("dump-folder")
+                .help("Folder to store csv file")
+                .index(1)
+                .required(true))
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self> where Self: Sized {
+        let ref dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap()); // Save to unwrap
+        match (|| -> OpResult<Self> {
+            let cb = OpReturnDump {
+                dump_folder:    PathBuf::from(dump_folder),
+                writer:         try!(OpReturnDump::create_writer(dump_folder.join("opreturn.csv.tmp"))),
+                registry:       DecoderRegistry::default(),
+                version_id:     0,
+                start_height: 0, end_height: 0, n_outputs: 0,
+                n_protocols:    HashMap::new()
+            };
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => return Err(
+                tag_err!(e, "Couldn't initialize opreturndump with folder: `{}`", dump_folder
+                        .as_path()
+                        .display()))
+        }
+    }
+
+    fn on_start(&mut self, coin_type: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        self.version_id = coin_type.version_id;
+        info!(target: "callback", "Using `opreturndump` with dump folder: {} ...", &self.dump_folder.display());
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        for (tx_index, tx) in block.txs.iter().enumerate() {
+            let decoded = self.registry.decode_tx(&tx.value, block.spent_outputs(tx_index), self.version_id);
+            if decoded.is_empty() {
+                continue;
+            }
+            let txid_str = utils::arr_to_hex_swapped(&tx.hash);
+            for output in decoded {
+                // (txid, indexOut, height, protocol, payload, decoded)
+                let (protocol, record) = match output.record {
+                    Some(ref record) => (record.protocol(), record.to_string()),
+                    None => ("", String::new())
+                };
+                self.writer.write_all(format!("{};{};{};{};{};{}\n",
+                    &txid_str,
+                    &output.index,
+                    &block_height,
+                    &protocol,
+                    &utils::arr_to_hex(&output.payload),
+                    &utils::csv_field(&record)).as_bytes()).unwrap();
+
+                self.n_outputs += 1;
+                if !protocol.is_empty() {
+                    *self.n_protocols.entry(protocol).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+
+    /// Finishes the current file and continues with a new one starting at `block_height`
+    fn checkpoints(&self) -> bool {
+        true
+    }
+
+    fn on_checkpoint(&mut self, block_height: usize) -> OpResult<bool> {
+        try!(self.finish_file(block_height));
+        self.start_height = block_height;
+        self.writer = try!(OpReturnDump::create_writer(self.dump_folder.join("opreturn.csv.tmp")));
+        Ok(true)
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+        self.finish_file(block_height).expect("Unable to rename tmp file!");
+
+        let mut protocols: Vec<_> = self.n_protocols.iter().collect();
+        protocols.sort();
+        info!(target: "callback", "Done.\nDumped all {} blocks:\n\
+                                   \t-> data outputs: {:9}\n{}",
+             self.end_height + 1, self.n_outputs,
+             protocols.iter()
+                .map(|&(p, n)| format!("\t-> {:13} {:9}", format!("{}:", p), n))
+                .collect::<Vec<String>>().join("\n"));
+    }
+}
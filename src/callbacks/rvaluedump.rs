@@ -117,7 +117,7 @@ impl Callback for RvalueDump {
     	    	let hash_val: HashMapVal = HashMapVal {
         			block_height: block_height,
         			output_val: output.out.value,
-        			address: output.script.address.clone(),
+        			address: String::from(output.script.address()),
         			//script_pubkey: utils::arr_to_hex(&output.out.script_pubkey)
         		};
                 self.r_value.insert(txid_str.clone() + &i.to_string(), hash_val);
@@ -8,14 +8,14 @@ use twox_hash::XxHash;
 use blockchain::proto::block::{self, Block};
 use blockchain::utils;
 use blockchain::parser::types::CoinType;
-use blockchain::proto::script::ScriptPattern;
+use blockchain::proto::script::{ScriptPattern, InputPattern};
 
 use callbacks::Callback;
 use errors::OpResult;
 
 #[derive(Default)]
 pub struct SimpleStats {
-    //X coin_type: CoinType,
+    version_id: u8,
 
     n_valid_blocks: u64,
     block_sizes: Vec<u32>,
@@ -34,6 +34,14 @@ pub struct SimpleStats {
     /// (block_height, txid)
     tx_first_occs: HashMap<ScriptPattern, (usize, [u8; 32], u32), BuildHasherDefault<XxHash>>,
 
+    /// Taproot stats, only available with undo data
+    has_undo: bool,
+    n_taproot_key_path: u64,
+    n_taproot_script_path: u64,
+    n_taproot_annex: u64,
+    /// Script path spends with merkle path length > 0
+    n_taproot_script_trees: u64,
+
     /// Time stats
     t_between_blocks: Vec<u32>,
     last_timestamp: u32
@@ -42,12 +50,46 @@ pub struct SimpleStats {
 
 impl SimpleStats {
 
+    /// Counts taproot spends, which are identified by the spent scriptPubKey from the undo data
+    fn process_taproot_inputs(&mut self, block: &Block) {
+        for (tx_index, tx) in block.txs.iter().enumerate() {
+            let spent = match block.spent_outputs(tx_index) {
+                Some(spent) => spent,
+                None => return
+            };
+            self.has_undo = true;
+            for (i, spent) in tx.value.inputs.iter().zip(spent) {
+                // Taproot spends always carry a witness
+                if i.witness.is_empty() {
+                    continue;
+                }
+                let input = i.eval_script(Some(&spent.out.out.script_pubkey), self.version_id);
+                match input.pattern {
+                    InputPattern::SignTaprootKeyPath => self.n_taproot_key_path += 1,
+                    InputPattern::SignTaprootScriptPath => self.n_taproot_script_path += 1,
+                    _ => ()
+                }
+                if let Some(taproot) = input.taproot {
+                    if taproot.annex.is_some() {
+                        self.n_taproot_annex += 1;
+                    }
+                    if let Some(script_path) = taproot.script_path {
+                        if !script_path.control_block.merkle_path.is_empty() {
+                            self.n_taproot_script_trees += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     /// Saves transaction pattern with txid of first occurence
     #[inline]
     fn process_tx_pattern(&mut self, script_pattern: ScriptPattern, block_height: usize, txid: [u8; 32], index: u32) {
-        // Strip exact OP_RETURN bytes
+        // Strip exact OP_RETURN bytes and multisig keys
         let pattern = match script_pattern {
-            ScriptPattern::DataOutput(_) => ScriptPattern::DataOutput(String::new()),
+            ScriptPattern::DataOutput(_) => ScriptPattern::DataOutput(Vec::new()),
+            ScriptPattern::Pay2MultiSig { m, n, .. } => ScriptPattern::Pay2MultiSig { m: m, n: n, pubkeys: Vec::new() },
             p @ _ => p
         };
         if !self.n_tx_types.contains_key(&pattern) {
@@ -73,7 +115,8 @@ impl Callback for SimpleStats {
         Ok(Default::default())
     }
 
-    fn on_start(&mut self, _: CoinType, _: usize) {
+    fn on_start(&mut self, coin_type: CoinType, _: usize) {
+        self.version_id = coin_type.version_id;
         info!(target: "callback", "Executing SimpleStats ...");
     }
 
@@ -81,6 +124,7 @@ impl Callback for SimpleStats {
         self.n_valid_blocks += 1;
         self.n_tx += block.tx_count.value;
         self.block_sizes.push(block.blocksize);
+        self.process_taproot_inputs(&block);
 
         for tx in block.txs {
             // Collect fee rewards
@@ -151,6 +195,17 @@ impl Callback for SimpleStats {
                 self.n_tx_total_volume as f64 / self.n_tx_outputs as f64 * 1E-8).unwrap();
             writeln!(&mut buffer, "").unwrap();
         }
+        writeln!(&mut buffer, "Taproot:").unwrap();
+        if !self.has_undo {
+            writeln!(&mut buffer, "   -> requires --undo to identify the spent outputs").unwrap();
+            writeln!(&mut buffer, "").unwrap();
+        } else {
+            writeln!(&mut buffer, "   -> key path spends:\t\t{}", self.n_taproot_key_path).unwrap();
+            writeln!(&mut buffer, "   -> script path spends:\t{}", self.n_taproot_script_path).unwrap();
+            writeln!(&mut buffer, "        with script tree:\t{}", self.n_taproot_script_trees).unwrap();
+            writeln!(&mut buffer, "   -> inputs with annex:\t{}", self.n_taproot_annex).unwrap();
+            writeln!(&mut buffer, "").unwrap();
+        }
         writeln!(&mut buffer, "Transaction Types:").unwrap();
         for (pattern, count) in &self.n_tx_types {
             writeln!(&mut buffer, "   -> {:?}: {} ({:.2}%)", pattern, count, (*count as f64 / self.n_tx_outputs as f64) * 100.00).unwrap();
@@ -0,0 +1,167 @@
+use std::fs::{self, File};
+use std::io::{BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgMatches, App, SubCommand};
+
+use callbacks::Callback;
+use errors::{OpError, OpResult};
+
+use blockchain::parser::types::CoinType;
+use blockchain::proto::Hashed;
+use blockchain::proto::block::Block;
+use blockchain::proto::raw::RawBlock;
+use blockchain::utils;
+use blockchain::utils::blkfile::BlkFile;
+use blockchain::utils::dump;
+use blockchain::utils::reader::BlockchainRead;
+use blockchain::utils::txindex::{TxIndex, TxIndexWriter, TxLocation};
+
+
+/// Builds an on-disk index from txid to the location of the transaction in the blk files
+pub struct TxIndexDump {
+    dump_folder:    PathBuf,
+    writer:         Option<TxIndexWriter>,
+
+    start_height:   usize,
+    end_height:     usize,
+    n_txs:          u64
+}
+
+impl Callback for TxIndexDump {
+
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b> where Self: Sized {
+        SubCommand::with_name("txindex")
+            .about("Builds a transaction index (see `gettx`)")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(Arg::with_name("dump-folder")
+                .help("Folder to store the index file")
+                .index(1)
+                .required(true))
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self> where Self: Sized {
+        let ref dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap()); // Save to unwrap
+        match (|| -> OpResult<Self> {
+            try!(fs::create_dir_all(dump_folder));
+            let cb = TxIndexDump {
+                dump_folder:    PathBuf::from(dump_folder),
+                writer:         Some(TxIndexWriter::new(PathBuf::from(dump_folder))),
+                start_height: 0, end_height: 0, n_txs: 0
+            };
+            Ok(cb)
+        })() {
+            Ok(s) => return Ok(s),
+            Err(e) => return Err(
+                tag_err!(e, "Couldn't initialize txindex with folder: `{}`", dump_folder
+                        .as_path()
+                        .display()))
+        }
+    }
+
+    fn on_start(&mut self, _: CoinType, block_height: usize) {
+        self.start_height = block_height;
+        info!(target: "callback", "Using `txindex` with dump folder: {} ...", &self.dump_folder.display());
+    }
+
+    fn on_block(&mut self, block: Block, block_height: usize) {
+        let writer = self.writer.as_mut().unwrap();
+        for (tx, tx_offset) in block.txs.iter().zip(block.tx_offsets()) {
+            writer.push(TxLocation {
+                txid: tx.hash,
+                blk_index: block.blk_index,
+                block_offset: block.blk_offset as u64,
+                tx_offset: tx_offset,
+                height: block_height as u32
+            }).expect("Unable to write transaction index run!");
+            self.n_txs += 1;
+        }
+    }
+
+    /// Only txids and offsets are required, so we don't need to decode the whole block
+    fn raw_blocks(&self) -> bool {
+        true
+    }
+
+    fn on_raw_block(&mut self, block: RawBlock, block_height: usize) {
+        let writer = self.writer.as_mut().unwrap();
+        for tx in block.txs().expect("Unable to read transactions!") {
+            let tx = tx.expect("Unable to read transaction!");
+            writer.push(TxLocation {
+                txid: tx.txid(),
+                blk_index: block.blk_index,
+                block_offset: block.blk_offset as u64,
+                tx_offset: tx.offset,
+                height: block_height as u32
+            }).expect("Unable to write transaction index run!");
+            self.n_txs += 1;
+        }
+    }
+
+    fn on_complete(&mut self, block_height: usize) {
+        self.end_height = block_height;
+
+        let path = self.dump_folder.join(format!("txindex-{}-{}.bin", self.start_height, self.end_height));
+        info!(target: "callback", "Merging {} records into {} ...", self.n_txs, path.display());
+        self.writer.take().unwrap().finish(&path).expect("Unable to write transaction index!");
+
+        info!(target: "callback", "Done.\nIndexed all {} blocks:\n\
+                                   \t-> transactions:   {:9}",
+             self.end_height + 1, self.n_txs);
+    }
+}
+
+/// Subcommand to fetch a single transaction with an index written by `txindex`
+pub fn build_gettx_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("gettx")
+        .about("Reads a single transaction from the blk files using a `txindex` file")
+        .version("0.1")
+        .author("gcarq <michael.egger@tsn.at>")
+        .arg(Arg::with_name("index")
+            .help("Index file written by txindex")
+            .index(1)
+            .required(true))
+        .arg(Arg::with_name("txid")
+            .help("Transaction id (hex)")
+            .index(2)
+            .required(true))
+}
+
+/// Looks up the transaction and decodes it straight from its blk file
+pub fn gettx(matches: &ArgMatches, coin_type: &CoinType, blockchain_dir: &Path) -> OpResult<()> {
+    let path = PathBuf::from(matches.value_of("index").unwrap()); // Save to unwrap
+    let txid_str = matches.value_of("txid").unwrap();
+    if txid_str.len() != 64 || !txid_str.chars().all(|c| c.is_digit(16)) {
+        return Err(OpError::from(format!("Invalid txid: {}", txid_str)));
+    }
+    let txid = utils::hex_to_arr32_swapped(txid_str);
+
+    let mut index = match TxIndex::open(&path) {
+        Ok(index) => index,
+        Err(e) => return Err(tag_err!(e, "Couldn't open transaction index: `{}`", path.display()))
+    };
+    let location = match try!(index.lookup(&txid)) {
+        Some(location) => location,
+        None => return Err(OpError::from(format!("Transaction not found: {}", txid_str)))
+    };
+
+    let blk_file = try!(BlkFile::open(blockchain_dir, location.blk_index));
+    // Only a single transaction is read, the default buffer size is enough
+    let mut file = try!(File::open(&blk_file.path));
+    try!(file.seek(SeekFrom::Start(location.file_offset())));
+    let mut reader = BufReader::new(file);
+    let tx = match try!(reader.read_txs(1, coin_type.version_id)).pop() {
+        Some(tx) => Hashed::double_sha256(tx),
+        None => return Err(OpError::from(String::from("Unable to read transaction")))
+    };
+    if tx.hash != txid {
+        return Err(OpError::from(format!("Index doesn't match {}, got {} instead. Rebuild the index.",
+            blk_file.path.display(), utils::arr_to_hex_swapped(&tx.hash))));
+    }
+
+    println!("height:   {}", location.height);
+    println!("location: {} @ {}", blk_file.path.display(), location.file_offset());
+    dump::print_tx(&tx, coin_type.version_id);
+    Ok(())
+}
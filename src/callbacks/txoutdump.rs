@@ -129,9 +129,10 @@ impl EvaluatedTxOut {
     #[inline]
     fn as_csv_short(&self, txid: &str, index: usize) -> String {
         // (@txid, indexOut, address)
+        // Multisig addresses are omitted, because the keys may belong to different owners
         format!("{};{};{}\n",
             &txid,
             &index,
-            &self.script.address)
+            &self.script.address())
     }
 }
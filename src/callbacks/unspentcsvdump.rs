@@ -1,26 +1,27 @@
 use std::fs::{self, File};
-use std::path::PathBuf;
-use std::io::{BufWriter, Write};
-use std::collections::HashMap;
-use std::collections::hash_map::Entry::{Occupied, Vacant};
+use std::path::{Path, PathBuf};
+use std::io::{self, BufWriter, Write};
 
 use clap::{Arg, ArgMatches, App, SubCommand};
 
 use callbacks::Callback;
 use errors::{OpError, OpResult};
 
+use blockchain::parser::chain::ChainStorage;
 use blockchain::parser::types::CoinType;
 use blockchain::proto::block::Block;
 use blockchain::utils;
+use blockchain::utils::chainstate::{ChainState, Coin};
+use blockchain::utils::utxoset::UtxoSet;
 
 
-/// Dumps the whole blockchain into csv files
+/// Dumps the unspent outputs into a csv file
 pub struct UnspentCsvDump {
-    // Each structure gets stored in a seperate csv file
     dump_folder:    PathBuf,
     unspent_writer: BufWriter<File>,
 
-    transactions_unspent: HashMap<String, HashMapVal>,
+    utxo_set:       Option<UtxoSet>,    // Consumed on completion
+    version_id:     u8,
 
     start_height:   usize,
     end_height:     usize,
@@ -29,14 +30,6 @@ pub struct UnspentCsvDump {
     out_count:      u64
 }
 
-struct HashMapVal {
-/*	txid:	String,
-	index:	usize,*/
-	block_height:	usize,
-	output_val:	u64,
-	address:	String
-}
-
 impl UnspentCsvDump {
     fn create_writer(cap: usize, path: PathBuf) -> OpResult<BufWriter<File>> {
         let file = match File::create(&path) {
@@ -58,103 +51,89 @@ impl Callback for UnspentCsvDump {
                 .help("Folder to store csv file")
                 .index(1)
                 .required(true))
+            .arg(Arg::with_name("max-memory")
+                .long("max-memory")
+                .value_name("MiB")
+                .help("Keeps at most MiB of unspent outputs in memory, the rest is written to temporary files in the dump folder")
+                .takes_value(true))
     }
 
     fn new(matches: &ArgMatches) -> OpResult<Self> where Self: Sized {
         let ref dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap()); // Save to unwrap
         match (|| -> OpResult<Self> {
             let cap = 4000000;
+            let utxo_set = match matches.value_of("max-memory") {
+                Some(_) => {
+                    let max_memory = try!(value_t!(matches, "max-memory", usize).map_err(|e| OpError::from(e.message)));
+                    UtxoSet::with_disk(PathBuf::from(dump_folder), max_memory << 20)
+                }
+                None => UtxoSet::new()
+            };
             let cb = UnspentCsvDump {
                 dump_folder:    PathBuf::from(dump_folder),
-                unspent_writer:   try!(UnspentCsvDump::create_writer(cap, dump_folder.join("unspent.csv.tmp"))),
-		transactions_unspent: HashMap::with_capacity(10000000), // Init hashmap for tracking the unspent transactions (with 10'000'000 mln preallocated entries)
+                unspent_writer: try!(UnspentCsvDump::create_writer(cap, dump_folder.join("unspent.csv.tmp"))),
+                utxo_set:       Some(utxo_set),
+                version_id:     0,
                 start_height: 0, end_height: 0, tx_count: 0, in_count: 0, out_count: 0
             };
             Ok(cb)
         })() {
             Ok(s) => return Ok(s),
             Err(e) => return Err(
-                tag_err!(e, "Couldn't initialize csvdump with folder: `{}`", dump_folder
+                tag_err!(e, "Couldn't initialize unspentcsvdump with folder: `{}`", dump_folder
                         .as_path()
                         .display()))
         }
     }
 
-    fn on_start(&mut self, _: CoinType, block_height: usize) {
+    fn on_start(&mut self, coin_type: CoinType, block_height: usize) {
         self.start_height = block_height;
+        self.version_id = coin_type.version_id;
         info!(target: "callback", "Using `unspentcsvdump` with dump folder: {} ...", &self.dump_folder.display());
     }
 
     fn on_block(&mut self, block: Block, block_height: usize) {
-        // serialize transaction
+        let utxo_set = self.utxo_set.as_mut().unwrap();
         for tx in block.txs {
-	    // For each transaction in the block,
-	    // 1. apply input transactions (remove (TxID == prevTxIDOut and prevOutID == spentOutID))
-	    // 2. apply output transactions (add (TxID + curOutID -> HashMapVal))
-	    // For each address, retain:
-	    // * block height as "last modified"
-	    // * output_val
-	    // * address
-
-            //self.tx_writer.write_all(tx.as_csv(&block_hash).as_bytes()).unwrap();
-            let txid_str = utils::arr_to_hex_swapped(&tx.hash);
-
-            for input in &tx.value.inputs {
-	    	let input_outpoint_txid_idx = utils::arr_to_hex_swapped(&input.outpoint.txid) + &input.outpoint.index.to_string();
-		let val: bool = match self.transactions_unspent.entry(input_outpoint_txid_idx.clone()) {
-			Occupied(_) => true,
-			Vacant(_) => false,
-		};
-
-		if val {
-			self.transactions_unspent.remove(&input_outpoint_txid_idx);
-		};
+            let is_coinbase = tx.value.is_coinbase();
+            if !is_coinbase {
+                for input in &tx.value.inputs {
+                    utxo_set.remove(input.outpoint.txid, input.outpoint.index)
+                        .expect("Unable to write unspent outputs!");
+                }
             }
             self.in_count += tx.value.in_count.value;
 
-            // serialize outputs
             for (i, output) in tx.value.outputs.iter().enumerate() {
-	    	let hash_val: HashMapVal = HashMapVal {
-			block_height: block_height,
-			output_val: output.out.value,
-			address: output.script.address.clone(),
-			//script_pubkey: utils::arr_to_hex(&output.out.script_pubkey)
-		};
-	    	self.transactions_unspent.insert(txid_str.clone() + &i.to_string(), hash_val);
+                utxo_set.insert(tx.hash, i as u32, block_height as u32, is_coinbase, &output.out)
+                    .expect("Unable to write unspent outputs!");
             }
             self.out_count += tx.value.out_count.value;
         }
         self.tx_count += block.tx_count.value;
     }
 
+    fn utxo_snapshot(&self) -> bool {
+        true
+    }
+
+    fn on_snapshot_coin(&mut self, coin: Coin) {
+        self.utxo_set.as_mut().unwrap()
+            .insert(coin.txid, coin.index, coin.height, coin.is_coinbase, &coin.out.out)
+            .expect("Unable to write unspent outputs!");
+    }
+
     fn on_complete(&mut self, block_height: usize) {
         self.end_height = block_height;
 
-	self.unspent_writer.write_all(format!(
-		"{};{};{};{};{}\n",
-		"txid",
-		"indexOut",
-		"height",
-		"value",
-		"address"
-		).as_bytes()
-	).unwrap();
-	for (key, value) in self.transactions_unspent.iter() {
-		let txid = &key[0..63];
-		let index = &key[64..key.len()-1];
-		//let  = key.len();
-		//let mut mut_key = key.clone();
-		//let index: String = mut_key.pop().unwrap().to_string();
-		self.unspent_writer.write_all(format!(
-				"{};{};{};{};{}\n",
-				txid,
-				index,
-				value.block_height,
-				value.output_val,
-				value.address
-			).as_bytes()
-		).unwrap();
-	}
+        self.unspent_writer.write_all(CSV_HEADER.as_bytes()).unwrap();
+        let mut n_unspent: u64 = 0;
+        let coins = self.utxo_set.take().unwrap().finish(self.version_id).expect("Unable to read unspent outputs!");
+        for coin in coins {
+            write_coin(&mut self.unspent_writer, &coin.expect("Unable to read unspent outputs!")).unwrap();
+            n_unspent += 1;
+        }
+        self.unspent_writer.flush().unwrap();
 
         // Keep in sync with c'tor
         for f in vec!["unspent"] {
@@ -167,7 +146,93 @@ impl Callback for UnspentCsvDump {
         info!(target: "callback", "Done.\nDumped all {} blocks:\n\
                                    \t-> transactions: {:9}\n\
                                    \t-> inputs:       {:9}\n\
-                                   \t-> outputs:      {:9}",
-             self.end_height + 1, self.tx_count, self.in_count, self.out_count);
+                                   \t-> outputs:      {:9}\n\
+                                   \t-> unspent:      {:9}",
+             self.end_height + 1, self.tx_count, self.in_count, self.out_count, n_unspent);
+    }
+}
+
+const CSV_HEADER: &'static str = "txid;indexOut;height;value;address;destinations\n";
+
+/// Writes a row of the unspent csv file
+fn write_coin<W: Write>(writer: &mut W, coin: &Coin) -> io::Result<()> {
+    writer.write_all(format!("{};{};{};{};{};{}\n",
+        utils::arr_to_hex_swapped(&coin.txid),
+        coin.index,
+        coin.height,
+        coin.out.out.value,
+        coin.out.script.address(),
+        coin.out.script.multi_addresses()).as_bytes())
+}
+
+pub fn build_chainstate_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("chainstate")
+        .about("Dumps the unspent outputs from the node's chainstate database to CSV file, without parsing any blocks")
+        .version("0.1")
+        .author("gcarq <michael.egger@tsn.at>")
+        .arg(Arg::with_name("dump-folder")
+            .help("Folder to store csv file")
+            .index(1)
+            .required(true))
+        .arg(Arg::with_name("chainstate-dir")
+            .long("chainstate-dir")
+            .value_name("DIR")
+            .help("Sets chainstate directory (default: chainstate next to the blockchain directory)")
+            .takes_value(true))
+}
+
+/// Streams the UTXO set from the chainstate LevelDB in the same format as unspentcsvdump.
+/// The node must not be running, as it keeps the database locked and compacts it in the background.
+/// The height of the chainstate's best block is looked up in the chain storage.
+pub fn dump_chainstate(matches: &ArgMatches, version_id: u8, blockchain_dir: &Path, chain_storage_path: &Path) -> OpResult<()> {
+    let dump_folder = PathBuf::from(matches.value_of("dump-folder").unwrap()); // Save to unwrap
+    let chainstate_dir = match matches.value_of("chainstate-dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => blockchain_dir.parent().unwrap_or(blockchain_dir).join("chainstate")
+    };
+    let chainstate = match ChainState::open(&chainstate_dir, version_id) {
+        Ok(chainstate) => chainstate,
+        Err(e) => return Err(tag_err!(e, "Couldn't open chainstate: `{}`", chainstate_dir.display()))
+    };
+    let chain_storage = match ChainStorage::load(chain_storage_path) {
+        Ok(storage) => storage,
+        Err(e) => return Err(tag_err!(e, "Couldn't load chain storage: `{}`", chain_storage_path.display()))
+    };
+    info!(target: "chainstate", "Dumping unspent outputs from {} ...", chainstate_dir.display());
+
+    try!(fs::create_dir_all(&dump_folder));
+    let mut writer = try!(UnspentCsvDump::create_writer(4000000, dump_folder.join("unspent.csv.tmp")));
+    try!(writer.write_all(CSV_HEADER.as_bytes()));
+
+    let mut coins = try!(chainstate.coins());
+    let mut n_coins: u64 = 0;
+    let mut total_value: u64 = 0;
+    while let Some(coin) = coins.next() {
+        let coin = try!(coin);
+        try!(write_coin(&mut writer, &coin));
+        n_coins += 1;
+        total_value += coin.out.out.value;
+        if n_coins % 10000000 == 0 {
+            info!(target: "chainstate", "Dumped {} unspent outputs ...", n_coins);
+        }
     }
+    try!(writer.flush());
+
+    let best_block = match coins.best_block {
+        Some(hash) => hash,
+        None => return Err(OpError::from(String::from("Chainstate doesn't contain a best block")))
+    };
+    let height = match chain_storage.get_height(&best_block) {
+        Some(height) => height,
+        None => return Err(OpError::from(format!("Best block of the chainstate not found in chain storage: {}. \
+                                                  Rerun the scan with --resume to index new blocks.",
+                                                 utils::arr_to_hex_swapped(&best_block))))
+    };
+    try!(fs::rename(dump_folder.join("unspent.csv.tmp"), dump_folder.join(format!("unspent-0-{}.csv", height))));
+
+    info!(target: "chainstate", "Done.\nDumped the chainstate at block {} (height {}):\n\
+                                 \t-> unspent outputs: {:9}\n\
+                                 \t-> total value:     {:9} satoshis",
+          utils::arr_to_hex_swapped(&best_block), height, n_coins, total_value);
+    Ok(())
 }
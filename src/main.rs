@@ -10,8 +10,9 @@ extern crate rustc_serialize;
 extern crate twox_hash;
 extern crate byteorder;
 extern crate rust_base58;
-extern crate csv;
 extern crate seek_bufread;
+extern crate memmap;
+extern crate num_bigint;
 
 #[macro_use]
 pub mod errors;
@@ -22,17 +23,21 @@ pub mod callbacks;
 
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::io::{ErrorKind};
+use std::io::ErrorKind;
 use std::sync::mpsc;
 use std::boxed::Box;
 
-use clap::{Arg, App};
+use clap::{Arg, ArgGroup, ArgMatches, App, SubCommand};
 use log::LogLevelFilter;
 
 use blockchain::parser::chain;
+use blockchain::parser::follow::Follower;
 use blockchain::parser::types::{CoinType, Bitcoin};
+use blockchain::proto::raw::RawBlock;
 use blockchain::utils;
 use blockchain::utils::blkfile::BlkFile;
+use blockchain::utils::dump;
+use blockchain::utils::utxosnapshot::UtxoSnapshot;
 use blockchain::parser::{ParseMode, BlockchainParser};
 use common::logger::SimpleLogger;
 use errors::{OpError, OpErrorKind, OpResult};
@@ -40,10 +45,17 @@ use callbacks::Callback;
 use callbacks::stats::SimpleStats;
 use callbacks::clusterizer::Clusterizer;
 use callbacks::csvdump::CsvDump;
-use callbacks::unspentcsvdump::UnspentCsvDump;
+use callbacks::unspentcsvdump::{self, UnspentCsvDump};
 use callbacks::weakwallets::WeakWallets;
 use callbacks::txoutdump::TxOutDump;
+use callbacks::inscriptions::Inscriptions;
+use callbacks::opreturndump::OpReturnDump;
+use callbacks::addrindex::{self, AddrIndexDump};
+use callbacks::txindex::{self, TxIndexDump};
+use callbacks::balances::Balances;
 
+/// Subcommands which don't need a pass over the blk files, see run_lookup()
+const LOOKUP_SUBCOMMANDS: [&'static str; 4] = ["query", "gettx", "getblock", "chainstate"];
 
 /// Holds all available user arguments
 pub struct ParserOptions {
@@ -51,19 +63,36 @@ pub struct ParserOptions {
     coin_type: CoinType,             /* Holds the name of the coin we want to parse                                                          */
     verify_merkle_root: bool,        /* Enable this if you want to check the merkle root of each block. Aborts if something is fishy.        */
     thread_count: u8,                /* Number of core threads. The callback gets sequentially called!                                       */
-    resume: bool,                    /* Resumes from latest known hash in chain.json.                                                        */
+    resume: bool,                    /* Resumes from latest known hash in chain storage.                                                     */
     reindex: bool,                   /* Forces reindexing                                                                                    */
+    recover: bool,                   /* Skips corrupted or zero-padded regions in blk files instead of aborting                              */
+    undo: bool,                      /* Reads the spent outputs of each block from rev*.dat files                                            */
+    follow: bool,                    /* Keeps running after the initial scan and passes new blocks to the callback                           */
+    confirmations: usize,            /* Number of blocks on top of a followed block before it is passed to the callback                      */
+    utxo_snapshot: Option<PathBuf>,  /* dumptxoutset file to seed the callback with, the scan starts after its block                        */
     blockchain_dir: PathBuf,         /* Path to directory where blk.dat files are stored                                                     */
-    chain_storage_path: PathBuf,     /* Path to the longest-chain.json generated by initial header scan                                      */
+    chain_storage_path: PathBuf,     /* Path to the chain storage generated by initial header scan                                           */
     worker_backlog: usize,           /* Maximum backlog for each thread. If the backlog is full the worker waits until there is some space.  */
                                      /* Usually this happens if the callback implementation is too slow or if we reached the I/O capabilites */
+    checkpoint_blocks: Option<usize>,   /* Flushes the callback and saves the chain storage every N blocks ...                              */
+    checkpoint_interval: Option<u64>,   /* ... or every N seconds during a FullData scan. (See Callback::on_checkpoint)                     */
     log_level_filter: LogLevelFilter /* Verbosity level, 0 = Error, 1 = Info, 2 = Debug, 3+ = Trace                                          */
 }
 
 fn main() {
+    let matches = build_app().get_matches();
+
+    // Lookup subcommands only read previously built indexes or the node's databases
+    if matches.subcommand_name().map_or(false, |name| LOOKUP_SUBCOMMANDS.contains(&name)) {
+        SimpleLogger::init(get_log_level_filter(&matches)).expect("Unable to initialize logger!");
+        if let Some(Err(desc)) = run_lookup(&matches) {
+            error!(target: "main", "{}", desc);
+        }
+        return;
+    }
 
     // Init user args
-    let mut options = match parse_args() {
+    let mut options = match parse_args(&matches) {
         Ok(o) => o,
         Err(desc) => {
             // Init logger to print outstanding error message
@@ -86,7 +115,7 @@ fn main() {
     let iterations = 2;
     for i in 0..iterations {
         // Load chain file into memory
-        let chain_file = match load_chain_file(&options.chain_storage_path) {
+        let mut chain_file = match load_chain_file(&options.chain_storage_path) {
             Ok(f) => f,
             Err(desc) => {
                 error!(target: "main", "Can't load chain storage. {}", desc);
@@ -103,7 +132,7 @@ fn main() {
         // Determine starting location based on previous scans.
         let start_blk_idx = match options.reindex {
             true => 0,
-            false => chain_file.latest_blk_idx - 1
+            false => chain_file.latest_blk_idx.saturating_sub(1)
         };
 
         // Load blk files from blockchain dir
@@ -115,12 +144,43 @@ fn main() {
             }
         };
 
+        if parse_mode == ParseMode::FullData {
+            if let Some(height) = (*options.callback).resume_height() {
+                if height != chain_file.get_cur_height() {
+                    error!(target: "main", "Callback output was saved at block {}, but the chain storage continues at block {}. \
+                                            Resume with the chain storage of that run or start with empty output.",
+                           height, chain_file.get_cur_height());
+                    return;
+                }
+            }
+        }
+        // Recent blocks could still be replaced, the follower processes them once they are confirmed
+        if parse_mode == ParseMode::FullData && options.follow {
+            chain_file.hold_back(options.confirmations);
+        }
+        if parse_mode == ParseMode::FullData {
+            if let Some(max_height) = (*options.callback).max_height() {
+                let len = chain_file.len();
+                chain_file.hold_back(len.saturating_sub(max_height));
+            }
+        }
         if parse_mode == ParseMode::FullData && chain_file.remaining() == 0 {
+            if options.follow {
+                (*options.callback).on_start(options.coin_type.clone(), chain_file.get_cur_height());
+                break;
+            }
             info!("All {} known blocks are processed! Try again with `--resume` to scan for new blocks, or force a full rescan with `--reindex`",
                 chain_file.get_cur_height());
             return;
         }
 
+        if parse_mode == ParseMode::FullData && options.utxo_snapshot.is_some() {
+            if let Err(desc) = load_utxo_snapshot(&mut options, &mut chain_file) {
+                error!(target: "main", "Can't load UTXO snapshot. {}", desc);
+                return;
+            }
+        }
+
         {   // Start parser
             let (tx, rx) = mpsc::sync_channel(options.worker_backlog);
             let mut parser = BlockchainParser::new(
@@ -132,6 +192,7 @@ fn main() {
             // Start threads
             if let Some(err) = parser.start_worker(tx).err() {
                 error!(target: "parser", "{}", err);
+                return;
             }
             // Dispatch thread messages
             if let Some(err) = parser.dispatch(rx).err() {
@@ -151,9 +212,51 @@ fn main() {
             resume = false;
         }
     }
+
+    if options.follow {
+        let chain_file = match load_chain_file(&options.chain_storage_path) {
+            Ok(f) => f,
+            Err(desc) => {
+                error!(target: "main", "Can't load chain storage. {}", desc);
+                return;
+            }
+        };
+        if let Err(err) = Follower::new(&mut options, chain_file).and_then(|mut f| f.run()) {
+            error!(target: "follow", "{}", err);
+            return;
+        }
+    }
     info!(target: "main", "Fin.");
 }
 
+/// Passes the unspent outputs of a `dumptxoutset` file to the callback
+/// and skips all blocks up to the snapshot block
+fn load_utxo_snapshot(options: &mut ParserOptions, chain_file: &mut chain::ChainStorage) -> OpResult<()> {
+    let path = transform!(options.utxo_snapshot.clone());
+    if !(*options.callback).utxo_snapshot() {
+        return Err(OpError::new(OpErrorKind::InvalidArgsError)
+            .join_msg("Callback doesn't track unspent outputs, --utxo-snapshot is not supported."));
+    }
+    if chain_file.get_cur_height() > 0 {
+        return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(&format!(
+            "Blocks up to height {} are already processed. Rerun with --reindex to start from the snapshot.",
+            chain_file.get_cur_height() - 1)));
+    }
+    let snapshot = try!(UtxoSnapshot::open(&path, &options.coin_type));
+    let height = match chain_file.get_height(&snapshot.base_hash) {
+        Some(height) => height,
+        None => return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&format!(
+            "Snapshot block {} is not part of the chain storage.", utils::arr_to_hex_swapped(&snapshot.base_hash))))
+    };
+    info!(target: "main", "Loading {} unspent outputs at height {} from {} ...",
+          snapshot.coins_count, height, path.display());
+    for coin in snapshot {
+        (*options.callback).on_snapshot_coin(try!(coin));
+    }
+    chain_file.skip_to(height + 1);
+    Ok(())
+}
+
 /// Initializes all required data
 fn load_chain_file(path: &Path) -> OpResult<chain::ChainStorage> {
     let err = match chain::ChainStorage::load(path.clone()) {
@@ -161,22 +264,26 @@ fn load_chain_file(path: &Path) -> OpResult<chain::ChainStorage> {
         Err(e) => e
     };
     match err.kind {
-        // If there is no storage, create a new one
-        OpErrorKind::IoError(err) => {
-            match err.kind() {
-                ErrorKind::NotFound => return Ok(chain::ChainStorage::default()),
-                _ => return Err(OpError::from(err)),
+        // If there is no storage, migrate a chain.json from previous versions or create a new one
+        OpErrorKind::IoError(ref io_err) if io_err.kind() == ErrorKind::NotFound => {
+            let legacy_path = path.with_extension("json");
+            if legacy_path.exists() {
+                let storage = try!(chain::ChainStorage::load(&legacy_path));
+                try!(storage.serialize(path));
+                info!(target: "main", "Migrated {} to {}", legacy_path.display(), path.display());
+                return chain::ChainStorage::load(path);
             }
+            Ok(chain::ChainStorage::default())
         }
-        kind @ _ => return Err(OpError::new(kind))
+        _ => Err(err)
     }
 }
 
-/// Parses args or panics if some requirements are not met.
-fn parse_args() -> OpResult<ParserOptions> {
+/// Builds the command line interface with all callbacks and lookup subcommands
+fn build_app<'a, 'b>() -> App<'a, 'b> {
     let coins = &["bitcoin", "testnet3", "namecoin", "litecoin",
                   "dogecoin", "myriadcoin", "unobtanium"];
-    let matches = App::new("Multithreaded Blockchain Parser written in Rust")
+    App::new("Multithreaded Blockchain Parser written in Rust")
         .version(crate_version!())
         .author("gcarq <michael.egger@tsn.at>")
         // Add flags
@@ -192,6 +299,15 @@ fn parse_args() -> OpResult<ParserOptions> {
             .long("reindex")
             .conflicts_with("resume")
             .help("Force complete reindexing"))
+        .arg(Arg::with_name("follow")
+            .long("follow")
+            .help("Keeps running after the scan and processes new blocks of a running node (requires callback support)"))
+        .arg(Arg::with_name("recover")
+            .long("recover")
+            .help("Skips corrupted or zero-padded regions in blk files instead of aborting"))
+        .arg(Arg::with_name("undo")
+            .long("undo")
+            .help("Reads the spent outputs of each block from rev*.dat files (see Block::undo)"))
         .arg(Arg::with_name("verbosity")
             .short("v")
             .multiple(true)
@@ -218,13 +334,34 @@ fn parse_args() -> OpResult<ParserOptions> {
         .arg(Arg::with_name("chain-storage")
             .long("chain-storage")
             .value_name("FILE")
-            .help("Specify path to chain storage. This is just a internal state file (default: chain.json)")
+            .help("Specify path to chain storage. This is just a internal state file (default: chain.dat)")
             .takes_value(true))
         .arg(Arg::with_name("backlog")
             .long("backlog")
             .value_name("COUNT")
             .help("Sets maximum worker backlog (default: 100)")
             .takes_value(true))
+        .arg(Arg::with_name("confirmations")
+            .long("confirmations")
+            .value_name("COUNT")
+            .requires("follow")
+            .help("Number of confirmations before a followed block is processed (default: 6)")
+            .takes_value(true))
+        .arg(Arg::with_name("utxo-snapshot")
+            .long("utxo-snapshot")
+            .value_name("FILE")
+            .help("Starts with the unspent outputs of a `dumptxoutset` file and parses the blocks after it (requires callback support)")
+            .takes_value(true))
+        .arg(Arg::with_name("checkpoint-blocks")
+            .long("checkpoint-blocks")
+            .value_name("COUNT")
+            .help("Saves a checkpoint every COUNT blocks to continue an aborted scan (requires callback support)")
+            .takes_value(true))
+        .arg(Arg::with_name("checkpoint-interval")
+            .long("checkpoint-interval")
+            .value_name("SECS")
+            .help("Saves a checkpoint every SECS seconds to continue an aborted scan (requires callback support)")
+            .takes_value(true))
         // Add callbacks
         .subcommand(UnspentCsvDump::build_subcommand())
         .subcommand(CsvDump::build_subcommand())
@@ -232,27 +369,153 @@ fn parse_args() -> OpResult<ParserOptions> {
         .subcommand(Clusterizer::build_subcommand())
         .subcommand(SimpleStats::build_subcommand())
         .subcommand(WeakWallets::build_subcommand())
-        .get_matches();
+        .subcommand(Inscriptions::build_subcommand())
+        .subcommand(OpReturnDump::build_subcommand())
+        .subcommand(AddrIndexDump::build_subcommand())
+        .subcommand(TxIndexDump::build_subcommand())
+        .subcommand(Balances::build_subcommand())
+        // Add lookup subcommands
+        .subcommand(addrindex::build_query_subcommand())
+        .subcommand(txindex::build_gettx_subcommand())
+        .subcommand(unspentcsvdump::build_chainstate_subcommand())
+        .subcommand(SubCommand::with_name("getblock")
+            .about("Reads a single block from the blk files using the chain storage")
+            .version("0.1")
+            .author("gcarq <michael.egger@tsn.at>")
+            .arg(Arg::with_name("height")
+                .long("height")
+                .value_name("N")
+                .help("Block height")
+                .takes_value(true))
+            .arg(Arg::with_name("hash")
+                .long("hash")
+                .value_name("HASH")
+                .help("Block hash (hex)")
+                .takes_value(true))
+            .group(ArgGroup::with_name("block")
+                .args(&["height", "hash"])
+                .required(true))
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Prints the block as JSON")))
+}
+
+/// Runs lookup subcommands (see LOOKUP_SUBCOMMANDS). Returns None if no lookup subcommand was specified.
+fn run_lookup(matches: &ArgMatches) -> Option<OpResult<()>> {
+    if let Some(ref matches) = matches.subcommand_matches("query") {
+        return Some(addrindex::query(matches));
+    }
+    if let Some(ref sub_matches) = matches.subcommand_matches("gettx") {
+        let coin_type = get_coin_type(matches);
+        return Some(txindex::gettx(sub_matches, &coin_type, &get_blockchain_dir(matches, &coin_type)));
+    }
+    if let Some(ref sub_matches) = matches.subcommand_matches("getblock") {
+        return Some(getblock(matches, sub_matches));
+    }
+    if let Some(ref sub_matches) = matches.subcommand_matches("chainstate") {
+        let coin_type = get_coin_type(matches);
+        let chain_storage_path = Path::new(matches.value_of("chain-storage").unwrap_or("chain.dat"));
+        return Some(unspentcsvdump::dump_chainstate(sub_matches, coin_type.version_id,
+                                                    &get_blockchain_dir(matches, &coin_type), chain_storage_path));
+    }
+    None
+}
+
+/// Looks up the block location in the chain storage and decodes it straight from its blk file
+fn getblock(matches: &ArgMatches, sub_matches: &ArgMatches) -> OpResult<()> {
+    let coin_type = get_coin_type(matches);
+    let blockchain_dir = get_blockchain_dir(matches, &coin_type);
+    let chain_storage_path = Path::new(matches.value_of("chain-storage").unwrap_or("chain.dat"));
+    let chain_storage = match chain::ChainStorage::load(chain_storage_path) {
+        Ok(storage) => storage,
+        Err(e) => return Err(tag_err!(e, "Couldn't load chain storage: `{}`", chain_storage_path.display()))
+    };
+
+    let height = match sub_matches.value_of("hash") {
+        Some(hash) => {
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_digit(16)) {
+                return Err(OpError::from(format!("Invalid block hash: {}", hash)));
+            }
+            match chain_storage.get_height(&utils::hex_to_arr32_swapped(hash)) {
+                Some(height) => height,
+                None => return Err(OpError::from(format!("Block not found in chain storage: {}", hash)))
+            }
+        }
+        None => try!(value_t!(sub_matches, "height", usize)
+            .map_err(|e| OpError::from(e.message)))
+    };
+    let location = match chain_storage.get_location(height) {
+        Some(location) => location,
+        None => return Err(OpError::from(format!("No block at height {} (chain storage has {} blocks)",
+            height, chain_storage.len())))
+    };
+
+    let blk_file = try!(BlkFile::open(&blockchain_dir, location.blk_index));
+    let block = try!(try!(RawBlock::read(&blk_file, location.blk_offset as usize, location.blocksize,
+                                         coin_type.version_id)).parse());
+    if Some(block.header.hash) != chain_storage.get_hash(height) {
+        return Err(OpError::from(format!("Chain storage doesn't match {}. Please rerun with --reindex.",
+            blk_file.path.display())));
+    }
+
+    if sub_matches.is_present("json") {
+        println!("{}", dump::block_to_json(&block, height, coin_type.version_id).pretty());
+    } else {
+        dump::print_block(&block, height, coin_type.version_id);
+    }
+    Ok(())
+}
+
+/// Coin type specified by the user (default: bitcoin)
+fn get_coin_type(matches: &ArgMatches) -> CoinType {
+    value_t!(matches, "coin", CoinType).unwrap_or(CoinType::from(Bitcoin))
+}
+
+/// Blockchain directory specified by the user or the default location of the coin
+fn get_blockchain_dir(matches: &ArgMatches, coin_type: &CoinType) -> PathBuf {
+    match matches.value_of("blockchain-dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => utils::get_absolute_blockchain_dir(coin_type)
+    }
+}
+
+/// Log level based on verbosity
+fn get_log_level_filter(matches: &ArgMatches) -> LogLevelFilter {
+    match matches.occurrences_of("verbosity") {
+        0 => LogLevelFilter::Info,
+        1 => LogLevelFilter::Debug,
+        _ => LogLevelFilter::Trace
+    }
+}
+
+/// Parses args or panics if some requirements are not met.
+fn parse_args(matches: &ArgMatches) -> OpResult<ParserOptions> {
 
     // Set flags
     let verify_merkle_root = matches.is_present("verify-merkle-root");
     let resume = matches.is_present("resume");
     let reindex = matches.is_present("reindex");
-    let log_level_filter = match matches.occurrences_of("verbosity") {
-        0 => LogLevelFilter::Info,
-        1 => LogLevelFilter::Debug,
-        _ => LogLevelFilter::Trace
-    };
+    let recover = matches.is_present("recover");
+    let follow = matches.is_present("follow");
+    let undo = matches.is_present("undo");
+    let log_level_filter = get_log_level_filter(matches);
 
     // Set options
-    let coin_type = value_t!(matches, "coin", CoinType).unwrap_or(CoinType::from(Bitcoin));
-    let mut blockchain_path = utils::get_absolute_blockchain_dir(&coin_type);
-    if matches.value_of("blockchain-dir").is_some() {
-        blockchain_path = PathBuf::from(matches.value_of("blockchain-dir").unwrap());
-    }
+    let coin_type = get_coin_type(matches);
+    let blockchain_path = get_blockchain_dir(matches, &coin_type);
     let thread_count = value_t!(matches, "threads", u8).unwrap_or(2);
-    let chain_storage_path = matches.value_of("chain-storage").unwrap_or("chain.json");
+    let chain_storage_path = matches.value_of("chain-storage").unwrap_or("chain.dat");
     let worker_backlog = value_t!(matches, "backlog", usize).unwrap_or(100);
+    let confirmations = value_t!(matches, "confirmations", usize).unwrap_or(6);
+    let utxo_snapshot = matches.value_of("utxo-snapshot").map(PathBuf::from);
+    let checkpoint_blocks = match matches.is_present("checkpoint-blocks") {
+        true => Some(try!(value_t!(matches, "checkpoint-blocks", usize).map_err(|e| OpError::from(e.message)))),
+        false => None
+    };
+    let checkpoint_interval = match matches.is_present("checkpoint-interval") {
+        true => Some(try!(value_t!(matches, "checkpoint-interval", u64).map_err(|e| OpError::from(e.message)))),
+        false => None
+    };
 
     // Set callback
     let callback: Box<Callback>;
@@ -268,6 +531,16 @@ fn parse_args() -> OpResult<ParserOptions> {
           callback = Box::new(try!(TxOutDump::new(matches)));
     } else if let Some(ref matches) = matches.subcommand_matches("clusterizer") {
          callback = Box::new(try!(Clusterizer::new(matches)));
+    } else if let Some(ref matches) = matches.subcommand_matches("inscriptions") {
+         callback = Box::new(try!(Inscriptions::new(matches)));
+    } else if let Some(ref matches) = matches.subcommand_matches("opreturndump") {
+         callback = Box::new(try!(OpReturnDump::new(matches)));
+    } else if let Some(ref matches) = matches.subcommand_matches("addrindex") {
+         callback = Box::new(try!(AddrIndexDump::new(matches)));
+    } else if let Some(ref matches) = matches.subcommand_matches("txindex") {
+         callback = Box::new(try!(TxIndexDump::new(matches)));
+    } else if let Some(ref matches) = matches.subcommand_matches("balances") {
+         callback = Box::new(try!(Balances::new(matches)));
     } else {
         clap::Error {
             message: String::from("error: No Callback specified.\nFor more information try --help"),
@@ -275,6 +548,18 @@ fn parse_args() -> OpResult<ParserOptions> {
             info: None
         }.exit();
     }
+    if !undo && callback.requires_undo() {
+        return Err(OpError::new(OpErrorKind::InvalidArgsError)
+            .join_msg("Callback resolves spent outputs from the undo data, --undo is required."));
+    }
+    if follow && !callback.checkpoints() {
+        return Err(OpError::new(OpErrorKind::InvalidArgsError)
+            .join_msg("Callback doesn't support checkpoints, they are required by --follow."));
+    }
+    if follow && callback.max_height().is_some() {
+        return Err(OpError::new(OpErrorKind::InvalidArgsError)
+            .join_msg("Callback stops at a max height, it can't be used with --follow."));
+    }
 
     Ok(ParserOptions {
         coin_type: coin_type,
@@ -283,9 +568,16 @@ fn parse_args() -> OpResult<ParserOptions> {
         thread_count: thread_count,
         resume: resume,
         reindex: reindex,
+        recover: recover,
+        undo: undo,
+        follow: follow,
+        confirmations: confirmations,
+        utxo_snapshot: utxo_snapshot,
         blockchain_dir: blockchain_path,
         chain_storage_path: PathBuf::from(chain_storage_path),
         worker_backlog: worker_backlog,
+        checkpoint_blocks: checkpoint_blocks,
+        checkpoint_interval: checkpoint_interval,
         log_level_filter: log_level_filter
     })
 }
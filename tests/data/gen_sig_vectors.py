@@ -0,0 +1,400 @@
+#!/usr/bin/env python3
+"""Generates the signature vectors of script_tests.json.
+
+Follows the TestBuilder of Bitcoin Core's src/test/script_tests.cpp: the same keys, the same
+crediting and spending transactions and signatures with a given R and S length, which are
+ground with the RFC6979 extra entropy. Prints the vectors as JSON lines.
+"""
+
+import hashlib
+import hmac
+import json
+import struct
+
+P = 0xfffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f
+N = 0xfffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141
+G = (0x79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798,
+     0x483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8)
+
+SIGHASH_ALL, SIGHASH_NONE, SIGHASH_SINGLE, SIGHASH_ANYONECANPAY = 1, 2, 3, 0x80
+
+
+def point_add(a, b):
+    if a is None:
+        return b
+    if b is None:
+        return a
+    if a[0] == b[0] and (a[1] + b[1]) % P == 0:
+        return None
+    if a == b:
+        lam = 3 * a[0] * a[0] * pow(2 * a[1], P - 2, P) % P
+    else:
+        lam = (b[1] - a[1]) * pow(b[0] - a[0], P - 2, P) % P
+    x = (lam * lam - a[0] - b[0]) % P
+    return x, (lam * (a[0] - x) - a[1]) % P
+
+
+def point_mul(k, point=G):
+    result = None
+    while k:
+        if k & 1:
+            result = point_add(result, point)
+        point = point_add(point, point)
+        k >>= 1
+    return result
+
+
+def sha256(data):
+    return hashlib.sha256(data).digest()
+
+
+def hash256(data):
+    return sha256(sha256(data))
+
+
+def hash160(data):
+    return hashlib.new('ripemd160', sha256(data)).digest()
+
+
+class Key(object):
+    def __init__(self, secret, compressed):
+        self.secret = secret
+        self.compressed = compressed
+        self.point = point_mul(secret)
+
+    def pubkey(self):
+        x, y = self.point
+        if self.compressed:
+            return bytes([2 + (y & 1)]) + x.to_bytes(32, 'big')
+        return b'\x04' + x.to_bytes(32, 'big') + y.to_bytes(32, 'big')
+
+    def hybrid_pubkey(self):
+        return bytes([6 + (self.point[1] & 1)]) + self.pubkey()[1:]
+
+    def nonce(self, msg, extra):
+        """RFC6979 with HMAC-SHA256, extra entropy is appended to the key material like libsecp256k1"""
+        data = self.secret.to_bytes(32, 'big') + msg + extra
+        v, k = b'\x01' * 32, b'\x00' * 32
+        k = hmac.new(k, v + b'\x00' + data, 'sha256').digest()
+        v = hmac.new(k, v, 'sha256').digest()
+        k = hmac.new(k, v + b'\x01' + data, 'sha256').digest()
+        v = hmac.new(k, v, 'sha256').digest()
+        while True:
+            v = hmac.new(k, v, 'sha256').digest()
+            nonce = int.from_bytes(v, 'big')
+            if 0 < nonce < N:
+                return nonce
+            k = hmac.new(k, v + b'\x00', 'sha256').digest()
+            v = hmac.new(k, v, 'sha256').digest()
+
+    def sign(self, sighash, test_case):
+        extra = struct.pack('<I', test_case) + b'\x00' * 28 if test_case else b''
+        z = int.from_bytes(sighash, 'big') % N
+        k = self.nonce(sighash, extra)
+        r = point_mul(k)[0] % N
+        s = pow(k, N - 2, N) * (z + r * self.secret) % N
+        return r, min(s, N - s)
+
+
+def der_int(value):
+    data = value.to_bytes((value.bit_length() + 7) // 8, 'big')
+    if data[0] & 0x80:
+        data = b'\x00' + data
+    return b'\x02' + bytes([len(data)]) + data
+
+
+def do_sign(key, sighash, len_r=32, len_s=32):
+    """Grinds a signature with the given R and S length, a 33 byte S is a high S value"""
+    test_case = 0
+    while True:
+        r, s = key.sign(sighash, test_case)
+        test_case += 1
+        if len_s == 33:
+            s = N - s
+        r_der, s_der = der_int(r), der_int(s)
+        if len(r_der) - 2 == len_r and len(s_der) - 2 == len_s:
+            body = r_der + s_der
+            return b'\x30' + bytes([len(body)]) + body
+
+
+def var_int(n):
+    if n < 0xfd:
+        return bytes([n])
+    return b'\xfd' + struct.pack('<H', n)
+
+
+def push(data):
+    if len(data) < 0x4c:
+        return bytes([len(data)]) + data
+    if len(data) <= 0xff:
+        return b'\x4c' + bytes([len(data)]) + data
+    return b'\x4d' + struct.pack('<H', len(data)) + data
+
+
+OPS = {0x00: '0', 0x4f: '-1', 0x61: 'NOP', 0x69: 'VERIFY', 0x76: 'DUP', 0x87: 'EQUAL', 0x88: 'EQUALVERIFY',
+       0x91: 'NOT', 0xa9: 'HASH160', 0xac: 'CHECKSIG', 0xad: 'CHECKSIGVERIFY', 0xae: 'CHECKMULTISIG'}
+for n in range(1, 17):
+    OPS[0x50 + n] = str(n)
+
+
+def format_script(script):
+    """Formats a script like Core's FormatScript: pushes as raw hex, other opcodes by name"""
+    tokens, i = [], 0
+    while i < len(script):
+        op = script[i]
+        if 0 < op < 0x4c:
+            tokens.append('0x%02x 0x%s' % (op, script[i + 1:i + 1 + op].hex()))
+            i += 1 + op
+        elif op == 0x4c:
+            n = script[i + 1]
+            tokens.append('0x4c 0x%02x 0x%s' % (n, script[i + 2:i + 2 + n].hex()))
+            i += 2 + n
+        elif op in OPS:
+            tokens.append(OPS[op])
+            i += 1
+        else:
+            raise ValueError('Unsupported opcode %02x' % op)
+    return ' '.join(tokens)
+
+
+def tx_input(txid, index, script_sig):
+    return txid + struct.pack('<I', index) + var_int(len(script_sig)) + script_sig + b'\xff\xff\xff\xff'
+
+
+def tx_output(value, script):
+    return struct.pack('<Q', value) + var_int(len(script)) + script
+
+
+def credit_txid(script_pubkey, amount):
+    tx = struct.pack('<I', 1) + b'\x01' + tx_input(b'\x00' * 32, 0xffffffff, b'\x00\x00')
+    tx += b'\x01' + tx_output(amount, script_pubkey) + struct.pack('<I', 0)
+    return hash256(tx)
+
+
+def sighash_legacy(prevout, script_code, amount, hash_type):
+    base = hash_type & 0x1f
+    if base == SIGHASH_SINGLE:
+        pass    # The spending transaction has one output, so the input always has one
+    tx = struct.pack('<I', 1) + b'\x01' + tx_input(prevout, 0, script_code)
+    if base == SIGHASH_NONE:
+        tx += b'\x00'
+    else:
+        tx += b'\x01' + tx_output(amount, b'')
+    tx += struct.pack('<I', 0) + struct.pack('<I', hash_type)
+    return hash256(tx)
+
+
+def sighash_witness_v0(prevout, script_code, amount, hash_type):
+    base = hash_type & 0x1f
+    anyone_can_pay = hash_type & SIGHASH_ANYONECANPAY
+    outpoint = prevout + struct.pack('<I', 0)
+    hash_prevouts = hash256(outpoint) if not anyone_can_pay else b'\x00' * 32
+    hash_sequence = hash256(b'\xff\xff\xff\xff') if not anyone_can_pay and base not in (SIGHASH_SINGLE, SIGHASH_NONE) \
+        else b'\x00' * 32
+    hash_outputs = hash256(tx_output(amount, b'')) if base != SIGHASH_NONE else b'\x00' * 32
+    data = struct.pack('<I', 1) + hash_prevouts + hash_sequence + outpoint
+    data += var_int(len(script_code)) + script_code + struct.pack('<Q', amount) + b'\xff\xff\xff\xff'
+    data += hash_outputs + struct.pack('<I', 0) + struct.pack('<I', hash_type)
+    return hash256(data)
+
+
+class TestBuilder(object):
+    """Builds a single vector. The signatures commit to the scriptPubKey and the amount,
+    so they are created once both are known."""
+
+    def __init__(self, script, comment, flags, p2sh=False, witness=None, amount=0):
+        self.script = script
+        self.comment = comment
+        self.flags = flags
+        self.amount = amount
+        self.witness_mode = witness
+        self.redeem = None
+        if witness == 'v0' and p2sh:
+            self.redeem = b'\x00\x20' + sha256(script)
+            self.spk = b'\xa9\x14' + hash160(self.redeem) + b'\x87'
+        elif witness == 'v0':
+            self.spk = b'\x00\x20' + sha256(script)
+        elif p2sh:
+            self.redeem = script
+            self.spk = b'\xa9\x14' + hash160(script) + b'\x87'
+        else:
+            self.spk = script
+        self.prevout = credit_txid(self.spk, amount)
+        self.pushes = []
+        self.sig_amount = amount
+
+    def sighash(self, hash_type):
+        if self.witness_mode == 'v0':
+            return sighash_witness_v0(self.prevout, self.script, self.sig_amount, hash_type)
+        return sighash_legacy(self.prevout, self.script, self.amount, hash_type)
+
+    def push_sig(self, key, hash_type=SIGHASH_ALL, len_r=32, len_s=32):
+        self.pushes.append(do_sign(key, self.sighash(hash_type), len_r, len_s) + bytes([hash_type & 0xff]))
+        return self
+
+    def push(self, data):
+        self.pushes.append(data)
+        return self
+
+    def num(self, n):
+        self.pushes.append(None if n == 0 else n)
+        return self
+
+    def edit_push(self, pos, find, replace):
+        last = self.pushes[-1]
+        assert last[pos:pos + len(find)] == find
+        self.pushes[-1] = last[:pos] + replace + last[pos + len(find):]
+        return self
+
+    def damage_push(self, pos):
+        last = bytearray(self.pushes[-1])
+        last[pos] ^= 1
+        self.pushes[-1] = bytes(last)
+        return self
+
+    def set_hash_type(self, hash_type):
+        self.pushes[-1] = self.pushes[-1][:-1] + bytes([hash_type])
+        return self
+
+    def build(self, expected):
+        if self.witness_mode == 'v0':
+            # Numbers on the witness stack are minimally encoded
+            items = [b'' if item is None else bytes([item]) if isinstance(item, int) else item
+                     for item in self.pushes]
+            witness = [i.hex() for i in items] + [self.script.hex()]
+            script_sig = push(self.redeem) if self.redeem else b''
+            return [witness + [self.amount / 1e8], format_script(script_sig), format_script(self.spk),
+                    self.flags, expected, self.comment]
+        script_sig = b''
+        for item in self.pushes:
+            if item is None:
+                script_sig += b'\x00'
+            elif isinstance(item, int):
+                script_sig += bytes([0x50 + item])
+            else:
+                script_sig += push(item)
+        if self.redeem:
+            script_sig += push(self.redeem)
+        return [format_script(script_sig), format_script(self.spk), self.flags, expected, self.comment]
+
+
+class WitnessKeyBuilder(TestBuilder):
+    """P2WPKH, optionally wrapped in P2SH. The script code is the implied P2PKH script."""
+
+    def __init__(self, key, comment, flags, p2sh=False, amount=1):
+        program = b'\x00\x14' + hash160(key.pubkey())
+        self.key = key
+        self.script = b'\x76\xa9\x14' + hash160(key.pubkey()) + b'\x88\xac'
+        self.comment = comment
+        self.flags = flags
+        self.amount = amount
+        self.witness_mode = 'v0'
+        self.redeem = program if p2sh else None
+        self.spk = b'\xa9\x14' + hash160(program) + b'\x87' if p2sh else program
+        self.prevout = credit_txid(self.spk, amount)
+        self.pushes = []
+        self.sig_amount = amount
+
+    def build(self, expected):
+        witness = [i.hex() for i in self.pushes] + [self.key.pubkey().hex()]
+        script_sig = push(self.redeem) if self.redeem else b''
+        return [witness + [self.amount / 1e8], format_script(script_sig), format_script(self.spk),
+                self.flags, expected, self.comment]
+
+
+def p2pk(key):
+    return push(key.pubkey()) + b'\xac'
+
+
+def p2pkh(key):
+    return b'\x76\xa9\x14' + hash160(key.pubkey()) + b'\x88\xac'
+
+
+def multisig(m, keys):
+    return bytes([0x50 + m]) + b''.join(push(k.pubkey()) for k in keys) + bytes([0x50 + len(keys), 0xae])
+
+
+def main():
+    key0, key0c = Key(1, False), Key(1, True)
+    key1, key1c = Key(0x100, False), Key(0x100, True)
+    key2, key2c = Key(0x10000, False), Key(0x10000, True)
+    tests = []
+
+    def add(builder, expected):
+        tests.append(builder.build(expected))
+
+    add(TestBuilder(p2pk(key0), "P2PK", "").push_sig(key0), "OK")
+    add(TestBuilder(p2pk(key0), "P2PK, bad sig", "").push_sig(key0).damage_push(10), "EVAL_FALSE")
+    add(TestBuilder(p2pkh(key1c), "P2PKH", "").push_sig(key1).push(key1c.pubkey()), "OK")
+    add(TestBuilder(p2pkh(key2c), "P2PKH, bad pubkey", "").push_sig(key2).push(key2c.pubkey()).damage_push(5),
+        "EQUALVERIFY")
+    add(TestBuilder(p2pk(key1), "P2PK anyonecanpay", "").push_sig(key1, SIGHASH_ALL | SIGHASH_ANYONECANPAY), "OK")
+    add(TestBuilder(p2pk(key1), "P2PK anyonecanpay marked with normal hashtype", "")
+        .push_sig(key1, SIGHASH_ALL | SIGHASH_ANYONECANPAY).set_hash_type(SIGHASH_ALL), "EVAL_FALSE")
+    add(TestBuilder(p2pk(key0c), "P2PK with SIGHASH_NONE", "").push_sig(key0, SIGHASH_NONE), "OK")
+    add(TestBuilder(p2pk(key0c), "P2PK with SIGHASH_SINGLE", "").push_sig(key0, SIGHASH_SINGLE), "OK")
+    add(TestBuilder(p2pk(key0), "P2SH(P2PK)", "P2SH", p2sh=True).push_sig(key0), "OK")
+    add(TestBuilder(p2pk(key0), "P2SH(P2PK), bad sig", "P2SH", p2sh=True).push_sig(key0).damage_push(10),
+        "EVAL_FALSE")
+    add(TestBuilder(p2pkh(key0), "P2SH(P2PKH)", "P2SH", p2sh=True).push_sig(key0).push(key0.pubkey()), "OK")
+    add(TestBuilder(multisig(3, [key0c, key1c, key2c]), "3-of-3", "")
+        .num(0).push_sig(key0).push_sig(key1).push_sig(key2), "OK")
+    add(TestBuilder(multisig(3, [key0c, key1c, key2c]), "3-of-3, 2 sigs", "")
+        .num(0).push_sig(key0).push_sig(key1).num(0), "EVAL_FALSE")
+    add(TestBuilder(multisig(2, [key0c, key1c, key2c]), "P2SH(2-of-3)", "P2SH", p2sh=True)
+        .num(0).push_sig(key1).push_sig(key2), "OK")
+    add(TestBuilder(multisig(2, [key0c, key1c, key2c]), "P2SH(2-of-3), 1 sig", "P2SH", p2sh=True)
+        .num(0).push_sig(key1).num(0), "EVAL_FALSE")
+    add(TestBuilder(multisig(2, [key0c, key1c, key2c]), "2-of-3 with signatures in the wrong order", "")
+        .num(0).push_sig(key2).push_sig(key1), "EVAL_FALSE")
+    add(TestBuilder(multisig(2, [key0c, key1c, key2c]), "2-of-3 with nonzero dummy but no NULLDUMMY", "")
+        .num(1).push_sig(key1).push_sig(key2), "OK")
+    add(TestBuilder(multisig(2, [key0c, key1c, key2c]), "2-of-3 with nonzero dummy", "NULLDUMMY")
+        .num(1).push_sig(key1).push_sig(key2), "SIG_NULLDUMMY")
+    add(TestBuilder(p2pk(key1), "P2PK with too much R padding but no DERSIG", "")
+        .push_sig(key1, SIGHASH_ALL, 31, 32).edit_push(1, bytes.fromhex("43021f"), bytes.fromhex("44022000")), "OK")
+    add(TestBuilder(p2pk(key1), "P2PK with too much R padding", "DERSIG")
+        .push_sig(key1, SIGHASH_ALL, 31, 32).edit_push(1, bytes.fromhex("43021f"), bytes.fromhex("44022000")), "SIG_DER")
+    add(TestBuilder(p2pk(key2), "P2PK with high S but no LOW_S", "").push_sig(key2, SIGHASH_ALL, 32, 33), "OK")
+    add(TestBuilder(p2pk(key2), "P2PK with high S", "LOW_S").push_sig(key2, SIGHASH_ALL, 32, 33), "SIG_HIGH_S")
+    hybrid = push(key0.hybrid_pubkey()) + b'\xac'
+    add(TestBuilder(hybrid, "P2PK with hybrid pubkey but no STRICTENC", "").push_sig(key0), "OK")
+    add(TestBuilder(hybrid, "P2PK with hybrid pubkey", "STRICTENC").push_sig(key0), "PUBKEYTYPE")
+    add(TestBuilder(p2pk(key1), "P2PK with undefined hashtype but no STRICTENC", "").push_sig(key1, 5), "OK")
+    add(TestBuilder(p2pk(key1), "P2PK with undefined hashtype", "STRICTENC").push_sig(key1, 5), "SIG_HASHTYPE")
+    add(TestBuilder(p2pk(key1) + b'\x91', "P2PK NOT with invalid sig and no NULLFAIL", "")
+        .push_sig(key1).damage_push(10), "OK")
+    add(TestBuilder(p2pk(key1) + b'\x91', "P2PK NOT with invalid sig", "NULLFAIL")
+        .push_sig(key1).damage_push(10), "NULLFAIL")
+
+    add(WitnessKeyBuilder(key0c, "Basic P2WPKH", "P2SH,WITNESS").push_sig(key0), "OK")
+    builder = WitnessKeyBuilder(key0c, "Basic P2WPKH with the wrong value", "P2SH,WITNESS")
+    builder.sig_amount = 0
+    add(builder.push_sig(key0), "EVAL_FALSE")
+    add(WitnessKeyBuilder(key0c, "Basic P2SH(P2WPKH)", "P2SH,WITNESS", p2sh=True).push_sig(key0), "OK")
+    add(WitnessKeyBuilder(key0, "P2WPKH with uncompressed key but no WITNESS_PUBKEYTYPE", "P2SH,WITNESS")
+        .push_sig(key0), "OK")
+    add(WitnessKeyBuilder(key0, "P2WPKH with uncompressed key", "P2SH,WITNESS,WITNESS_PUBKEYTYPE")
+        .push_sig(key0), "WITNESS_PUBKEYTYPE")
+    add(TestBuilder(p2pk(key0c), "Basic P2WSH", "P2SH,WITNESS", witness='v0', amount=1).push_sig(key0), "OK")
+    builder = TestBuilder(p2pk(key0c), "Basic P2WSH with the wrong value", "P2SH,WITNESS", witness='v0', amount=1)
+    builder.sig_amount = 0
+    add(builder.push_sig(key0), "EVAL_FALSE")
+    add(TestBuilder(p2pk(key0c), "Basic P2SH(P2WSH)", "P2SH,WITNESS", p2sh=True, witness='v0', amount=1)
+        .push_sig(key0), "OK")
+    add(TestBuilder(multisig(2, [key0c, key1c]), "P2WSH 2-of-2 with SIGHASH_SINGLE|ANYONECANPAY", "P2SH,WITNESS",
+                    witness='v0', amount=1)
+        .num(0).push_sig(key0, SIGHASH_SINGLE | SIGHASH_ANYONECANPAY).push_sig(key1, SIGHASH_NONE), "OK")
+    add(TestBuilder(multisig(1, [key0, key1c]), "P2WSH CHECKMULTISIG with uncompressed key",
+                    "P2SH,WITNESS,WITNESS_PUBKEYTYPE", witness='v0', amount=1)
+        .num(0).push_sig(key0), "WITNESS_PUBKEYTYPE")
+    add(TestBuilder(multisig(1, [key0, key1c]), "P2WSH CHECKMULTISIG with uncompressed key but no WITNESS_PUBKEYTYPE",
+                    "P2SH,WITNESS", witness='v0', amount=1)
+        .num(0).push_sig(key0), "OK")
+
+    for test in tests:
+        print(json.dumps(test) + ',')
+
+
+if __name__ == '__main__':
+    main()